@@ -0,0 +1,143 @@
+/* Compiling grammars ahead of time, from a build script. The grammar is read and
+ * checked when your crate builds, and what comes out is Rust source that puts the
+ * parser together directly, so programs with big grammars don't spend their startup
+ * parsing and checking them.
+ *
+ *     // build.rs
+ *     fn main() {
+ *         let out_dir = std::env::var("OUT_DIR").unwrap();
+ *         parsley_codegen::compile("grammar.psl", out_dir).expect("Grammar is ok");
+ *     }
+ *
+ *     // main.rs
+ *     mod grammar {
+ *         include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
+ *     }
+ *     let parser = grammar::parser();
+ *
+ * Literals are turned into terminals by the token type, so the token type has to be
+ * known up front. compile picks LexedToken for grammars with @token rules and CharToken
+ * otherwise. For a token type of your own, use compile_for. Terminals that need
+ * Parser::register_terminal can't be compiled, since the closures only exist at runtime. */
+
+use parsley::compiled::{compile_definition_file, CompiledGrammar, CompiledLexer, RuleExpression};
+use parsley::{CharToken, DefinitionError, LexedToken, Token};
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+
+/* Compiles the grammar file (and anything it imports) into out_dir, as a file with the
+ * same name ending in .rs, holding `pub fn parser() -> Parser<...>`. Gives the path of
+ * that file. Also tells cargo to rerun the build script when any of the grammar's
+ * files change. */
+pub fn compile(grammar: impl AsRef<Path>, out_dir: impl AsRef<Path>) -> Result<PathBuf, DefinitionError> {
+    let (compiled, files) = compile_definition_file::<LexedToken>(grammar.as_ref())?;
+    if compiled.lexer.is_some() {
+        write_parser(grammar.as_ref(), out_dir.as_ref(), &compiled, &files, "::parsley::LexedToken")
+    }
+    else {
+        let (compiled, files) = compile_definition_file::<CharToken>(grammar.as_ref())?;
+        write_parser(grammar.as_ref(), out_dir.as_ref(), &compiled, &files, "::parsley::CharToken")
+    }
+}
+
+/* Like compile, for parsers of T. The generated code names T by token_type, which is
+ * a path to it from wherever the file is included, like "crate::lexer::Token". */
+pub fn compile_for<T: Token>(grammar: impl AsRef<Path>, out_dir: impl AsRef<Path>, token_type: &str) -> Result<PathBuf, DefinitionError> {
+    let (compiled, files) = compile_definition_file::<T>(grammar.as_ref())?;
+    write_parser(grammar.as_ref(), out_dir.as_ref(), &compiled, &files, token_type)
+}
+
+/* Private Implementation */
+
+fn write_parser(grammar: &Path, out_dir: &Path, compiled: &CompiledGrammar, files: &[PathBuf], token_type: &str) -> Result<PathBuf, DefinitionError> {
+    let file_name = grammar.file_stem().ok_or_else(|| error(format!("{} is not a file", grammar.display())))?;
+    let path = out_dir.join(file_name).with_extension("rs");
+
+    let source_name = grammar.file_name().unwrap_or(file_name).to_string_lossy();
+    std::fs::write(&path, generate_parser(compiled, &source_name, token_type))
+        .map_err(|err| error(format!("Cannot write {}: {err}", path.display())))?;
+
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+    Ok(path)
+}
+
+// The Rust source for a compiled grammar, see compile.
+fn generate_parser(compiled: &CompiledGrammar, source_name: &str, token_type: &str) -> String {
+    let CompiledGrammar { rules, externs, labels, sync_tokens, lexer, .. } = compiled;
+
+    let mut code = format!("// Generated by parsley_codegen from {source_name}, edits will be lost.\n\n");
+    writeln!(code, "pub fn parser() -> ::parsley::Parser<{token_type}> {{").expect("Infallible");
+    code += "    #[allow(unused_imports)]\n";
+    code += "    use ::parsley::compiled::{CharacterClass as Class, CompiledGrammar, CompiledLexer, RuleExpression::*};\n\n";
+    code += "    let grammar = CompiledGrammar {\n";
+    writeln!(code, "        rules: {},", rule_list(rules, 2)).expect("Infallible");
+    writeln!(code, "        externs: vec![{}],", externs.iter().map(|name| format!("{name:?}.into()")).collect::<Vec<_>>().join(", ")).expect("Infallible");
+    writeln!(code, "        labels: {},", pair_list(labels)).expect("Infallible");
+    writeln!(code, "        sync_tokens: {},", rule_list(sync_tokens, 2)).expect("Infallible");
+    code += "        locations: vec![],\n";  // They point into the definition, which the generated code doesn't come with.
+    match lexer {
+        Some(CompiledLexer { rules, token_rules, labels }) => {
+            code += "        lexer: Some(CompiledLexer {\n";
+            writeln!(code, "            rules: {},", rule_list(rules, 3)).expect("Infallible");
+            let token_rules = token_rules.iter().map(|(name, skip)| format!("({name:?}.into(), {skip})")).collect::<Vec<_>>();
+            writeln!(code, "            token_rules: vec![{}],", token_rules.join(", ")).expect("Infallible");
+            writeln!(code, "            labels: {},", pair_list(labels)).expect("Infallible");
+            code += "        }),\n";
+        }
+        None => code += "        lexer: None,\n",
+    }
+    code += "    };\n";
+    code += "    ::parsley::Parser::from_compiled(grammar).expect(\"The grammar was checked when it was compiled\")\n";
+    code += "}\n";
+    code
+}
+
+fn error(message: String) -> DefinitionError {
+    DefinitionError { message, location: None, rule_name: None, snippet: None }
+}
+
+// A vec! of (name, expression) pairs, one per line at the given level of indentation.
+fn rule_list(rules: &[(String, RuleExpression)], indent: usize) -> String {
+    if rules.is_empty() {
+        return "vec![]".to_string();
+    }
+
+    let mut list = "vec![\n".to_string();
+    for (name, expr) in rules {
+        writeln!(list, "{}({name:?}.into(), {}),", "    ".repeat(indent + 1), expression(expr)).expect("Infallible");
+    }
+    list + &"    ".repeat(indent) + "]"
+}
+
+fn pair_list(pairs: &[(String, String)]) -> String {
+    format!("vec![{}]", pairs.iter().map(|(a, b)| format!("({a:?}.into(), {b:?}.into())")).collect::<Vec<_>>().join(", "))
+}
+
+// Code that makes the expression, with the variants of RuleExpression in scope.
+fn expression(expr: &RuleExpression) -> String {
+    let list = |exprs: &[RuleExpression]| exprs.iter().map(expression).collect::<Vec<_>>().join(", ");
+    match expr {
+        RuleExpression::Terminal(terminal) => format!("Terminal({terminal:?}.into())"),
+        RuleExpression::RuleName(name) => format!("RuleName({name:?}.into())"),
+        RuleExpression::Concatenation(exprs) => format!("Concatenation(vec![{}])", list(exprs)),
+        RuleExpression::Alternatives(exprs) => format!("Alternatives(vec![{}])", list(exprs)),
+        RuleExpression::OrderedAlternatives(exprs) => format!("OrderedAlternatives(vec![{}])", list(exprs)),
+        RuleExpression::Optional(expr) => format!("Optional(Box::new({}))", expression(expr)),
+        RuleExpression::OneOrMore(expr) => format!("OneOrMore(Box::new({}))", expression(expr)),
+        RuleExpression::Many(expr) => format!("Many(Box::new({}))", expression(expr)),
+        RuleExpression::CharacterClass(class) 
+            => format!("CharacterClass(Class::from_ranges({:?}, &{:?}, {}))", class.source, class.ranges(), class.is_negated()),
+        RuleExpression::Negation(expr, description) => format!("Negation(Box::new({}), {description:?}.into())", expression(expr)),
+        RuleExpression::Wildcard => "Wildcard".to_string(),
+        RuleExpression::EndOfInput => "EndOfInput".to_string(),
+        RuleExpression::Repetition(expr, min, max) => format!("Repetition(Box::new({}), {min}, {max:?})", expression(expr)),
+        RuleExpression::PositiveLookahead(expr) => format!("PositiveLookahead(Box::new({}))", expression(expr)),
+        RuleExpression::NegativeLookahead(expr, description) 
+            => format!("NegativeLookahead(Box::new({}), {description:?}.into())", expression(expr)),
+        RuleExpression::External(name) => format!("External({name:?}.into())"),
+    }
+}
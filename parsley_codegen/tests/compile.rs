@@ -0,0 +1,56 @@
+use parsley::{CharToken, LexedToken, Parser};
+
+use std::path::Path;
+
+// The generated code, checked in so that the test below notices when it changes.
+#[path = "compiled/calculator.rs"]
+mod calculator;
+#[path = "compiled/words.rs"]
+mod words;
+
+
+fn grammar(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/grammars").join(name)
+}
+
+#[test]
+fn generated_code() {
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    for (name, expected) in [
+        ("calculator", include_str!("compiled/calculator.rs")),
+        ("words", include_str!("compiled/words.rs")),
+    ] {
+        let path = parsley_codegen::compile(grammar(&format!("{name}.psl")), out_dir).expect("Compiles");
+        assert_eq!(path, out_dir.join(format!("{name}.rs")));
+        assert_eq!(std::fs::read_to_string(path).expect("Written"), expected, "{name}");
+    }
+}
+
+#[test]
+fn compiled_parsers() {
+    let defined: Parser<LexedToken> = parsley::define_parser_from_file(grammar("calculator.psl")).expect("Parser definition ok");
+    let compiled = calculator::parser();
+    for input in ["x = 1 + 2.5 * f(3, y);", "1 - 2 - 3; g();", "x = ;", "(1"] {
+        let tree = |parser: &Parser<LexedToken>| match parser.tokenize(input) {
+            Ok(tokens) => parser.parse_tokens(&tokens, "Program").map_or_else(|err| err.to_string(), |tree| tree.to_string()),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(tree(&compiled), tree(&defined), "{input}");
+    }
+    assert_eq!(compiled.label("Statement"), Some("a statement"));
+
+    let defined: Parser<CharToken> = parsley::define_parser_from_file(grammar("words.psl")).expect("Parser definition ok");
+    let compiled = words::parser();
+    for input in ["Compiled parsers work.", "Twice  spaced"] {
+        let tree = |parser: &Parser<CharToken>| parser.parse_string(input, "Sentence")
+            .map_or_else(|err| err.to_string(), |tree| tree.to_string());
+        assert_eq!(tree(&compiled), tree(&defined), "{input}");
+    }
+}
+
+#[test]
+fn errors() {
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let err = parsley_codegen::compile(grammar("missing.psl"), out_dir).expect_err("No such file");
+    assert!(err.message.starts_with("Cannot read"));
+}
@@ -0,0 +1,34 @@
+// Generated by parsley_codegen from calculator.psl, edits will be lost.
+
+pub fn parser() -> ::parsley::Parser<::parsley::LexedToken> {
+    #[allow(unused_imports)]
+    use ::parsley::compiled::{CharacterClass as Class, CompiledGrammar, CompiledLexer, RuleExpression::*};
+
+    let grammar = CompiledGrammar {
+        rules: vec![
+            ("Atom".into(), Alternatives(vec![Terminal("Number".into()), Concatenation(vec![Terminal("Name".into()), NegativeLookahead(Box::new(Terminal("\"(\"".into())), "!\"(\"".into())]), Concatenation(vec![Terminal("Name".into()), Terminal("\"(\"".into()), Optional(Box::new(Concatenation(vec![RuleName("Expr".into()), Many(Box::new(Concatenation(vec![Terminal("\",\"".into()), RuleName("Expr".into())])))]))), Terminal("\")\"".into())]), Concatenation(vec![Terminal("\"(\"".into()), RuleName("Expr".into()), Terminal("\")\"".into())])])),
+            ("Expr".into(), OrderedAlternatives(vec![Concatenation(vec![RuleName("Expr".into()), CharacterClass(Class::from_ranges("[+\\-]", &[('+', '+'), ('-', '-')], false)), RuleName("Term".into())]), RuleName("Term".into())])),
+            ("Program".into(), Concatenation(vec![Many(Box::new(Concatenation(vec![RuleName("Statement".into()), Terminal("\";\"".into())]))), EndOfInput])),
+            ("Statement".into(), Alternatives(vec![Concatenation(vec![Terminal("Name".into()), Terminal("\"=\"".into()), RuleName("Expr".into())]), RuleName("Expr".into())])),
+            ("Term".into(), Alternatives(vec![Concatenation(vec![RuleName("Term".into()), Alternatives(vec![Terminal("\"*\"".into()), Terminal("\"/\"".into())]), RuleName("Atom".into())]), RuleName("Atom".into())])),
+        ],
+        externs: vec![],
+        labels: vec![("Statement".into(), "a statement".into())],
+        sync_tokens: vec![
+            ("Statement".into(), Terminal("\";\"".into())),
+        ],
+        locations: vec![],
+        lexer: Some(CompiledLexer {
+            rules: vec![
+                ("Digit".into(), CharacterClass(Class::from_ranges("[0-9]", &[('0', '9')], false))),
+                ("Name".into(), Concatenation(vec![CharacterClass(Class::from_ranges("[a-zA-Z_]", &[('A', 'Z'), ('_', '_'), ('a', 'z')], false)), Many(Box::new(CharacterClass(Class::from_ranges("[a-zA-Z_0-9]", &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], false))))])),
+                ("Number".into(), Concatenation(vec![OneOrMore(Box::new(RuleName("Digit".into()))), Optional(Box::new(Concatenation(vec![Terminal(".".into()), Repetition(Box::new(RuleName("Digit".into())), 1, None)])))])),
+                ("Punctuation".into(), CharacterClass(Class::from_ranges("[=;,()+\\-*/]", &[('(', '-'), ('/', '/'), (';', ';'), ('=', '=')], false))),
+                ("Space".into(), OneOrMore(Box::new(CharacterClass(Class::from_ranges("[ \\t\\n]", &[('\t', '\n'), (' ', ' ')], false))))),
+            ],
+            token_rules: vec![("Space".into(), true), ("Number".into(), false), ("Name".into(), false), ("Punctuation".into(), false)],
+            labels: vec![("Number".into(), "a number".into())],
+        }),
+    };
+    ::parsley::Parser::from_compiled(grammar).expect("The grammar was checked when it was compiled")
+}
@@ -0,0 +1,19 @@
+// Generated by parsley_codegen from words.psl, edits will be lost.
+
+pub fn parser() -> ::parsley::Parser<::parsley::CharToken> {
+    #[allow(unused_imports)]
+    use ::parsley::compiled::{CharacterClass as Class, CompiledGrammar, CompiledLexer, RuleExpression::*};
+
+    let grammar = CompiledGrammar {
+        rules: vec![
+            ("Sentence".into(), Concatenation(vec![RuleName("Word".into()), Many(Box::new(Concatenation(vec![Terminal(" ".into()), RuleName("Word".into())]))), Optional(Box::new(Terminal(".".into())))])),
+            ("Word".into(), OneOrMore(Box::new(CharacterClass(Class::from_ranges("[^ .]", &[(' ', ' '), ('.', '.')], true))))),
+        ],
+        externs: vec![],
+        labels: vec![],
+        sync_tokens: vec![],
+        locations: vec![],
+        lexer: None,
+    };
+    ::parsley::Parser::from_compiled(grammar).expect("The grammar was checked when it was compiled")
+}
@@ -0,0 +1,339 @@
+/* #[derive(FromSyntaxTree)], for structs and enums. The generated code leans on
+ * parsley::FieldCursor for the actual work, so all this does is pick apart the type
+ * and say which field takes what.
+ *
+ * Impls are for every token type with Display, rather than bounding on the field
+ * types, since a recursive type's bounds would need themselves to hold first. */
+
+use super::{compile_error, parse_name_attribute};
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+use std::fmt::Write;
+
+
+pub fn derive(input: TokenStream) -> TokenStream {
+    let result = parse_type(input).and_then(|parsed| match parsed {
+        Parsed::Struct { name, rule, fields } => Ok(implement_struct(&name, rule.as_deref().unwrap_or(&name), &fields)),
+        Parsed::Enum { name, rule, variants } => implement_enum(&name, rule.as_deref(), &variants),
+    });
+
+    match result {
+        Ok(code) => code.parse().expect("Valid tokens"),
+        Err(message) => compile_error(&message),
+    }
+}
+
+
+/* Private Implementation */
+
+enum Parsed {
+    Struct { name: String, rule: Option<String>, fields: Fields },
+    Enum { name: String, rule: Option<String>, variants: Vec<Variant> },
+}
+
+enum Fields {
+    Named (Vec<Field>),
+    Tuple (Vec<Field>),
+    Unit,
+}
+
+struct Field {
+    name: String,  // The index, for tuple fields.
+    ty: String,
+    filter: String,  // Code for the parsley::FieldFilter.
+}
+
+struct Variant {
+    ident: String,
+    rule: String,
+    fields: Fields,
+}
+
+fn parse_type(input: TokenStream) -> Result<Parsed, String> {
+    let mut trees = input.into_iter().peekable();
+    let mut rule = None;
+
+    // Attributes, visibility and anything else in front of the struct or enum keyword.
+    let keyword = loop {
+        match trees.next() {
+            Some(TokenTree::Punct(punct)) if punct.as_char() == '#' => {
+                if let Some(TokenTree::Group(attribute)) = trees.next() {
+                    set_once(&mut rule, parse_name_attribute(attribute.stream(), "rule")?, "rule")?;
+                }
+            }
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "struct" || ident.to_string() == "enum" => break ident.to_string(),
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "union" => {
+                return Err("FromSyntaxTree can't be derived for unions".to_string());
+            }
+            Some(_) => (),
+            None => return Err("Expected a struct or an enum".to_string()),
+        }
+    };
+
+    let Some(TokenTree::Ident(name)) = trees.next() else {
+        return Err(format!("Expected the name of the {keyword}"));
+    };
+    let name = name.to_string();
+
+    match (keyword.as_str(), trees.next()) {
+        (_, Some(TokenTree::Punct(punct))) if punct.as_char() == '<' =>
+            Err("FromSyntaxTree can't be derived for generic types".to_string()),
+        ("struct", Some(TokenTree::Group(body))) if body.delimiter() == Delimiter::Brace =>
+            Ok(Parsed::Struct { name, rule, fields: Fields::Named(parse_fields(body.stream(), true)?) }),
+        ("struct", Some(TokenTree::Group(body))) if body.delimiter() == Delimiter::Parenthesis =>
+            Ok(Parsed::Struct { name, rule, fields: Fields::Tuple(parse_fields(body.stream(), false)?) }),
+        ("struct", Some(TokenTree::Punct(punct))) if punct.as_char() == ';' =>
+            Ok(Parsed::Struct { name, rule, fields: Fields::Unit }),
+        ("enum", Some(TokenTree::Group(body))) if body.delimiter() == Delimiter::Brace =>
+            Ok(Parsed::Enum { name, rule, variants: parse_variants(body.stream())? }),
+        _ => Err(format!("Expected the body of the {keyword}")),
+    }
+}
+
+// Splits on commas outside of angle brackets. Commas inside other brackets are already in groups.
+fn split_commas(body: TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut items = vec![vec![]];
+    let mut depth = 0;
+    for tree in body {
+        match &tree {
+            TokenTree::Punct(punct) if punct.as_char() == ',' && depth == 0 => {
+                items.push(vec![]);
+                continue;
+            }
+            TokenTree::Punct(punct) if punct.as_char() == '<' => depth += 1,
+            TokenTree::Punct(punct) if punct.as_char() == '>' && depth > 0 => depth -= 1,
+            _ => (),
+        }
+        items.last_mut().expect("Never empty").push(tree);
+    }
+
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+fn parse_fields(body: TokenStream, named: bool) -> Result<Vec<Field>, String> {
+    let mut fields = vec![];
+    for (index, item) in split_commas(body).into_iter().enumerate() {
+        let mut trees = item.into_iter().peekable();
+        let mut rule = None;
+        let mut token = None;
+
+        // Attributes and visibility.
+        while let Some(tree) = trees.peek() {
+            match tree {
+                TokenTree::Punct(punct) if punct.as_char() == '#' => {
+                    trees.next();
+                    if let Some(TokenTree::Group(attribute)) = trees.next() {
+                        set_once(&mut rule, parse_name_attribute(attribute.stream(), "rule")?, "rule")?;
+                        set_once(&mut token, parse_name_attribute(attribute.stream(), "token")?, "token")?;
+                    }
+                }
+                TokenTree::Ident(ident) if ident.to_string() == "pub" => {
+                    trees.next();
+                    if matches!(trees.peek(), Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis) {
+                        trees.next();
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        let name = if named {
+            let Some(TokenTree::Ident(name)) = trees.next() else {
+                return Err("Expected a field name".to_string());
+            };
+            match trees.next() {
+                Some(TokenTree::Punct(punct)) if punct.as_char() == ':' => (),
+                _ => return Err(format!("Expected : after field {name}")),
+            }
+            name.to_string()
+        }
+        else {
+            index.to_string()
+        };
+
+        let filter = match (rule, token) {
+            (Some(_), Some(_)) => return Err(format!("Field {name} can't have both #[rule] and #[token]")),
+            (Some(rule), None) => format!("::parsley::FieldFilter::Rule(::std::option::Option::Some({rule:?}))"),
+            (None, Some(token)) => format!("::parsley::FieldFilter::Token({token:?})"),
+            (None, None) => "::parsley::FieldFilter::Rule(::std::option::Option::None)".to_string(),
+        };
+        let ty = trees.collect::<TokenStream>().to_string();
+        if ty.is_empty() {
+            return Err(format!("Expected a type for field {name}"));
+        }
+        fields.push(Field { name, ty, filter });
+    }
+
+    Ok(fields)
+}
+
+fn parse_variants(body: TokenStream) -> Result<Vec<Variant>, String> {
+    let mut variants = vec![];
+    for item in split_commas(body) {
+        let mut rule = None;
+        let mut ident = None;
+        let mut fields = Fields::Unit;
+
+        let mut trees = item.into_iter();
+        while let Some(tree) = trees.next() {
+            match tree {
+                TokenTree::Punct(punct) if punct.as_char() == '#' && ident.is_none() => {
+                    if let Some(TokenTree::Group(attribute)) = trees.next() {
+                        set_once(&mut rule, parse_name_attribute(attribute.stream(), "rule")?, "rule")?;
+                    }
+                }
+                TokenTree::Ident(name) if ident.is_none() => ident = Some(name.to_string()),
+                TokenTree::Group(group) if ident.is_some() && group.delimiter() == Delimiter::Brace =>
+                    fields = Fields::Named(parse_fields(group.stream(), true)?),
+                TokenTree::Group(group) if ident.is_some() && group.delimiter() == Delimiter::Parenthesis =>
+                    fields = Fields::Tuple(parse_fields(group.stream(), false)?),
+                _ => break,  // A discriminant.
+            }
+        }
+
+        let ident = ident.ok_or("Expected a variant")?;
+        let rule = rule.unwrap_or_else(|| ident.clone());
+        variants.push(Variant { ident, rule, fields });
+    }
+
+    Ok(variants)
+}
+
+fn set_once(slot: &mut Option<String>, value: Option<String>, attribute_name: &str) -> Result<(), String> {
+    if let Some(value) = value {
+        if slot.replace(value).is_some() {
+            return Err(format!("Only one #[{attribute_name}] attribute is allowed here"));
+        }
+    }
+    Ok(())
+}
+
+// The type inside Vec<...> or Option<...>, if the field is one.
+fn strip_wrapper<'t>(ty: &'t str, wrapper: &str) -> Option<&'t str> {
+    ty.strip_prefix(wrapper)?.trim_start().strip_prefix('<')?.strip_suffix('>').map(str::trim)
+}
+
+// An expression that builds the fields from `fields`, a FieldCursor, like `Name { a: ..., b: ... }`.
+fn construct(path: &str, fields: &Fields) -> String {
+    let take = |field: &Field| {
+        let Field { name, ty, filter } = field;
+        if let Some(inner) = strip_wrapper(ty, "Vec") {
+            format!("fields.many::<{inner}>({filter})?")
+        }
+        else if let Some(inner) = strip_wrapper(ty, "Option") {
+            format!("fields.optional::<{inner}>({filter})?")
+        }
+        else {
+            format!("fields.one::<{ty}>({name:?}, {filter})?")
+        }
+    };
+
+    match fields {
+        Fields::Named(fields) => {
+            let values = fields.iter().fold(String::new(), |mut values, field| {
+                write!(values, "{}: {},", field.name, take(field)).expect("Infallible");
+                values
+            });
+            format!("{path} {{ {values} }}")
+        }
+        Fields::Tuple(fields) => {
+            let values = fields.iter().fold(String::new(), |mut values, field| {
+                write!(values, "{},", take(field)).expect("Infallible");
+                values
+            });
+            format!("{path}({values})")
+        }
+        Fields::Unit => path.to_string(),
+    }
+}
+
+fn implement_struct(name: &str, rule: &str, fields: &Fields) -> String {
+    let value = construct(name, fields);
+
+    format!("
+        impl<T: ::parsley::Token + ::std::fmt::Display> ::parsley::FromSyntaxTree<T> for {name} {{
+            fn from_syntax_tree(tree: &::parsley::SyntaxTree<T>) -> ::std::result::Result<Self, ::parsley::ShapeError> {{
+                #[allow(unused_mut)]
+                let mut fields = ::parsley::FieldCursor::new(tree, {name:?}, {rule:?})?;
+                let value = {value};
+                fields.finish()?;
+                ::std::result::Result::Ok(value)
+            }}
+
+            fn accepts(tree: &::parsley::SyntaxTree<T>) -> bool {{
+                tree.rule_name() == ::std::option::Option::Some({rule:?})
+            }}
+
+            fn describe() -> ::std::string::String {{
+                ::std::string::String::from({rule:?})
+            }}
+        }}
+    ")
+}
+
+fn implement_enum(name: &str, rule: Option<&str>, variants: &[Variant]) -> Result<String, String> {
+    if variants.is_empty() {
+        return Err("FromSyntaxTree can't be derived for enums without variants".to_string());
+    }
+
+    let look_through = match rule {
+        Some(rule) => format!("let tree = ::parsley::FieldCursor::look_through(tree, {rule:?})?;"),
+        None => String::new(),
+    };
+
+    let mut attempts = String::new();
+    for Variant { ident, rule, fields } in variants {
+        let value = construct(&format!("{name}::{ident}"), fields);
+        let type_name = format!("{name}::{ident}");
+        write!(attempts, "
+            if tree.rule_name() == ::std::option::Option::Some({rule:?}) {{
+                let attempt = || -> ::std::result::Result<Self, ::parsley::ShapeError> {{
+                    #[allow(unused_mut)]
+                    let mut fields = ::parsley::FieldCursor::new(tree, {type_name:?}, {rule:?})?;
+                    let value = {value};
+                    fields.finish()?;
+                    ::std::result::Result::Ok(value)
+                }};
+                match attempt() {{
+                    ::std::result::Result::Ok(value) => return ::std::result::Result::Ok(value),
+                    ::std::result::Result::Err(err) => error = ::std::option::Option::Some(err),
+                }}
+            }}
+        ").expect("Infallible");
+    }
+
+    let mut rules = variants.iter().map(|variant| variant.rule.as_str()).collect::<Vec<_>>();
+    rules.dedup();
+    let expected = rules.join(" or ");
+    let patterns = rules.iter().map(|rule| format!("{rule:?}")).collect::<Vec<_>>().join(" | ");
+    let accepts = match rule {
+        Some(rule) => format!("
+            match ::parsley::FieldCursor::look_through(tree, {rule:?}) {{
+                ::std::result::Result::Ok(tree) => ::std::matches!(tree.rule_name(), ::std::option::Option::Some({patterns})),
+                ::std::result::Result::Err(_) => false,
+            }}
+        "),
+        None => format!("::std::matches!(tree.rule_name(), ::std::option::Option::Some({patterns}))"),
+    };
+
+    Ok(format!("
+        impl<T: ::parsley::Token + ::std::fmt::Display> ::parsley::FromSyntaxTree<T> for {name} {{
+            fn from_syntax_tree(tree: &::parsley::SyntaxTree<T>) -> ::std::result::Result<Self, ::parsley::ShapeError> {{
+                {look_through}
+                let mut error = ::std::option::Option::None;
+                {attempts}
+                ::std::result::Result::Err(error.unwrap_or_else(|| ::parsley::unexpected_shape(tree, {expected:?})))
+            }}
+
+            fn accepts(tree: &::parsley::SyntaxTree<T>) -> bool {{
+                {accepts}
+            }}
+
+            fn describe() -> ::std::string::String {{
+                ::std::string::String::from({expected:?})
+            }}
+        }}
+    "))
+}
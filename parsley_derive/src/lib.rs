@@ -0,0 +1,185 @@
+/* #[derive(ParsleyToken)] for token enums, and #[derive(FromSyntaxTree)] (in
+ * from_tree.rs). Use them through parsley, which re-exports them.
+ *
+ * There's no syn or quote here, the input is simple enough to pick apart by hand.
+ * For ParsleyToken, all we need are the enum's name, and the name and
+ * #[token("...")] attribute of each variant. Fields are never looked at, since
+ * `Enum::Variant { .. }` matches unit, tuple and struct variants alike. */
+
+mod from_tree;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use std::fmt::Write;
+
+
+/* Implements parsley::Token for an enum. A terminal `_Name` in the grammar matches
+ * tokens of the variant called Name, or of the variant marked #[token("Name")].
+ * Several variants can share a name, and then the terminal matches any of them.
+ * Terminals naming no variant make the definition fail. */
+#[proc_macro_derive(ParsleyToken, attributes(token))]
+pub fn derive_parsley_token(input: TokenStream) -> TokenStream {
+    match parse_enum(input) {
+        Ok((name, variants)) => implement_token(&name, &variants),
+        Err(message) => compile_error(&message),
+    }
+}
+
+
+/* Implements parsley::FromSyntaxTree for a struct or enum, building it from the
+ * node of the rule it is named after (or the rule given by #[rule("Name")]), with
+ * its fields filled from the node's children in order. See parsley::FromSyntaxTree. */
+#[proc_macro_derive(FromSyntaxTree, attributes(rule, token))]
+pub fn derive_from_syntax_tree(input: TokenStream) -> TokenStream {
+    from_tree::derive(input)
+}
+
+
+/* Private Implementation */
+
+struct Variant {
+    ident: String,
+    token_type: String,
+}
+
+fn parse_enum(input: TokenStream) -> Result<(String, Vec<Variant>), String> {
+    let mut trees = input.into_iter();
+
+    // Attributes, visibility and anything else in front of the enum keyword.
+    loop {
+        match trees.next() {
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "enum" => break,
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "struct" || ident.to_string() == "union" => {
+                return Err("ParsleyToken can only be derived for enums".to_string());
+            }
+            Some(_) => (),
+            None => return Err("Expected an enum".to_string()),
+        }
+    }
+
+    let Some(TokenTree::Ident(name)) = trees.next() else {
+        return Err("Expected the name of the enum".to_string());
+    };
+
+    match trees.next() {
+        Some(TokenTree::Group(body)) if body.delimiter() == Delimiter::Brace => {
+            Ok((name.to_string(), parse_variants(body.stream())?))
+        }
+        Some(TokenTree::Punct(punct)) if punct.as_char() == '<' => {
+            Err("ParsleyToken can't be derived for generic enums".to_string())
+        }
+        _ => Err("Expected the body of the enum".to_string()),
+    }
+}
+
+fn parse_variants(body: TokenStream) -> Result<Vec<Variant>, String> {
+    let mut variants = vec![];
+    let mut rename = None;
+    let mut ident = None;
+
+    let mut trees = body.into_iter();
+    while let Some(tree) = trees.next() {
+        match tree {
+            TokenTree::Punct(punct) if punct.as_char() == '#' && ident.is_none() => {
+                let Some(TokenTree::Group(attribute)) = trees.next() else {
+                    return Err("Expected an attribute after #".to_string());
+                };
+                if let Some(token_type) = parse_name_attribute(attribute.stream(), "token")? {
+                    if rename.replace(token_type).is_some() {
+                        return Err("A variant can only have one #[token] attribute".to_string());
+                    }
+                }
+            }
+            TokenTree::Ident(name) if ident.is_none() => ident = Some(name.to_string()),
+            TokenTree::Punct(punct) if punct.as_char() == ',' => {
+                let ident = ident.take().ok_or("Expected a variant before ,")?;
+                let token_type = rename.take().unwrap_or_else(|| ident.clone());
+                variants.push(Variant { ident, token_type });
+            }
+            _ => (),  // Fields and discriminants.
+        }
+    }
+
+    if let Some(ident) = ident {
+        let token_type = rename.unwrap_or_else(|| ident.clone());
+        variants.push(Variant { ident, token_type });
+    }
+
+    Ok(variants)
+}
+
+// Returns the name from #[attribute_name("Name")], or None for any other attribute.
+fn parse_name_attribute(attribute: TokenStream, attribute_name: &str) -> Result<Option<String>, String> {
+    let mut trees = attribute.into_iter();
+    match trees.next() {
+        Some(TokenTree::Ident(ident)) if ident.to_string() == attribute_name => (),
+        _ => return Ok(None),
+    }
+
+    let bad_attribute = || format!("Expected #[{attribute_name}(\"Name\")]");
+    let Some(TokenTree::Group(arguments)) = trees.next() else {
+        return Err(bad_attribute());
+    };
+
+    let mut arguments = arguments.stream().into_iter();
+    match (arguments.next(), arguments.next()) {
+        (Some(TokenTree::Literal(literal)), None) => {
+            // Escapes would need unescaping, and token names shouldn't need any.
+            let literal = literal.to_string();
+            match literal.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+                Some(name) if !name.contains('\\') => Ok(Some(name.to_string())),
+                _ => Err(bad_attribute()),
+            }
+        }
+        _ => Err(bad_attribute()),
+    }
+}
+
+// `compile_error!("message");`, put together by hand so that no message can fail to parse.
+fn compile_error(message: &str) -> TokenStream {
+    TokenStream::from_iter([
+        TokenTree::Ident(Ident::new("compile_error", Span::call_site())),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(Delimiter::Parenthesis, TokenTree::Literal(Literal::string(message)).into())),
+        TokenTree::Punct(Punct::new(';', Spacing::Alone)),
+    ])
+}
+
+fn implement_token(name: &str, variants: &[Variant]) -> TokenStream {
+    let mut token_types = variants.iter().map(|variant| &variant.token_type).collect::<Vec<_>>();
+    token_types.sort();
+    token_types.dedup();
+
+    // Each token type's kind is its position in token_types.
+    let mut kinds = String::new();
+    let mut arms = String::new();
+    for (kind, token_type) in token_types.iter().enumerate() {
+        let patterns = variants.iter()
+            .filter(|variant| variant.token_type == **token_type)
+            .map(|variant| format!("{name}::{} {{ .. }}", variant.ident))
+            .collect::<Vec<String>>()
+            .join(" | ");
+        writeln!(kinds, "{token_type:?} => ::std::option::Option::Some({kind}),").expect("Infallible");
+        writeln!(arms, "{kind} => ::std::matches!(token, {patterns}),").expect("Infallible");
+    }
+
+    format!("
+        impl ::parsley::Token for {name} {{
+            type Kind = usize;
+
+            fn kind(token_type: &str) -> ::std::option::Option<usize> {{
+                match token_type {{
+                    {kinds}
+                    _ => ::std::option::Option::None,
+                }}
+            }}
+
+            fn matches(kind: &usize, token: &Self) -> bool {{
+                match kind {{
+                    {arms}
+                    _ => false,
+                }}
+            }}
+        }}
+    ").parse().expect("Valid tokens")
+}
@@ -0,0 +1,418 @@
+/* Reads grammars written in ABNF (RFC 5234), the notation most RFCs use, so that a
+ * protocol can be parsed straight from the grammar in its spec. Covers all of ABNF,
+ * including incremental alternatives (`rule =/ more`), except prose values like
+ * `<some description>`, which have to be written out as rules first. Like in ABNF
+ * itself, rule names and quoted strings ignore case, and the core rules from
+ * appendix B (ALPHA, DIGIT, CRLF, ...) can be used without defining them. */
+
+use crate::define::{build_parser, case_insensitive_literal_to_combination, literal_to_combination};
+use crate::define::{CharacterClass, CompiledGrammar, DefinitionError, RuleExpression};
+use crate::{Parser, Token};
+
+use std::collections::HashMap;
+
+
+/* Public Interface */
+
+/* Makes a parser from an ABNF grammar. Rules keep the names they are defined with, so
+ * the grammar from RFC 3986 gives a parser where `parse_string(uri, "URI")` works. */
+pub fn define_parser_from_abnf<T: Token>(definition: &str) -> Result<Parser<T>, DefinitionError> {
+    let mut rules = read_rules::<T>(definition, &core_rules::<T>())?;
+    rules.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    build_parser(CompiledGrammar { rules, externs: vec![], labels: vec![], sync_tokens: vec![], locations: vec![], lexer: None }, HashMap::new())
+}
+
+
+/* Private Implementation */
+
+// RFC 5234, appendix B.1. Only the ones a grammar uses end up in its parser.
+const CORE_RULES: &str = r#"
+ALPHA = %x41-5A / %x61-7A
+BIT = "0" / "1"
+CHAR = %x01-7F
+CR = %x0D
+CRLF = CR LF
+CTL = %x00-1F / %x7F
+DIGIT = %x30-39
+DQUOTE = %x22
+HEXDIG = DIGIT / "A" / "B" / "C" / "D" / "E" / "F"
+HTAB = %x09
+LF = %x0A
+LWSP = *(WSP / CRLF WSP)
+OCTET = %x00-FF
+SP = %x20
+VCHAR = %x21-7E
+WSP = SP / HTAB
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AbnfToken {
+    Name (String),
+    Defined,  // =
+    Incremental,  // =/
+    Slash,
+    LeftParenthesis,
+    RightParenthesis,
+    LeftBracket,
+    RightBracket,
+    Repeat (usize, Option<usize>),  // Like 1*, *3 or 2, in front of an element.
+    Text (String, bool),  // A quoted string, and whether it is case sensitive.
+    Characters (Vec<char>),  // Like %x0D.0A
+    Range (char, char, String),  // Like %x41-5A, with the source text.
+}
+
+// A token with where it starts, and whether it is at the very start of a line, which is what begins a rule.
+struct Located {
+    token: AbnfToken,
+    offset: usize,
+    starts_line: bool,
+}
+
+fn core_rules<T: Token>() -> HashMap<String, RuleExpression> {
+    read_rules::<T>(CORE_RULES, &HashMap::new())
+        .expect("Core rules are valid")
+        .into_iter()
+        .collect()
+}
+
+/* Reads every rule in the definition, along with any core rules they use. Names are
+ * looked up ignoring case, but each rule keeps the spelling it was first defined with. */
+fn read_rules<T: Token>(definition: &str, core: &HashMap<String, RuleExpression>) -> Result<Vec<(String, RuleExpression)>, DefinitionError> {
+    let tokens = tokenize(definition)?;
+
+    let mut statements: Vec<&[Located]> = vec![];
+    let mut start = 0;
+    for i in 1..=tokens.len() {
+        if i == tokens.len() || tokens[i].starts_line {
+            statements.push(&tokens[start..i]);
+            start = i;
+        }
+    }
+
+    let mut names = HashMap::new();
+    for statement in &statements {
+        match statement {
+            [Located { token: AbnfToken::Name(name), starts_line: true, .. }, Located { token: AbnfToken::Defined | AbnfToken::Incremental, .. }, ..] => {
+                names.entry(name.to_ascii_lowercase()).or_insert_with(|| name.clone());
+            }
+            [first, ..] => return Err(DefinitionError::new(
+                "Expected a rule, like `name = elements`, at the start of the line".to_string()
+            ).locate(definition, first.offset, None)),
+            [] => (),
+        }
+    }
+
+    let mut rules: Vec<(String, RuleExpression)> = vec![];
+    let mut indices = HashMap::new();
+    let mut used_core = vec![];
+    for statement in statements.into_iter().filter(|statement| !statement.is_empty()) {
+        let AbnfToken::Name(name) = &statement[0].token else { unreachable!("Checked above") };
+        let name = &names[&name.to_ascii_lowercase()];
+        let located_error = |message: String, offset: usize| DefinitionError::new(message).locate(definition, offset, Some(name));
+
+        let mut reader = Reader { tokens: &statement[2..], position: 0, names: &names, core, used_core: &mut used_core, end: statement[statement.len() - 1].offset };
+        let expr = reader.alternation::<T>().map_err(|(message, offset)| located_error(message, offset))?;
+        if let Some(extra) = reader.tokens.get(reader.position) {
+            return Err(located_error("Unexpected token in rule".to_string(), extra.offset));
+        }
+
+        match (&statement[1].token, indices.get(name).copied()) {
+            (AbnfToken::Defined, None) => {
+                indices.insert(name.clone(), rules.len());
+                rules.push((name.clone(), expr));
+            }
+            (AbnfToken::Defined, Some(_)) => return Err(located_error(
+                format!("Rule \"{name}\" is defined more than once, use =/ to add alternatives to it"), statement[0].offset
+            )),
+            (_, None) => return Err(located_error(
+                format!("Cannot add alternatives to rule \"{name}\", it is not defined before this"), statement[0].offset
+            )),
+            (_, Some(index)) => {
+                let base = &mut rules[index].1;
+                let mut alternatives = match std::mem::replace(base, RuleExpression::Wildcard) {
+                    RuleExpression::Alternatives(exprs) => exprs,
+                    expr => vec![expr],
+                };
+                match expr {
+                    RuleExpression::Alternatives(exprs) => alternatives.extend(exprs),
+                    expr => alternatives.push(expr),
+                }
+                *base = RuleExpression::Alternatives(alternatives);
+            }
+        }
+    }
+
+    // Core rules can use other core rules, so keep going until nothing new is needed.
+    while let Some(core_name) = used_core.pop() {
+        if indices.contains_key(&core_name) {
+            continue;
+        }
+        let expr = core[&core_name].clone();
+        let mut referenced = vec![];
+        expr.referenced_rules(&mut referenced);
+        used_core.extend(referenced.into_iter().map(ToString::to_string));
+
+        indices.insert(core_name.clone(), rules.len());
+        rules.push((core_name, expr));
+    }
+
+    Ok(rules)
+}
+
+// Reads the elements of one rule, with errors giving a message and the offset to point at.
+struct Reader<'a> {
+    tokens: &'a [Located],
+    position: usize,
+    names: &'a HashMap<String, String>,
+    core: &'a HashMap<String, RuleExpression>,
+    used_core: &'a mut Vec<String>,
+    end: usize,  // Where to point when the rule ends too soon, at its last token.
+}
+
+impl Reader<'_> {
+    fn peek(&self) -> Option<&AbnfToken> {
+        self.tokens.get(self.position).map(|located| &located.token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end, |located| located.offset)
+    }
+
+    fn alternation<T: Token>(&mut self) -> Result<RuleExpression, (String, usize)> {
+        let mut alternatives = vec![self.concatenation::<T>()?];
+        while self.peek() == Some(&AbnfToken::Slash) {
+            self.position += 1;
+            alternatives.push(self.concatenation::<T>()?);
+        }
+        Ok(if alternatives.len() == 1 { alternatives.remove(0) } else { RuleExpression::Alternatives(alternatives) })
+    }
+
+    fn concatenation<T: Token>(&mut self) -> Result<RuleExpression, (String, usize)> {
+        let mut elements = vec![];
+        while !matches!(self.peek(), None | Some(AbnfToken::Slash | AbnfToken::RightParenthesis | AbnfToken::RightBracket)) {
+            elements.push(self.repetition::<T>()?);
+        }
+        match elements.len() {
+            0 => Err(("Expected an element".to_string(), self.offset())),
+            1 => Ok(elements.remove(0)),
+            _ => Ok(RuleExpression::Concatenation(elements)),
+        }
+    }
+
+    fn repetition<T: Token>(&mut self) -> Result<RuleExpression, (String, usize)> {
+        let Some(&AbnfToken::Repeat(min, max)) = self.peek() else {
+            return self.element::<T>();
+        };
+        let offset = self.offset();
+        self.position += 1;
+        if max.is_some_and(|max| max < min) {
+            return Err(("Repetition has a maximum below its minimum".to_string(), offset));
+        }
+
+        let expr = Box::new(self.element::<T>()?);
+        Ok(match (min, max) {
+            (1, Some(1)) => *expr,
+            (0, Some(1)) => RuleExpression::Optional(expr),
+            (0, None) => RuleExpression::Many(expr),
+            (1, None) => RuleExpression::OneOrMore(expr),
+            (min, max) => RuleExpression::Repetition(expr, min, max),
+        })
+    }
+
+    fn element<T: Token>(&mut self) -> Result<RuleExpression, (String, usize)> {
+        let offset = self.offset();
+        let Some(token) = self.peek().cloned() else {
+            return Err(("Expected an element".to_string(), offset));
+        };
+        self.position += 1;
+
+        let literal = |result: Result<RuleExpression, DefinitionError>| result.map_err(|err| (err.message, offset));
+        match token {
+            AbnfToken::Name(name) => {
+                let lowercase = name.to_ascii_lowercase();
+                if let Some(defined) = self.names.get(&lowercase) {
+                    return Ok(RuleExpression::RuleName(defined.into()));
+                }
+                let core_name = name.to_ascii_uppercase();
+                if self.core.contains_key(&core_name) {
+                    self.used_core.push(core_name.clone());
+                    return Ok(RuleExpression::RuleName(core_name.into()));
+                }
+                Err((format!("Rule \"{name}\" is not defined"), offset))
+            }
+            AbnfToken::LeftParenthesis | AbnfToken::LeftBracket => {
+                let expr = self.alternation::<T>()?;
+                let close = if token == AbnfToken::LeftParenthesis { AbnfToken::RightParenthesis } else { AbnfToken::RightBracket };
+                if self.peek() != Some(&close) {
+                    return Err((format!("Expected {}", if token == AbnfToken::LeftParenthesis { ")" } else { "]" }), self.offset()));
+                }
+                self.position += 1;
+                Ok(if token == AbnfToken::LeftParenthesis { expr } else { RuleExpression::Optional(Box::new(expr)) })
+            }
+            AbnfToken::Text(text, true) => literal(literal_to_combination::<T>(&text)),
+            // Case doesn't matter for most strings in ABNF, which are mostly punctuation.
+            AbnfToken::Text(text, false) if text.chars().all(|ch| ch.to_lowercase().eq(ch.to_uppercase())) => literal(literal_to_combination::<T>(&text)),
+            AbnfToken::Text(text, false) => literal(case_insensitive_literal_to_combination::<T>(&text)),
+            AbnfToken::Characters(chars) => literal(literal_to_combination::<T>(&chars.into_iter().collect::<String>())),
+            AbnfToken::Range(start, end, source) => Ok(RuleExpression::CharacterClass(CharacterClass::from_ranges(&source, &[(start, end)], false))),
+            AbnfToken::Repeat(..) => Err(("Expected an element after the repetition".to_string(), offset)),
+            AbnfToken::Defined | AbnfToken::Incremental | AbnfToken::Slash | AbnfToken::RightParenthesis | AbnfToken::RightBracket
+                => Err(("Expected an element".to_string(), offset)),
+        }
+    }
+}
+
+/* Splits the definition into tokens, dropping whitespace and comments (from ';' to
+ * the end of the line). A rule continues onto every following line that is indented. */
+fn tokenize(definition: &str) -> Result<Vec<Located>, DefinitionError> {
+    let mut tokens = vec![];
+    let mut chars = definition.char_indices().peekable();
+    let error = |message: &str, offset: usize| Err(DefinitionError::new(message.to_string()).locate(definition, offset, None));
+
+    while let Some((offset, ch)) = chars.next() {
+        let starts_line = offset == 0 || definition[..offset].ends_with('\n');
+        let token = match ch {
+            _ if ch.is_whitespace() => continue,
+            ';' => {
+                while chars.next_if(|(_, ch)| *ch != '\n').is_some() {}
+                continue;
+            }
+            '=' if chars.next_if(|(_, ch)| *ch == '/').is_some() => AbnfToken::Incremental,
+            '=' => AbnfToken::Defined,
+            '/' => AbnfToken::Slash,
+            '(' => AbnfToken::LeftParenthesis,
+            ')' => AbnfToken::RightParenthesis,
+            '[' => AbnfToken::LeftBracket,
+            ']' => AbnfToken::RightBracket,
+            '<' => return error("Prose values can't be turned into rules, write this one out in ABNF", offset),
+            '"' => match read_text(&mut chars) {
+                Some(text) => AbnfToken::Text(text, false),
+                None => return error("Unterminated string", offset),
+            },
+            _ if ch.is_ascii_alphabetic() => {
+                let mut name = ch.to_string();
+                while let Some((_, ch)) = chars.next_if(|(_, ch)| ch.is_ascii_alphanumeric() || *ch == '-' || *ch == '_') {
+                    name.push(ch);
+                }
+                AbnfToken::Name(name)
+            }
+            _ if ch.is_ascii_digit() || ch == '*' => {
+                let mut source = ch.to_string();
+                while let Some((_, ch)) = chars.next_if(|(_, ch)| ch.is_ascii_digit() || *ch == '*') {
+                    source.push(ch);
+                }
+                let bound = |digits: &str, default: Option<usize>| if digits.is_empty() { Ok(default) } else { digits.parse().map(Some) };
+                let repeat = match source.split_once('*') {
+                    None => source.parse().map(|count| (count, Some(count))),
+                    Some((min, max)) => bound(min, Some(0)).and_then(|min| Ok((min.unwrap_or(0), bound(max, None)?))),
+                };
+                match repeat {
+                    Ok((min, max)) => AbnfToken::Repeat(min, max),
+                    Err(_) => return error("Bad repetition, expected something like 1*3, *2 or 4", offset),
+                }
+            }
+            '%' => match chars.next().map(|(_, ch)| ch.to_ascii_lowercase()) {
+                Some(case @ ('s' | 'i')) => match chars.next() {
+                    Some((_, '"')) => match read_text(&mut chars) {
+                        Some(text) => AbnfToken::Text(text, case == 's'),
+                        None => return error("Unterminated string", offset),
+                    },
+                    _ => return error("Expected a string after %s or %i", offset),
+                },
+                Some(base @ ('b' | 'd' | 'x')) => {
+                    let mut source = format!("%{base}");
+                    while let Some((_, ch)) = chars.next_if(|(_, ch)| ch.is_ascii_alphanumeric() || *ch == '.' || *ch == '-') {
+                        source.push(ch);
+                    }
+                    match read_value(&source) {
+                        Some(token) => token,
+                        None => return error("Bad numeric value, expected something like %x41, %x41-5A or %d13.10", offset),
+                    }
+                }
+                _ => return error("Expected %b, %d, %x, %s or %i", offset),
+            },
+            _ => return error(&format!("Unexpected character '{ch}'"), offset),
+        };
+        tokens.push(Located { token, offset, starts_line });
+    }
+
+    Ok(tokens)
+}
+
+// Reads the rest of a quoted string, which has no escapes in ABNF.
+fn read_text(chars: &mut impl Iterator<Item = (usize, char)>) -> Option<String> {
+    let mut text = String::new();
+    for (_, ch) in chars {
+        if ch == '"' {
+            return Some(text);
+        }
+        text.push(ch);
+    }
+    None
+}
+
+// Reads a numeric value like %x41-5A or %d13.10, including the % and base.
+fn read_value(source: &str) -> Option<AbnfToken> {
+    let radix = match &source[..2] {
+        "%b" => 2,
+        "%d" => 10,
+        _ => 16,
+    };
+    let value = |digits: &str| u32::from_str_radix(digits, radix).ok().and_then(char::from_u32);
+
+    let digits = &source[2..];
+    if let Some((start, end)) = digits.split_once('-') {
+        let (start, end) = (value(start)?, value(end)?);
+        return (start <= end).then(|| AbnfToken::Range(start, end, source.to_string()));
+    }
+    digits.split('.').map(value).collect::<Option<Vec<char>>>().map(AbnfToken::Characters)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::CharToken;
+
+    #[test]
+    fn test_abnf() {
+        let parser: Parser<CharToken> = define_parser_from_abnf(r#"
+; Something like a header from RFC 5322
+header = field-name ":" value CRLF
+Field-Name = 1*ftext
+ftext = %x21-39 / %x3B-7E
+value = 1*( %x61-7A / WSP )
+value =/ %s"Empty" / %d60.62   ; Case matters for these
+        "#).expect("Parser definition ok");
+
+        assert!(parser.parse_string("Subject: hello there\r\n", "header").is_ok());
+        assert!(parser.parse_string("X-Empty:<>\r\n", "header").is_ok());
+        assert!(parser.parse_string("X-Empty:Empty\r\n", "header").is_ok());
+        assert!(parser.parse_string("X-Empty:EMPTY\r\n", "header").is_err());
+        assert!(parser.parse_string("Subject hello\r\n", "header").is_err());
+        assert!(parser.parse_string("Subject: hello\n", "header").is_err());
+        assert!(parser.rules.contains_key("Field-Name") && !parser.rules.contains_key("field-name"));
+        assert!(parser.rules.contains_key("CR") && !parser.rules.contains_key("DIGIT"));
+
+        let parser: Parser<CharToken> = define_parser_from_abnf("word = 2*3\"ab\" / 1%x61\n").expect("Parser definition ok");
+        for (input, ok) in [("abab", true), ("ABaBab", true), ("ab", false), ("abababab", false), ("a", true), ("A", false)] {
+            assert_eq!(parser.parse_string(input, "word").is_ok(), ok, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_abnf_errors() {
+        let error = |definition| define_parser_from_abnf::<CharToken>(definition).map(|_| ()).expect_err("Invalid definition").to_string();
+
+        assert_eq!(error("a = b\n"), "Rule \"b\" is not defined at line 1, column 5 (in rule \"a\")\n    a = b\n        ^");
+        assert_eq!(error("a = \"x\"\nA = \"y\"\n"), "Rule \"a\" is defined more than once, use =/ to add alternatives to it at line 2, column 1 (in rule \"a\")\n    A = \"y\"\n    ^");
+        assert_eq!(error("a =/ \"x\"\n").lines().next(), Some("Cannot add alternatives to rule \"a\", it is not defined before this at line 1, column 1 (in rule \"a\")"));
+        assert_eq!(error("a = <anything>\n").lines().next(), Some("Prose values can't be turned into rules, write this one out in ABNF at line 1, column 5"));
+        assert_eq!(error("a = 3*2\"x\"\n").lines().next(), Some("Repetition has a maximum below its minimum at line 1, column 5 (in rule \"a\")"));
+        assert_eq!(error("a = (\"x\"\n").lines().next(), Some("Expected ) at line 1, column 6 (in rule \"a\")"));
+        assert_eq!(error("a = %x5A-41\n").lines().next(), Some("Bad numeric value, expected something like %x41, %x41-5A or %d13.10 at line 1, column 5"));
+        assert_eq!(error("  a = \"x\"\n").lines().next(), Some("Expected a rule, like `name = elements`, at the start of the line at line 1, column 3"));
+    }
+}
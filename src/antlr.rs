@@ -0,0 +1,592 @@
+/* Reads the common subset of ANTLR 4 grammars (.g4 files), so the many grammars
+ * already written for ANTLR can be reused. Parser rules, lexer rules (including
+ * fragments, and ones sent to `skip` or another channel), alternatives, the EBNF
+ * operators, ranges like 'a'..'z', sets like [a-z] and ~ all carry over, and labels
+ * (`x=expr`, `# Alternative`) are accepted but dropped, since trees don't have them.
+ *
+ * Anything that needs ANTLR's runtime, like actions, semantic predicates, lexer modes,
+ * options and rule arguments, is rejected with the position of the construct. Note
+ * that ANTLR resolves ambiguous alternatives by their order, and Parsley doesn't. */
+
+use crate::define::{build_parser, compile_definition, describe_expression, describe_single_token, literal_to_combination};
+use crate::define::{CharacterClass, CollectedDefinition, DefinitionError, RuleDefinition, RuleExpression, RuleKind, RuleMode};
+use crate::{CharToken, Parser, Token};
+
+use itertools::Itertools;
+
+use std::collections::{HashMap, HashSet};
+
+
+/* Public Interface */
+
+/* Makes a parser from an ANTLR grammar. Literals used in parser rules get their own
+ * tokens, like they would in ANTLR, so combined grammars work over LexedTokens. For
+ * a parser grammar, with no lexer rules, token names are matched with T::kind. */
+pub fn define_parser_from_antlr<T: Token>(definition: &str) -> Result<Parser<T>, DefinitionError> {
+    let tokens = tokenize(definition)?;
+    let mut reader = Reader { definition, tokens: &tokens, position: 0, rule_name: None, skip: false, literals: vec![] };
+
+    let mut rules = vec![];
+    while let Some(token) = reader.peek() {
+        match token {
+            G4Token::Identifier(keyword) if keyword == "grammar"
+                || (keyword == "lexer" || keyword == "parser") && matches!(reader.peek_at(1), Some(G4Token::Identifier(next)) if next == "grammar")
+                => reader.header()?,
+            G4Token::Identifier(keyword) if keyword == "tokens" && reader.peek_at(1) == Some(&G4Token::Action) => reader.position += 2,
+            G4Token::Identifier(keyword) if keyword == "options" || keyword == "channels" || keyword == "import" || keyword == "mode" =>
+                return reader.error(&format!("{keyword} isn't supported"), reader.offset()),
+            G4Token::At => return reader.error("Named actions aren't supported", reader.offset()),
+            G4Token::Identifier(_) => rules.push(reader.rule::<T>()?),
+            _ => return reader.error("Expected a rule", reader.offset()),
+        }
+    }
+
+    // Token names with no lexer rule come from the token type instead.
+    let lexical_names = rules.iter().filter(|rule| rule.kind != RuleKind::Syntactic).map(|rule| rule.name.clone()).collect::<HashSet<_>>();
+    let mut referenced = vec![];
+    for rule in rules.iter().filter(|rule| rule.kind == RuleKind::Syntactic) {
+        rule.expr.referenced_rules(&mut referenced);
+    }
+    let token_kinds = referenced.into_iter()
+        .filter(|name| is_lexical(name) && !lexical_names.contains(*name))
+        .map(ToString::to_string)
+        .collect::<HashSet<_>>();
+    for rule in rules.iter_mut().filter(|rule| rule.kind == RuleKind::Syntactic) {
+        rule.expr.replace_token_references(&token_kinds);
+    }
+
+    // ANTLR makes a token for each literal in the parser rules, which wins over the other lexer rules.
+    if !lexical_names.is_empty() {
+        let mut implicit = vec![];
+        for literal in reader.literals.iter().unique() {
+            let expr = literal_to_combination::<CharToken>(literal)?;
+            if !rules.iter().any(|rule| rule.kind == RuleKind::Token && rule.expr == expr) {
+                implicit.push(rule_definition(RuleKind::Token, format!("T__{}", implicit.len()), expr));
+            }
+        }
+        rules.splice(0..0, implicit);
+    }
+
+    build_parser(compile_definition(CollectedDefinition { rules, externs: vec![], sync_tokens: vec![] })?, HashMap::new())
+}
+
+
+/* Private Implementation */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum G4Token {
+    Identifier (String),
+    Literal (String),  // With the escapes already replaced.
+    CharacterSet (Vec<(char, char)>, String),  // Like [a-z\n], with the source text.
+    Colon,
+    Semicolon,
+    Bar,
+    LeftParenthesis,
+    RightParenthesis,
+    QuestionMark,
+    Star,
+    Plus,
+    Tilde,
+    Dot,
+    Range,  // ..
+    Assign,  // =
+    PlusAssign,  // +=
+    Hash,
+    Arrow,  // ->
+    Comma,
+    Action,  // Anything in braces.
+    ElementOptions,  // Anything in angle brackets, like <assoc=right>.
+    At,
+}
+
+// An element of an alternative, before non-greedy loops are turned into something Parsley has.
+enum Element {
+    Plain (RuleExpression),
+    NonGreedy (RuleExpression, bool, usize),  // The repeated expression, whether it needs at least one, and its offset.
+}
+
+struct Reader<'a> {
+    definition: &'a str,
+    tokens: &'a [(G4Token, usize)],
+    position: usize,
+    rule_name: Option<String>,  // The rule being read, for errors.
+    skip: bool,  // Whether the lexer rule being read sends its tokens to `skip` or another channel.
+    literals: Vec<String>,  // Every literal in the parser rules, in order.
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<&'a G4Token> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, distance: usize) -> Option<&'a G4Token> {
+        self.tokens.get(self.position + distance).map(|(token, _)| token)
+    }
+
+    // Where the next token starts, or the last one if there are no more.
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position.min(self.tokens.len().saturating_sub(1))).map_or(0, |(_, offset)| *offset)
+    }
+
+    fn error<X>(&self, message: &str, offset: usize) -> Result<X, DefinitionError> {
+        Err(DefinitionError::new(message.to_string()).locate(self.definition, offset, self.rule_name.as_deref()))
+    }
+
+    fn expect(&mut self, token: &G4Token, description: &str) -> Result<(), DefinitionError> {
+        if self.peek() != Some(token) {
+            return self.error(&format!("Expected {description}"), self.offset());
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    // Reads `grammar Name;`, maybe with lexer or parser in front.
+    fn header(&mut self) -> Result<(), DefinitionError> {
+        if matches!(self.peek(), Some(G4Token::Identifier(keyword)) if keyword != "grammar") {
+            self.position += 1;
+        }
+        self.expect(&G4Token::Identifier("grammar".to_string()), "grammar")?;
+        match self.peek() {
+            Some(G4Token::Identifier(_)) => self.position += 1,
+            _ => return self.error("Expected the grammar's name", self.offset()),
+        }
+        self.expect(&G4Token::Semicolon, ";")
+    }
+
+    fn rule<T: Token>(&mut self) -> Result<RuleDefinition, DefinitionError> {
+        let fragment = self.peek() == Some(&G4Token::Identifier("fragment".to_string()));
+        if fragment {
+            self.position += 1;
+        }
+        let Some(G4Token::Identifier(name)) = self.peek().cloned() else {
+            return self.error("Expected a rule name", self.offset());
+        };
+        self.position += 1;
+        self.rule_name = Some(name.clone());
+        self.skip = false;
+
+        match self.peek() {
+            Some(G4Token::CharacterSet(..)) => return self.error("Rule arguments aren't supported", self.offset()),
+            Some(G4Token::Identifier(keyword)) => return self.error(&format!("{keyword} isn't supported"), self.offset()),
+            Some(G4Token::At) => return self.error("Named actions aren't supported", self.offset()),
+            _ => (),
+        }
+        self.expect(&G4Token::Colon, ":")?;
+
+        let (kind, expr) = if is_lexical(&name) {
+            let expr = self.alternatives::<CharToken>(true)?;
+            (if fragment { RuleKind::Fragment } else if self.skip { RuleKind::Skip } else { RuleKind::Token }, expr)
+        }
+        else {
+            (RuleKind::Syntactic, self.alternatives::<T>(false)?)
+        };
+        self.expect(&G4Token::Semicolon, ";")?;
+
+        if let Some(G4Token::Identifier(keyword)) = self.peek().filter(|token| matches!(token, G4Token::Identifier(keyword) if keyword == "catch" || keyword == "finally")) {
+            return self.error(&format!("{keyword} isn't supported"), self.offset());
+        }
+
+        self.rule_name = None;
+        Ok(rule_definition(kind, name, expr))
+    }
+
+    // Empty alternatives, which ANTLR allows, make the whole choice optional.
+    fn alternatives<T: Token>(&mut self, lexical: bool) -> Result<RuleExpression, DefinitionError> {
+        let offset = self.offset();
+        let mut alternatives = vec![self.alternative::<T>(lexical)?];
+        while self.peek() == Some(&G4Token::Bar) {
+            self.position += 1;
+            alternatives.push(self.alternative::<T>(lexical)?);
+        }
+
+        let optional = alternatives.iter().any(Option::is_none);
+        let mut alternatives = alternatives.into_iter().flatten().collect::<Vec<_>>();
+        let expr = match alternatives.len() {
+            0 => return self.error("Matching nothing is not supported", offset),
+            1 => alternatives.remove(0),
+            _ => RuleExpression::Alternatives(alternatives),
+        };
+        Ok(if optional { RuleExpression::Optional(Box::new(expr)) } else { expr })
+    }
+
+    fn alternative<T: Token>(&mut self, lexical: bool) -> Result<Option<RuleExpression>, DefinitionError> {
+        let mut elements = vec![];
+        loop {
+            let offset = self.offset();
+            match self.peek() {
+                None | Some(G4Token::Bar | G4Token::RightParenthesis | G4Token::Semicolon) => break,
+                Some(G4Token::Hash) => {
+                    self.position += 1;
+                    match self.peek() {
+                        Some(G4Token::Identifier(_)) => self.position += 1,
+                        _ => return self.error("Expected a label after #", self.offset()),
+                    }
+                }
+                Some(G4Token::Arrow) if lexical => {
+                    self.position += 1;
+                    self.commands()?;
+                }
+                Some(G4Token::Arrow) => return self.error("Lexer commands only belong in lexer rules", offset),
+                Some(G4Token::Action) if self.peek_at(1) == Some(&G4Token::QuestionMark) =>
+                    return self.error("Semantic predicates aren't supported", offset),
+                Some(G4Token::Action) => return self.error("Actions aren't supported", offset),
+                Some(G4Token::ElementOptions) => return self.error("Element options like <assoc=right> aren't supported", offset),
+                Some(_) => elements.push(self.element::<T>(lexical)?),
+            }
+        }
+
+        if elements.is_empty() {
+            return Ok(None);
+        }
+        sequence(elements, self).map(Some)
+    }
+
+    fn element<T: Token>(&mut self, lexical: bool) -> Result<Element, DefinitionError> {
+        if matches!((self.peek(), self.peek_at(1)), (Some(G4Token::Identifier(_)), Some(G4Token::Assign | G4Token::PlusAssign))) {
+            self.position += 2;  // Labels like `left=expr` or `args+=expr` name parts of a tree, which Parsley doesn't do.
+        }
+
+        let expr = self.atom::<T>(lexical)?;
+        let offset = self.offset();
+        let expr = match self.peek() {
+            Some(G4Token::QuestionMark) => RuleExpression::Optional(Box::new(expr)),
+            Some(G4Token::Star) => RuleExpression::Many(Box::new(expr)),
+            Some(G4Token::Plus) => RuleExpression::OneOrMore(Box::new(expr)),
+            _ => return Ok(Element::Plain(expr)),
+        };
+        self.position += 1;
+
+        if self.peek() != Some(&G4Token::QuestionMark) {
+            return Ok(Element::Plain(expr));
+        }
+        self.position += 1;
+        match expr {
+            RuleExpression::Many(inner) => Ok(Element::NonGreedy(*inner, false, offset)),
+            RuleExpression::OneOrMore(inner) => Ok(Element::NonGreedy(*inner, true, offset)),
+            _ => self.error("Non-greedy ?? isn't supported", offset),
+        }
+    }
+
+    fn atom<T: Token>(&mut self, lexical: bool) -> Result<RuleExpression, DefinitionError> {
+        let offset = self.offset();
+        let Some(token) = self.peek().cloned() else {
+            return self.error("Expected an element", offset);
+        };
+        self.position += 1;
+
+        match token {
+            G4Token::Identifier(name) if name == "EOF" => Ok(RuleExpression::EndOfInput),
+            G4Token::Identifier(name) => Ok(RuleExpression::RuleName(name.into())),
+            G4Token::Literal(start) if self.peek() == Some(&G4Token::Range) => {
+                self.position += 1;
+                let Some(G4Token::Literal(end)) = self.peek().cloned() else {
+                    return self.error("Expected a literal after ..", self.offset());
+                };
+                self.position += 1;
+
+                match (single_char(&start), single_char(&end)) {
+                    _ if !lexical => self.error("Ranges only work in lexer rules", offset),
+                    (Some(start_char), Some(end_char)) if start_char <= end_char => Ok(RuleExpression::CharacterClass(
+                        CharacterClass::from_ranges(&format!("'{start}'..'{end}'"), &[(start_char, end_char)], false)
+                    )),
+                    _ => self.error("Bad range, expected something like 'a'..'z'", offset),
+                }
+            }
+            G4Token::Literal(literal) => {
+                if !lexical {
+                    self.literals.push(literal.clone());
+                }
+                literal_to_combination::<T>(&literal).or_else(|err| self.error(&err.message, offset))
+            }
+            G4Token::CharacterSet(_, _) if !lexical => self.error("Sets like [a-z] only work in lexer rules", offset),
+            G4Token::CharacterSet(ranges, source) => Ok(RuleExpression::CharacterClass(CharacterClass::from_ranges(&source, &ranges, false))),
+            G4Token::Dot => Ok(RuleExpression::Wildcard),
+            G4Token::Tilde => {
+                let inner = self.atom::<T>(lexical)?;
+                match describe_single_token(&inner) {
+                    Ok(description) => Ok(RuleExpression::Negation(Box::new(inner), format!("~{description}"))),
+                    Err(err) => self.error(&err.message, offset),
+                }
+            }
+            G4Token::LeftParenthesis => {
+                let expr = self.alternatives::<T>(lexical)?;
+                self.expect(&G4Token::RightParenthesis, ")")?;
+                Ok(expr)
+            }
+            _ => self.error("Expected an element", offset),
+        }
+    }
+
+    // Reads the commands after ->, of which only skip and channel(...) have a meaning here.
+    fn commands(&mut self) -> Result<(), DefinitionError> {
+        loop {
+            let offset = self.offset();
+            let Some(G4Token::Identifier(command)) = self.peek().cloned() else {
+                return self.error("Expected a lexer command", offset);
+            };
+            self.position += 1;
+
+            match command.as_str() {
+                "skip" => self.skip = true,
+                "channel" => {
+                    self.expect(&G4Token::LeftParenthesis, "(")?;
+                    match self.peek() {
+                        Some(G4Token::Identifier(_)) => self.position += 1,
+                        _ => return self.error("Expected a channel", self.offset()),
+                    }
+                    self.expect(&G4Token::RightParenthesis, ")")?;
+                    self.skip = true;
+                }
+                _ => return self.error(&format!("Lexer command {command} isn't supported"), offset),
+            }
+
+            if self.peek() != Some(&G4Token::Comma) {
+                return Ok(());
+            }
+            self.position += 1;
+        }
+    }
+}
+
+/* Joins the elements of an alternative. A non-greedy loop stops as soon as the rest of
+ * the alternative can match, so '<' .*? '>' becomes "<" (!">" .)* ">" */
+fn sequence(elements: Vec<Element>, reader: &Reader) -> Result<RuleExpression, DefinitionError> {
+    let mut exprs = vec![];
+    let mut elements = elements.into_iter();
+    while let Some(element) = elements.next() {
+        match element {
+            Element::Plain(expr) => exprs.push(expr),
+            Element::NonGreedy(inner, at_least_once, offset) => {
+                let rest = elements.by_ref().collect::<Vec<_>>();
+                if rest.is_empty() {
+                    return reader.error("A non-greedy loop needs something after it in the same alternative", offset);
+                }
+                let rest = sequence(rest, reader)?;
+
+                let description = format!("!{}", describe_expression(&rest));
+                let step = RuleExpression::Concatenation(vec![RuleExpression::NegativeLookahead(Box::new(rest.clone()), description), inner.clone()]);
+                if at_least_once {
+                    exprs.push(inner);
+                }
+                exprs.push(RuleExpression::Many(Box::new(step)));
+                match rest {
+                    RuleExpression::Concatenation(rest) => exprs.extend(rest),
+                    rest => exprs.push(rest),
+                }
+            }
+        }
+    }
+    Ok(if exprs.len() == 1 { exprs.remove(0) } else { RuleExpression::Concatenation(exprs) })
+}
+
+fn rule_definition(kind: RuleKind, name: String, expr: RuleExpression) -> RuleDefinition {
+    RuleDefinition { kind, mode: RuleMode::Define, name, expr, ordered: false, label: None, file: None, locations: None }
+}
+
+// In ANTLR, rules starting with a capital letter are lexer rules.
+fn is_lexical(name: &str) -> bool {
+    name.starts_with(|ch: char| ch.is_ascii_uppercase())
+}
+
+fn single_char(string: &str) -> Option<char> {
+    let mut chars = string.chars();
+    chars.next().filter(|_| chars.next().is_none())
+}
+
+/* Splits the grammar into tokens, dropping whitespace and comments. Actions and element
+ * options are kept as single tokens, just so they can be reported. */
+fn tokenize(definition: &str) -> Result<Vec<(G4Token, usize)>, DefinitionError> {
+    let mut tokens = vec![];
+    let mut chars = definition.char_indices().peekable();
+    let error = |message: &str, offset: usize| Err(DefinitionError::new(message.to_string()).locate(definition, offset, None));
+
+    while let Some((offset, ch)) = chars.next() {
+        let token = match ch {
+            _ if ch.is_whitespace() => continue,
+            '/' if chars.next_if(|(_, ch)| *ch == '/').is_some() => {
+                while chars.next_if(|(_, ch)| *ch != '\n').is_some() {}
+                continue;
+            }
+            '/' if chars.next_if(|(_, ch)| *ch == '*').is_some() => {
+                match definition[offset + 2..].find("*/") {
+                    Some(length) => while chars.next_if(|(next, _)| *next < offset + 2 + length + 2).is_some() {},
+                    None => return error("Unterminated comment", offset),
+                }
+                continue;
+            }
+            ':' => G4Token::Colon,
+            ';' => G4Token::Semicolon,
+            '|' => G4Token::Bar,
+            '(' => G4Token::LeftParenthesis,
+            ')' => G4Token::RightParenthesis,
+            '?' => G4Token::QuestionMark,
+            '*' => G4Token::Star,
+            '+' if chars.next_if(|(_, ch)| *ch == '=').is_some() => G4Token::PlusAssign,
+            '+' => G4Token::Plus,
+            '~' => G4Token::Tilde,
+            '.' if chars.next_if(|(_, ch)| *ch == '.').is_some() => G4Token::Range,
+            '.' => G4Token::Dot,
+            '=' => G4Token::Assign,
+            '#' => G4Token::Hash,
+            '-' if chars.next_if(|(_, ch)| *ch == '>').is_some() => G4Token::Arrow,
+            ',' => G4Token::Comma,
+            '@' => G4Token::At,
+            '{' | '<' => {
+                let (open, close) = if ch == '{' { ('{', '}') } else { ('<', '>') };
+                let mut depth = 1;
+                while depth > 0 {
+                    match chars.next() {
+                        Some((_, ch)) if ch == open => depth += 1,
+                        Some((_, ch)) if ch == close => depth -= 1,
+                        Some(_) => (),
+                        None => return error(&format!("Unterminated {open}"), offset),
+                    }
+                }
+                if ch == '{' { G4Token::Action } else { G4Token::ElementOptions }
+            }
+            '\'' => {
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, '\\')) => match read_escape(&mut chars) {
+                            Some(ch) => literal.push(ch),
+                            None => return error("Bad escape sequence", offset),
+                        },
+                        Some((_, ch)) => literal.push(ch),
+                        None => return error("Unterminated literal", offset),
+                    }
+                }
+                G4Token::Literal(literal)
+            }
+            '[' => {
+                let mut members = vec![];  // (character, was escaped)
+                loop {
+                    match chars.next() {
+                        Some((_, ']')) => break,
+                        Some((_, '\\')) => match read_escape(&mut chars) {
+                            Some(ch) => members.push((ch, true)),
+                            None => return error("Bad escape sequence", offset),
+                        },
+                        Some((_, ch)) => members.push((ch, false)),
+                        None => return error("Unterminated set", offset),
+                    }
+                }
+                let end = chars.peek().map_or(definition.len(), |(next, _)| *next);
+                match set_ranges(&members) {
+                    Some(ranges) => G4Token::CharacterSet(ranges, definition[offset..end].to_string()),
+                    None => return error("Bad set, expected something like [a-zA-Z_]", offset),
+                }
+            }
+            _ if ch.is_alphabetic() || ch == '_' => {
+                let mut name = ch.to_string();
+                while let Some((_, ch)) = chars.next_if(|(_, ch)| ch.is_alphanumeric() || *ch == '_') {
+                    name.push(ch);
+                }
+                G4Token::Identifier(name)
+            }
+            _ => return error(&format!("Unexpected character '{ch}'"), offset),
+        };
+        tokens.push((token, offset));
+    }
+
+    Ok(tokens)
+}
+
+// Reads the rest of an escape after the backslash. Escapes ANTLR doesn't know stand for the character itself.
+fn read_escape(chars: &mut std::iter::Peekable<impl Iterator<Item = (usize, char)>>) -> Option<char> {
+    match chars.next()?.1 {
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        'b' => Some('\u{8}'),
+        'f' => Some('\u{c}'),
+        'u' if chars.next_if(|(_, ch)| *ch == '{').is_some() => {
+            let digits = std::iter::from_fn(|| chars.next_if(|(_, ch)| *ch != '}')).map(|(_, ch)| ch).collect::<String>();
+            chars.next()?;
+            u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32)
+        }
+        'u' => {
+            let digits = chars.by_ref().take(4).map(|(_, ch)| ch).collect::<String>();
+            u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32)
+        }
+        ch => Some(ch),
+    }
+}
+
+// The ranges in a set, where an unescaped dash between two characters makes a range.
+fn set_ranges(members: &[(char, bool)]) -> Option<Vec<(char, char)>> {
+    let mut ranges = vec![];
+    let mut i = 0;
+    while i < members.len() {
+        if i + 2 < members.len() && members[i + 1] == ('-', false) {
+            let (start, end) = (members[i].0, members[i + 2].0);
+            if start > end {
+                return None;
+            }
+            ranges.push((start, end));
+            i += 3;
+        }
+        else {
+            ranges.push((members[i].0, members[i].0));
+            i += 1;
+        }
+    }
+    (!ranges.is_empty()).then_some(ranges)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::LexedToken;
+
+    #[test]
+    fn test_antlr() {
+        let parser: Parser<LexedToken> = define_parser_from_antlr(r#"
+            grammar Calc;
+
+            /* Statements, one per line */
+            prog : stat+ EOF ;
+            stat : expr NEWLINE             # printExpr
+                 | id=ID '=' expr NEWLINE   # assign
+                 | NEWLINE                  # blank
+                 ;
+            expr : atom (op+=('*'|'/'|'+'|'-') atom)* ;
+            atom : INT | ID | STRING | '(' expr ')' | '-' atom ;
+
+            ID : LETTER (LETTER | [0-9])* ;
+            INT : [0-9]+ ;
+            NEWLINE : '\r'? '\n' ;
+            STRING : '"' ~["\r\n]* '"' ;
+            WS : [ \t]+ -> skip ;
+            COMMENT : '/*' .*? '*/' -> channel(HIDDEN) ;
+            fragment LETTER : 'a'..'z' | 'A'..'Z' | '_' ;
+        "#).expect("Parser definition ok");
+
+        let tokens = parser.tokenize("x = 1 + -2 * (y - \"3\") /* a * b */\n\n/* c */ x /* d */\n").expect("Tokenizes");
+        assert_eq!(tokens.iter().map(|token| token.kind.as_str()).collect::<Vec<_>>(), vec![
+            "ID", "T__0", "INT", "T__3", "T__4", "INT", "T__1", "T__5", "ID", "T__4", "STRING", "T__6", "NEWLINE", "NEWLINE", "ID", "NEWLINE"
+        ]);
+        assert!(parser.parse_tokens(&tokens, "prog").is_ok());
+        assert!(parser.parse_tokens(&parser.tokenize("x = \n").expect("Tokenizes"), "prog").is_err());
+        let tokens = parser.tokenize("/* open").expect("Tokenizes");
+        assert_eq!(tokens.iter().map(|token| token.kind.as_str()).collect::<Vec<_>>(), vec!["T__2", "T__1", "ID"]);
+    }
+
+    #[test]
+    fn test_antlr_errors() {
+        let error = |definition| define_parser_from_antlr::<LexedToken>(definition).map(|_| ()).expect_err("Unsupported").to_string();
+
+        assert_eq!(error("a : B {print();} ;").lines().next(), Some("Actions aren't supported at line 1, column 7 (in rule \"a\")"));
+        assert_eq!(error("a : {p}? B ;").lines().next(), Some("Semantic predicates aren't supported at line 1, column 5 (in rule \"a\")"));
+        assert_eq!(error("grammar G;\noptions { tokenVocab=L; }").lines().next(), Some("options isn't supported at line 2, column 1"));
+        assert_eq!(error("A : 'x' -> pushMode(M) ;").lines().next(), Some("Lexer command pushMode isn't supported at line 1, column 12 (in rule \"A\")"));
+        assert_eq!(error("e : <assoc=right> e '^' e | INT ;").lines().next(), Some("Element options like <assoc=right> aren't supported at line 1, column 5 (in rule \"e\")"));
+        assert_eq!(error("a [int x] : B ;").lines().next(), Some("Rule arguments aren't supported at line 1, column 3 (in rule \"a\")"));
+        assert_eq!(error("A : 'x' .*? ;").lines().next(), Some("A non-greedy loop needs something after it in the same alternative at line 1, column 10 (in rule \"A\")"));
+        assert_eq!(error("a : [a-z] ;").lines().next(), Some("Sets like [a-z] only work in lexer rules at line 1, column 5 (in rule \"a\")"));
+        assert_eq!(error("a : B\n  | C\n"), "Expected ; at line 2, column 5 (in rule \"a\")\n      | C\n        ^");
+    }
+}
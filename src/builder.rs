@@ -0,0 +1,172 @@
+/* Collects everything needed to make a parser in one place, so that new options
+ * don't each need their own setter or parameter. */
+
+use crate::define::{define_parser_with_terminals, define_parser_from_file_with_terminals, DefinitionError};
+use crate::parse::{Matcher, Predicate, Scanner};
+use crate::{AmbiguityPolicy, ParseAlgorithm, ParseLimits, Parser, Token};
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+
+/* Public Interface */
+
+/* Builds a parser from a definition, with every setting applied up front:
+ *
+ *     let parser = ParserBuilder::<CharToken>::new(definition)
+ *         .ambiguity_policy(AmbiguityPolicy::RejectAmbiguity)
+ *         .anchored(false)
+ *         .build()?;
+ *
+ * Anything left unset keeps the same default as define_parser. */
+pub struct ParserBuilder<T: Token> {
+    source: DefinitionSource,
+    ambiguity_policy: AmbiguityPolicy,
+    anchored: bool,
+    algorithm: ParseAlgorithm,
+    ordered_choice: bool,
+    lossless: bool,
+    limits: ParseLimits,
+    predicates: Vec<(String, Predicate<T>)>,
+    terminals: HashMap<String, Matcher<T>>,
+    scanners: Vec<(String, Scanner<T>)>,
+}
+
+impl<T: Token> ParserBuilder<T> {
+    pub fn new(definition: &str) -> ParserBuilder<T> {
+        ParserBuilder::with_source(DefinitionSource::Text(definition.to_string()))
+    }
+
+    /* Reads the definition from a file, which may import others. See define_parser_from_file. */
+    pub fn from_file(path: impl Into<PathBuf>) -> ParserBuilder<T> {
+        ParserBuilder::with_source(DefinitionSource::File(path.into()))
+    }
+
+    pub fn ambiguity_policy(mut self, policy: AmbiguityPolicy) -> Self {
+        self.ambiguity_policy = policy;
+        self
+    }
+
+    pub fn anchored(mut self, anchored: bool) -> Self {
+        self.anchored = anchored;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: ParseAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn ordered_choice(mut self, ordered_choice: bool) -> Self {
+        self.ordered_choice = ordered_choice;
+        self
+    }
+
+    pub fn lossless(mut self, lossless: bool) -> Self {
+        self.lossless = lossless;
+        self
+    }
+
+    pub fn limits(mut self, limits: ParseLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn predicate(mut self, rule_name: &str, predicate: impl Fn(&[T], Range<usize>) -> bool + 'static) -> Self {
+        self.predicates.push((rule_name.to_string(), Box::new(predicate)));
+        self
+    }
+
+    /* Supplies the scanner for a rule declared `external`. See Parser::register_external. */
+    pub fn external(mut self, rule_name: &str, scanner: impl Fn(&[T], usize) -> Option<usize> + 'static) -> Self {
+        self.scanners.push((rule_name.to_string(), Box::new(scanner)));
+        self
+    }
+
+    /* Matches the terminal `_name` with the closure. Unlike Parser::register_terminal,
+     * the token type doesn't need to know the name. */
+    pub fn terminal(mut self, name: &str, matcher: impl Fn(&T) -> bool + 'static) -> Self {
+        self.terminals.insert(name.to_string(), Box::new(matcher));
+        self
+    }
+
+    pub fn build(self) -> Result<Parser<T>, DefinitionError> {
+        let mut parser = match self.source {
+            DefinitionSource::Text(definition) => define_parser_with_terminals(&definition, self.terminals)?,
+            DefinitionSource::File(path) => define_parser_from_file_with_terminals(path, self.terminals)?,
+        };
+
+        parser.set_ambiguity_policy(self.ambiguity_policy);
+        parser.set_anchored(self.anchored);
+        parser.set_algorithm(self.algorithm);
+        parser.set_ordered_choice(self.ordered_choice);
+        parser.set_lossless(self.lossless);
+        parser.set_limits(self.limits);
+        for (rule_name, predicate) in self.predicates {
+            parser.add_boxed_predicate(&rule_name, predicate)?;
+        }
+        for (rule_name, scanner) in self.scanners {
+            parser.add_boxed_scanner(&rule_name, scanner)?;
+        }
+
+        Ok(parser)
+    }
+}
+
+
+/* Private Implementation */
+
+enum DefinitionSource {
+    Text (String),
+    File (PathBuf),
+}
+
+impl<T: Token> ParserBuilder<T> {
+    fn with_source(source: DefinitionSource) -> ParserBuilder<T> {
+        ParserBuilder {
+            source,
+            ambiguity_policy: AmbiguityPolicy::default(),
+            anchored: true,
+            algorithm: ParseAlgorithm::default(),
+            ordered_choice: false,
+            lossless: false,
+            limits: ParseLimits::default(),
+            predicates: vec![],
+            terminals: HashMap::new(),
+            scanners: vec![],
+        }
+    }
+}
+
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CharToken, ParseError};
+
+    #[test]
+    fn test_builder() {
+        let parser = ParserBuilder::<CharToken>::new(r#"Letters : Letter+ ; Letter : [a-z] | "ab" ;"#)
+            .ambiguity_policy(AmbiguityPolicy::RejectAmbiguity)
+            .anchored(false)
+            .predicate("Letter", |tokens, span| tokens[span.start].token_type != 'z')
+            .build()
+            .expect("ok");
+
+        assert!(matches!(parser.parse_string("ab", "Letters"), Err(ParseError::Ambiguous(_))));
+        assert_eq!(parser.parse_string("xyz", "Letters").expect("No error").span(), 0..2);
+
+        assert!(ParserBuilder::<CharToken>::new("A : \"a\" ;").predicate("B", |_, _| true).build().is_err());
+
+        let parser = ParserBuilder::<CharToken>::new("Number : _digit+ ;")
+            .terminal("digit", |token| token.token_type.is_ascii_digit())
+            .build()
+            .expect("ok");
+        assert_eq!(parser.parse_string("123", "Number").expect("No error").span(), 0..3);
+        assert!(ParserBuilder::<CharToken>::new("Number : _digit+ ;").build().is_err());
+        assert!(ParserBuilder::<CharToken>::from_file("does/not/exist.psl").build().is_err());
+    }
+}
@@ -0,0 +1,324 @@
+/* Generating AST types from a grammar, so they can't drift out of sync with it. The
+ * output is Rust source with one type per rule, which converts from syntax trees with
+ * FromSyntaxTree. Write it out from a build script, or once by hand and then edit it:
+ *
+ *     let parser: Parser<LexedToken> = parsley::define_parser_from_file("grammar.psl")?;
+ *     std::fs::write(out_dir.join("ast.rs"), parsley::codegen::generate_ast(&parser))?;
+ *
+ * Each rule becomes one of:
+ *
+ *     Expr : Sum | Number ;        An enum with a variant per rule, `Sum(Box<Sum>)` and `Number(Number)`.
+ *     Sum : Expr "+" Expr ;        A struct with a field for each rule and named token in
+ *                                  it, in order. Repeated ones become Vecs, optional ones Options.
+ *     Ident : [a-z]+ ;             A struct holding the text, when the rule uses no rules or named tokens.
+ *     Unit : "(" ")" ;             A unit struct, when the rule only has literals.
+ *
+ * Fields are Boxed where the types would otherwise contain themselves. Literals in
+ * the rules are taken to be punctuation and get no fields, which for single character
+ * tokens is every terminal. Choices that aren't a whole rule make every field in them
+ * optional, so a rule like `Statement : "let" Name "=" Expr | "print" Expr ;` doesn't
+ * say which alternative it was. Splitting alternatives into rules of their own fixes that. */
+
+use crate::define::RuleExpression;
+use crate::{Parser, Symbol, Token};
+
+use itertools::Itertools;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+
+/* Rust source defining a type for every rule in the parser, along with conversions
+ * from syntax trees. The source expects to be in a module of its own, and refers to
+ * this crate as ::parsley. */
+pub fn generate_ast<T: Token>(parser: &Parser<T>) -> String {
+    let mut code = "// Generated from the grammar by parsley::codegen::generate_ast, edits will be lost.\n".to_string();
+
+    let rule_names = parser.rules.keys().map(Symbol::as_str).chain(parser.externs.iter().map(String::as_str)).sorted().collect::<Vec<_>>();
+    let reachable = reachable_rules(&parser.rules);
+    for rule_name in rule_names {
+        let boxed = |other: &str| reachable.get(other).is_some_and(|rules| rules.contains(rule_name));
+        code += "\n";
+        match parser.rules.get(rule_name) {
+            Some(expr) => generate_rule(&mut code, rule_name, expr, &boxed),
+            None => generate_text(&mut code, rule_name),
+        }
+    }
+
+    code
+}
+
+
+/* Private Implementation */
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Amount {
+    One,
+    Optional,
+    Many,
+}
+
+#[derive(PartialEq, Eq)]
+enum Content<'a> {
+    Rule (&'a str),
+    Token (&'a str),
+}
+
+struct Field<'a> {
+    content: Content<'a>,
+    amount: Amount,
+}
+
+fn generate_rule(code: &mut String, rule_name: &str, expr: &RuleExpression, boxed: &dyn Fn(&str) -> bool) {
+    if let RuleExpression::Alternatives(alternatives) | RuleExpression::OrderedAlternatives(alternatives) = expr {
+        let choices = alternatives.iter()
+            .map(|alternative| match alternative {
+                RuleExpression::RuleName(name) if name != rule_name => Some(name.as_str()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>();
+        if let Some(choices) = choices.filter(|choices| choices.iter().all_unique()) {
+            return generate_choice(code, rule_name, &choices, boxed);
+        }
+    }
+
+    let mut fields = vec![];
+    collect_fields(expr, Amount::One, &mut fields);
+    let fields = merge_fields(fields);
+
+    let name = type_name(rule_name);
+    let derive = "#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]\n";
+    let rule_attribute = if name == rule_name { String::new() } else { format!("#[rule({rule_name:?})]\n") };
+
+    if fields.is_empty() && only_literals(expr) {
+        writeln!(code, "{derive}{rule_attribute}pub struct {name};").expect("Infallible");
+        return;
+    }
+    else if fields.is_empty() {
+        return generate_text(code, rule_name);
+    }
+
+    writeln!(code, "{derive}{rule_attribute}pub struct {name} {{").expect("Infallible");
+    let mut used_names = HashSet::new();
+    for Field { content, amount } in &fields {
+        let (name, ty, attribute) = match content {
+            Content::Rule(name) if *amount != Amount::Many && boxed(name) => (*name, format!("Box<{}>", type_name(name)), String::new()),
+            Content::Rule(name) => (*name, type_name(name), String::new()),
+            Content::Token(kind) => (*kind, "String".to_string(), format!("#[token({kind:?})] ")),
+        };
+        let (name, ty) = match amount {
+            Amount::One => (field_name(name), ty),
+            Amount::Optional => (field_name(name), format!("Option<{ty}>")),
+            Amount::Many => (plural(&field_name(name)), format!("Vec<{ty}>")),
+        };
+        let name = (1..).map(|n| if n == 1 { name.clone() } else { format!("{name}_{n}") })
+            .find(|name| used_names.insert(name.clone()))
+            .expect("Some name is free");
+        writeln!(code, "    {attribute}pub {}: {ty},", escape_keyword(&name)).expect("Infallible");
+    }
+    code.push_str("}\n");
+}
+
+// An enum for rules that are a choice between other rules. The derive can't express this, since the variants hold whole nodes rather than their children.
+fn generate_choice(code: &mut String, rule_name: &str, choices: &[&str], boxed: &dyn Fn(&str) -> bool) {
+    let name = type_name(rule_name);
+    writeln!(code, "#[derive(Debug, Clone, PartialEq)]\npub enum {name} {{").expect("Infallible");
+    for choice in choices {
+        let ty = if boxed(choice) { format!("Box<{}>", type_name(choice)) } else { type_name(choice) };
+        writeln!(code, "    {}({ty}),", type_name(choice)).expect("Infallible");
+    }
+    code.push_str("}\n\n");
+
+    let arms = choices.iter()
+        .map(|choice| format!("            Some({choice:?}) => ::parsley::FromSyntaxTree::from_syntax_tree(tree).map({name}::{}),\n", type_name(choice)))
+        .collect::<String>();
+    let expected = choices.join(" or ");
+    code.push_str(&implementation(rule_name, &format!("
+        let tree = ::parsley::FieldCursor::look_through(tree, {rule_name:?})?;
+        match tree.rule_name() {{
+{arms}            _ => Err(::parsley::unexpected_shape(tree, {expected:?})),
+        }}")));
+}
+
+// A struct holding the text of the node, for rules made of tokens that aren't worth picking apart.
+fn generate_text(code: &mut String, rule_name: &str) {
+    let name = type_name(rule_name);
+    writeln!(code, "#[derive(Debug, Clone, PartialEq)]\npub struct {name}(pub String);\n").expect("Infallible");
+    code.push_str(&implementation(rule_name, &format!("
+        ::parsley::FieldCursor::new(tree, {name:?}, {rule_name:?})?;
+        Ok({name}(tree.token_text()))")));
+}
+
+// A FromSyntaxTree impl for the rule's type, around the body of from_syntax_tree.
+fn implementation(rule_name: &str, body: &str) -> String {
+    format!("\
+impl<T: ::parsley::Token + std::fmt::Display> ::parsley::FromSyntaxTree<T> for {name} {{
+    fn from_syntax_tree(tree: &::parsley::SyntaxTree<T>) -> Result<Self, ::parsley::ShapeError> {{{body}
+    }}
+
+    fn accepts(tree: &::parsley::SyntaxTree<T>) -> bool {{
+        tree.rule_name() == Some({rule_name:?})
+    }}
+
+    fn describe() -> String {{
+        String::from({rule_name:?})
+    }}
+}}
+", name = type_name(rule_name), body = body.trim_end())
+}
+
+// The fields for whatever the expression matches, in order.
+fn collect_fields<'a>(expr: &'a RuleExpression, amount: Amount, fields: &mut Vec<Field<'a>>) {
+    match expr {
+        RuleExpression::RuleName(name) => fields.push(Field { content: Content::Rule(name), amount }),
+        RuleExpression::Terminal(terminal) if is_named(terminal) => fields.push(Field { content: Content::Token(terminal), amount }),
+        RuleExpression::Concatenation(exprs) => {
+            for expr in exprs {
+                collect_fields(expr, amount, fields);
+            }
+        }
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+            for expr in exprs {
+                collect_fields(expr, amount.max(Amount::Optional), fields);
+            }
+        }
+        RuleExpression::Optional(expr) => collect_fields(expr, amount.max(Amount::Optional), fields),
+        RuleExpression::Repetition(expr, 0, Some(1)) => collect_fields(expr, amount.max(Amount::Optional), fields),
+        RuleExpression::Repetition(expr, 1, Some(1)) => collect_fields(expr, amount, fields),
+        RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr) | RuleExpression::Repetition(expr, ..)
+            => collect_fields(expr, Amount::Many, fields),
+        RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_) | RuleExpression::Negation(..)
+        | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_)
+        | RuleExpression::PositiveLookahead(_) | RuleExpression::NegativeLookahead(..) => (),
+    }
+}
+
+// Neighbouring fields of the same thing become one Vec, unless they are both exactly one, since FieldCursor takes as many as it can.
+fn merge_fields(fields: Vec<Field>) -> Vec<Field> {
+    let mut merged: Vec<Field> = vec![];
+    for field in fields {
+        match merged.last_mut() {
+            Some(last) if last.content == field.content && (last.amount, field.amount) != (Amount::One, Amount::One) => last.amount = Amount::Many,
+            _ => merged.push(field),
+        }
+    }
+    merged
+}
+
+// Whether the expression matches nothing but literals, which carry no information.
+fn only_literals(expr: &RuleExpression) -> bool {
+    match expr {
+        RuleExpression::Terminal(terminal) => !is_named(terminal),
+        RuleExpression::RuleName(_) | RuleExpression::CharacterClass(_) | RuleExpression::Negation(..)
+        | RuleExpression::Wildcard | RuleExpression::External(_) => false,
+        RuleExpression::EndOfInput | RuleExpression::PositiveLookahead(_) | RuleExpression::NegativeLookahead(..) => true,
+        RuleExpression::Concatenation(exprs) | RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs)
+            => exprs.iter().all(only_literals),
+        RuleExpression::Optional(expr) | RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr)
+        | RuleExpression::Repetition(expr, ..) => only_literals(expr),
+    }
+}
+
+// Terminals from _Name or @token rules, rather than from literals like "(".
+fn is_named(terminal: &str) -> bool {
+    terminal.chars().count() > 1 && terminal.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && terminal.starts_with(|c: char| c.is_alphabetic())
+}
+
+// For each rule, every rule that its type could contain.
+fn reachable_rules(rules: &HashMap<Symbol, RuleExpression>) -> HashMap<&str, HashSet<&str>> {
+    let mut reachable = HashMap::new();
+    for start in rules.keys() {
+        let mut seen = HashSet::new();
+        let mut stack = vec![start.as_str()];
+        while let Some(rule_name) = stack.pop() {
+            let mut names = vec![];
+            if let Some(expr) = rules.get(rule_name) {
+                expr.referenced_rules(&mut names);
+            }
+            stack.extend(names.into_iter().filter(|name| seen.insert(*name)));
+        }
+        reachable.insert(start.as_str(), seen);
+    }
+    reachable
+}
+
+fn type_name(rule_name: &str) -> String {
+    let name = rule_name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| part[..1].to_uppercase() + &part[1..])
+        .collect::<String>();
+    escape_keyword(&name)
+}
+
+fn field_name(name: &str) -> String {
+    let mut field = String::new();
+    for (i, c) in name.char_indices() {
+        if c.is_uppercase() && i > 0 && !name[..i].ends_with('_') {
+            field.push('_');
+        }
+        field.extend(c.to_lowercase());
+    }
+    field
+}
+
+fn plural(name: &str) -> String {
+    if name.ends_with('s') { format!("{name}es") } else { format!("{name}s") }
+}
+
+fn escape_keyword(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn", "for",
+        "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct",
+        "trait", "true", "type", "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final", "macro",
+        "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+    ];
+    match name {
+        "self" | "Self" | "super" => format!("{name}_"),
+        name if KEYWORDS.contains(&name) => format!("r#{name}"),
+        name => name.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{define_parser, CharToken, Parser};
+
+    use indoc::indoc;
+
+    #[test]
+    fn test_generate_ast() {
+        let parser: Parser<CharToken> = define_parser(r#"
+            extern Comment ;
+            type : "type " Ident_Name "=" Ident_Name ("|" Ident_Name)* Comment? ;
+            Ident_Name : [a-z]+ ;
+        "#).expect("Parser definition ok");
+
+        let code = generate_ast(&parser);
+        assert!(code.contains(indoc! {r#"
+            #[derive(Debug, Clone, PartialEq)]
+            pub struct Comment(pub String);
+        "#}));
+        assert!(code.contains("impl<T: ::parsley::Token + std::fmt::Display> ::parsley::FromSyntaxTree<T> for IdentName {"));
+        assert!(code.contains(indoc! {r#"
+            #[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+            #[rule("type")]
+            pub struct Type {
+                pub ident_name: IdentName,
+                pub ident_names: Vec<IdentName>,
+                pub comment: Option<Comment>,
+            }
+        "#}));
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(type_name("expr"), "Expr");
+        assert_eq!(type_name("Self"), "Self_");
+        assert_eq!(field_name("ParamList"), "param_list");
+        assert_eq!(escape_keyword(&field_name("Type")), "r#type");
+        assert_eq!(plural("class"), "classes");
+    }
+}
@@ -3,39 +3,158 @@
 
 use super::Parser;
 use super::Token;
+use super::CharToken;
+use crate::parse::{Lexer, LineMap, Matcher, SourceLocation, Symbol};
 
+use by_address::ByAddress;
 use itertools::Itertools;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 
 /* Public Interface */
 
 pub fn define_parser<T: Token>(definition: &str) -> Result<Parser<T>, DefinitionError> {
-    let tokens = tokenize(definition)?;
-    let rule_token_slices = tokens.split(|t| t == &DefinitionToken::Operator(Operator::Semicolon));
+    define_parser_with_terminals(definition, HashMap::new())
+}
 
-    match rule_token_slices.clone().last() {
-        None => return Err(DefinitionError("No rules defined".to_string())),
-        Some(slice) if slice != vec![] => return Err(DefinitionError("Missing final semicolon".to_string())),
-        _ => ()
+/* Reads the definition from a file. Definitions read this way can contain import
+ * statements like `import "lexical.psl";`, which pull in all of the rules from another
+ * file. Paths are relative to the file doing the importing, and each file is only
+ * read once, no matter how many times it is imported. */
+pub fn define_parser_from_file<T: Token>(path: impl AsRef<Path>) -> Result<Parser<T>, DefinitionError> {
+    define_parser_from_file_with_terminals(path, HashMap::new())
+}
+
+/* Why a definition was rejected. Mistakes in the definition text itself, like a
+ * missing semicolon or a bad escape, also say where they are: the location of the
+ * token or statement that is wrong, the rule it defines, and the line it is on. */
+#[derive(PartialEq, Eq, Debug)]
+pub struct DefinitionError {
+    pub message: String,
+    pub location: Option<SourceLocation>,  // Not known for problems with how the rules fit together.
+    pub rule_name: Option<String>,  // The rule being defined where the mistake is.
+    pub snippet: Option<String>,  // The line of the definition with the mistake on it.
+}
+
+impl DefinitionError {
+    pub(crate) fn new(message: String) -> DefinitionError {
+        DefinitionError { message, location: None, rule_name: None, snippet: None }
     }
 
-    // TODO: Better error reporting - report all errors, and allow for diagnostics that
-    // print the line or at least the rule name.
+    // Points the error at a byte offset into the definition.
+    pub(crate) fn locate(self, definition: &str, byte_offset: usize, rule_name: Option<&str>) -> DefinitionError {
+        let line_start = definition[..byte_offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = definition[byte_offset..].find('\n').map_or(definition.len(), |i| byte_offset + i);
 
-    let rules_map = rule_token_slices
-        .dropping_back(1)
-        .map(|slice| parse_rule::<T>(slice))
-        .collect::<Result<HashMap<String, RuleExpression>, DefinitionError>>()?;
+        DefinitionError {
+            location: Some(LineMap::new(definition).location_of_byte(byte_offset)),
+            rule_name: rule_name.map(ToString::to_string),
+            snippet: Some(definition[line_start..line_end].trim_end().to_string()),
+            ..self
+        }
+    }
+}
 
-    let parser = Parser::<T> {rules: rules_map, phantom: std::marker::PhantomData};
-        
-    validate_parser(parser)
+impl std::fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(location) = self.location {
+            write!(f, " at line {}, column {}", location.line, location.column)?;
+        }
+        if let Some(rule_name) = &self.rule_name {
+            write!(f, " (in rule \"{rule_name}\")")?;
+        }
+        if let (Some(snippet), Some(location)) = (&self.snippet, self.location) {
+            write!(f, "\n    {snippet}\n    {}^", " ".repeat(location.column - 1))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+/* Like define_parser, but terminals named in `terminals` are matched by the given
+ * closure rather than the token type, see Parser::register_terminal. */
+pub(crate) fn define_parser_with_terminals<T: Token>(definition: &str, terminals: HashMap<String, Matcher<T>>) 
+        -> Result<Parser<T>, DefinitionError> {
+    let mut collected = CollectedDefinition::default();
+    for statement in parse_statements::<T>(definition)? {
+        match statement {
+            Statement::Rule(rule) => collected.rules.push(rule),
+            Statement::Extern(rule_names) => collected.externs.extend(rule_names),
+            Statement::Recover(rule_name, expr) => collected.sync_tokens.push((rule_name, expr)),
+            Statement::Import(import) => return Err(DefinitionError::new(format!(
+                "Cannot import \"{import}\" here, use define_parser_from_file for definitions with imports"
+            ))),
+        }
+    }
+
+    build_parser(compile_definition(collected)?, terminals)
+}
+
+pub(crate) fn define_parser_from_file_with_terminals<T: Token>(path: impl AsRef<Path>, terminals: HashMap<String, Matcher<T>>) 
+        -> Result<Parser<T>, DefinitionError> {
+    let mut collected = CollectedDefinition::default();
+    read_definition_file::<T>(path.as_ref(), &mut HashSet::new(), &mut collected)?;
+    build_parser(compile_definition(collected)?, terminals)
 }
 
-#[derive(PartialEq, Eq, Debug)]
-pub struct DefinitionError (String);
+
+/* A definition that has been read and checked, ready to become a parser without doing
+ * either again. Used by parsley_codegen, which writes these out as Rust source. */
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledGrammar {
+    pub rules: Vec<(String, RuleExpression)>,
+    pub externs: Vec<String>,
+    pub labels: Vec<(String, String)>,
+    pub sync_tokens: Vec<(String, RuleExpression)>,
+    pub locations: Vec<(String, RuleLocations)>,  // Only for rules that came from a definition.
+    pub lexer: Option<CompiledLexer>,
+}
+
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledLexer {
+    pub rules: Vec<(String, RuleExpression)>,  // Including @fragment rules.
+    pub token_rules: Vec<(String, bool)>,  // In the order they were defined, and whether each is @skip.
+    pub labels: Vec<(String, String)>,
+}
+
+/* Where a rule's choices and repetitions are in its definition, so that analyses like
+ * Parser::conflicts can point at them. Each choice has the start of every alternative,
+ * and each repetition the ?, *, + or {n,m} that makes it one. They are listed in the
+ * order RuleExpression::located_parts gives. */
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleLocations {
+    pub start: SourceLocation,  // The start of the whole expression.
+    pub parts: Vec<Vec<SourceLocation>>,
+}
+
+/* Reads and checks a definition file like define_parser_from_file, but stops short of
+ * making the parser. Also gives every file that was read, imports included. */
+#[doc(hidden)]
+pub fn compile_definition_file<T: Token>(path: impl AsRef<Path>) -> Result<(CompiledGrammar, Vec<PathBuf>), DefinitionError> {
+    let mut visited = HashSet::new();
+    let mut collected = CollectedDefinition::default();
+    read_definition_file::<T>(path.as_ref(), &mut visited, &mut collected)?;
+
+    let compiled = compile_definition(collected)?;
+    build_parser::<T>(compiled.clone(), HashMap::new())?;  // Only to check it.
+    Ok((compiled, visited.into_iter().sorted().collect()))
+}
+
+impl<T: Token> Parser<T> {
+    /* For code from parsley_codegen. The grammar was checked when it was compiled, so
+     * this only puts the parser together. */
+    #[doc(hidden)]
+    pub fn from_compiled(grammar: CompiledGrammar) -> Result<Parser<T>, DefinitionError> {
+        assemble_parser(grammar, HashMap::new())
+    }
+}
 
 
 /* Private Implementation */
@@ -44,9 +163,14 @@ pub struct DefinitionError (String);
  * to the tokens consumed by the parser (i.e. the parse::Token trait) */
 #[derive(PartialEq, Eq, Debug, Clone, PartialOrd, Ord)]
 enum DefinitionToken {
+    Annotation (String),  // Such as @token, without the @. These only belong before a rule name.
     Operator (Operator),
     Identifier (String),
     StringLiteral (String), // This holds the string that appears in the source, escape sequences are not proccessed.
+    CaseInsensitiveLiteral (String),  // Written i"select", otherwise just like StringLiteral.
+    CharacterClass (CharacterClass),
+    Wildcard,
+    EndOfInput,
     LeftParenthesis,
     RightParenthesis,
 }
@@ -60,61 +184,450 @@ enum Operator {
     Bar,
     Plus,
     Star,
-    QuestionMark
+    QuestionMark,
+    Repetition (usize, Option<usize>),  // {n}, {n,m} or {n,}
+    Tilde,
+    Ampersand,
+    Bang,
     // possibly more to come as the language gets more interesting
 }
 // Note: Ord definition reflects precedence, so Bar has least precedence.
 
+enum Statement {
+    Rule (RuleDefinition),
+    Import (String),  // The path, as written.
+    Extern (Vec<String>),  // Rules that another parser will provide, see Parser::merge.
+    Recover (String, RuleExpression),  // `recover Rule : ";" ;` gives the rule's sync tokens, see Parser::parse_tokens_recovering.
+}
+
+impl Statement {
+    // `external Name;` is shorthand for a rule that only a registered scanner can match.
+    fn external(rule_name: String) -> Statement {
+        Statement::Rule(RuleDefinition {
+            kind: RuleKind::Syntactic,
+            mode: RuleMode::Define,
+            expr: RuleExpression::External(rule_name.clone()),
+            name: rule_name,
+            ordered: false,
+            label: None,
+            file: None,
+            locations: None,
+        })
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct CollectedDefinition {
+    pub(crate) rules: Vec<RuleDefinition>,
+    pub(crate) externs: Vec<String>,
+    pub(crate) sync_tokens: Vec<(String, RuleExpression)>,
+}
+
+// A rule as it was written, before it is sorted into the parser or its lexer.
+pub(crate) struct RuleDefinition {
+    pub(crate) kind: RuleKind,
+    pub(crate) mode: RuleMode,
+    pub(crate) name: String,
+    pub(crate) expr: RuleExpression,
+    pub(crate) ordered: bool,  // Marked @ordered, so its alternatives are tried in order.
+    pub(crate) label: Option<String>,  // From @label("..."), shown in errors instead of the name.
+    pub(crate) file: Option<PathBuf>,  // Only known when reading definitions from files.
+    pub(crate) locations: Option<RuleLocations>,  // Only known when reading definitions.
+}
+
+/* Rules can only be defined once, but a later rule can replace an earlier one by
+ * starting with `override`, or add alternatives to it by starting with `extend`. This
+ * lets a grammar build on one it imports. */
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum RuleMode {
+    Define,
+    Override,
+    Extend,
+}
+
+/* Lexical rules (marked with @token, @skip or @fragment) match characters, and are
+ * used to split the input into tokens before parsing. @fragment rules are helpers
+ * that only other lexical rules can use. Everything else is a syntactic rule. */
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum RuleKind {
+    Syntactic,
+    Token,
+    Skip,
+    Fragment,
+}
+
 /* Describes the rules for what matches a specific rule. The name of the associated
  * rule is stored externally (i.e. as a hash map key) */
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RuleExpression {
-    Terminal (String),  // This string is passed into T::matches
-    RuleName (String),
+    Terminal (String),  // This string is resolved with T::kind, see Parser::kinds
+    RuleName (Symbol),
     Concatenation (Vec<RuleExpression>),
     Alternatives (Vec<RuleExpression>),
+    OrderedAlternatives (Vec<RuleExpression>),  // Like Alternatives, but only the first one that matches is used.
     Optional (Box<RuleExpression>),
     OneOrMore (Box<RuleExpression>),
-    Many (Box<RuleExpression>)
+    Many (Box<RuleExpression>),
+    CharacterClass (CharacterClass),  // Matches a single token, if it is a character within the class.
+    Negation (Box<RuleExpression>, String),  // Matches a single token that the inner expression does not. String describes the expression.
+    Wildcard,  // Matches any single token.
+    EndOfInput,  // Matches no tokens, and only at the end of the input.
+    Repetition (Box<RuleExpression>, usize, Option<usize>),  // Between min and max (inclusive) matches. No max means unbounded.
+    PositiveLookahead (Box<RuleExpression>),  // Matches no tokens, but only if the inner expression would match here.
+    NegativeLookahead (Box<RuleExpression>, String),  // The opposite of PositiveLookahead. String describes the expression.
+    External (String),  // The whole body of an `external` rule, matched by the scanner registered under this name.
+}
+
+/* A set of characters, written like [a-zA-Z_] in the definition language, or 
+ * [^a-zA-Z_] for every character except those. */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CharacterClass {
+    pub source: String,  // The class as written in the definition, used to report errors.
+    ranges: Vec<(char, char)>,  // Inclusive ranges, sorted and non-overlapping.
+    negated: bool,
+}
+
+impl CharacterClass {
+    fn new(source: String, mut ranges: Vec<(char, char)>, negated: bool) -> CharacterClass {
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(char, char)> = vec![];
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some((_, last_end)) if (*last_end as u32) + 1 >= start as u32 => *last_end = end.max(*last_end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        CharacterClass { source, ranges: merged, negated }
+    }
+
+    /* For code from parsley_codegen, which writes classes out by their ranges. */
+    #[doc(hidden)]
+    pub fn from_ranges(source: &str, ranges: &[(char, char)], negated: bool) -> CharacterClass {
+        CharacterClass::new(source.to_string(), ranges.to_vec(), negated)
+    }
+
+    #[doc(hidden)]
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+
+    #[doc(hidden)]
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    pub fn contains(&self, ch: char) -> bool {
+        let index = self.ranges.partition_point(|(start, _)| *start <= ch);
+        let in_ranges = index > 0 && self.ranges[index - 1].1 >= ch;
+        in_ranges != self.negated
+    }
+}
+
+fn read_definition_file<T: Token>(path: &Path, visited: &mut HashSet<PathBuf>, collected: &mut CollectedDefinition) 
+        -> Result<(), DefinitionError> {
+    let read_error = |err: std::io::Error| DefinitionError::new(format!("Cannot read {}: {err}", path.display()));
+
+    if !visited.insert(path.canonicalize().map_err(read_error)?) {
+        return Ok(());  // Also what keeps import cycles from going on forever.
+    }
+
+    let definition = std::fs::read_to_string(path).map_err(read_error)?;
+    let statements = parse_statements::<T>(&definition)
+        .map_err(|err| DefinitionError { message: format!("In {}: {}", path.display(), err.message), ..err })?;
+
+    for statement in statements {
+        match statement {
+            Statement::Rule(rule) => collected.rules.push(RuleDefinition { file: Some(path.to_path_buf()), ..rule }),
+            Statement::Extern(rule_names) => collected.externs.extend(rule_names),
+            Statement::Recover(rule_name, expr) => collected.sync_tokens.push((rule_name, expr)),
+            Statement::Import(import) => {
+                let import_path = path.parent().unwrap_or(Path::new("")).join(import);
+                read_definition_file::<T>(&import_path, visited, collected)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_statements<T: Token>(definition: &str) -> Result<Vec<Statement>, DefinitionError> {
+    let (tokens, offsets): (Vec<DefinitionToken>, Vec<usize>) = tokenize_with_offsets(definition)?.into_iter().unzip();
+    let statement_token_slices = tokens.split(|t| t == &DefinitionToken::Operator(Operator::Semicolon));
+
+    match statement_token_slices.clone().next_back() {
+        None => return Err(DefinitionError::new("No rules defined".to_string())),
+        Some(slice) if slice != vec![] => {
+            let start = tokens.len() - slice.len();
+            return Err(DefinitionError::new("Missing final semicolon".to_string())
+                .locate(definition, offsets[start], statement_rule_name(slice)));
+        }
+        _ => ()
+    }
+
+    // TODO: Better error reporting - report all errors, not just the first.
+
+    let line_map = LineMap::new(definition);
+    let mut start = 0;  // Index of the slice's first token, or its semicolon if it is empty.
+    statement_token_slices
+        .dropping_back(1)
+        .map(|slice| {
+            let slice_offsets = &offsets[start..start + slice.len()];
+            let offset = offsets[start];
+            start += slice.len() + 1;
+            parse_statement::<T>(slice, slice_offsets, &line_map)
+                .map_err(|err| err.locate(definition, offset, statement_rule_name(slice)))
+        })
+        .flatten_ok()
+        .collect()
+}
+
+fn parse_statement<T: Token>(slice: &[DefinitionToken], offsets: &[usize], line_map: &LineMap) -> Result<Vec<Statement>, DefinitionError> {
+    let rule_names = |tokens: &[DefinitionToken], keyword: &str| tokens.iter()
+        .map(|token| match token {
+            DefinitionToken::Identifier(rule_name) => Ok(rule_name.clone()),
+            _ => Err(DefinitionError::new(format!("{keyword} declarations can only list rule names"))),
+        })
+        .collect::<Result<Vec<String>, DefinitionError>>();
+
+    match slice {
+        [DefinitionToken::Identifier(keyword), DefinitionToken::StringLiteral(path)] if keyword == "import" 
+            => Ok(vec![Statement::Import(path.clone())]),
+        // Rules can still be called extern or external, since their names are followed by a colon.
+        [DefinitionToken::Identifier(keyword), names @ ..] if keyword == "extern" && !names.is_empty() 
+                && names.first() != Some(&DefinitionToken::Operator(Operator::Colon))
+            => rule_names(names, "Extern").map(|names| vec![Statement::Extern(names)]),
+        [DefinitionToken::Identifier(keyword), names @ ..] if keyword == "external" && !names.is_empty() 
+                && names.first() != Some(&DefinitionToken::Operator(Operator::Colon))
+            => rule_names(names, "External").map(|names| names.into_iter().map(Statement::external).collect()),
+        [DefinitionToken::Identifier(keyword), rest @ ..] if keyword == "recover" 
+                && rest.get(1) == Some(&DefinitionToken::Operator(Operator::Colon))
+            => parse_rule::<T>(rest).map(|(rule_name, expr)| vec![Statement::Recover(rule_name, expr)]),
+        _ => parse_annotated_rule::<T>(slice, offsets, line_map).map(|rule| vec![Statement::Rule(rule)]),
+    }
+}
+
+// The rule a statement defines (or declares sync tokens for): the name before its colon.
+fn statement_rule_name(slice: &[DefinitionToken]) -> Option<&str> {
+    let colon = slice.iter().position(|token| token == &DefinitionToken::Operator(Operator::Colon))?;
+    match slice.get(colon.checked_sub(1)?) {
+        Some(DefinitionToken::Identifier(rule_name)) => Some(rule_name),
+        _ => None,
+    }
+}
+
+/* Makes the parser and (if there are any lexical rules) its lexer, and checks that the rules make sense. */
+pub(crate) fn build_parser<T: Token>(compiled: CompiledGrammar, terminals: HashMap<String, Matcher<T>>) 
+        -> Result<Parser<T>, DefinitionError> {
+    let mut parser = assemble_parser(compiled, terminals)?;
+    if let Some(lexer) = parser.lexer.take() {
+        parser.lexer = Some(Box::new(Lexer { parser: validate_parser(lexer.parser)?, ..*lexer }));
+    }
+    validate_parser(parser)
+}
+
+// Builds the parser without checking that the rules make sense.
+fn assemble_parser<T: Token>(compiled: CompiledGrammar, terminals: HashMap<String, Matcher<T>>) -> Result<Parser<T>, DefinitionError> {
+    let CompiledGrammar { rules, externs, labels, sync_tokens, locations, lexer } = compiled;
+
+    let mut parser = Parser::<T>::from_rules(rules.into_iter().collect(), terminals)?;
+    parser.externs = externs.into_iter().collect();
+    parser.labels = labels.into_iter().collect();
+    parser.locations = locations.into_iter().collect();
+
+    for (rule_name, expr) in sync_tokens {
+        parser.add_sync_tokens(&rule_name, expr)?;
+    }
+
+    if let Some(CompiledLexer { rules, token_rules, labels }) = lexer {
+        let mut lexer_parser = Parser::<CharToken>::from_rules(rules.into_iter().collect(), HashMap::new())?;
+        lexer_parser.set_anchored(false);
+        lexer_parser.labels = labels.into_iter().collect();
+        parser.lexer = Some(Box::new(Lexer { parser: lexer_parser, token_rules }));
+    }
+
+    Ok(parser)
+}
+
+/* Resolves overrides and extensions, and sorts the rules into the parser's and the
+ * lexer's. Rules are sorted by name, so compiling the same definition twice gives the same result. */
+pub(crate) fn compile_definition(CollectedDefinition { rules, externs, sync_tokens }: CollectedDefinition) -> Result<CompiledGrammar, DefinitionError> {
+    let mut resolved: Vec<RuleDefinition> = vec![];
+    let mut indices = HashMap::new();
+    for rule in rules {
+        match (rule.mode, indices.get(&rule.name).copied()) {
+            (RuleMode::Define, None) => {
+                indices.insert(rule.name.clone(), resolved.len());
+                resolved.push(rule);
+            }
+            (RuleMode::Define, Some(index)) => return Err(DefinitionError::new(match (&resolved[index].file, &rule.file) {
+                (Some(first_file), Some(file)) => format!(
+                    "Rule \"{}\" is defined more than once (in {} and {})", rule.name, first_file.display(), file.display()
+                ),
+                _ => format!("Rule \"{}\" is defined more than once", rule.name),
+            })),
+            (RuleMode::Override | RuleMode::Extend, None) => return Err(DefinitionError::new(format!(
+                "Cannot {} rule \"{}\", it is not defined before this", 
+                if rule.mode == RuleMode::Override { "override" } else { "extend" }, 
+                rule.name
+            ))),
+            (RuleMode::Override, Some(index)) => resolved[index] = rule,
+            (RuleMode::Extend, Some(index)) => {
+                let base = &mut resolved[index];
+                if base.kind != rule.kind || base.ordered != rule.ordered || base.label != rule.label {
+                    return Err(DefinitionError::new(format!("Rule \"{}\" is extended with a different annotation than it was defined with", rule.name)));
+                }
+
+                base.locations = match (base.locations.take(), rule.locations) {
+                    (Some(locations), Some(extension)) => Some(locations.extend(&base.expr, extension, &rule.expr)),
+                    _ => None,
+                };
+                let mut alternatives = match std::mem::replace(&mut base.expr, RuleExpression::Wildcard) {
+                    RuleExpression::Alternatives(exprs) => exprs,
+                    expr => vec![expr],
+                };
+                match rule.expr {
+                    RuleExpression::Alternatives(exprs) => alternatives.extend(exprs),
+                    expr => alternatives.push(expr),
+                }
+                base.expr = RuleExpression::Alternatives(alternatives);
+            }
+        }
+    }
+    let rules = resolved;
+
+    let token_names = rules.iter()
+        .filter(|rule| rule.kind == RuleKind::Token)
+        .map(|rule| rule.name.clone())
+        .collect::<HashSet<String>>();
+
+    let mut compiled = CompiledGrammar { rules: vec![], externs, labels: vec![], sync_tokens: vec![], locations: vec![], lexer: None };
+    let mut lexer = CompiledLexer { rules: vec![], token_rules: vec![], labels: vec![] };
+    for RuleDefinition { kind, name, mut expr, ordered, label, locations, .. } in rules {
+        if ordered {
+            expr = expr.into_ordered();
+        }
+        if let Some(label) = label {
+            if kind == RuleKind::Syntactic { &mut compiled.labels } else { &mut lexer.labels }.push((name.clone(), label));
+        }
+
+        match kind {
+            RuleKind::Syntactic => {
+                expr.replace_token_references(&token_names);
+                if let Some(locations) = locations {
+                    compiled.locations.push((name.clone(), locations));
+                }
+                compiled.rules.push((name, expr));
+            }
+            RuleKind::Token | RuleKind::Skip => {
+                lexer.token_rules.push((name.clone(), kind == RuleKind::Skip));
+                lexer.rules.push((name, expr));
+            }
+            RuleKind::Fragment => {
+                lexer.rules.push((name, expr));
+            }
+        }
+    }
+
+    for (rule_name, mut expr) in sync_tokens {
+        expr.replace_token_references(&token_names);
+        if describe_single_token(&expr).is_err() {
+            return Err(DefinitionError::new(format!("Sync tokens for \"{rule_name}\" must each match a single token")));
+        }
+        compiled.sync_tokens.push((rule_name, expr));
+    }
+
+    compiled.rules.sort_by(|(a, _), (b, _)| a.cmp(b));
+    compiled.labels.sort();
+    compiled.locations.sort_by(|(a, _), (b, _)| a.cmp(b));
+    if !lexer.rules.is_empty() {
+        lexer.rules.sort_by(|(a, _), (b, _)| a.cmp(b));
+        lexer.labels.sort();
+        compiled.lexer = Some(lexer);
+    }
+    Ok(compiled)
 }
 
-/* Converts a string into tokens. Whitespace is removed, but considered in order
- * to differentiate adjacent identifiers. Also strips comments */
-fn tokenize(definition: &str) -> Result<Vec<DefinitionToken>, DefinitionError> {
+/* Converts a string into tokens, each with the byte offset it starts at. Whitespace
+ * is removed, but considered in order to differentiate adjacent identifiers. Also
+ * strips comments, which are either line comments starting with '#' or "//", or block
+ * comments between "/*" and "*/" */
+fn tokenize_with_offsets(definition: &str) -> Result<Vec<(DefinitionToken, usize)>, DefinitionError> {
     let mut tokens = Vec::new();
     let mut curr_token = String::new();
+    let mut curr_start = 0;  // Byte offset of curr_token in the definition.
+    let mut block_comment_start = 0;
     let mut quote_mode = false;
+    let mut bracket_mode = false;
+    let mut brace_mode = false;
     let mut comment_mode = false;
+    let mut block_comment_mode = false;
     let mut slash_mode = false;
 
-    let push_curr_token = |curr_token: &mut String, tokens: &mut Vec<DefinitionToken>| -> Result<(), DefinitionError>{
+    let push_curr_token = |curr_token: &mut String, curr_start: usize, tokens: &mut Vec<(DefinitionToken, usize)>| -> Result<(), DefinitionError>{
         if !curr_token.is_empty() {
-            tokens.push(string_to_token(curr_token.clone())?);
+            let token = string_to_token(curr_token.clone()).map_err(|err| err.locate(definition, curr_start, None))?;
+            tokens.push((token, curr_start));
             curr_token.clear();
         }    
         Ok(())
     };
 
-    for char in definition.chars() {
+    let mut chars = definition.char_indices().peekable();
+    while let Some((offset, char)) = chars.next() {
+        if curr_token.is_empty() {
+            curr_start = offset;
+        }
+
         if comment_mode && char == '\n' {
             comment_mode = false;
         }
         else if comment_mode {
             continue;
         }
+        else if block_comment_mode {
+            if char == '*' && chars.peek().map(|(_, next)| *next) == Some('/') {
+                chars.next();
+                block_comment_mode = false;
+            }
+        }
         else if slash_mode {
             slash_mode = false;
             curr_token.push(char);
         }
+        else if bracket_mode && char == ']' {
+            bracket_mode = false;
+            curr_token.push(']');
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+        }
+        else if bracket_mode && char == '\\' {
+            slash_mode = true;
+            curr_token.push('\\');
+        }
+        else if bracket_mode {
+            curr_token.push(char);
+        }
+        else if brace_mode && char == '}' {
+            brace_mode = false;
+            curr_token.push('}');
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+        }
+        else if brace_mode {
+            curr_token.push(char);
+        }
         else if char == '"' && !quote_mode {
             quote_mode = true;
-            push_curr_token(&mut curr_token, &mut tokens)?;
+            if curr_token != "i" {  // i"..." is a case insensitive literal
+                push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+                curr_start = offset;
+            }
             curr_token.push('"');
         }
         else if char == '"' && quote_mode {
             quote_mode = false;
             curr_token.push('"');
-            push_curr_token(&mut curr_token, &mut tokens)?;
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
         }
         else if quote_mode && char == '\\' {
             slash_mode = true;
@@ -123,24 +636,52 @@ fn tokenize(definition: &str) -> Result<Vec<DefinitionToken>, DefinitionError> {
         else if quote_mode {
             curr_token.push(char);
         }
-        else if char == '#' {
+        else if char == '{' {
+            brace_mode = true;
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+            curr_start = offset;
+            curr_token.push('{');
+        }
+        else if char == '[' {
+            bracket_mode = true;
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+            curr_start = offset;
+            curr_token.push('[');
+        }
+        else if char == '#' || (char == '/' && chars.peek().map(|(_, next)| *next) == Some('/')) {
             comment_mode = true;
-            push_curr_token(&mut curr_token, &mut tokens)?;
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+        }
+        else if char == '/' && chars.peek().map(|(_, next)| *next) == Some('*') {
+            chars.next();
+            block_comment_mode = true;
+            block_comment_start = offset;
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
         }
         else if char.is_whitespace() {
-            push_curr_token(&mut curr_token, &mut tokens)?;
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+        }
+        else if char == '@' {
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
+            curr_start = offset;
+            curr_token.push('@');
         }
         else if is_identifier_char(char) {
             curr_token.push(char);
         }
         else {
-            push_curr_token(&mut curr_token, &mut tokens)?;
+            push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
 
-            tokens.push(string_to_token(char.to_string())?);
+            let token = string_to_token(char.to_string()).map_err(|err| err.locate(definition, offset, None))?;
+            tokens.push((token, offset));
         }
     }
 
-    push_curr_token(&mut curr_token, &mut tokens)?;
+    if block_comment_mode {
+        return Err(DefinitionError::new("Unterminated block comment".to_string()).locate(definition, block_comment_start, None));
+    }
+
+    push_curr_token(&mut curr_token, curr_start, &mut tokens)?;
 
     Ok(tokens)
 }
@@ -154,17 +695,30 @@ fn string_to_token(mut string: String) -> Result<DefinitionToken, DefinitionErro
         "+" => Ok(DefinitionToken::Operator(Operator::Plus)),
         "*" => Ok(DefinitionToken::Operator(Operator::Star)),
         "?" => Ok(DefinitionToken::Operator(Operator::QuestionMark)),
+        "~" => Ok(DefinitionToken::Operator(Operator::Tilde)),
+        "&" => Ok(DefinitionToken::Operator(Operator::Ampersand)),
+        "!" => Ok(DefinitionToken::Operator(Operator::Bang)),
+        "." => Ok(DefinitionToken::Wildcard),
+        "$" => Ok(DefinitionToken::EndOfInput),
         "(" => Ok(DefinitionToken::LeftParenthesis),
         ")" => Ok(DefinitionToken::RightParenthesis),
-        _ if string.starts_with('"') && string.ends_with('"')
+        _ if string.len() >= 2 && string.starts_with('"') && string.ends_with('"')
             => {
                 string.remove(string.len() - 1);
                 string.remove(0);
                 Ok(DefinitionToken::StringLiteral(deliteralize(&string)?))
             }
+        _ if string.len() >= 3 && string.starts_with("i\"") && string.ends_with('"')
+            => Ok(DefinitionToken::CaseInsensitiveLiteral(deliteralize(&string[2..string.len() - 1])?)),
+        _ if string.len() >= 2 && string.starts_with('[') && string.ends_with(']')
+            => Ok(DefinitionToken::CharacterClass(parse_character_class(string)?)),
+        _ if string.len() >= 2 && string.starts_with('{') && string.ends_with('}')
+            => Ok(DefinitionToken::Operator(parse_repetition(&string)?)),
         _ if string.chars().all(is_identifier_char)
             => Ok(DefinitionToken::Identifier(string)),
-        _ => Err(DefinitionError(format!("Unrecognized token in parser definition: \"{string}\"")))
+        _ if string.len() >= 2 && string.starts_with('@') && string[1..].chars().all(is_identifier_char)
+            => Ok(DefinitionToken::Annotation(string[1..].to_string())),
+        _ => Err(DefinitionError::new(format!("Unrecognized token in parser definition: \"{string}\"")))
     }
 }
 
@@ -172,34 +726,34 @@ fn is_identifier_char(char: char) -> bool {
     char.is_ascii_alphanumeric() || char == '_'
 }
 
+/* Parses the bounds of a repetition, which look like {n}, {n,m} or {n,} */
+fn parse_repetition(string: &str) -> Result<Operator, DefinitionError> {
+    let bad_repetition = || DefinitionError::new(format!("Bad repetition {string}, expected {{n}}, {{n,m}} or {{n,}}"));
+    let parse_bound = |bound: &str| bound.trim().parse::<usize>().map_err(|_| bad_repetition());
+
+    let inner = &string[1..string.len() - 1];
+    let (min, max) = match inner.split_once(',') {
+        None => (parse_bound(inner)?, Some(parse_bound(inner)?)),
+        Some((min, max)) if max.trim().is_empty() => (parse_bound(min)?, None),
+        Some((min, max)) => (parse_bound(min)?, Some(parse_bound(max)?)),
+    };
+
+    if max.is_some_and(|max| max < min) {
+        return Err(DefinitionError::new(format!("Repetition {string} has a maximum below its minimum")));
+    }
+
+    Ok(Operator::Repetition(min, max))
+}
+
 /* Given a string that may have escape sequences, substitutes those escape sequences with 
- * the characters they represent. 
- * 
- * Currently supports all single character escape sequences supported by Rust, 
- * i.e. those that can be typed written as a backslash followed by a single character.
- * There are other escape sequences that could be supported, but I would need to
- * rewrite tokenize() above to be smarter. */
+ * the characters they represent. */
 fn deliteralize(string: &str) -> Result<String, DefinitionError> {
     let mut result = String::new();
 
-    let mut slash_mode = false;
-    for ch in string.chars() {
-        if slash_mode {
-            match ch {
-                '\\' => result.push('\\'),
-                'n' => result.push('\n'),
-                'r' => result.push('\r'),
-                't' => result.push('\t'),
-                '0' => result.push('\0'),
-                '\'' => result.push('\''),
-                '"' => result.push('"'),
-                _ => return Err(DefinitionError("Bad escape sequence".to_owned())),
-            }
-
-            slash_mode = false;
-        }
-        else if ch == '\\' {
-            slash_mode = true;
+    let mut chars = string.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            result.push(read_escape(&mut chars)?);
         }
         else {
             result.push(ch);
@@ -209,33 +763,208 @@ fn deliteralize(string: &str) -> Result<String, DefinitionError> {
     Ok(result)
 }
 
+/* Reads the rest of an escape sequence, after the backslash.
+ * 
+ * Currently supports all single character escape sequences supported by Rust, 
+ * i.e. those that can be typed written as a backslash followed by a single character,
+ * as well as unicode escapes like \u{1F600} and byte escapes like \x0A. */
+fn read_escape(chars: &mut impl Iterator<Item = char>) -> Result<char, DefinitionError> {
+    match chars.next() {
+        Some('u') => read_unicode_escape(chars),
+        Some('x') => read_byte_escape(chars),
+        Some('\\') => Ok('\\'),
+        Some('n') => Ok('\n'),
+        Some('r') => Ok('\r'),
+        Some('t') => Ok('\t'),
+        Some('0') => Ok('\0'),
+        Some('\'') => Ok('\''),
+        Some('"') => Ok('"'),
+        _ => Err(DefinitionError::new("Bad escape sequence".to_owned())),
+    }
+}
+
+// Reads the {XXXX} part of a \u{XXXX} escape.
+fn read_unicode_escape(chars: &mut impl Iterator<Item = char>) -> Result<char, DefinitionError> {
+    let bad_escape = || DefinitionError::new("Bad unicode escape, expected \\u{XXXX} with 1 to 6 hex digits".to_owned());
+
+    if chars.next() != Some('{') {
+        return Err(bad_escape());
+    }
+
+    let mut digits = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(ch) => digits.push(ch),
+            None => return Err(DefinitionError::new("Unterminated unicode escape, expected } after the hex digits".to_owned())),
+        }
+    }
+    if digits.is_empty() || digits.len() > 6 {
+        return Err(bad_escape());
+    }
+
+    let code_point = u32::from_str_radix(&digits, 16).map_err(|_| bad_escape())?;
+    char::from_u32(code_point)
+        .ok_or_else(|| DefinitionError::new(format!("\\u{{{digits}}} is not a valid unicode character")))
+}
+
+/* Reads the XX part of a \xXX escape. Unlike in Rust, anything up to \xFF is allowed,
+ * so that ByteToken grammars can name every byte. For other tokens, \xE9 is just é. */
+fn read_byte_escape(chars: &mut impl Iterator<Item = char>) -> Result<char, DefinitionError> {
+    let digits = chars.take(2).collect::<String>();
+    match u8::from_str_radix(&digits, 16) {
+        Ok(byte) if digits.len() == 2 => Ok(char::from(byte)),
+        _ => Err(DefinitionError::new("Bad byte escape, expected \\xXX with 2 hex digits".to_owned())),
+    }
+}
+
+/* Parses a character class such as [a-zA-Z_], including the brackets. Besides the
+ * usual escape sequences, brackets, dashes and carets can be escaped with a backslash.
+ * A dash at the start or end of the class stands for itself. */
+fn parse_character_class(source: String) -> Result<CharacterClass, DefinitionError> {
+    let mut chars = source[1..source.len() - 1].chars().peekable();
+    let mut members = vec![];  // (character, was escaped)
+
+    let negated = chars.next_if_eq(&'^').is_some();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if matches!(chars.peek(), Some('[' | ']' | '-' | '^')) => members.push((chars.next().expect("peeked"), true)),
+            '\\' => members.push((read_escape(&mut chars)?, true)),
+            _ => members.push((ch, false)),
+        }
+    }
+
+    let mut ranges = vec![];
+    let mut i = 0;
+    while i < members.len() {
+        if i + 2 < members.len() && members[i + 1] == ('-', false) {
+            let (start, end) = (members[i].0, members[i + 2].0);
+            if start > end {
+                return Err(DefinitionError::new(format!("Backwards range in character class {source}")));
+            }
+            ranges.push((start, end));
+            i += 3;
+        }
+        else {
+            ranges.push((members[i].0, members[i].0));
+            i += 1;
+        }
+    }
+
+    if ranges.is_empty() {
+        return Err(DefinitionError::new("Empty character class".to_string()));
+    }
+
+    Ok(CharacterClass::new(source, ranges, negated))
+}
+
+/* Reads the annotation (if any) in front of a rule, then parses the rest of the rule.
+ * Lexical rules are always parsed as rules over characters, whatever T is. */
+fn parse_annotated_rule<T: Token>(tokens: &[DefinitionToken], offsets: &[usize], line_map: &LineMap) -> Result<RuleDefinition, DefinitionError> {
+    let mode = match tokens {
+        [DefinitionToken::Identifier(keyword), next, ..] if next != &DefinitionToken::Operator(Operator::Colon) => match keyword.as_str() {
+            "override" => RuleMode::Override,
+            "extend" => RuleMode::Extend,
+            _ => RuleMode::Define,
+        },
+        _ => RuleMode::Define,
+    };
+    let (tokens, offsets) = if mode == RuleMode::Define { (tokens, offsets) } else { (&tokens[1..], &offsets[1..]) };
+
+    // @ordered and @label can go with any other annotation, but a rule only has one kind.
+    let mut kind = None;
+    let mut ordered = false;
+    let mut label = None;
+    let mut annotation_count = 0;
+    while let Some(DefinitionToken::Annotation(annotation)) = tokens.get(annotation_count) {
+        annotation_count += 1;
+        let annotated_kind = match annotation.as_str() {
+            "token" => RuleKind::Token,
+            "skip" => RuleKind::Skip,
+            "fragment" => RuleKind::Fragment,
+            "ordered" if !ordered => {
+                ordered = true;
+                continue;
+            }
+            "ordered" => return Err(DefinitionError::new("A rule can only be annotated @ordered once".to_string())),
+            "label" => {
+                // @label("an identifier") takes the label as an argument.
+                let Some([DefinitionToken::LeftParenthesis, DefinitionToken::StringLiteral(text), DefinitionToken::RightParenthesis]) 
+                        = tokens.get(annotation_count..annotation_count + 3) else {
+                    return Err(DefinitionError::new("Bad label, expected @label(\"...\")".to_string()));
+                };
+                if label.replace(text.clone()).is_some() {
+                    return Err(DefinitionError::new("A rule can only be annotated @label once".to_string()));
+                }
+                annotation_count += 3;
+                continue;
+            }
+            _ => return Err(DefinitionError::new(format!("Unknown annotation @{annotation}"))),
+        };
+
+        if kind.replace(annotated_kind).is_some() {
+            return Err(DefinitionError::new("A rule can have at most one of @token, @skip and @fragment".to_string()));
+        }
+    }
+    let kind = kind.unwrap_or(RuleKind::Syntactic);
+
+    // Extensions can start with a bar, like `extend Rule : | "more" ;`
+    let mut tokens = tokens[annotation_count..].to_vec();
+    let mut offsets = offsets[annotation_count..].to_vec();
+    if mode == RuleMode::Extend && tokens.get(2) == Some(&DefinitionToken::Operator(Operator::Bar)) {
+        tokens.remove(2);
+        offsets.remove(2);
+    }
+
+    let mut parts = vec![];
+    let (name, expr) = match kind {
+        RuleKind::Syntactic => parse_located_rule::<T>(&tokens, &offsets, &mut parts)?,
+        RuleKind::Token | RuleKind::Skip | RuleKind::Fragment => parse_located_rule::<CharToken>(&tokens, &offsets, &mut parts)?,
+    };
+    let locations = RuleLocations {
+        start: line_map.location_of_byte(offsets[2]),  // The rule parsed, so there is an expression.
+        parts: parts.into_iter().map(|part| part.into_iter().map(|offset| line_map.location_of_byte(offset)).collect()).collect(),
+    };
+
+    Ok(RuleDefinition { kind, mode, name, expr, ordered, label, file: None, locations: Some(locations) })
+}
+
+// For rules that don't need locations.
 fn parse_rule<T: Token>(tokens: &[DefinitionToken]) -> Result<(String, RuleExpression), DefinitionError> {
+    parse_located_rule::<T>(tokens, &vec![0; tokens.len()], &mut vec![])
+}
+
+/* Also adds the byte offsets of each choice and repetition in the rule to `parts`, see
+ * RuleLocations. `offsets` has the offset of each token. */
+fn parse_located_rule<T: Token>(tokens: &[DefinitionToken], offsets: &[usize], parts: &mut Vec<Vec<usize>>) 
+        -> Result<(String, RuleExpression), DefinitionError> {
     let tokens = tokens.to_vec();
 
-    if tokens.get(1).ok_or(DefinitionError("Not enough tokens in rule".to_owned()))? != &DefinitionToken::Operator(Operator::Colon) {
-        return Err(DefinitionError("Second token in rule is not ':'. Syntax: <Rule> : <Rule Expression> ;".to_owned()));
+    if tokens.get(1).ok_or(DefinitionError::new("Not enough tokens in rule".to_owned()))? != &DefinitionToken::Operator(Operator::Colon) {
+        return Err(DefinitionError::new("Second token in rule is not ':'. Syntax: <Rule> : <Rule Expression> ;".to_owned()));
     }
 
     let rule_name = match &tokens[0] {
         DefinitionToken::Identifier(str) => str.clone(),
-        _ => Err(DefinitionError("First token of rule must be an identifier. Syntax: <Rule> : <Rule Expression> ;".to_owned()))?
+        _ => Err(DefinitionError::new("First token of rule must be an identifier. Syntax: <Rule> : <Rule Expression> ;".to_owned()))?
     };
 
-    Ok((rule_name, parse_expression::<T>(&tokens[2..])?))
+    Ok((rule_name, parse_expression::<T>(&tokens[2..], &offsets[2..], parts)?))
 }
 
-#[allow(clippy::match_on_vec_items)]
-fn parse_expression<T: Token>(tokens: &[DefinitionToken]) -> Result<RuleExpression, DefinitionError> {
+fn parse_expression<T: Token>(tokens: &[DefinitionToken], offsets: &[usize], parts: &mut Vec<Vec<usize>>) 
+        -> Result<RuleExpression, DefinitionError> {
     if tokens.is_empty() {
-        return Err(DefinitionError("Encountered empty subexpression".to_string()));
+        return Err(DefinitionError::new("Encountered empty subexpression".to_string()));
     }
 
     if tokens[0] == DefinitionToken::RightParenthesis {
-        return Err(DefinitionError("Encountered right parenthesis at left of subexpression".to_string()));
+        return Err(DefinitionError::new("Encountered right parenthesis at left of subexpression".to_string()));
     }
 
     if tokens[tokens.len() - 1] == DefinitionToken::LeftParenthesis {
-        return Err(DefinitionError("Encountered left parenthesis at left of subexpression".to_string()));
+        return Err(DefinitionError::new("Encountered left parenthesis at left of subexpression".to_string()));
     }
 
     /* Scan and determine most relevant operator (least precedence!). */
@@ -260,16 +989,16 @@ fn parse_expression<T: Token>(tokens: &[DefinitionToken]) -> Result<RuleExpressi
             }
         }
         else if paren_nesting < 0 {
-            return Err(DefinitionError("Too many right parentheses in subexpression!".to_owned()));
+            return Err(DefinitionError::new("Too many right parentheses in subexpression!".to_owned()));
         }
     }
 
     if paren_nesting > 0 {
-        return Err(DefinitionError("Too many left parentheses in subexpression!".to_owned()));
+        return Err(DefinitionError::new("Too many left parentheses in subexpression!".to_owned()));
     }
 
     if min_precedence_indices.is_empty() {
-        return parse_expression::<T>(&tokens[1..tokens.len()-1]);
+        return parse_expression::<T>(&tokens[1..tokens.len()-1], &offsets[1..offsets.len()-1], parts);
     }
 
     match tokens[min_precedence_indices[0]] {
@@ -278,18 +1007,28 @@ fn parse_expression<T: Token>(tokens: &[DefinitionToken]) -> Result<RuleExpressi
                 .chain(min_precedence_indices.into_iter().map(|u| u as i32))
                 .chain(std::iter::once(tokens.len() as i32));
 
-            let sub_expressions = delimiters.clone()
+            let ranges = delimiters.clone()
                 .zip(delimiters.skip(1))
-                .map(|(left, right)| parse_expression::<T>(&tokens[((left+1) as usize)..(right as usize)]))
+                .map(|(left, right)| ((left+1) as usize)..(right as usize))
+                .collect::<Vec<_>>();
+
+            let sub_expressions = ranges.iter()
+                .map(|range| parse_expression::<T>(&tokens[range.clone()], &offsets[range.clone()], parts))
                 .collect::<Result<Vec<RuleExpression>, DefinitionError>>()?;
+            parts.push(ranges.into_iter().map(|range| offsets[range.start]).collect());  // After the alternatives, see RuleLocations.
             Ok(RuleExpression::Alternatives(sub_expressions))
         }
-        DefinitionToken::Identifier(_) | DefinitionToken::StringLiteral(_) 
-        | DefinitionToken::Operator(Operator::Plus | Operator::Star | Operator::QuestionMark) => {
+        DefinitionToken::Identifier(_) | DefinitionToken::StringLiteral(_) | DefinitionToken::CaseInsensitiveLiteral(_) 
+        | DefinitionToken::CharacterClass(_)
+        | DefinitionToken::Wildcard | DefinitionToken::EndOfInput 
+        | DefinitionToken::Operator(Operator::Plus | Operator::Star | Operator::QuestionMark | Operator::Repetition(..) 
+            | Operator::Tilde | Operator::Ampersand | Operator::Bang) => {
             let mut paren_nesting = 0;
             let mut curr_left_paren = 0;
 
             let mut sub_expressions = vec![];
+            let mut pending_negations = 0;
+            let mut lookaheads = vec![];  // (index of the atom it applies to, is positive)
 
             for i in 0..tokens.len() {
                 if tokens[i] == DefinitionToken::LeftParenthesis {
@@ -301,34 +1040,64 @@ fn parse_expression<T: Token>(tokens: &[DefinitionToken]) -> Result<RuleExpressi
                 else if tokens[i] == DefinitionToken::RightParenthesis {
                     paren_nesting -= 1;
                     if paren_nesting == 0 {
-                        sub_expressions.push(parse_expression::<T>(&tokens[curr_left_paren + 1..i])?);
+                        let expr = parse_expression::<T>(&tokens[curr_left_paren + 1..i], &offsets[curr_left_paren + 1..i], parts)?;
+                        push_atom(&mut sub_expressions, &mut pending_negations, expr)?;
                     }
                 }
                 else if paren_nesting == 0 {
                     match &tokens[i] {
-                        DefinitionToken::Identifier(rule_name) if rule_name.chars().next().expect("exists") == '_'
-                            => sub_expressions.push(RuleExpression::Terminal(rule_name[1..].to_string())),
+                        DefinitionToken::Identifier(rule_name) if rule_name.chars().next().expect("exists") == '_' 
+                            => push_atom(&mut sub_expressions, &mut pending_negations, RuleExpression::Terminal(rule_name[1..].to_string()))?,
                         DefinitionToken::Identifier(rule_name)
-                            => sub_expressions.push(RuleExpression::RuleName(rule_name.clone())),
+                            => push_atom(&mut sub_expressions, &mut pending_negations, RuleExpression::RuleName(rule_name.into()))?,
                         DefinitionToken::StringLiteral(literal)
-                            => sub_expressions.push(literal_to_combination::<T>(literal)?),
-                        DefinitionToken::Operator(Operator::Plus) => {
-                            let len = sub_expressions.len();  // appease borrow checker
-                            sub_expressions[len - 1] = RuleExpression::OneOrMore(Box::new(sub_expressions[sub_expressions.len() - 1].clone()));
-                        }
-                        DefinitionToken::Operator(Operator::Star) => {
-                            let len = sub_expressions.len();  
-                            sub_expressions[len - 1] = RuleExpression::Many(Box::new(sub_expressions[sub_expressions.len() - 1].clone()));
-                        }
-                        DefinitionToken::Operator(Operator::QuestionMark) => {
-                            let len = sub_expressions.len();  
-                            sub_expressions[len - 1] = RuleExpression::Optional(Box::new(sub_expressions[sub_expressions.len() - 1].clone()));
+                            => push_atom(&mut sub_expressions, &mut pending_negations, literal_to_combination::<T>(literal)?)?,
+                        DefinitionToken::CaseInsensitiveLiteral(literal)
+                            => push_atom(&mut sub_expressions, &mut pending_negations, case_insensitive_literal_to_combination::<T>(literal)?)?,
+                        DefinitionToken::CharacterClass(class)
+                            => push_atom(&mut sub_expressions, &mut pending_negations, RuleExpression::CharacterClass(class.clone()))?,
+                        DefinitionToken::Wildcard
+                            => push_atom(&mut sub_expressions, &mut pending_negations, RuleExpression::Wildcard)?,
+                        DefinitionToken::EndOfInput
+                            => push_atom(&mut sub_expressions, &mut pending_negations, RuleExpression::EndOfInput)?,
+                        DefinitionToken::Operator(Operator::Tilde) => pending_negations += 1,
+                        DefinitionToken::Operator(Operator::Ampersand) => lookaheads.push((sub_expressions.len(), true)),
+                        DefinitionToken::Operator(Operator::Bang) => lookaheads.push((sub_expressions.len(), false)),
+                        DefinitionToken::Operator(op @ (Operator::Plus | Operator::Star | Operator::QuestionMark | Operator::Repetition(..))) => {
+                            let last = sub_expressions.pop()
+                                .ok_or_else(|| DefinitionError::new(format!("Operator {op:?} has nothing to apply to")))?;
+
+                            sub_expressions.push(match op {
+                                Operator::Plus => RuleExpression::OneOrMore(Box::new(last)),
+                                Operator::Star => RuleExpression::Many(Box::new(last)),
+                                Operator::Repetition(min, max) => RuleExpression::Repetition(Box::new(last), *min, *max),
+                                _ => RuleExpression::Optional(Box::new(last)),
+                            });
+                            parts.push(vec![offsets[i]]);
                         }
                         _ => ()
                     }
                 }
             }
 
+            if pending_negations > 0 {
+                return Err(DefinitionError::new("Operator Tilde has nothing to apply to".to_string()));
+            }
+
+            /* Unlike ~, lookaheads bind more loosely than the postfix operators, so !"a"*
+             * means !("a"*). Applying them last (innermost first) gets this right. */
+            for (index, positive) in lookaheads.into_iter().rev() {
+                let target = sub_expressions.get_mut(index)
+                    .ok_or_else(|| DefinitionError::new(format!("Operator {} has nothing to apply to", if positive { "Ampersand" } else { "Bang" })))?;
+                let inner = Box::new(std::mem::replace(target, RuleExpression::Wildcard));
+                *target = if positive { 
+                    RuleExpression::PositiveLookahead(inner) 
+                } else { 
+                    let description = format!("!{}", describe_expression(&inner));
+                    RuleExpression::NegativeLookahead(inner, description) 
+                };
+            }
+
             if sub_expressions.len() == 1 {
                 return Ok(sub_expressions[0].clone());
             }
@@ -336,31 +1105,323 @@ fn parse_expression<T: Token>(tokens: &[DefinitionToken]) -> Result<RuleExpressi
             Ok(RuleExpression::Concatenation(sub_expressions))
         }
 
-        DefinitionToken::Operator(a) => Err(DefinitionError(format!("Bad operator {a:?}"))),
+        DefinitionToken::Operator(a) => Err(DefinitionError::new(format!("Bad operator {a:?}"))),
+
+        DefinitionToken::Annotation(ref annotation)
+            => Err(DefinitionError::new(format!("Annotation @{annotation} must come before the rule name"))),
 
         DefinitionToken::LeftParenthesis | DefinitionToken::RightParenthesis 
-            => Err(DefinitionError("Subexpression is only parentheses".to_string())),
+            => Err(DefinitionError::new("Subexpression is only parentheses".to_string())),
+    }
+}
+
+/* Adds an atom (anything that isn't an operator) to a concatenation. Prefix negation
+ * binds more tightly than the postfix operators, so ~"a"* means (~"a")* */
+fn push_atom(sub_expressions: &mut Vec<RuleExpression>, pending_negations: &mut usize, mut expr: RuleExpression) 
+        -> Result<(), DefinitionError> {
+    for _ in 0..std::mem::take(pending_negations) {
+        let description = format!("~{}", describe_single_token(&expr)?);
+        expr = RuleExpression::Negation(Box::new(expr), description);
+    }
+
+    sub_expressions.push(expr);
+    Ok(())
+}
+
+// Describes an expression for error messages, ensuring it always matches exactly one token.
+pub(crate) fn describe_single_token(expr: &RuleExpression) -> Result<String, DefinitionError> {
+    match expr {
+        RuleExpression::Terminal(term) => Ok(term.clone()),
+        RuleExpression::CharacterClass(class) => Ok(class.source.clone()),
+        RuleExpression::Negation(_, description) => Ok(description.clone()),
+        RuleExpression::Wildcard => Ok(".".to_string()),
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => Ok(format!("({})", 
+            exprs.iter().map(describe_single_token).collect::<Result<Vec<_>, _>>()?.join(" | ")
+        )),
+        _ => Err(DefinitionError::new("Only expressions that match a single token can be negated with ~".to_string())),
     }
 }
 
-fn literal_to_combination<T: Token>(literal: &str) -> Result<RuleExpression, DefinitionError> {
+// Describes any expression for error messages, roughly as it would be written in a definition.
+pub(crate) fn describe_expression(expr: &RuleExpression) -> String {
+    let describe_all = |exprs: &[RuleExpression], separator| exprs.iter().map(describe_expression).join(separator);
+    match expr {
+        RuleExpression::Terminal(name) | RuleExpression::External(name) => name.clone(),
+        RuleExpression::RuleName(name) => name.to_string(),
+        RuleExpression::CharacterClass(class) => class.source.clone(),
+        RuleExpression::Negation(_, description) | RuleExpression::NegativeLookahead(_, description) => description.clone(),
+        RuleExpression::Wildcard => ".".to_string(),
+        RuleExpression::EndOfInput => "$".to_string(),
+        RuleExpression::Concatenation(exprs) => format!("({})", describe_all(exprs, " ")),
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => format!("({})", describe_all(exprs, " | ")),
+        RuleExpression::Optional(expr) => format!("{}?", describe_expression(expr)),
+        RuleExpression::OneOrMore(expr) => format!("{}+", describe_expression(expr)),
+        RuleExpression::Many(expr) => format!("{}*", describe_expression(expr)),
+        RuleExpression::Repetition(expr, min, Some(max)) => format!("{}{{{min},{max}}}", describe_expression(expr)),
+        RuleExpression::Repetition(expr, min, None) => format!("{}{{{min},}}", describe_expression(expr)),
+        RuleExpression::PositiveLookahead(expr) => format!("&{}", describe_expression(expr)),
+    }
+}
+
+pub(crate) fn literal_to_combination<T: Token>(literal: &str) -> Result<RuleExpression, DefinitionError> {
     match T::type_sequence_from_literal(literal) {
-        Some(sequence) if sequence.is_empty() => Err(DefinitionError("Matching no tokens is forbidden".to_string())),
+        Some(sequence) if sequence.is_empty() => Err(DefinitionError::new("Matching no tokens is forbidden".to_string())),
         Some(sequence) if sequence.len() == 1 => Ok(RuleExpression::Terminal(sequence[0].clone())),
         Some(sequence) if sequence.len() > 1
             => Ok(RuleExpression::Concatenation(sequence.into_iter().map(RuleExpression::Terminal).collect())),
-        Some(_) => Err(DefinitionError("Something went horribly wrong".to_owned())),
-        None => Err(DefinitionError("Token type does not support converting string literals".to_owned())),
+        Some(_) => Err(DefinitionError::new("Something went horribly wrong".to_owned())),
+        None => Err(DefinitionError::new("Token type does not support converting string literals".to_owned())),
+    }
+}
+
+/* Token types that can't match case insensitively themselves get one character class
+ * per character instead, like [sS] [eE] [lL] [eE] [cC] [tT] for i"select". */
+pub(crate) fn case_insensitive_literal_to_combination<T: Token>(literal: &str) -> Result<RuleExpression, DefinitionError> {
+    if let Some(sequence) = T::type_sequence_from_literal_ignoring_case(literal) {
+        return match sequence.len() {
+            0 => Err(DefinitionError::new("Matching no tokens is forbidden".to_string())),
+            1 => Ok(RuleExpression::Terminal(sequence[0].clone())),
+            _ => Ok(RuleExpression::Concatenation(sequence.into_iter().map(RuleExpression::Terminal).collect())),
+        };
+    }
+
+    let mut classes = literal.chars()
+        .map(|ch| {
+            // Only simple case mappings, so "ß" doesn't try to match "SS".
+            let simple = |mapped: Vec<char>| if mapped.len() == 1 { mapped[0] } else { ch };
+            let lower = simple(ch.to_lowercase().collect());
+            let upper = simple(ch.to_uppercase().collect());
+
+            let members = [ch, lower, upper].into_iter().unique().collect::<Vec<char>>();
+            RuleExpression::CharacterClass(CharacterClass::new(
+                format!("[{}]", members.iter().collect::<String>()),
+                members.iter().map(|ch| (*ch, *ch)).collect(),
+                false
+            ))
+        })
+        .collect::<Vec<RuleExpression>>();
+
+    match classes.len() {
+        0 => Err(DefinitionError::new("Matching no tokens is forbidden".to_string())),
+        1 => Ok(classes.remove(0)),
+        _ => Ok(RuleExpression::Concatenation(classes)),
+    }
+}
+
+impl RuleExpression {
+    // Adds the names of all rules used within this expression to `names`.
+    pub(crate) fn referenced_rules<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_) 
+            | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => (),
+            RuleExpression::RuleName(name) => names.push(name),
+            RuleExpression::Concatenation(exprs) | RuleExpression::Alternatives(exprs) 
+            | RuleExpression::OrderedAlternatives(exprs) => {
+                for expr in exprs {
+                    expr.referenced_rules(names);
+                }
+            }
+            RuleExpression::Optional(expr) | RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr)
+            | RuleExpression::Negation(expr, _) | RuleExpression::Repetition(expr, ..) 
+            | RuleExpression::PositiveLookahead(expr) | RuleExpression::NegativeLookahead(expr, _) => expr.referenced_rules(names),
+        }
+    }
+
+    // Adds every terminal within this expression to `terminals`.
+    pub(crate) fn terminals<'a>(&'a self, terminals: &mut Vec<&'a str>) {
+        match self {
+            RuleExpression::Terminal(term) => terminals.push(term),
+            RuleExpression::CharacterClass(_) | RuleExpression::RuleName(_)
+            | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => (),
+            RuleExpression::Concatenation(exprs) | RuleExpression::Alternatives(exprs) 
+            | RuleExpression::OrderedAlternatives(exprs) => {
+                for expr in exprs {
+                    expr.terminals(terminals);
+                }
+            }
+            RuleExpression::Optional(expr) | RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr)
+            | RuleExpression::Negation(expr, _) | RuleExpression::Repetition(expr, ..) 
+            | RuleExpression::PositiveLookahead(expr) | RuleExpression::NegativeLookahead(expr, _) => expr.terminals(terminals),
+        }
+    }
+
+    // Syntactic rules may refer to @token rules by name, which means "a token of that kind".
+    pub(crate) fn replace_token_references(&mut self, token_names: &HashSet<String>) {
+        match self {
+            RuleExpression::RuleName(name) if token_names.contains(name.as_str()) => *self = RuleExpression::Terminal(name.to_string()),
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_) | RuleExpression::RuleName(_)
+            | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => (),
+            RuleExpression::Concatenation(exprs) | RuleExpression::Alternatives(exprs) 
+            | RuleExpression::OrderedAlternatives(exprs) => {
+                for expr in exprs {
+                    expr.replace_token_references(token_names);
+                }
+            }
+            RuleExpression::Optional(expr) | RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr)
+            | RuleExpression::Negation(expr, _) | RuleExpression::Repetition(expr, ..) 
+            | RuleExpression::PositiveLookahead(expr) | RuleExpression::NegativeLookahead(expr, _) => expr.replace_token_references(token_names),
+        }
+    }
+
+    // Makes every reference to a rule share the symbol that names the rule itself.
+    pub(crate) fn intern_rule_names(&mut self, rule_names: &HashSet<Symbol>) {
+        match self {
+            RuleExpression::RuleName(name) => if let Some(interned) = rule_names.get(name) {
+                *name = interned.clone();
+            }
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_)
+            | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => (),
+            RuleExpression::Concatenation(exprs) | RuleExpression::Alternatives(exprs) 
+            | RuleExpression::OrderedAlternatives(exprs) => {
+                for expr in exprs {
+                    expr.intern_rule_names(rule_names);
+                }
+            }
+            RuleExpression::Optional(expr) | RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr)
+            | RuleExpression::Negation(expr, _) | RuleExpression::Repetition(expr, ..) 
+            | RuleExpression::PositiveLookahead(expr) | RuleExpression::NegativeLookahead(expr, _) => expr.intern_rule_names(rule_names),
+        }
+    }
+
+    // Makes every choice within this expression an ordered one, for rules marked @ordered.
+    /* The choices and repetitions within this expression, each after everything inside
+     * it, which is the order the definition parser finishes them in. */
+    pub(crate) fn located_parts<'a>(&'a self, parts: &mut Vec<&'a RuleExpression>) {
+        match self {
+            RuleExpression::Terminal(_) | RuleExpression::RuleName(_) | RuleExpression::CharacterClass(_)
+            | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => (),
+            RuleExpression::Concatenation(exprs) => {
+                for expr in exprs {
+                    expr.located_parts(parts);
+                }
+            }
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+                for expr in exprs {
+                    expr.located_parts(parts);
+                }
+                parts.push(self);
+            }
+            RuleExpression::Optional(expr) | RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr)
+            | RuleExpression::Repetition(expr, ..) => {
+                expr.located_parts(parts);
+                parts.push(self);
+            }
+            RuleExpression::Negation(expr, _) | RuleExpression::PositiveLookahead(expr) 
+            | RuleExpression::NegativeLookahead(expr, _) => expr.located_parts(parts),
+        }
+    }
+
+    // The expressions directly inside this one, in order.
+    pub(crate) fn subexpressions(&self) -> &[RuleExpression] {
+        match self {
+            RuleExpression::Terminal(_) | RuleExpression::RuleName(_) | RuleExpression::CharacterClass(_)
+            | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => &[],
+            RuleExpression::Concatenation(exprs) | RuleExpression::Alternatives(exprs) 
+            | RuleExpression::OrderedAlternatives(exprs) => exprs,
+            RuleExpression::Optional(expr) | RuleExpression::OneOrMore(expr) | RuleExpression::Many(expr)
+            | RuleExpression::Negation(expr, _) | RuleExpression::Repetition(expr, ..) 
+            | RuleExpression::PositiveLookahead(expr) | RuleExpression::NegativeLookahead(expr, _) => std::slice::from_ref(&**expr),
+        }
+    }
+
+    /* Works out a value for every expression from the values of its subexpressions, from
+     * the innermost out, and gives this one's. It keeps its own stack, so that deeply
+     * nested expressions can't overflow the call stack. */
+    pub(crate) fn fold<'e, R>(&'e self, mut f: impl FnMut(&'e RuleExpression, Vec<R>) -> R) -> R {
+        // Each expression being worked on, with the subexpressions still to do and the values of those done.
+        let mut pending = vec![(self, self.subexpressions().iter(), vec![])];
+        loop {
+            let (_, exprs, _) = pending.last_mut().expect("An expression is being worked on");
+            if let Some(expr) = exprs.next() {
+                pending.push((expr, expr.subexpressions().iter(), vec![]));
+                continue;
+            }
+
+            let (expr, _, values) = pending.pop().expect("An expression is being worked on");
+            let value = f(expr, values);
+            match pending.last_mut() {
+                Some((_, _, values)) => values.push(value),
+                None => return value,
+            }
+        }
+    }
+
+    fn into_ordered(self) -> RuleExpression {
+        let ordered = |expr: Box<RuleExpression>| Box::new(expr.into_ordered());
+        match self {
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) 
+                => RuleExpression::OrderedAlternatives(exprs.into_iter().map(RuleExpression::into_ordered).collect()),
+            RuleExpression::Concatenation(exprs) => RuleExpression::Concatenation(exprs.into_iter().map(RuleExpression::into_ordered).collect()),
+            RuleExpression::Optional(expr) => RuleExpression::Optional(ordered(expr)),
+            RuleExpression::OneOrMore(expr) => RuleExpression::OneOrMore(ordered(expr)),
+            RuleExpression::Many(expr) => RuleExpression::Many(ordered(expr)),
+            RuleExpression::Repetition(expr, min, max) => RuleExpression::Repetition(ordered(expr), min, max),
+            RuleExpression::PositiveLookahead(expr) => RuleExpression::PositiveLookahead(ordered(expr)),
+            RuleExpression::NegativeLookahead(expr, description) => RuleExpression::NegativeLookahead(ordered(expr), description),
+            expr @ (RuleExpression::Terminal(_) | RuleExpression::RuleName(_) | RuleExpression::CharacterClass(_)
+            | RuleExpression::Negation(..) | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_)) => expr,
+        }
+    }
+}
+
+impl RuleLocations {
+    /* Matches each choice and repetition in `expr` (the rule these are the locations of)
+     * with its locations. Empty if they don't line up, since no location beats a wrong one. */
+    pub(crate) fn of_parts<'a>(&'a self, expr: &'a RuleExpression) -> HashMap<ByAddress<&'a RuleExpression>, &'a [SourceLocation]> {
+        let mut exprs = vec![];
+        expr.located_parts(&mut exprs);
+        if exprs.len() != self.parts.len() {
+            return HashMap::new();
+        }
+        exprs.into_iter().map(ByAddress).zip(self.parts.iter().map(Vec::as_slice)).collect()
+    }
+
+    // For `extend`, which makes one choice out of the alternatives of both rules. It comes after everything else.
+    fn extend(self, expr: &RuleExpression, extension: RuleLocations, extension_expr: &RuleExpression) -> RuleLocations {
+        let split = |RuleLocations { start, mut parts }: RuleLocations, expr: &RuleExpression| match expr {
+            RuleExpression::Alternatives(_) => {
+                let alternatives = parts.pop().unwrap_or_default();
+                (parts, alternatives)
+            }
+            _ => (parts, vec![start]),
+        };
+
+        let start = self.start;
+        let (mut parts, mut alternatives) = split(self, expr);
+        let (extension_parts, extension_alternatives) = split(extension, extension_expr);
+        parts.extend(extension_parts);
+        alternatives.extend(extension_alternatives);
+        parts.push(alternatives);
+        RuleLocations { start, parts }
     }
 }
 
-#[allow(clippy::unnecessary_wraps)]
 fn validate_parser<T: Token>(parser: Parser<T>) -> Result<Parser<T>, DefinitionError> {
-    // TODO!
+    // TODO: Ensure at most one modifier per literal (basically, ensure Definition Language Grammar)
+
+    let references = parser.rules.iter()
+        .map(|(rule_name, expr)| {
+            let mut names = vec![];
+            expr.referenced_rules(&mut names);
+            (rule_name.as_str(), names)
+        })
+        .collect::<HashMap<&str, Vec<&str>>>();
 
     // Ensure all rules are spelled correctly
-    // Ensure at most one modifier per literal (basically, ensure Definition Language Grammar)
-    // Ensure no left recursion
+    for rule_name in references.keys().sorted() {
+        if let Some(undefined) = references[rule_name].iter().find(|name| !parser.rules.contains_key(**name) && !parser.externs.contains(**name)) {
+            let token_names = parser.lexer.iter()
+                .flat_map(|lexer| lexer.token_rules.iter())
+                .filter(|(_, skip)| !skip)
+                .map(|(token_name, _)| token_name.as_str());
+            let names = parser.rules.keys().map(Symbol::as_str).chain(parser.externs.iter().map(String::as_str)).chain(token_names);
+            return Err(DefinitionError::new(match crate::parse::closest_name(undefined, names) {
+                Some(suggestion) => format!("Rule \"{rule_name}\" uses undefined rule \"{undefined}\", did you mean \"{suggestion}\"?"),
+                None => format!("Rule \"{rule_name}\" uses undefined rule \"{undefined}\""),
+            }));
+        }
+    }
+
     Ok(parser)
 }
 
@@ -375,6 +1436,13 @@ mod tests {
     use super::Operator::*;
     use super::RuleExpression::*;
 
+    // Most tests don't care where the tokens are.
+    fn tokenize(definition: &str) -> Result<Vec<DefinitionToken>, String> {
+        tokenize_with_offsets(definition)
+            .map(|tokens| tokens.into_iter().map(|(token, _)| token).collect())
+            .map_err(|err| err.message)
+    }
+
     #[test]
     fn test_tokenize() {
         assert_eq!(
@@ -398,8 +1466,172 @@ mod tests {
                 Operator(Semicolon)
             ])
         );
+
+        assert_eq!(
+            tokenize(r#"a: "." .* ;"#),
+            Ok(vec![
+                Identifier("a".to_string()),
+                Operator(Colon),
+                StringLiteral(".".to_string()),
+                DefinitionToken::Wildcard,
+                Operator(Star),
+                Operator(Semicolon)
+            ])
+        );
+    }
+
+
+    #[test]
+    fn test_tokenize_comments() {
+        assert_eq!(
+            tokenize("a # one \n b // two \n c /* three \n four */ d /**/ e/***/f \"/*not*/\" // \"five"),
+            Ok(vec![
+                Identifier("a".to_string()),
+                Identifier("b".to_string()),
+                Identifier("c".to_string()),
+                Identifier("d".to_string()),
+                Identifier("e".to_string()),
+                Identifier("f".to_string()),
+                StringLiteral("/*not*/".to_string()),
+            ])
+        );
+
+        assert_eq!(
+            tokenize("a /* b "),
+            Err("Unterminated block comment".to_string())
+        );
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(
+            tokenize(r#""\n\t\r\0\\\"\'" "\u{41}\u{1F600}\u{e9}" [\u{61}-\u{63}]"#),
+            Ok(vec![
+                StringLiteral("\n\t\r\0\\\"\'".to_string()),
+                StringLiteral("A\u{1F600}é".to_string()),
+                DefinitionToken::CharacterClass(super::CharacterClass::new(r"[\u{61}-\u{63}]".to_string(), vec![('a', 'c')], false)),
+            ])
+        );
+
+        assert_eq!(tokenize(r#""\x0A\xff" [\x00-\x1F]"#).expect("ok")[0], StringLiteral("\n\u{ff}".to_string()));
+        assert!(tokenize(r#""\x4""#).is_err());
+        assert!(tokenize(r#""\xG0""#).is_err());
+        assert!(tokenize(r#""\q""#).is_err());
+        assert!(tokenize(r#""\u41""#).is_err());
+        assert!(tokenize(r#""\u{}""#).is_err());
+        assert!(tokenize(r#""\u{1234567}""#).is_err());
+        assert!(tokenize(r#""\u{D800}""#).is_err());
+        assert!(tokenize(r#""\u{4G}""#).is_err());
+        assert!(tokenize(r#""\u{41""#).is_err());
+    }
+
+    #[test]
+    fn test_case_insensitive_literals() {
+        assert_eq!(
+            tokenize(r#"i"Ab" i "c" pi"d""#),
+            Ok(vec![
+                CaseInsensitiveLiteral("Ab".to_string()),
+                Identifier("i".to_string()),
+                StringLiteral("c".to_string()),
+                Identifier("pi".to_string()),
+                StringLiteral("d".to_string()),
+            ])
+        );
+
+        assert_eq!(
+            case_insensitive_literal_to_combination::<crate::CharToken>("a1"),
+            Ok(Concatenation(vec![
+                RuleExpression::CharacterClass(super::CharacterClass::new("[aA]".to_string(), vec![('a', 'a'), ('A', 'A')], false)),
+                RuleExpression::CharacterClass(super::CharacterClass::new("[1]".to_string(), vec![('1', '1')], false)),
+            ]))
+        );
     }
 
+    #[test]
+    fn test_character_classes() {
+        let tokens = tokenize(r#"[a-c_] [\]\-] [-a] ["\n] [z-a]"#);
+        assert_eq!(tokens, Err("Backwards range in character class [z-a]".to_string()));
+
+        let tokens = tokenize(r#"[a-c_] [\]\-] [-a] ["\n] [x-zy]"#).unwrap();
+        let classes = tokens.iter()
+            .map(|token| match token {
+                DefinitionToken::CharacterClass(class) => class,
+                _ => panic!("Expected character class"),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(classes[0].source, "[a-c_]");
+        assert_eq!(classes[0].ranges, vec![('_', '_'), ('a', 'c')]);
+        assert!(classes[0].contains('b') && classes[0].contains('_') && !classes[0].contains('d'));
+        assert_eq!(classes[1].ranges, vec![('-', '-'), (']', ']')]);
+        assert_eq!(classes[2].ranges, vec![('-', '-'), ('a', 'a')]);
+        assert_eq!(classes[3].ranges, vec![('\n', '\n'), ('"', '"')]);
+        assert_eq!(classes[4].ranges, vec![('x', 'z')]);
+
+        assert_eq!(tokenize("[]"), Err("Empty character class".to_string()));
+
+        let tokens = tokenize(r#"[^"\n] [\^]"#).unwrap();
+        let (DefinitionToken::CharacterClass(negated), DefinitionToken::CharacterClass(caret)) = (&tokens[0], &tokens[1]) 
+            else { panic!("Expected character classes") };
+
+        assert!(negated.contains('a') && !negated.contains('"') && !negated.contains('\n'));
+        assert!(caret.contains('^') && !caret.contains('a'));
+    }
+
+    #[test]
+    fn test_repetition() {
+        assert_eq!(
+            parse_rule::<crate::CharToken>(&tokenize(r#"Rule: "a"{3} "b"{ 1 , 2 } ("c" | "d"){0,}"#).unwrap()),
+            Ok(("Rule".to_string(), Concatenation(vec![
+                RuleExpression::Repetition(Box::new(Terminal("a".to_string())), 3, Some(3)),
+                RuleExpression::Repetition(Box::new(Terminal("b".to_string())), 1, Some(2)),
+                RuleExpression::Repetition(Box::new(Alternatives(vec![Terminal("c".to_string()), Terminal("d".to_string())])), 0, None),
+            ])))
+        );
+
+        assert!(tokenize(r#""a"{3,2}"#).is_err());
+        assert!(tokenize(r#""a"{x}"#).is_err());
+        assert!(tokenize(r#""a"{,2}"#).is_err());
+    }
+
+    #[test]
+    fn test_negation() {
+        assert_eq!(
+            parse_rule::<crate::CharToken>(&tokenize(r#"Rule: ~"a"* ~("b" | [cd] | ~_e)"#).unwrap()),
+            Ok(("Rule".to_string(), Concatenation(vec![
+                Many(Box::new(Negation(Box::new(Terminal("a".to_string())), "~a".to_string()))),
+                Negation(
+                    Box::new(Alternatives(vec![
+                        Terminal("b".to_string()),
+                        RuleExpression::CharacterClass(super::CharacterClass::new("[cd]".to_string(), vec![('c', 'd')], false)),
+                        Negation(Box::new(Terminal("e".to_string())), "~e".to_string()),
+                    ])),
+                    "~(b | [cd] | ~e)".to_string()
+                ),
+            ])))
+        );
+
+        assert!(parse_rule::<crate::CharToken>(&tokenize(r#"Rule: ~"ab""#).unwrap()).is_err());
+        assert!(parse_rule::<crate::CharToken>(&tokenize(r#"Rule: ~Other"#).unwrap()).is_err());
+        assert!(parse_rule::<crate::CharToken>(&tokenize(r#"Rule: "a" ~"#).unwrap()).is_err());
+        assert!(parse_rule::<crate::CharToken>(&tokenize(r#"Rule: * "a""#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_lookahead() {
+        assert_eq!(
+            parse_rule::<crate::CharToken>(&tokenize(r#"Rule: !"a"* &B ~"c"+ !!D"#).unwrap()),
+            Ok(("Rule".to_string(), Concatenation(vec![
+                NegativeLookahead(Box::new(Many(Box::new(Terminal("a".to_string())))), "!a*".to_string()),
+                PositiveLookahead(Box::new(RuleName("B".into()))),
+                OneOrMore(Box::new(Negation(Box::new(Terminal("c".to_string())), "~c".to_string()))),
+                NegativeLookahead(Box::new(NegativeLookahead(Box::new(RuleName("D".into())), "!D".to_string())), "!!D".to_string()),
+            ])))
+        );
+
+        assert!(parse_rule::<crate::CharToken>(&tokenize(r#"Rule: "a" !"#).unwrap()).is_err());
+        assert!(parse_rule::<crate::CharToken>(&tokenize(r#"Rule: "a" & *"#).unwrap()).is_err());
+    }
 
     #[test]
     fn test_parse_rule() {
@@ -409,27 +1641,27 @@ mod tests {
             parse_rule::<crate::CharToken>(&tokenize("Color : Number Number Number | HexString | ColorName").unwrap()),
             Ok(("Color".to_string(), Alternatives(vec![
                 Concatenation(vec![
-                    RuleName("Number".to_string()),
-                    RuleName("Number".to_string()),
-                    RuleName("Number".to_string()),
+                    RuleName("Number".into()),
+                    RuleName("Number".into()),
+                    RuleName("Number".into()),
                 ]),
-                RuleName("HexString".to_string()),
-                RuleName("ColorName".to_string()),
+                RuleName("HexString".into()),
+                RuleName("ColorName".into()),
             ])))
         );
 
         assert_eq!(
             parse_rule::<crate::CharToken>(&tokenize("Rule: (A | (B | (C) D) | ((E)))").unwrap()),
             Ok(("Rule".to_string(), Alternatives(vec![
-                RuleName("A".to_string()),
+                RuleName("A".into()),
                 Alternatives(vec![
-                    RuleName("B".to_string()),
+                    RuleName("B".into()),
                     Concatenation(vec![
-                        RuleName("C".to_string()),
-                        RuleName("D".to_string()),
+                        RuleName("C".into()),
+                        RuleName("D".into()),
                     ])
                 ]),
-                RuleName("E".to_string()),
+                RuleName("E".into()),
             ])))
         );
 
@@ -478,8 +1710,179 @@ mod tests {
         let parser : Parser<crate::CharToken> = define_parser(&def).expect("ok");
 
         ["program", "identifier", "number", "string", "assignment", "alphabetic_character", "digit", "white_space", "all_characters_no_quote"]
-            .map(|name| {
+            .into_iter().for_each(|name| {
                 assert!(parser.rules.contains_key(name));
             });
     }
+
+    #[test]
+    fn test_imports() {
+        let dir = std::env::temp_dir().join(format!("parsley_test_imports_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("main.psl"), "import \"lib/words.psl\"; Sentence : Word (\" \" Word)* ;").unwrap();
+        std::fs::write(dir.join("lib/words.psl"), "import \"letters.psl\"; import \"../main.psl\"; Word : Letter+ ;").unwrap();
+        std::fs::write(dir.join("lib/letters.psl"), "Letter : [a-z] ;").unwrap();
+        std::fs::write(dir.join("conflict.psl"), "import \"lib/letters.psl\"; Letter : [A-Z] ;").unwrap();
+
+        let parser = define_parser_from_file::<crate::CharToken>(dir.join("main.psl")).expect("ok");
+        assert_eq!(parser.rules.keys().sorted().collect::<Vec<_>>(), vec!["Letter", "Sentence", "Word"]);
+
+        assert_eq!(
+            define_parser_from_file::<crate::CharToken>(dir.join("conflict.psl")).err().map(|err| err.message),
+            Some(format!(
+                "Rule \"Letter\" is defined more than once (in {} and {})",
+                dir.join("lib/letters.psl").display(),
+                dir.join("conflict.psl").display()
+            ))
+        );
+
+        assert!(define_parser_from_file::<crate::CharToken>(dir.join("missing.psl")).is_err());
+        assert!(define_parser::<crate::CharToken>("import \"main.psl\"; A : \"a\" ;").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_override_and_extend() {
+        let parser = define_parser::<crate::CharToken>(r#"
+            Expr : Literal ("+" Literal)* ;
+            Literal : "a" | "b" ;
+            Digit : "0" ;
+            extend Literal : | "c" | "d" ;
+            extend Literal : Digit ;
+            override Digit : [0-9] ;
+            override : "x" ;  # Just a rule named override
+            Start : Expr override ;
+        "#).expect("ok");
+
+        assert_eq!(parser.rules["Literal"], Alternatives(vec![
+            Terminal("a".to_string()),
+            Terminal("b".to_string()),
+            Terminal("c".to_string()),
+            Terminal("d".to_string()),
+            RuleName("Digit".into()),
+        ]));
+        assert!(matches!(parser.rules["Digit"], RuleExpression::CharacterClass(_)));
+
+        assert_eq!(
+            define_parser::<crate::CharToken>("override A : \"a\" ; A : \"b\" ;").err().map(|err| err.message),
+            Some("Cannot override rule \"A\", it is not defined before this".to_string())
+        );
+        assert_eq!(
+            define_parser::<crate::LexedToken>("A : B ; @token B : \"b\" ; extend B : | \"c\" ;").err().map(|err| err.message),
+            Some("Rule \"B\" is extended with a different annotation than it was defined with".to_string())
+        );
+        define_parser::<crate::LexedToken>("A : B ; @token B : \"b\" ; extend @token B : | \"c\" ;").expect("ok");
+    }
+
+    #[test]
+    fn test_ordered_annotation() {
+        let parser = define_parser::<crate::LexedToken>(r#"
+            @ordered Statement : "let" Name | Name ("=" | "+=") Name ;
+            @ordered @token Name : [a-z]+ | "$" ;
+            @token Symbol : "=" | "+=" ;
+        "#).expect("ok");
+
+        assert_eq!(parser.rules["Statement"], OrderedAlternatives(vec![
+            Concatenation(vec![Terminal("\"let\"".to_string()), Terminal("Name".to_string())]),
+            Concatenation(vec![
+                Terminal("Name".to_string()),
+                OrderedAlternatives(vec![Terminal("\"=\"".to_string()), Terminal("\"+=\"".to_string())]),
+                Terminal("Name".to_string()),
+            ]),
+        ]));
+        let lexer = parser.lexer.as_ref().expect("Has a lexer");
+        assert!(matches!(lexer.parser.rules["Name"], OrderedAlternatives(_)));
+        assert!(matches!(lexer.parser.rules["Symbol"], Alternatives(_)));
+
+        assert_eq!(
+            define_parser::<crate::CharToken>("@token @skip A : \"a\" ;").err().map(|err| err.message),
+            Some("A rule can have at most one of @token, @skip and @fragment".to_string())
+        );
+        assert_eq!(
+            define_parser::<crate::CharToken>("@ordered @ordered A : \"a\" ;").err().map(|err| err.message),
+            Some("A rule can only be annotated @ordered once".to_string())
+        );
+        assert_eq!(
+            define_parser::<crate::CharToken>("@ordered A : \"a\" | B ; B : \"b\" ; extend A : \"c\" ;").err().map(|err| err.message),
+            Some("Rule \"A\" is extended with a different annotation than it was defined with".to_string())
+        );
+    }
+
+    #[test]
+    fn test_label_annotation() {
+        let parser = define_parser::<crate::LexedToken>(r#"
+            @label("an assignment") Statement : Name "=" Name ;
+            @token @label("a name") Name : [a-z]+ ;
+            @token Symbol : "=" ;
+        "#).expect("ok");
+
+        assert_eq!(parser.label("Statement"), Some("an assignment"));
+        assert_eq!(parser.label("Name"), Some("a name"));
+        assert_eq!(parser.label("Symbol"), None);
+        assert_eq!(parser.labels.len(), 1);
+
+        assert_eq!(
+            define_parser::<crate::CharToken>("@label A : \"a\" ;").err().map(|err| err.message),
+            Some("Bad label, expected @label(\"...\")".to_string())
+        );
+        assert_eq!(
+            define_parser::<crate::CharToken>("@label(\"x\") @label(\"y\") A : \"a\" ;").err().map(|err| err.message),
+            Some("A rule can only be annotated @label once".to_string())
+        );
+        assert!(define_parser::<crate::CharToken>("@label(\"x\") A : \"a\" ; extend A : \"c\" ;").is_err());
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let err = define_parser::<crate::CharToken>("A : B ;\nB : \"b\" | ( \"c\" ;\nC : \"c\" ;").err().expect("Should fail");
+        assert_eq!(err.location, Some(SourceLocation { line: 2, column: 1, byte_offset: 8 }));
+        assert_eq!(err.rule_name.as_deref(), Some("B"));
+        assert_eq!(err.snippet.as_deref(), Some("B : \"b\" | ( \"c\" ;"));
+        assert!(err.to_string().starts_with(&format!("{} at line 2, column 1 (in rule \"B\")\n", err.message)));
+
+        let err = define_parser::<crate::CharToken>("A : \"a\" ;\n  B : \"\\q\" ;").err().expect("Should fail");
+        assert_eq!(err.message, "Bad escape sequence");
+        assert_eq!(err.location, Some(SourceLocation { line: 2, column: 7, byte_offset: 16 }));
+        assert_eq!(err.to_string(), "Bad escape sequence at line 2, column 7\n      B : \"\\q\" ;\n          ^");
+
+        let err = define_parser::<crate::CharToken>("A : \"a\" ;\nB : \"b\"").err().expect("Should fail");
+        assert_eq!(err.message, "Missing final semicolon");
+        assert_eq!((err.location.map(|location| location.line), err.rule_name.as_deref()), (Some(2), Some("B")));
+
+        // Mistakes in how the rules fit together don't have a place in the text.
+        let err = define_parser::<crate::CharToken>("A : B ;").err().expect("Should fail");
+        assert_eq!((err.location, err.rule_name, err.snippet), (None, None, None));
+    }
+
+    #[test]
+    fn test_validate_parser() {
+        assert_eq!(
+            define_parser::<crate::CharToken>("A : B | \"a\" ; B : \"b\" ; A : \"c\" ;").err().map(|err| err.message),
+            Some("Rule \"A\" is defined more than once".to_string())
+        );
+
+        assert_eq!(
+            define_parser::<crate::CharToken>("A : (B | \"a\") C? ; B : \"b\" ;").err().map(|err| err.message),
+            Some("Rule \"A\" uses undefined rule \"C\"".to_string())
+        );
+
+        assert_eq!(
+            define_parser::<crate::LexedToken>("A : Literel \"+\" Litreal ; Literal : Digit+ ; @token Digit : [0-9] ; @token Plus : \"+\" ;")
+                .err().map(|err| err.message),
+            Some("Rule \"A\" uses undefined rule \"Literel\", did you mean \"Literal\"?".to_string())
+        );
+        assert_eq!(
+            define_parser::<crate::LexedToken>("A : Digt+ ; @token Digit : [0-9] ;").err().map(|err| err.message),
+            Some("Rule \"A\" uses undefined rule \"Digt\", did you mean \"Digit\"?".to_string())
+        );
+        assert_eq!(crate::parse::closest_name("Exprssion", ["Expression", "Expr"]), Some("Expression"));
+        assert_eq!(crate::parse::closest_name("Term", ["Expression", "Factor"]), None);
+
+        // The start rule is picked when parsing, so rules nothing else leads to are fine (see GrammarWarning::Unreachable).
+        define_parser::<crate::CharToken>("A : B ; B : \"b\" ; C : D \"c\" ; D : C | \"d\" ;").expect("ok");
+        define_parser::<crate::CharToken>(r#"Expr : Term ("+" Term)* ; Term : [0-9] | "(" Expr ")" ; Ident : [a-z]+ ;"#).expect("ok");
+        define_parser::<crate::CharToken>("A : B ; C : B ; B : \"b\" ;").expect("ok");
+        define_parser::<crate::CharToken>("C : D \"c\" ; D : C | \"d\" ;").expect("ok");
+    }
 }
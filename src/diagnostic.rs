@@ -0,0 +1,130 @@
+/* miette::Diagnostic for the error types, with the `miette` feature turned on.
+ *
+ * A DefinitionError carries the line of the definition it is about, so it acts as its
+ * own source code, and miette can draw it with the right line number. A ParseError
+ * doesn't keep the input around, so its spans are byte offsets into the string that
+ * was parsed, and that string has to be attached with Report::with_source_code. */
+
+use crate::{DefinitionError, ParseError};
+
+use miette::{Diagnostic, LabeledSpan, MietteError, MietteSpanContents, SourceCode, SourceSpan, SpanContents};
+
+use std::fmt::Display;
+
+
+impl Diagnostic for ParseError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        let code = match self {
+            ParseError::Internal(_) => "parsley::internal",
+            ParseError::UnknownRule { .. } => "parsley::unknown_rule",
+            ParseError::UnexpectedToken { .. } => "parsley::unexpected_token",
+            ParseError::UnexpectedEof { .. } => "parsley::unexpected_eof",
+            ParseError::Ambiguous(_) => "parsley::ambiguous",
+            ParseError::Cancelled { .. } => "parsley::cancelled",
+            ParseError::LimitExceeded { .. } => "parsley::limit_exceeded",
+        };
+        Some(Box::new(code))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        match self {
+            ParseError::UnexpectedToken { context, .. } | ParseError::UnexpectedEof { context, .. } if !context.is_empty() =>
+                Some(Box::new(format!("while parsing {}", context.join(" > ")))),
+            _ => None,
+        }
+    }
+
+    // Only parses of strings, or of tokens that know their span, can point at the input.
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        let ParseError::UnexpectedToken { location, span, .. } = self else {
+            return None;
+        };
+        let span = span.clone().or_else(|| location.map(|location| location.byte_offset..location.byte_offset))?;
+
+        Some(Box::new(std::iter::once(LabeledSpan::new_with_span(Some("unexpected token".to_string()), span))))
+    }
+}
+
+impl Diagnostic for DefinitionError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new("parsley::definition"))
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.snippet_start().map(|_| self as &dyn SourceCode)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        let (location, snippet, start) = (self.location?, self.snippet.as_ref()?, self.snippet_start()?);
+        let length = snippet[location.byte_offset - start..].chars().next().map_or(0, char::len_utf8);
+        let label = self.rule_name.as_ref().map(|rule_name| format!("in rule \"{rule_name}\""));
+
+        Some(Box::new(std::iter::once(LabeledSpan::new(label, location.byte_offset, length))))
+    }
+}
+
+/* The only source a DefinitionError has is the line with the mistake on it, but its
+ * spans are offsets into the whole definition. So that one line is handed out as if
+ * it were read from the right place in the definition. */
+impl SourceCode for DefinitionError {
+    fn read_span<'a>(&'a self, _span: &SourceSpan, _lines_before: usize, _lines_after: usize)
+            -> Result<Box<dyn SpanContents<'a> + 'a>, MietteError> {
+        let (Some(location), Some(snippet), Some(start)) = (self.location, &self.snippet, self.snippet_start()) else {
+            return Err(MietteError::OutOfBounds);
+        };
+
+        let span = SourceSpan::new(start.into(), snippet.len().into());
+        Ok(Box::new(MietteSpanContents::new(snippet.as_bytes(), span, location.line - 1, 0, 1)))
+    }
+}
+
+
+/* Private Implementation */
+
+impl DefinitionError {
+    // Where the snippet starts in the definition, found by going back from the location to the start of its line.
+    fn snippet_start(&self) -> Option<usize> {
+        let (location, snippet) = (self.location?, self.snippet.as_ref()?);
+        let column_offset = snippet.char_indices().nth(location.column - 1).map_or(snippet.len(), |(i, _)| i);
+        location.byte_offset.checked_sub(column_offset)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{define_parser, CharToken, Parser};
+
+    use miette::Diagnostic;
+
+    #[test]
+    fn test_parse_error_diagnostic() {
+        let parser: Parser<CharToken> = define_parser(r#"
+            @label("a sum") Sum : Number ("+" Number)* ;
+            Number : [0-9]+ ;
+        "#).expect("Parser definition ok");
+
+        let err = parser.parse_string("1+é2", "Sum").expect_err("Parse fails");
+        assert_eq!(err.code().map(|code| code.to_string()), Some("parsley::unexpected_token".to_string()));
+        assert_eq!(err.help().map(|help| help.to_string()), Some("while parsing Sum > Number".to_string()));
+        let labels = err.labels().expect("Has a label").collect::<Vec<_>>();
+        assert_eq!((labels[0].offset(), labels[0].len()), (2, 2));
+
+        let report = format!("{:?}", miette::Report::new(err).with_source_code("1+é2"));
+        assert!(report.contains("unexpected token"));
+    }
+
+    #[test]
+    fn test_definition_error_diagnostic() {
+        let definition = "A : \"a\" ;\nB : \"é\" \"c ;\n";
+        let err = define_parser::<CharToken>(definition).err().expect("Definition fails");
+        let location = err.location.expect("Has a location");
+        let labels = err.labels().expect("Has a label").collect::<Vec<_>>();
+        assert_eq!(labels[0].offset(), location.byte_offset);
+        assert_eq!(&definition[labels[0].offset()..labels[0].offset() + labels[0].len()], "\"");
+
+        let contents = err.source_code().expect("Has source").read_span(&labels[0].inner().clone(), 1, 1).expect("Span found");
+        assert_eq!(contents.data(), "B : \"é\" \"c ;".as_bytes());
+        assert_eq!((contents.line(), contents.span().offset()), (1, 10));
+    }
+}
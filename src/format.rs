@@ -0,0 +1,323 @@
+/* A formatter for definitions, so grammars keep one style no matter who edits them.
+ * Each statement goes on its own line, with single spaces between elements and none
+ * inside parentheses or before quantifiers. Comments and single blank lines are kept.
+ *
+ * A rule stays on one line if it fits. Otherwise (or if its alternatives were already
+ * on separate lines) each alternative gets a line, with the bars lined up under the
+ * colon, and alternatives that are still too long wrap between their elements:
+ *
+ *     Statement : "let" Name "=" Expr
+ *               | "print" Expr
+ *               ;
+ */
+
+/* Public Interface */
+
+/* Formats a definition. Definitions that can't be read (like one with an unterminated
+ * string) come back unchanged, since there's no telling what they meant. */
+pub fn format_grammar(definition: &str) -> String {
+    let Some(lexemes) = lex(definition) else {
+        return definition.to_string();
+    };
+
+    let mut lines: Vec<String> = vec![];
+    let mut statement: Vec<Lexeme> = vec![];
+    let mut last_was_statement = false;  // Whether a comment on the same line trails the last statement.
+    for lexeme in lexemes {
+        let blank_line = lexeme.newlines_before >= 2 && !lines.is_empty();
+        match lexeme.piece {
+            Piece::Comment(comment) if statement.is_empty() && lexeme.newlines_before == 0 && last_was_statement => {
+                let last = lines.last_mut().expect("A statement was written");
+                *last = format!("{last}  {comment}");
+            }
+            Piece::Comment(comment) if statement.is_empty() => {
+                if blank_line {
+                    lines.push(String::new());
+                }
+                lines.push(comment);
+                last_was_statement = false;
+            }
+            Piece::Word(word) if word == ";" => {
+                if statement.first().is_some_and(|first| first.newlines_before >= 2) && !lines.is_empty() {
+                    lines.push(String::new());
+                }
+                lines.extend(format_statement(&std::mem::take(&mut statement)));
+                last_was_statement = true;
+            }
+            piece => statement.push(Lexeme { piece, newlines_before: lexeme.newlines_before }),
+        }
+    }
+
+    // Anything after the last semicolon isn't a whole statement, but shouldn't be lost either.
+    if !statement.is_empty() {
+        lines.push(join(&statement));
+    }
+
+    lines.into_iter().map(|line| line + "\n").collect()
+}
+
+
+/* Private Implementation */
+
+const MAX_WIDTH: usize = 100;
+
+enum Piece {
+    Word (String),  // Anything that isn't a comment, as written, except {n, m} loses its spaces.
+    Comment (String),
+}
+
+struct Lexeme {
+    piece: Piece,
+    newlines_before: usize,  // Since the last lexeme, to keep blank lines and trailing comments where they were.
+}
+
+impl Lexeme {
+    fn word(&self) -> Option<&str> {
+        match &self.piece {
+            Piece::Word(word) => Some(word),
+            Piece::Comment(_) => None,
+        }
+    }
+}
+
+// A piece of a rule that can't be split across lines, like `Name` or `("," Expr)*`.
+struct Chunk {
+    text: String,
+    ends_line: bool,  // A line comment, which nothing can follow on the same line.
+}
+
+fn format_statement(lexemes: &[Lexeme]) -> Vec<String> {
+    let Some(colon) = lexemes.iter().position(|lexeme| lexeme.word() == Some(":")) else {
+        return vec![format!("{};", join(lexemes))];  // Like import "file.psl"; or extern Name;
+    };
+
+    // Comments before the colon are moved above the statement, where they can't get in the way.
+    let mut lines = lexemes[..colon].iter()
+        .filter_map(|lexeme| match &lexeme.piece {
+            Piece::Comment(comment) => Some(comment.clone()),
+            Piece::Word(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let head = join(lexemes[..colon].iter().filter(|lexeme| lexeme.word().is_some()));
+
+    let mut alternatives = vec![vec![]];
+    let mut depth = 0;
+    let mut split_lines = false;  // Whether the alternatives were already on separate lines.
+    for lexeme in &lexemes[colon + 1..] {
+        match lexeme.word() {
+            Some("|") if depth == 0 => {
+                split_lines |= lexeme.newlines_before > 0;
+                alternatives.push(vec![]);
+                continue;
+            }
+            Some("(") => depth += 1,
+            Some(")") => depth -= 1,
+            _ => (),
+        }
+        alternatives.last_mut().expect("Never empty").push(lexeme);
+    }
+    let alternatives = alternatives.iter().map(|alternative| chunks(alternative)).collect::<Vec<_>>();
+
+    let has_comments = alternatives.iter().flatten().any(|chunk| chunk.ends_line || chunk.text.starts_with("/*"));
+    if !split_lines && !has_comments {
+        let one_line = format!("{head} : {} ;", alternatives.iter()
+            .map(|alternative| alternative.iter().map(|chunk| chunk.text.as_str()).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+            .join(" | "))
+            .replace(":  |", ": |");  // A leading bar, like `extend Rule : | "more" ;`
+        if one_line.chars().count() <= MAX_WIDTH {
+            lines.push(one_line);
+            return lines;
+        }
+    }
+
+    let indent = " ".repeat(head.chars().count() + 1);
+    for (i, alternative) in alternatives.iter().enumerate() {
+        let prefix = if i == 0 { format!("{head} : ") } else { format!("{indent}| ") };
+        if alternative.is_empty() {
+            lines.push(prefix.trim_end().to_string());
+            continue;
+        }
+
+        let mut line = prefix;
+        let mut line_empty = true;
+        for chunk in alternative {
+            if !line_empty && line.chars().count() + 1 + chunk.text.chars().count() > MAX_WIDTH {
+                lines.push(std::mem::replace(&mut line, format!("{indent}  ")));
+                line_empty = true;
+            }
+            if !line_empty {
+                line.push_str(if chunk.ends_line { "  " } else { " " });
+            }
+            line.push_str(&chunk.text);
+            line_empty = false;
+
+            if chunk.ends_line {
+                lines.push(std::mem::replace(&mut line, format!("{indent}  ")));
+                line_empty = true;
+            }
+        }
+        if !line_empty {
+            lines.push(line);
+        }
+    }
+    lines.push(format!("{indent};"));
+    lines
+}
+
+// Splits an alternative at the spaces between its top level elements, where it can wrap.
+fn chunks(lexemes: &[&Lexeme]) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = vec![];
+    let mut depth = 0;
+    let mut previous: Option<&str> = None;
+    for lexeme in lexemes {
+        let word = match &lexeme.piece {
+            Piece::Comment(comment) => {
+                chunks.push(Chunk { text: comment.clone(), ends_line: !comment.starts_with("/*") });
+                previous = None;
+                continue;
+            }
+            Piece::Word(word) => word.as_str(),
+        };
+
+        match previous {
+            Some(previous) if !space_between(previous, word) => chunks.last_mut().expect("Has previous").text.push_str(word),
+            Some(_) if depth > 0 => {
+                let last = chunks.last_mut().expect("Has previous");
+                last.text.push(' ');
+                last.text.push_str(word);
+            }
+            _ => chunks.push(Chunk { text: word.to_string(), ends_line: false }),
+        }
+        match word {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            _ => (),
+        }
+        previous = Some(word);
+    }
+    chunks
+}
+
+fn join<'a>(lexemes: impl IntoIterator<Item = &'a Lexeme>) -> String {
+    let mut text = String::new();
+    let mut previous: Option<&str> = None;
+    for lexeme in lexemes {
+        let word = match &lexeme.piece {
+            Piece::Word(word) => word.as_str(),
+            Piece::Comment(comment) => comment.as_str(),
+        };
+        if previous.is_some_and(|previous| space_between(previous, word) && !(previous == "@label" && word == "(")) {
+            text.push(' ');
+        }
+        text.push_str(word);
+        previous = Some(word);
+    }
+    text
+}
+
+// Prefix operators and ( stick to what follows them, and quantifiers and ) to what precedes them.
+fn space_between(previous: &str, next: &str) -> bool {
+    let prefix = matches!(previous, "(" | "~" | "&" | "!");
+    let postfix = matches!(next, ")" | "?" | "*" | "+") || next.starts_with('{');
+    !prefix && !postfix
+}
+
+/* Splits the definition into words and comments, the same way the definition reader
+ * does, but keeping the comments and how many lines are between each. */
+fn lex(definition: &str) -> Option<Vec<Lexeme>> {
+    let mut lexemes = vec![];
+    let mut newlines = 0;
+    let mut chars = definition.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        let piece = match ch {
+            '\n' => {
+                newlines += 1;
+                continue;
+            }
+            _ if ch.is_whitespace() => continue,
+            '#' | '/' if ch == '#' || chars.peek() == Some(&'/') => {
+                let rest = std::iter::from_fn(|| chars.next_if(|ch| *ch != '\n'));
+                Piece::Comment(std::iter::once(ch).chain(rest).collect::<String>().trim_end().to_string())
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                let mut comment = String::from("/");
+                while !comment.ends_with("*/") || comment.len() < 4 {
+                    comment.push(chars.next()?);
+                }
+                Piece::Comment(comment)
+            }
+            '"' => Piece::Word(read_delimited(ch, '"', &mut chars)?),
+            '[' => Piece::Word(read_delimited(ch, ']', &mut chars)?),
+            '{' => Piece::Word(read_delimited(ch, '}', &mut chars)?.split_whitespace().collect()),
+            'i' if chars.peek() == Some(&'"') => Piece::Word(format!("i{}", read_delimited(chars.next()?, '"', &mut chars)?)),
+            _ if ch == '@' || ch.is_ascii_alphanumeric() || ch == '_' => {
+                let rest = std::iter::from_fn(|| chars.next_if(|ch| ch.is_ascii_alphanumeric() || *ch == '_'));
+                Piece::Word(std::iter::once(ch).chain(rest).collect())
+            }
+            _ => Piece::Word(ch.to_string()),
+        };
+        lexemes.push(Lexeme { piece, newlines_before: std::mem::take(&mut newlines) });
+    }
+
+    Some(lexemes)
+}
+
+// Reads up to and including the closing delimiter, skipping over escapes.
+fn read_delimited(open: char, close: char, chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut text = open.to_string();
+    loop {
+        let ch = chars.next()?;
+        text.push(ch);
+        match ch {
+            '\\' => text.push(chars.next()?),
+            _ if ch == close => return Some(text),
+            _ => (),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_grammar() {
+        let formatted = format_grammar(indoc::indoc! {r#"
+            # Statements
+              Statement:"let"   Name "=" Expr ";"|"print" ( Expr ( "," Expr ) * ) ? ";" ;  # Two kinds
+
+
+            @label ( "a name" )  Name : [a-z_] + {1 , 3} ;
+            Term : ~ [\]]  ! "x" ;
+            extend Term : | "y" ;
+            Expr : Term
+                | Expr "+" Term   // Left recursive
+                ;
+            import "other.psl" ;
+            Long : "a very long literal" "another very long literal" "yet another long literal" "and one more literal" ;
+        "#});
+
+        assert_eq!(formatted, indoc::indoc! {r#"
+            # Statements
+            Statement : "let" Name "=" Expr ";" | "print" (Expr ("," Expr)*)? ";" ;  # Two kinds
+
+            @label("a name") Name : [a-z_]+{1,3} ;
+            Term : ~[\]] !"x" ;
+            extend Term : | "y" ;
+            Expr : Term
+                 | Expr "+" Term  // Left recursive
+                 ;
+            import "other.psl";
+            Long : "a very long literal" "another very long literal" "yet another long literal"
+                   "and one more literal"
+                 ;
+        "#});
+        assert_eq!(format_grammar(&formatted), formatted);
+
+        crate::define_parser::<crate::CharToken>(&formatted.replace("import \"other.psl\";", "")).expect("Still a valid definition");
+        assert_eq!(format_grammar("A : \"unterminated ;"), "A : \"unterminated ;");
+    }
+}
@@ -6,20 +6,109 @@
     clippy::cast_sign_loss,  // Allow by default, not with -D clippy::pedantic
     clippy::cast_possible_truncation,  // I know
     clippy::cast_possible_wrap,  // I know
+    clippy::result_large_err,  // Parse errors are big, but parsing only fails once.
 )]
 
 mod define;
 
 pub use define::define_parser;
+pub use define::define_parser_from_file;
+pub use define::DefinitionError;
+
+
+mod abnf;
+
+pub use abnf::define_parser_from_abnf;
+
+
+mod antlr;
+
+pub use antlr::define_parser_from_antlr;
+
+
+mod format;
+
+pub use format::format_grammar;
+
+/* For parsley_codegen and the code it generates. */
+#[doc(hidden)]
+pub mod compiled {
+    pub use crate::define::{compile_definition_file, CharacterClass, CompiledGrammar, CompiledLexer, RuleExpression};
+}
+
+
+mod builder;
+
+pub use builder::ParserBuilder;
 
 
 mod parse;
 
 pub use parse::Parser;
 pub use parse::ParseError;
+pub use parse::ParseBudget;
+pub use parse::ParseLimits;
+pub use parse::Limit;
+pub use parse::ParseProgress;
+pub use parse::ParseStats;
+pub use parse::{ParseProfile, RuleProfile};
 pub use parse::SyntaxTree;
+pub use parse::Symbol;
+pub use parse::Captured;
 pub use parse::Token;
 pub use parse::CharToken;
+pub use parse::ByteToken;
+pub use parse::LexedToken;
+pub use parse::LexedTokenKind;
+pub use parse::AmbiguityPolicy;
+pub use parse::ConflictPolicy;
+pub use parse::ParseAlgorithm;
+pub use parse::Ambiguity;
+pub use parse::EbnfNotation;
+pub use parse::ParseEvent;
+pub use parse::LineMap;
+pub use parse::SourceLocation;
+pub use parse::StreamingParse;
+pub use parse::ParseSession;
+pub use parse::ParseSnapshot;
+pub use parse::Matches;
+pub use parse::LazyTree;
+pub use parse::CompactTree;
+pub use parse::ChartDump;
+pub use parse::ParseDebugger;
+pub use parse::Frontier;
+pub use parse::FirstSet;
+pub use parse::FollowSet;
+pub use parse::NodeRef;
+pub use parse::Descendants;
+pub use parse::TreeQuery;
+pub use parse::QueryError;
+pub use parse::GrammarWarning;
+pub use parse::GrammarConflict;
+pub use parse::LikelyAmbiguity;
+pub use parse::FromSyntaxTree;
+pub use parse::ShapeError;
+#[cfg(feature = "rowan")]
+pub use parse::RowanKinds;
+
+#[doc(hidden)]
+pub use parse::{FieldCursor, FieldFilter, unexpected_shape};  // For #[derive(FromSyntaxTree)].
+
+pub use parsley_derive::ParsleyToken;
+pub use parsley_derive::FromSyntaxTree;
+
+
+pub mod report;
+
+
+pub mod codegen;
+
+
+#[cfg(feature = "miette")]
+mod diagnostic;
+
+#[cfg(feature = "serde")]
+mod serialize;
 
 
 mod utils;
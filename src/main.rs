@@ -1,14 +1,16 @@
 
 fn main() {
-    let parser : parsley::Parser<parsley::CharToken> = parsley::define_parser(r#"
+    let parser : parsley::Parser<parsley::LexedToken> = parsley::define_parser(r#"
+        @skip Whitespace : [ \t\r\n]+ ;
+        @token Literal : [a-d] ;
+        @token Symbol : [-+*/()] ;
+
         PlusMinusExpr :  MultDivExpr  (("+" | "-") MultDivExpr)* ;
         MultDivExpr : AtomicExpr (("*" | "/") AtomicExpr)* ;
-        AtomicExpr : OptWhitespace (Literal | "(" PlusMinusExpr ")" ) OptWhitespace;
-        Literal : "a" | "b" | "c" | "d" ;
-        OptWhitespace : (" " | "\t" | "\n" | "\r\n" | "\'" | "\"" )* ; # Yeah the quotes are kinda weird
+        AtomicExpr : Literal | "(" PlusMinusExpr ")" ;
     "#).expect("Not an error?");
     
-    let tree = parser.parse_string("   ( a + b)*( c +  a  * \n\n\n\t\t '''\"\"\" (  d )+ c  )", "PlusMinusExpr")
+    let tree = parser.parse_string("   ( a + b)*( c +  a  * \n\n\n\t\t (  d )+ c  )", "PlusMinusExpr")
         .expect("Good parse");
     println!("{tree}");
 
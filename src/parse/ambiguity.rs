@@ -0,0 +1,78 @@
+
+use super::{SyntaxTree, Token};
+
+
+/* Decides what the parser does when the input can be parsed more than one way. */
+#[derive(Default)]
+pub enum AmbiguityPolicy {
+    #[default]
+    FirstMatch,  // Silently return one of the parses. This is the default.
+    RejectAmbiguity,  // Fail with ParseError::Ambiguous.
+    WarnAndPick (Box<dyn Fn(&Ambiguity)>),  // Report the ambiguity to the callback, then return one of the parses.
+}
+
+/* Describes an ambiguous parse. The rule and index point at the innermost rule
+ * where two of the parses stop agreeing with each other. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ambiguity {
+    pub parse_count: usize,
+    pub rule_name: String,
+    pub index: usize,  // The token index where the disputed rule begins.
+}
+
+impl Ambiguity {
+    // Expects at least two distinct trees.
+    pub(crate) fn between<T: Token>(trees: &[SyntaxTree<T>]) -> Ambiguity {
+        let (rule_name, index) = divergence(&trees[0], &trees[1]);
+        Ambiguity { parse_count: trees.len(), rule_name, index }
+    }
+}
+
+fn divergence<T: Token>(mut left: &SyntaxTree<T>, mut right: &SyntaxTree<T>) -> (String, usize) {
+    loop {
+        let (rule_name, span, left_subs, right_subs) = match (left, right) {
+            (
+                SyntaxTree::RuleNode { rule_name, subexpressions: left_subs, span },
+                SyntaxTree::RuleNode { subexpressions: right_subs, .. }
+            ) => (rule_name, span, left_subs, right_subs),
+            _ => return (String::new(), left.span().start),  // Unreachable for trees rooted at the same rule.
+        };
+
+        let first_difference = left_subs.iter()
+            .zip(right_subs.iter())
+            .find(|(left_sub, right_sub)| !same_shape(left_sub, right_sub));
+
+        match first_difference {
+            // Only blame the child if both parses agree on which tokens it covers.
+            Some((
+                left_sub @ SyntaxTree::RuleNode { rule_name: left_name, span: left_span, .. }, 
+                right_sub @ SyntaxTree::RuleNode { rule_name: right_name, span: right_span, .. }
+            )) if left_name == right_name && left_span == right_span => (left, right) = (left_sub, right_sub),
+            _ => return (rule_name.to_string(), span.start),
+        }
+    }
+}
+
+pub(super) fn same_shape<T: Token>(left: &SyntaxTree<T>, right: &SyntaxTree<T>) -> bool {
+    let mut pending = vec![(left, right)];
+    while let Some((left, right)) = pending.pop() {
+        let same = match (left, right) {
+            (
+                SyntaxTree::RuleNode { rule_name: left_name, subexpressions: left_subs, span: left_span },
+                SyntaxTree::RuleNode { rule_name: right_name, subexpressions: right_subs, span: right_span }
+            ) => {
+                pending.extend(left_subs.iter().zip(right_subs.iter()));
+                left_name == right_name && left_span == right_span && left_subs.len() == right_subs.len()
+            }
+            (SyntaxTree::TokenNode { index: left_index, .. }, SyntaxTree::TokenNode { index: right_index, .. }) 
+                => left_index == right_index,
+            (SyntaxTree::ErrorNode { span: left_span, .. }, SyntaxTree::ErrorNode { span: right_span, .. })
+                => left_span == right_span,
+            _ => false,
+        };
+        if !same {
+            return false;
+        }
+    }
+    true
+}
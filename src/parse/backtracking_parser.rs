@@ -1,219 +1,1018 @@
 
-use crate::{Token, define::RuleExpression};
-use super::{Parser, ParseError, SyntaxTree};
+use crate::{Token, define::{RuleExpression, CharacterClass}};
+use super::{Captured, Limit, ParseEvent, Parser, ParseError, Symbol, SyntaxTree};
+use super::budget::Deadline;
+use super::observe::Observers;
+use super::progress::ProgressReporter;
+use super::stats::StatsRecorder;
+use super::trace::{self, RuleSpans};
 
 use std::collections::{HashMap, HashSet};
-use std::rc::Rc;
+use std::ops::Range;
 
 use by_address::ByAddress;
 
 
-#[derive(Clone, Debug)]
-struct Continuation<'a, T: Token>(usize, Vec<Rc<IntermediateSyntaxTree<'a, T>>>); // usize is the next token to parse
+#[derive(Clone, Copy, Debug)]
+struct Continuation(usize, Children); // usize is the next token to parse
 
-impl<'a, T: Token> PartialEq for Continuation<'a, T> {
+impl PartialEq for Continuation {
     fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0 && self.1.iter().zip(other.1.iter()).all(|(a, b)| Rc::ptr_eq(a, b))
+        self.0 == other.0 && self.1 == other.1
     }
 }
 
-impl<'a, T: Token> PartialOrd for Continuation<'a, T> {
+impl PartialOrd for Continuation {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
-impl<'a, T: Token> Eq for Continuation<'a, T> {}
-impl<'a, T: Token> Ord for Continuation<'a, T> {
+impl Eq for Continuation {}
+impl Ord for Continuation {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.0.cmp(&other.0)
     }
 }
 
-pub fn backtracking_parse<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str) -> Result<SyntaxTree<T>, ParseError> {
-    let start_expr = RuleExpression::RuleName(start_rule.to_string());
+pub fn backtracking_parse<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, anchored: bool, deadline: &Deadline, 
+        stats: &StatsRecorder) -> Result<SyntaxTree<T>, ParseError> {
+    let (arena, roots) = complete_parses(parser, tokens, start_rule, anchored, deadline, stats)?;
+    Ok(arena.to_final(roots[0]))
+}
+
+// Like backtracking_parse, but walks the parse for the callback instead of building a tree.
+pub fn backtracking_parse_events<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, anchored: bool,
+        on_event: &mut dyn FnMut(ParseEvent<T>)) -> Result<(), ParseError> {
+    let (arena, roots) = complete_parses(parser, tokens, start_rule, anchored, &Deadline::none(), &StatsRecorder::new())?;
+    arena.events(roots[0], on_event);
+    Ok(())
+}
+
+// Like backtracking_parse, but leaves the tree as it is, for LazyTree to build from.
+pub(super) fn backtracking_parse_lazy<'a, T: Token>(parser: &'a Parser<T>, tokens: &[T], start_rule: &str, anchored: bool) 
+        -> Result<(Arena<'a, T>, NodeId), ParseError> {
+    let (arena, roots) = complete_parses(parser, tokens, start_rule, anchored, &Deadline::none(), &StatsRecorder::new())?;
+    Ok((arena, roots[0]))
+}
 
-    let mut memo_map: HashMap<(ByAddress<&RuleExpression>, usize), Vec<Continuation<T>>> = HashMap::new();
-    let mut failure_info = FailureCache::new();
+// Returns every distinct syntax tree that covers the whole input.
+pub fn backtracking_parse_all<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, anchored: bool, deadline: &Deadline, 
+        stats: &StatsRecorder) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+    let (arena, roots) = complete_parses(parser, tokens, start_rule, anchored, deadline, stats)?;
+    Ok(arena.distinct_trees(roots))
+}
 
-    parse_expr(parser, tokens, 0, &start_expr, &mut memo_map, &mut failure_info)?;
+/* What the backtracking parser has worked out about some tokens: the memo map, and the
+ * nodes its continuations refer to. A ParseSession keeps one between parses of the
+ * same tokens, so that they can share the work. */
+pub(super) struct Memo<'a, T: Token> {
+    map: HashMap<MemoKey<'a>, Vec<Continuation>>,
+    continuations: usize,
+    arena: Arena<'a, T>,
+}
 
-    if let Some(Continuation (_, trees)) = memo_map[&(ByAddress(&start_expr), 0)].clone().into_iter()
-            .find(|Continuation (i, _)| *i == tokens.len()) {
-        
-        Ok(intermediate_to_final(&trees[0]))
+impl<T: Token> Memo<'_, T> {
+    pub(super) fn new() -> Self {
+        Memo { map: HashMap::new(), continuations: 0, arena: Arena::new() }
     }
-    else if failure_info.index < tokens.len() {
-        Err(ParseError::IncompleteParse { 
-            index: failure_info.index, 
-            terminals: failure_info.failures.into_iter().map(ToString::to_string).collect() 
-        })
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.map.is_empty()
     }
-    else {
-        Err(ParseError::OutOfInput { 
-            terminals: failure_info.failures.into_iter().map(ToString::to_string).collect() 
-        })
+
+    // Forgets everything, but keeps the memory for the next tokens.
+    pub(super) fn clear(&mut self) {
+        self.map.clear();
+        self.continuations = 0;
+        self.arena.nodes.clear();
+        self.arena.lists.clear();
     }
-    // TODO - also handle ambiguous parse. (?)
 }
 
-// Stores failure information to allow creating nice errors.
-struct FailureCache<'a> {
-    failures: HashSet<&'a str>,
+// Like backtracking_parse_all, but starts from what the memo knows about the tokens,
+// and leaves everything this parse works out in it. Without all, only the first tree is built.
+pub(super) fn memoized_parse<'a, T: Token>(parser: &'a Parser<T>, tokens: &[T], start_rule: &str, anchored: bool, 
+        memo: &mut Memo<'a, T>, all: bool) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+    let _span = trace::parse_span("backtracking", start_rule, tokens.len());
+    let mut state = ParseState::new(parser, tokens).with_memo(std::mem::replace(memo, Memo::new())).with_progress().with_observers();
+    let roots = state.complete_parses(start_rule, anchored);
+    *memo = state.into_memo();
+
+    let roots = roots?;
+    match all {
+        true => Ok(memo.arena.distinct_trees(roots)),
+        false => Ok(vec![memo.arena.to_final(roots[0])]),
+    }
+}
+
+fn complete_parses<'a, T: Token>(parser: &'a Parser<T>, tokens: &[T], start_rule: &str, anchored: bool, deadline: &Deadline, 
+        stats: &StatsRecorder) -> Result<(Arena<'a, T>, Vec<NodeId>), ParseError> {
+    let _span = trace::parse_span("backtracking", start_rule, tokens.len());
+    let mut state = ParseState::new(parser, tokens).with_deadline(deadline).with_stats(stats).with_progress().with_observers();
+    let roots = state.complete_parses(start_rule, anchored)?;
+    Ok((state.arena, roots))
+}
+
+/* Stores failure information to allow creating nice errors. Each failure can come
+ * with the rule it is expected as part of: the outermost rule (other than the start
+ * rule) that started right where the failure is. Errors can then say that a rule was
+ * expected, instead of listing everything that rule could start with.
+ *
+ * The context is the chain of rules, outermost first, that a failure at the furthest
+ * index was inside. Of all the failures there, the one deepest in the rules wins,
+ * since it says the most. Finding the rules can be slow, so callers pass a closure
+ * that is only run for failures that aren't behind the furthest one. */
+#[derive(Clone)]
+pub(super) struct FailureCache<'a> {
+    failures: HashSet<(&'a str, Option<&'a str>)>,
     index: usize,
+    context: Vec<&'a str>,
 }
 
 impl<'a> FailureCache<'a> {
-    fn new() -> FailureCache<'a> {
-        Self { failures: HashSet::new(), index: 0 }
+    pub(super) fn new() -> FailureCache<'a> {
+        Self { failures: HashSet::new(), index: 0, context: vec![] }
     }
 
-    fn log(&mut self, index: usize, expected: &'a str) {
-        if index > self.index {
+    pub(super) fn log(&mut self, index: usize, expected: &'a str) {
+        self.log_in_context(index, expected, || (None, vec![]));
+    }
+
+    // rules gives the rule the failure is expected as part of, and the context.
+    pub(super) fn log_in_context(&mut self, index: usize, expected: &'a str, 
+            rules: impl FnOnce() -> (Option<&'a str>, Vec<&'a str>)) {
+        if index < self.index {
+            return;
+        }
+
+        let (rule_name, context) = rules();
+        if index > self.index || self.failures.is_empty() {
             self.index = index;
             self.failures.clear();
+            self.context.clear();
+        }
+        if context.len() > self.context.len() {
+            self.context = context;
+        }
+        self.failures.insert((expected, rule_name));
+    }
+
+    pub(super) fn index(&self) -> usize {
+        self.index
+    }
+
+    pub(super) fn failures(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> + '_ {
+        self.failures.iter().copied()
+    }
+
+    pub(super) fn context(&self) -> &[&'a str] {
+        &self.context
+    }
+
+    // The error for a parse that failed, given the input. Rules are listed by their labels, if they have them.
+    pub(super) fn into_error<T: Token>(self, parser: &Parser<T>, tokens: &[T]) -> ParseError {
+        let terminals = self.failures.iter().map(|(terminal, _)| terminal.to_string()).collect();
+        let context = self.context.iter().map(ToString::to_string).collect();
+        let expected = self.failures.iter()
+            .map(|(terminal, rule_name)| rule_name.unwrap_or(terminal))
+            .map(|name| parser.label(name).unwrap_or(name).to_string())
+            .collect();
+        if self.index < tokens.len() {
+            ParseError::UnexpectedToken { index: self.index, terminals, expected, context, location: None, span: tokens[self.index].span() }
+        }
+        else {
+            ParseError::UnexpectedEof { terminals, expected, context }
+        }
+    }
+}
+
+type MemoKey<'a> = (ByAddress<&'a RuleExpression>, usize);
+
+pub(super) struct ParseState<'a, 't, T: Token> {
+    parser: &'a Parser<T>,
+    tokens: &'t [T],
+    memo_map: HashMap<MemoKey<'a>, Vec<Continuation>>,
+    memo_continuations: usize,  // How many continuations the memo map holds in all, for ParseLimits.
+    arena: Arena<'a, T>,
+    failure_info: FailureCache<'a>,
+
+    /* Left recursion support. Rules currently being parsed map to whether they have
+     * been (left) recursively reached, and the log records the order in which
+     * memo entries were made, so that entries computed from an outdated seed can
+     * be thrown out. */
+    rules_in_progress: HashMap<MemoKey<'a>, bool>,
+    memo_log: Vec<MemoKey<'a>>,
+
+    rule_stack: Vec<(&'a str, usize)>,  // The rules being parsed and where they started, for FailureCache.
+    deadline: Deadline,
+    progress: ProgressReporter<'a>,
+    steps: usize,  // Counted for ParseLimits.
+    stats: StatsRecorder,
+    rule_spans: RuleSpans,
+    observers: Option<&'a Observers<T>>,
+}
+
+impl<'a, 't, T: Token> ParseState<'a, 't, T> {
+    pub(super) fn new(parser: &'a Parser<T>, tokens: &'t [T]) -> ParseState<'a, 't, T> {
+        ParseState { 
+            parser, 
+            tokens, 
+            memo_map: HashMap::new(), 
+            memo_continuations: 0,
+            arena: Arena::new(),
+            failure_info: FailureCache::new(), 
+            rules_in_progress: HashMap::new(),
+            memo_log: vec![],
+            rule_stack: vec![],
+            deadline: Deadline::none(),
+            progress: ProgressReporter::none(),
+            steps: 0,
+            stats: StatsRecorder::new(),
+            rule_spans: RuleSpans::new(),
+            observers: None,
+        }
+    }
+
+    pub(super) fn with_deadline(mut self, deadline: &Deadline) -> ParseState<'a, 't, T> {
+        self.deadline = deadline.clone();
+        self
+    }
+
+    fn with_stats(mut self, stats: &StatsRecorder) -> ParseState<'a, 't, T> {
+        self.stats = stats.clone();
+        self
+    }
+
+    fn with_memo(mut self, memo: Memo<'a, T>) -> ParseState<'a, 't, T> {
+        (self.memo_map, self.memo_continuations, self.arena) = (memo.map, memo.continuations, memo.arena);
+        self
+    }
+
+    fn into_memo(self) -> Memo<'a, T> {
+        Memo { map: self.memo_map, continuations: self.memo_continuations, arena: self.arena }
+    }
+
+    // Only the state for the main parse reports progress, not the ones used for lookaheads.
+    fn with_progress(mut self) -> ParseState<'a, 't, T> {
+        self.progress = ProgressReporter::new(self.parser, self.tokens.len());
+        self
+    }
+
+    // Likewise for the callbacks from Parser::on_enter_rule and friends.
+    fn with_observers(mut self) -> ParseState<'a, 't, T> {
+        self.observers = Some(&self.parser.observers);
+        self
+    }
+
+    // Runs the parse, and returns the root of every parse that consumed all tokens.
+    // If the parse is not anchored, settles for the parses that consumed the most tokens.
+    // Never returns an empty vector, failing to parse is reported as an error.
+    fn complete_parses(&mut self, start_rule: &str, anchored: bool) -> Result<Vec<NodeId>, ParseError> {
+        let nodes_before = self.arena.nodes.len();
+        let continuations = self.rule_continuations(0, start_rule);
+        self.stats.count(|stats| stats.nodes += self.arena.nodes.len() - nodes_before);
+        let continuations = continuations?;
+        let end = if anchored {
+            Some(self.tokens.len())
+        }
+        else {
+            continuations.iter().map(|Continuation (i, _)| *i).max()
+        };
+
+        let trees = continuations.iter()
+            .filter(|Continuation (i, _)| Some(*i) == end)
+            .map(|Continuation (_, trees)| self.arena.flatten(*trees)[0])
+            .collect::<Vec<_>>();
+
+        if trees.is_empty() {
+            return Err(std::mem::replace(&mut self.failure_info, FailureCache::new()).into_error(self.parser, self.tokens));
+        }
+
+        Ok(trees)
+    }
+
+    // Whether expr can match starting at token_index, in any way.
+    pub(super) fn matches_at(&mut self, token_index: usize, expr: &'a RuleExpression) -> Result<bool, ParseError> {
+        self.parse_expr(token_index, expr)?;
+        Ok(!self.memo_map[&(ByAddress(expr), token_index)].is_empty())
+    }
+
+    fn parse_expr(&mut self, token_index: usize, expr: &'a RuleExpression) -> Result<(), ParseError> {
+        self.run(Need::Expr(expr, token_index))
+    }
+
+    /* Computes whatever the need is for, and everything that depends on, without
+     * recursing. Each frame on the stack is an expression part way through being
+     * computed. When a frame needs the continuations of another expression that
+     * aren't in the memo map yet, a frame for that one goes on top, and the frame
+     * below picks up where it left off once they're memoized. */
+    fn run(&mut self, need: Need<'a>) -> Result<(), ParseError> {
+        let mut stack = vec![];
+        self.start(need, &mut stack);
+
+        while let Some(frame) = stack.last_mut() {
+            if self.deadline.passed_sometimes() {
+                return Err(ParseError::Cancelled { index: self.failure_info.index(), location: None });
+            }
+
+            self.steps += 1;
+            if let Some(limit) = self.parser.limits.check_steps(self.steps) {
+                return Err(self.limit_exceeded(limit, frame.index()));
+            }
+            if let Some(limit) = self.parser.limits.check_memory(|| self.memory_used()) {
+                return Err(self.limit_exceeded(limit, frame.index()));
+            }
+
+            let step = match frame {
+                Frame::Expr(task) => task.step(self)?,
+                Frame::RuleBody(task) => task.step(self)?,
+            };
+
+            match step {
+                Step::Need(need) => self.start(need, &mut stack),
+                Step::Done(continuations) => match stack.pop().expect("frame exists") {
+                    Frame::Expr(task) => {
+                        self.check_continuations(task.index, &continuations)?;
+                        let key = (ByAddress(task.expr), task.index);
+                        self.memo_insert(key, continuations);
+                        self.memo_log.push(key);
+                    }
+                    Frame::RuleBody(task) => {
+                        self.rules_in_progress.remove(&task.key);
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    // Pushes a frame for the need, unless the memo map already has it.
+    fn start(&mut self, need: Need<'a>, stack: &mut Vec<Frame<'a>>) {
+        match need {
+            Need::Expr(expr, token_index) => {
+                let memoized = self.memo_map.contains_key(&(ByAddress(expr), token_index));
+                self.stats.count(|stats| if memoized { stats.memo_hits += 1 } else { stats.memo_misses += 1 });
+                if memoized {
+                    trace::memo_hit(token_index);
+                }
+                else {
+                    self.progress.reached(token_index, || self.memo_map.len());
+                    stack.push(Frame::Expr(ExprTask::new(expr, token_index)));
+                }
+            }
+            Need::RuleBody(rule_expr, token_index) => {
+                let key = (ByAddress(rule_expr), token_index);
+                let memoized = self.memo_map.contains_key(&key);
+                self.stats.count(|stats| if memoized { stats.memo_hits += 1 } else { stats.memo_misses += 1 });
+                if memoized {
+                    trace::memo_hit(token_index);
+                    if let Some(recursed) = self.rules_in_progress.get_mut(&key) {
+                        *recursed = true;
+                    }
+                    return;
+                }
+
+                self.memo_insert(key, vec![]);
+                self.memo_log.push(key);
+                self.rules_in_progress.insert(key, false);
+                let log_start = self.memo_log.len();
+                stack.push(Frame::RuleBody(RuleBodyTask { key, log_start, body: ExprTask::new(rule_expr, token_index) }));
+            }
+        }
+    }
+
+    // All changes to the memo map go through here and memo_remove, to keep count for ParseLimits.
+    fn memo_insert(&mut self, key: MemoKey<'a>, continuations: Vec<Continuation>) -> Option<Vec<Continuation>> {
+        self.memo_continuations += continuations.len();
+        self.stats.count(|stats| {
+            stats.continuations += continuations.len();
+            stats.peak_width = stats.peak_width.max(continuations.len());
+        });
+        let old = self.memo_map.insert(key, continuations);
+        self.memo_continuations -= old.as_ref().map_or(0, Vec::len);
+        old
+    }
+
+    fn memo_remove(&mut self, key: &MemoKey<'a>) {
+        if let Some(continuations) = self.memo_map.remove(key) {
+            self.memo_continuations -= continuations.len();
+        }
+    }
+
+    // An estimate, see ParseLimits::memory.
+    fn memory_used(&self) -> usize {
+        self.memo_map.len() * size_of::<(MemoKey<'a>, Vec<Continuation>)>()
+            + self.memo_continuations * size_of::<Continuation>()
+            + self.arena.nodes.len() * size_of::<IntermediateSyntaxTree<'a, T>>()
+            + self.arena.lists.len() * size_of::<ListNode>()
+    }
+
+    // Enters a rule, as far as FailureCache and the depth limit are concerned.
+    fn push_rule(&mut self, rule_name: &'a str, token_index: usize) -> Result<(), ParseError> {
+        self.rule_stack.push((rule_name, token_index));
+        self.stats.enter_rule();
+        self.rule_spans.enter(rule_name, token_index);
+        if let Some(observers) = self.observers {
+            observers.enter_rule(rule_name, token_index);
+        }
+        match self.parser.limits.check_depth(self.rule_stack.len()) {
+            Some(limit) => Err(self.limit_exceeded(limit, token_index)),
+            None => Ok(()),
+        }
+    }
+
+    // Exits the rule entered by push_rule, once it has been parsed.
+    fn exit_rule(&mut self, rule_name: &str, token_index: usize, matches: usize) {
+        self.stats.exit_rule(rule_name, matches > 0);
+        self.rule_spans.exit(matches);
+        if let Some(observers) = self.observers {
+            observers.exit_rule(rule_name, token_index, matches > 0);
+        }
+    }
+
+    fn check_continuations(&self, token_index: usize, continuations: &[Continuation]) -> Result<(), ParseError> {
+        match self.parser.limits.check_continuations(continuations.len()) {
+            Some(limit) => Err(self.limit_exceeded(limit, token_index)),
+            None => Ok(()),
+        }
+    }
+
+    fn limit_exceeded(&self, limit: Limit, token_index: usize) -> ParseError {
+        let rule_name = self.rule_stack.last().map(|(rule_name, _)| rule_name.to_string());
+        ParseError::LimitExceeded { limit, rule_name, index: token_index, location: None }
+    }
+
+    fn log_failure(&mut self, token_index: usize, expected: &'a str) {
+        trace::failure(expected, token_index);
+        let rule_stack = &self.rule_stack;
+        self.failure_info.log_in_context(token_index, expected, || {
+            let rule_name = rule_stack.iter().skip(1)
+                .find(|(_, start)| *start == token_index)
+                .map(|(rule_name, _)| *rule_name);
+            (rule_name, rule_stack.iter().map(|(rule_name, _)| *rule_name).collect())
+        });
+    }
+
+    // The continuation after consuming a single token.
+    fn token_continuation(&mut self, token_index: usize, expr: &RuleExpression) -> Continuation {
+        let token = &self.tokens[token_index];
+        if let Some(observers) = self.observers {
+            observers.token(token, token_index);
+        }
+        let node = IntermediateSyntaxTree::TokenNode(token.clone(), token_index, self.parser.capture(expr, token));
+        let node = self.arena.add(node);
+        Continuation (token_index + 1, self.arena.single(node))
+    }
+
+    // Parses a rule, making a rule node for each way it matches. The names in the nodes
+    // are the parser's own, so the trees can outlive whatever rule_name borrows from.
+    fn rule_continuations(&mut self, token_index: usize, rule_name: &str) -> Result<Vec<Continuation>, ParseError> {
+        let Some((rule_name, rule_expr)) = self.parser.rules.get_key_value(rule_name) else {
+            return Err(self.parser.unknown_rule(rule_name));
+        };
+
+        self.push_rule(rule_name, token_index)?;
+        let result = self.run(Need::RuleBody(rule_expr, token_index));
+        self.rule_stack.pop();
+        result?;
+
+        let continuations = self.finish_rule(token_index, rule_name, rule_expr);
+        self.exit_rule(rule_name, token_index, continuations.len());
+        Ok(continuations)
+    }
+
+    // Once the body of a rule has been parsed, checks its predicates and makes its nodes.
+    fn finish_rule(&mut self, token_index: usize, rule_name: &'a Symbol, rule_expr: &'a RuleExpression) -> Vec<Continuation> {
+        let predicates = self.parser.predicates.get(rule_name.as_str());
+        let (accepted, rejected): (Vec<_>, Vec<_>) = self.memo_map[&(ByAddress(rule_expr), token_index)].iter().copied()
+            .partition(|Continuation (a, _)| predicates.is_none_or(|predicates| 
+                predicates.iter().all(|predicate| predicate(self.tokens, token_index..*a))
+            ));
+
+        if accepted.is_empty() && !rejected.is_empty() {
+            self.log_failure(token_index, rule_name);
+        }
+
+        accepted.into_iter()
+            .map(|Continuation (a, subtrees)| {
+                let node = self.arena.add(IntermediateSyntaxTree::RuleNode { 
+                    rule_name, 
+                    subexpressions: self.arena.flatten(subtrees), 
+                    span: token_index..a 
+                });
+                Continuation (a, self.arena.single(node))
+            })
+            .collect()
+    }
+}
+
+/* The work stack of ParseState::run. */
+
+#[derive(Clone, Copy)]
+enum Need<'a> {
+    Expr (&'a RuleExpression, usize),  // The continuations of an expression, memoized.
+    RuleBody (&'a RuleExpression, usize),  // The same for a rule's body, with left recursion handled.
+}
+
+enum Step<'a> {
+    Need (Need<'a>),  // Step again once this is in the memo map.
+    Done (Vec<Continuation>),
+}
+
+enum Frame<'a> {
+    Expr (ExprTask<'a>),
+    RuleBody (RuleBodyTask<'a>),
+}
+
+impl Frame<'_> {
+    fn index(&self) -> usize {
+        match self {
+            Frame::Expr(task) => task.index,
+            Frame::RuleBody(task) => task.body.index,
         }
+    }
+}
+
+/* Parses the body of a rule, growing a seed if the rule turns out to be left
+ * recursive (see Warth et al., "Packrat Parsers Can Support Left Recursion").
+ * 
+ * The seed starts out as a failed parse. Each pass re-parses the rule using the
+ * previous pass's results for the recursive calls, and we stop once a pass
+ * fails to reach any new token index. */
+struct RuleBodyTask<'a> {
+    key: MemoKey<'a>,
+    log_start: usize,  // Memo entries made after this were computed from the seed.
+    body: ExprTask<'a>,
+}
 
-        if index == self.index {
-            self.failures.insert(expected);
+impl<'a> RuleBodyTask<'a> {
+    fn step<T: Token>(&mut self, state: &mut ParseState<'a, '_, T>) -> Result<Step<'a>, ParseError> {
+        loop {
+            let continuations = match self.body.step(state)? {
+                Step::Need(need) => return Ok(Step::Need(need)),
+                Step::Done(continuations) => continuations,
+            };
+
+            state.check_continuations(self.body.index, &continuations)?;
+            let seed = state.memo_insert(self.key, continuations).expect("seed exists");
+
+            if !state.rules_in_progress[&self.key] {
+                return Ok(Step::Done(vec![]));
+            }
+
+            let seed_ends = seed.iter().map(|Continuation (i, _)| *i).collect::<HashSet<_>>();
+            if state.memo_map[&self.key].iter().all(|Continuation (i, _)| seed_ends.contains(i)) {
+                return Ok(Step::Done(vec![]));
+            }
+
+            for stale_key in state.memo_log.split_off(self.log_start) {
+                state.memo_remove(&stale_key);
+            }
+            self.body = ExprTask::new(self.body.expr, self.body.index);
         }
     }
 }
 
-fn parse_expr<'a, T: Token>(
-    parser: &'a Parser<T>, 
-    tokens: &[T], 
-    token_index: usize, 
+/* One expression at one index, part way through. Every Need a task returns is asked
+ * for exactly once, so a task knows it is in the memo map the next time it steps. */
+struct ExprTask<'a> {
     expr: &'a RuleExpression,
-    memo_map: &mut HashMap<(ByAddress<&'a RuleExpression>, usize), Vec<Continuation<'a, T>>>,
-    failure_info: &mut FailureCache<'a>
-) -> Result<(), ParseError> {
+    index: usize,
+    continuations: Vec<Continuation>,
+    progress: Progress<'a>,
+}
 
-    // Prevent stack overflow by allocating additional stack as required.
-    stacker::maybe_grow(32 * 1024, 1024 * 1024, || {
+enum Progress<'a> {
+    Start,
+    Lookahead (FailureCache<'a>),  // Whatever the lookahead looked at isn't really expected, so its failures are undone.
+    Rule (&'a Symbol, &'a RuleExpression),
+    Alternative (usize),  // The alternative being parsed.
+    Optional,
+    Sequence (usize, Extension<'a>),  // The expression being parsed, and the passes through it.
+    Repeat (usize, Extension<'a>),  // How many times the inner expression has been parsed so far.
+}
 
-        if memo_map.contains_key(&(ByAddress(expr), token_index)) {
-            return Ok(());
+/* Extends each continuation in a pass by every way expr matches after it, which is
+ * one part of a concatenation or one round of a repetition. Joining the subtrees is
+ * O(1), see Children. */
+struct Extension<'a> {
+    expr: &'a RuleExpression,
+    pass: Vec<Continuation>,
+    position: usize,  // The continuation in pass being extended.
+    asked: bool,  // Whether the one at position has been asked for.
+    next_pass: Vec<Continuation>,
+}
+
+impl<'a> Extension<'a> {
+    fn new(expr: &'a RuleExpression, pass: Vec<Continuation>) -> Extension<'a> {
+        Extension { expr, pass, position: 0, asked: false, next_pass: vec![] }
+    }
+
+    // Returns what it needs, or None once next_pass is complete.
+    fn step<T: Token>(&mut self, state: &mut ParseState<'a, '_, T>) -> Option<Need<'a>> {
+        while let Some(&Continuation (index, old_trees)) = self.pass.get(self.position) {
+            if !self.asked {
+                self.asked = true;
+                return Some(Need::Expr(self.expr, index));
+            }
+
+            for &Continuation (i, subtrees) in &state.memo_map[&(ByAddress(self.expr), index)] {
+                self.next_pass.push(Continuation (i, state.arena.join(old_trees, subtrees)));
+            }
+            self.position += 1;
+            self.asked = false;
         }
+        None
+    }
+}
+
+impl<'a> ExprTask<'a> {
+    fn new(expr: &'a RuleExpression, index: usize) -> ExprTask<'a> {
+        ExprTask { expr, index, continuations: vec![], progress: Progress::Start }
+    }
 
-        let mut continuations = vec![];
+    fn step<T: Token>(&mut self, state: &mut ParseState<'a, '_, T>) -> Result<Step<'a>, ParseError> {
+        let (expr, token_index) = (self.expr, self.index);
 
-        match expr {
-            RuleExpression::Terminal(term) => {
-                if token_index < tokens.len() && T::matches(term, &tokens[token_index])? {
-                    continuations.push(Continuation (
-                        token_index + 1,
-                        vec![Rc::new(IntermediateSyntaxTree::TokenNode(tokens[token_index].clone()))]
-                    ));
+        match (&mut self.progress, expr) {
+            (Progress::Start, RuleExpression::Terminal(term)) => {
+                if token_index < state.tokens.len() && state.parser.terminals[term].matches(&state.tokens[token_index]) {
+                    self.continuations.push(state.token_continuation(token_index, expr));
                 }
                 else {
-                    failure_info.log(token_index, term);
-                }
-            },
-            RuleExpression::RuleName(rule_name) => {
-                match parser.rules.get(rule_name) {
-                    Some(rule_expr) => {
-                        parse_expr(parser, tokens, token_index, rule_expr, memo_map, failure_info)?;
-                        continuations = memo_map[&(ByAddress(rule_expr), token_index)].clone().into_iter()
-                            .map(|Continuation (a, subtrees)| 
-                                Continuation (a, vec![Rc::new(IntermediateSyntaxTree::RuleNode { rule_name, subexpressions: subtrees })])
-                            )
-                            .collect();
+                    state.log_failure(token_index, term);
+                }
+            },
+            (Progress::Start, RuleExpression::CharacterClass(class)) => {
+                if token_index < state.tokens.len() && class_matches(class, &state.tokens[token_index])? {
+                    self.continuations.push(state.token_continuation(token_index, expr));
+                }
+                else {
+                    state.log_failure(token_index, &class.source);
+                }
+            },
+            (Progress::Start, RuleExpression::Wildcard) => {
+                if token_index < state.tokens.len() {
+                    self.continuations.push(state.token_continuation(token_index, expr));
+                }
+                else {
+                    state.log_failure(token_index, ".");
+                }
+            },
+            (Progress::Start, RuleExpression::External(rule_name)) => {
+                match state.parser.scan(rule_name, state.tokens, token_index)? {
+                    Some(length) => {
+                        let mut trees = None;
+                        for i in token_index..token_index + length {
+                            let node = state.arena.add(IntermediateSyntaxTree::TokenNode(state.tokens[i].clone(), i, None));
+                            let token = state.arena.single(node);
+                            trees = state.arena.join(trees, token);
+                        }
+                        self.continuations.push(Continuation (token_index + length, trees));
                     }
-                    None => return Err("Rule not found".into()),
+                    None => state.log_failure(token_index, rule_name),
                 }
             },
-            RuleExpression::Concatenation(exprs) => {
-                let mut curr_pass = vec![Continuation (token_index, vec![])];
-
-                for expr in exprs {
-                    curr_pass = extend_all(curr_pass, parser, tokens, expr, memo_map, failure_info)?;
+            (Progress::Start, RuleExpression::EndOfInput) => {
+                if token_index == state.tokens.len() {
+                    self.continuations.push(Continuation (token_index, None));
+                }
+                else {
+                    state.log_failure(token_index, "$");
                 }
-
-                continuations = curr_pass.into_iter().collect();
             },
-            RuleExpression::Alternatives(exprs) => {
-                for expr in exprs {
-                    parse_expr(parser, tokens, token_index, expr, memo_map, failure_info)?;
-
-                    continuations.append(&mut memo_map[&(ByAddress(expr), token_index)].clone());
+            (Progress::Start, RuleExpression::Negation(inner_expr, description)) => {
+                if token_index < state.tokens.len() && !single_token_matches(state.parser, inner_expr, &state.tokens[token_index])? {
+                    self.continuations.push(state.token_continuation(token_index, expr));
+                }
+                else {
+                    state.log_failure(token_index, description);
                 }
             },
-            RuleExpression::Optional(expr) => {
-                continuations.push(Continuation (token_index, vec![]));
+            (Progress::Start, RuleExpression::PositiveLookahead(inner_expr) | RuleExpression::NegativeLookahead(inner_expr, _)) => {
+                self.progress = Progress::Lookahead(state.failure_info.clone());
+                return Ok(Step::Need(Need::Expr(inner_expr, token_index)));
+            },
+            (Progress::Lookahead(failure_info), RuleExpression::PositiveLookahead(inner_expr) | RuleExpression::NegativeLookahead(inner_expr, _)) => {
+                state.failure_info = std::mem::replace(failure_info, FailureCache::new());
+
+                let matched = !state.memo_map[&(ByAddress(&**inner_expr), token_index)].is_empty();
 
-                parse_expr(parser, tokens, token_index, expr, memo_map, failure_info)?;
-                continuations.append(&mut memo_map[&(ByAddress(&**expr), token_index)].clone());
+                if matched == matches!(expr, RuleExpression::PositiveLookahead(_)) {
+                    self.continuations.push(Continuation (token_index, None));
+                }
+                else if let RuleExpression::NegativeLookahead(_, description) = expr {
+                    state.log_failure(token_index, description);
+                }
+            },
+            (Progress::Start, RuleExpression::RuleName(rule_name)) => {
+                let Some((rule_name, rule_expr)) = state.parser.rules.get_key_value(rule_name.as_str()) else {
+                    return Err(state.parser.unknown_rule(rule_name));
+                };
+                state.push_rule(rule_name, token_index)?;
+                self.progress = Progress::Rule(rule_name, rule_expr);
+                return Ok(Step::Need(Need::RuleBody(rule_expr, token_index)));
             },
-            RuleExpression::Many(inner_expr) | RuleExpression::OneOrMore(inner_expr) => {
-                if let RuleExpression::Many(_) = expr {
-                    continuations.push(Continuation(token_index, vec![]));
+            (&mut Progress::Rule(rule_name, rule_expr), _) => {
+                state.rule_stack.pop();
+                self.continuations = state.finish_rule(token_index, rule_name, rule_expr);
+                state.exit_rule(rule_name, token_index, self.continuations.len());
+            },
+            (Progress::Start, RuleExpression::Concatenation(exprs)) => {
+                if let Some(first) = exprs.first() {
+                    self.progress = Progress::Sequence(0, Extension::new(first, vec![Continuation (token_index, None)]));
+                    return self.step(state);
                 }
+                self.continuations.push(Continuation (token_index, None));
+            },
+            (Progress::Sequence(position, extension), RuleExpression::Concatenation(exprs)) => {
+                loop {
+                    if let Some(need) = extension.step(state) {
+                        return Ok(Step::Need(need));
+                    }
 
-                let mut curr_pass = vec![Continuation (token_index, vec![])];
+                    let pass = std::mem::take(&mut extension.next_pass);
+                    *position += 1;
+                    match exprs.get(*position) {
+                        Some(expr) => *extension = Extension::new(expr, pass),
+                        None => {
+                            self.continuations = pass;
+                            break;
+                        }
+                    }
+                }
+            },
+            (Progress::Start, RuleExpression::Alternatives(_) | RuleExpression::OrderedAlternatives(_)) => {
+                self.progress = Progress::Alternative(0);
+                return self.step(state);
+            },
+            (Progress::Alternative(position), RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs)) => {
+                let ordered = state.parser.ordered_choice || matches!(expr, RuleExpression::OrderedAlternatives(_));
 
-                while !curr_pass.is_empty() {
-                    curr_pass = extend_all(curr_pass, parser, tokens, inner_expr, memo_map, failure_info)?;
+                // The alternative at position has been parsed, except the first time through.
+                if *position > 0 {
+                    self.continuations.extend_from_slice(&state.memo_map[&(ByAddress(&exprs[*position - 1]), token_index)]);
+                }
 
-                    continuations.append(&mut curr_pass.clone());
+                // Committed to the first alternative that matches, later ones aren't even tried.
+                let committed = ordered && !self.continuations.is_empty();
+                if let Some(expr) = exprs.get(*position).filter(|_| !committed) {
+                    trace::alternative(*position, token_index);
+                    *position += 1;
+                    return Ok(Step::Need(Need::Expr(expr, token_index)));
                 }
             },
+            (Progress::Start, RuleExpression::Optional(inner_expr)) => {
+                self.continuations.push(Continuation (token_index, None));
+                self.progress = Progress::Optional;
+                return Ok(Step::Need(Need::Expr(inner_expr, token_index)));
+            },
+            (Progress::Optional, RuleExpression::Optional(inner_expr)) => {
+                self.continuations.extend_from_slice(&state.memo_map[&(ByAddress(&**inner_expr), token_index)]);
+            },
+            (Progress::Start, RuleExpression::Many(inner_expr) | RuleExpression::OneOrMore(inner_expr) | RuleExpression::Repetition(inner_expr, ..)) => {
+                if matches!(expr, RuleExpression::Many(_) | RuleExpression::Repetition(_, 0, _)) {
+                    self.continuations.push(Continuation (token_index, None));
+                }
+                if !matches!(expr, RuleExpression::Repetition(_, _, Some(0))) {
+                    self.progress = Progress::Repeat(0, Extension::new(inner_expr, vec![Continuation (token_index, None)]));
+                    return self.step(state);
+                }
+            },
+            (Progress::Repeat(count, extension), RuleExpression::Many(inner_expr) | RuleExpression::OneOrMore(inner_expr) | RuleExpression::Repetition(inner_expr, ..)) => {
+                let (min, max) = match expr {
+                    RuleExpression::Repetition(_, min, max) => (*min, *max),
+                    _ => (0, None),
+                };
+
+                loop {
+                    if let Some(need) = extension.step(state) {
+                        return Ok(Step::Need(need));
+                    }
+
+                    let pass = std::mem::take(&mut extension.next_pass);
+                    *count += 1;
+                    if *count >= min {
+                        self.continuations.extend_from_slice(&pass);
+                    }
+                    if pass.is_empty() || max.is_some_and(|max| *count >= max) {
+                        break;
+                    }
+                    *extension = Extension::new(inner_expr, pass);
+                }
+            },
+            (_, _) => unreachable!("progress doesn't match the expression"),
         }
 
-        memo_map.insert((ByAddress(expr), token_index), continuations);
-        Ok(())
-    })
+        Ok(Step::Done(std::mem::take(&mut self.continuations)))
+    }
 }
 
-// `curr_pass` is a vector of continuations. This function attempts to parse `expr`
-// from each of the continuation, generating a new vector of continuations, possibly
-// with more or fewer elements.
-// Possibly the bottleneck of the algorithm...
-fn extend_all<'a, T: Token>(
-    curr_pass: Vec<Continuation<'a, T>>,
-    parser: &'a Parser<T>, 
-    tokens: &[T], 
-    expr: &'a RuleExpression,
-    memo_map: &mut HashMap<(ByAddress<&'a RuleExpression>, usize), Vec<Continuation<'a, T>>>,
-    failure_info: &mut FailureCache<'a>
-) -> Result<Vec<Continuation<'a, T>>, ParseError> {
-
-    let mut next_pass = Vec::new();
-    for Continuation (index, old_trees) in curr_pass {
-        parse_expr(parser, tokens, index, expr, memo_map, failure_info)?;
-        next_pass.append(&mut memo_map[&(ByAddress(expr), index)].clone().into_iter()
-            .map(|Continuation (i, subtrees)| {
-                let mut final_trees = old_trees.clone();
-                final_trees.append(&mut subtrees.clone());
-
-                Continuation (i, final_trees)
-            })
-            .collect()
-        );
+// Checks a token against an expression that matches exactly one token (see define::describe_single_token).
+pub(super) fn single_token_matches<T: Token>(parser: &Parser<T>, expr: &RuleExpression, token: &T) -> Result<bool, ParseError> {
+    match expr {
+        RuleExpression::Terminal(term) => Ok(parser.terminals[term].matches(token)),
+        RuleExpression::CharacterClass(class) => class_matches(class, token),
+        RuleExpression::Negation(inner_expr, _) => Ok(!single_token_matches(parser, inner_expr, token)?),
+        RuleExpression::Wildcard => Ok(true),
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+            for expr in exprs {
+                if single_token_matches(parser, expr, token)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        _ => Err("Negated expression does not match a single token".into()),
     }
+}
 
-    Ok(next_pass)
+fn class_matches<T: Token>(class: &CharacterClass, token: &T) -> Result<bool, ParseError> {
+    match token.as_char() {
+        Some(ch) => Ok(class.contains(ch)),
+        None => Err("Token type does not support character classes".into()),
+    }
 }
 
 
+/* Every node made during a parse lives in one arena, and nodes refer to each other by
+ * index, rather than each being a separate allocation. Nodes are never removed, even
+ * the ones from parses that went nowhere, but they all go at once when the parse ends.
+ *
+ * The subtrees a continuation has parsed so far live in the arena too, as a tree of
+ * joins, so that extending a continuation (which happens for every way every part of a
+ * concatenation matches) is one push instead of a copy of every subtree before it. The
+ * joins are only flattened into a list when a rule node is made. The Earley parser
+ * builds its trees in an arena too, for the same reason. */
+pub(super) struct Arena<'a, T: Token> {
+    nodes: Vec<IntermediateSyntaxTree<'a, T>>,
+    lists: Vec<ListNode>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct NodeId(usize);
+
+// The subtrees parsed so far, in order, or None if there are none.
+pub(super) type Children = Option<ListId>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct ListId(usize);
+
+#[derive(Clone, Copy, Debug)]
+enum ListNode {
+    One (NodeId),
+    Join (ListId, ListId),
+}
+
 #[derive(Clone, Debug)]
-enum IntermediateSyntaxTree<'a, T: Token> { // Vec contains Rc's, to be removed later.
-    RuleNode {rule_name: &'a str, subexpressions: Vec<Rc<IntermediateSyntaxTree<'a, T>>>},
-    TokenNode (T)
-}
-
-fn intermediate_to_final<T: Token>(root: &Rc<IntermediateSyntaxTree<T>>) -> SyntaxTree<T> {
-    // Prevent stack overflow by allocating additional stack as required.
-    stacker::maybe_grow(32 * 1024, 1024 * 1024, || {
-        match &*root.clone() {
-            IntermediateSyntaxTree::RuleNode {rule_name, subexpressions} => 
-                SyntaxTree::RuleNode {
-                    rule_name: (*rule_name).to_string(), 
-                    subexpressions: subexpressions.iter()
-                        .map(|rc_refcell_tree| intermediate_to_final(rc_refcell_tree))
-                        .collect()
+pub(super) enum IntermediateSyntaxTree<'a, T: Token> {
+    RuleNode {rule_name: &'a Symbol, subexpressions: Vec<NodeId>, span: Range<usize>},
+    TokenNode (T, usize, Option<Captured>)
+}
+
+impl<'a, T: Token> Arena<'a, T> {
+    pub(super) fn new() -> Self {
+        Arena { nodes: vec![], lists: vec![] }
+    }
+
+    pub(super) fn add(&mut self, node: IntermediateSyntaxTree<'a, T>) -> NodeId {
+        self.nodes.push(node);
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub(super) fn single(&mut self, node: NodeId) -> Children {
+        self.lists.push(ListNode::One(node));
+        Some(ListId(self.lists.len() - 1))
+    }
+
+    pub(super) fn join(&mut self, left: Children, right: Children) -> Children {
+        match (left, right) {
+            (Some(left), Some(right)) => {
+                self.lists.push(ListNode::Join(left, right));
+                Some(ListId(self.lists.len() - 1))
+            }
+            (left, None) => left,
+            (None, right) => right,
+        }
+    }
+
+    pub(super) fn flatten(&self, children: Children) -> Vec<NodeId> {
+        let mut nodes = vec![];
+        let mut pending = children.into_iter().collect::<Vec<_>>();
+        while let Some(list) = pending.pop() {
+            match self.lists[list.0] {
+                ListNode::One(node) => nodes.push(node),
+                ListNode::Join(left, right) => pending.extend([right, left]),
+            }
+        }
+        nodes
+    }
+
+    pub(super) fn get(&self, id: NodeId) -> &IntermediateSyntaxTree<'a, T> {
+        &self.nodes[id.0]
+    }
+
+    pub(super) fn to_final(&self, root: NodeId) -> SyntaxTree<T> {
+        // The rules whose children are being built, with the children built so far.
+        let mut building: Vec<(NodeId, Vec<SyntaxTree<T>>)> = vec![];
+        let mut next = root;
+        loop {
+            let mut built = match self.get(next) {
+                IntermediateSyntaxTree::RuleNode {rule_name, subexpressions, span} => match subexpressions.first() {
+                    Some(&first) => {
+                        building.push((next, Vec::with_capacity(subexpressions.len())));
+                        next = first;
+                        continue;
+                    }
+                    None => SyntaxTree::RuleNode {rule_name: (*rule_name).clone(), subexpressions: vec![], span: span.clone()},
                 },
-            IntermediateSyntaxTree::TokenNode(token) => SyntaxTree::TokenNode(token.clone()),
+                IntermediateSyntaxTree::TokenNode(token, index, captured) 
+                    => SyntaxTree::TokenNode {token: token.clone(), index: *index, captured: captured.clone()},
+            };
+
+            // Hands the node to its parent, finishing every rule that it was the last child of.
+            loop {
+                let Some((parent, children)) = building.last_mut() else {
+                    return built;
+                };
+                let IntermediateSyntaxTree::RuleNode {rule_name, subexpressions, span} = self.get(*parent) else {
+                    unreachable!("only rules have children");
+                };
+                children.push(built);
+                if let Some(&sibling) = subexpressions.get(children.len()) {
+                    next = sibling;
+                    break;
+                }
+
+                let (_, children) = building.pop().expect("rule is being built");
+                built = SyntaxTree::RuleNode {rule_name: (*rule_name).clone(), subexpressions: children, span: span.clone()};
+            }
         }
-    })
+    }
+
+    fn events(&self, root: NodeId, on_event: &mut dyn FnMut(ParseEvent<T>)) {
+        // The rules that have been entered, with the children they have left.
+        let mut entered = vec![];
+        let mut next = Some(root);
+        loop {
+            match next.map(|node| self.get(node)) {
+                Some(IntermediateSyntaxTree::RuleNode {rule_name, subexpressions, ..}) => {
+                    on_event(ParseEvent::Enter(rule_name));
+                    entered.push((rule_name, subexpressions.iter()));
+                }
+                Some(IntermediateSyntaxTree::TokenNode(token, ..)) => on_event(ParseEvent::Token(token)),
+                None => (),
+            }
+
+            let Some((rule_name, children)) = entered.last_mut() else {
+                return;
+            };
+            next = children.next().copied();
+            if next.is_none() {
+                on_event(ParseEvent::Exit(rule_name));
+                entered.pop();
+            }
+        }
+    }
+
+    // The trees for the roots, leaving out any with the same shape as one before it.
+    pub(super) fn distinct_trees(&self, roots: Vec<NodeId>) -> Vec<SyntaxTree<T>> {
+        let mut distinct_trees: Vec<NodeId> = vec![];
+        for tree in roots {
+            if !distinct_trees.iter().any(|&other| self.same_shape(other, tree)) {
+                distinct_trees.push(tree);
+            }
+        }
+
+        distinct_trees.into_iter().map(|tree| self.to_final(tree)).collect()
+    }
+
+    /* Structural equality, used to weed out duplicate parses. Token nodes are not
+     * compared, since two trees of the same shape that cover the same input must
+     * place the same token at each leaf. */
+    fn same_shape(&self, left: NodeId, right: NodeId) -> bool {
+        let mut pending = vec![(left, right)];
+        while let Some((left, right)) = pending.pop() {
+            if left == right {
+                continue;
+            }
+
+            match (self.get(left), self.get(right)) {
+                (
+                    IntermediateSyntaxTree::RuleNode { rule_name: left_name, subexpressions: left_subs, .. },
+                    IntermediateSyntaxTree::RuleNode { rule_name: right_name, subexpressions: right_subs, .. }
+                ) => {
+                    if left_name != right_name || left_subs.len() != right_subs.len() {
+                        return false;
+                    }
+                    pending.extend(left_subs.iter().copied().zip(right_subs.iter().copied()));
+                }
+                (IntermediateSyntaxTree::TokenNode(..), IntermediateSyntaxTree::TokenNode(..)) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
 }
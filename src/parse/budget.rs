@@ -0,0 +1,96 @@
+/* Limits on how long a parse can run, for callers like editors that would rather have
+ * no tree than wait on a pathological input. A parse that runs out of budget fails
+ * with ParseError::Cancelled, which says how far it got.
+ *
+ * The backtracking and Earley algorithms check the budget as they go. LL(1) parses
+ * take time linear in the input, so they aren't checked, but with ParseAlgorithm::Auto
+ * a grammar that isn't LL(1) falls back to backtracking, which is. */
+
+use super::{CharToken, Parser, ParseError, SyntaxTree, Token};
+use super::stats::StatsRecorder;
+
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+
+/* Public Interface */
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseBudget {
+    timeout: Option<Duration>,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl ParseBudget {
+    pub fn new() -> ParseBudget {
+        ParseBudget::default()
+    }
+
+    /* Gives up once the parse has taken this long. */
+    pub fn timeout(mut self, timeout: Duration) -> ParseBudget {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /* Gives up once the flag is set, which can be done from another thread. */
+    pub fn cancel_flag(mut self, flag: Arc<AtomicBool>) -> ParseBudget {
+        self.cancel = Some(flag);
+        self
+    }
+}
+
+impl<T: Token> Parser<T> {
+    /* Like parse_tokens, but fails with ParseError::Cancelled once the budget runs out. */
+    pub fn parse_tokens_with_budget(&self, tokens: &[T], start_rule: &str, budget: &ParseBudget) -> Result<SyntaxTree<T>, ParseError> {
+        self.parse_tokens_anchored(tokens, start_rule, self.anchored, &budget.start(), &StatsRecorder::new())
+    }
+}
+
+impl Parser<CharToken> {
+    pub fn parse_string_with_budget(&self, input: &str, start_rule: &str, budget: &ParseBudget) -> Result<SyntaxTree<CharToken>, ParseError> {
+        self.parse_tokens_with_budget(&super::string_to_tokens(input), start_rule, budget)
+            .map_err(|err| super::locate_error(err, input))
+    }
+}
+
+
+/* Private Implementation */
+
+impl ParseBudget {
+    fn start(&self) -> Deadline {
+        Deadline {
+            at: self.timeout.map(|timeout| Instant::now() + timeout),
+            cancel: self.cancel.clone(),
+            checks: Cell::new(0),
+        }
+    }
+}
+
+/* A budget for one parse, which the algorithms check as they go. */
+#[derive(Debug, Clone)]
+pub(crate) struct Deadline {
+    at: Option<Instant>,
+    cancel: Option<Arc<AtomicBool>>,
+    checks: Cell<u32>,
+}
+
+impl Deadline {
+    pub(crate) fn none() -> Deadline {
+        ParseBudget::default().start()
+    }
+
+    pub(crate) fn passed(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+            || self.at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    // Like passed, for checks in tight loops, where looking at the clock every time would
+    // slow parsing down. Only really checks on the first call and every so often after.
+    pub(crate) fn passed_sometimes(&self) -> bool {
+        let checks = self.checks.get();
+        self.checks.set(checks.wrapping_add(1));
+        checks.is_multiple_of(256) && self.passed()
+    }
+}
@@ -0,0 +1,105 @@
+/* The whole Earley chart of a parse, for seeing where a parse went wrong in a grammar.
+ * Parsley has no GSS (the backtracking parser keeps a memo table instead, which has no
+ * layers to look at), but the Earley chart is the same idea: each set is a layer, one
+ * per token index, and each item in it is a production part way through being matched.
+ *
+ * An item is written as its production with a dot at how far it has got, like
+ * `Sum → Sum • "+" Num`, along with the token it started at. Productions that the
+ * algorithm made up for groups and quantifiers are named by number, like `#4`. Links
+ * go from an item to the items it predicted (the productions of the rule after its
+ * dot), and from an item to the item it became by matching the symbol after its dot.
+ *
+ * An item that is neither complete nor ever advanced is dead: that's as far as that
+ * possibility got. Lots of items in one set mean the grammar is keeping lots of
+ * possibilities open there.
+ *
+ * to_dot puts each set in its own box, with dead items in red. to_json gives one
+ * object on a single line:
+ *
+ *     "sets"     A list per set, of items: {"item", "origin", "complete", "dead"}.
+ *     "links"    {"from": [set, item], "to": [set, item], "kind": "predict" or "advance"}. */
+
+use super::{Parser, ParseError, Token};
+use super::earley_parser::earley_chart;
+use super::export::{dot_string, json_string};
+
+
+/* Public Interface */
+
+pub struct ChartDump {
+    pub(super) sets: Vec<Vec<DumpItem>>,
+    pub(super) links: Vec<DumpLink>,
+}
+
+impl<T: Token> Parser<T> {
+    /* Runs the Earley algorithm over the tokens, and returns its chart, whether or not
+     * there is a parse. */
+    pub fn earley_chart(&self, tokens: &[T], start_rule: &str) -> Result<ChartDump, ParseError> {
+        Ok(earley_chart(self, tokens, start_rule)?.dump())
+    }
+}
+
+impl ChartDump {
+    /* The number of items, in every set. */
+    pub fn len(&self) -> usize {
+        self.sets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /* The chart as a Graphviz graph, see the module docs. */
+    pub fn to_dot(&self) -> String {
+        let mut dot = "digraph EarleyChart {\n    rankdir=LR;\n    node [fontname=\"monospace\", shape=box];\n".to_string();
+        for (index, set) in self.sets.iter().enumerate() {
+            dot += &format!("    subgraph cluster_{index} {{\n        label=\"{index}\";\n");
+            for (i, item) in set.iter().enumerate() {
+                let label = dot_string(&format!("{}  ({})", item.text, item.origin));
+                let style = if item.dead { ", color=red, fontcolor=red" } else if item.complete { ", style=bold" } else { "" };
+                dot += &format!("        s{index}_{i} [label={label}{style}];\n");
+            }
+            dot += "    }\n";
+        }
+        for link in &self.links {
+            let style = if link.predict { " [style=dashed]" } else { "" };
+            dot += &format!("    s{}_{} -> s{}_{}{style};\n", link.from.0, link.from.1, link.to.0, link.to.1);
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /* The chart as JSON, see the module docs. */
+    pub fn to_json(&self) -> String {
+        let sets = self.sets.iter()
+            .map(|set| {
+                let items = set.iter()
+                    .map(|item| format!(r#"{{"item":{},"origin":{},"complete":{},"dead":{}}}"#,
+                        json_string(&item.text), item.origin, item.complete, item.dead))
+                    .collect::<Vec<_>>();
+                format!("[{}]", items.join(","))
+            })
+            .collect::<Vec<_>>();
+        let links = self.links.iter()
+            .map(|link| format!(r#"{{"from":[{},{}],"to":[{},{}],"kind":"{}"}}"#,
+                link.from.0, link.from.1, link.to.0, link.to.1, if link.predict { "predict" } else { "advance" }))
+            .collect::<Vec<_>>();
+        format!(r#"{{"sets":[{}],"links":[{}]}}"#, sets.join(","), links.join(","))
+    }
+}
+
+
+/* Private Implementation */
+
+pub(super) struct DumpItem {
+    pub(super) text: String,  // The dotted production.
+    pub(super) origin: usize,
+    pub(super) complete: bool,
+    pub(super) dead: bool,
+}
+
+pub(super) struct DumpLink {
+    pub(super) from: (usize, usize),  // Set, and item in the set.
+    pub(super) to: (usize, usize),
+    pub(super) predict: bool,  // Otherwise, an advance.
+}
@@ -0,0 +1,244 @@
+/* Trees stored flat, for when they get big. Every node of a SyntaxTree is its own
+ * allocation, with a Vec of children and a copy of everything it holds, which adds up
+ * fast for a tree with millions of nodes. A CompactTree keeps all the nodes in one
+ * table of small fixed size entries, the children of each node as a range of one
+ * shared list of node indices, and the tokens in a list of their own. Indices are
+ * 32 bits, so inputs can't go past 4 billion tokens.
+ *
+ * Nodes are looked at through NodeRef, which has an accessor for everything a
+ * SyntaxTree node holds. Code that wants to match on the enum can call view() on any
+ * node to get that subtree back as a SyntaxTree. */
+
+use super::{Captured, Parser, ParseError, Symbol, SyntaxTree, Token};
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+
+/* Public Interface */
+
+pub struct CompactTree<T: Token> {
+    nodes: Vec<Node>,
+    children: Vec<u32>,  // Indices into nodes. Each rule's children are a contiguous range.
+    tokens: Vec<T>,  // The tokens of token nodes and error nodes.
+    rule_names: Vec<Symbol>,  // Each name once, nodes refer to them by index.
+    expected: Vec<HashSet<String>>,  // One for each error node.
+    captured: HashMap<u32, Captured>,  // By node. Most tokens don't capture anything.
+    root: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct NodeRef<'t, T: Token> {
+    tree: &'t CompactTree<T>,
+    id: u32,
+}
+
+impl<T: Token> Parser<T> {
+    /* Like parse_tokens, but returns a CompactTree. */
+    pub fn parse_compact(&self, tokens: &[T], start_rule: &str) -> Result<CompactTree<T>, ParseError> {
+        self.parse_tokens(tokens, start_rule).map(CompactTree::from)
+    }
+}
+
+impl<T: Token> From<SyntaxTree<T>> for CompactTree<T> {
+    fn from(tree: SyntaxTree<T>) -> CompactTree<T> {
+        let mut builder = Builder {
+            tree: CompactTree {
+                nodes: vec![],
+                children: vec![],
+                tokens: vec![],
+                rule_names: vec![],
+                expected: vec![],
+                captured: HashMap::new(),
+                root: 0,
+            },
+            name_ids: HashMap::new(),
+        };
+        builder.tree.root = builder.add(tree);
+        builder.tree
+    }
+}
+
+impl<T: Token> CompactTree<T> {
+    pub fn root(&self) -> NodeRef<'_, T> {
+        NodeRef { tree: self, id: self.root }
+    }
+
+    /* The number of nodes, of every kind. */
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /* The whole tree as a SyntaxTree, the same one it was made from. */
+    pub fn to_tree(&self) -> SyntaxTree<T> {
+        self.root().view()
+    }
+}
+
+impl<'t, T: Token> NodeRef<'t, T> {
+    /* The rule this node is for, or None for tokens and errors. */
+    pub fn rule_name(&self) -> Option<&'t Symbol> {
+        match self.node() {
+            Node::Rule { name, .. } => Some(&self.tree.rule_names[*name as usize]),
+            Node::Token { .. } | Node::Error { .. } => None,
+        }
+    }
+
+    /* The range of token indices covered by this node, like SyntaxTree::span. */
+    pub fn span(&self) -> Range<usize> {
+        match self.node() {
+            Node::Rule { span, .. } | Node::Error { span, .. } => span.start as usize..span.end as usize,
+            Node::Token { index, .. } => *index as usize..*index as usize + 1,
+        }
+    }
+
+    /* The node's children, which only rule nodes have. */
+    pub fn children(&self) -> impl ExactSizeIterator<Item = NodeRef<'t, T>> + 't {
+        let tree = self.tree;
+        let ids = match self.node() {
+            Node::Rule { children, .. } => &tree.children[children.start as usize..children.end as usize],
+            Node::Token { .. } | Node::Error { .. } => &[],
+        };
+        ids.iter().map(move |&id| NodeRef { tree, id })
+    }
+
+    pub fn child(&self, index: usize) -> Option<NodeRef<'t, T>> {
+        self.children().nth(index)
+    }
+
+    /* The token of a token node. */
+    pub fn token(&self) -> Option<&'t T> {
+        match self.node() {
+            Node::Token { token, .. } => Some(&self.tree.tokens[*token as usize]),
+            Node::Rule { .. } | Node::Error { .. } => None,
+        }
+    }
+
+    /* The skipped tokens of an error node, and what was expected instead. */
+    pub fn error(&self) -> Option<(&'t [T], &'t HashSet<String>)> {
+        match self.node() {
+            Node::Error { tokens, expected, .. }
+                => Some((&self.tree.tokens[tokens.start as usize..tokens.end as usize], &self.tree.expected[*expected as usize])),
+            Node::Rule { .. } | Node::Token { .. } => None,
+        }
+    }
+
+    /* The value captured from this node's token, if there is one of type V. */
+    pub fn captured<V: 'static>(&self) -> Option<&'t V> {
+        self.tree.captured.get(&self.id).and_then(|value| value.downcast_ref())
+    }
+
+    /* This node and everything under it, as a SyntaxTree. */
+    pub fn view(&self) -> SyntaxTree<T> {
+        // The rule nodes being viewed, with the children still to do and the trees of those done.
+        let mut pending = vec![(NodeRef { tree: self.tree, id: self.id }, self.children(), vec![])];
+        loop {
+            let (_, children, _) = pending.last_mut().expect("A node is being viewed");
+            let tree = match children.next() {
+                Some(child) if child.rule_name().is_some() => {
+                    let grandchildren = child.children();
+                    pending.push((child, grandchildren, vec![]));
+                    continue;
+                }
+                Some(child) => child.view_node(vec![]),
+                None => {
+                    let (node, _, subexpressions) = pending.pop().expect("A node is being viewed");
+                    node.view_node(subexpressions)
+                }
+            };
+            match pending.last_mut() {
+                Some((_, _, done)) => done.push(tree),
+                None => return tree,
+            }
+        }
+    }
+}
+
+
+/* Private Implementation */
+
+// Kept small, since there is one for every node. Ranges index into the tree's lists.
+enum Node {
+    Rule { name: u32, children: Range<u32>, span: Range<u32> },
+    Token { token: u32, index: u32 },
+    Error { tokens: Range<u32>, expected: u32, span: Range<u32> },
+}
+
+struct Builder<T: Token> {
+    tree: CompactTree<T>,
+    name_ids: HashMap<Symbol, u32>,
+}
+
+impl<T: Token> Builder<T> {
+    // Children are added before their parent, so the root ends up last.
+    fn add(&mut self, tree: SyntaxTree<T>) -> u32 {
+        tree.fold_owned(|node, children| self.add_node(node, &children))
+    }
+
+    // Rule nodes come without their children, which have been added already.
+    fn add_node(&mut self, tree: SyntaxTree<T>, child_ids: &[u32]) -> u32 {
+        let node = match tree {
+            SyntaxTree::RuleNode { rule_name, span, .. } => {
+                let next_name = self.tree.rule_names.len() as u32;
+                let name = *self.name_ids.entry(rule_name).or_insert_with_key(|rule_name| {
+                    self.tree.rule_names.push(rule_name.clone());
+                    next_name
+                });
+
+                let children = self.tree.children.len()..self.tree.children.len() + child_ids.len();
+                self.tree.children.extend_from_slice(child_ids);
+                Node::Rule { name, children: to_u32(children), span: to_u32(span) }
+            }
+            SyntaxTree::TokenNode { token, index, captured } => {
+                if let Some(captured) = captured {
+                    self.tree.captured.insert(self.tree.nodes.len() as u32, captured);
+                }
+                self.tree.tokens.push(token);
+                Node::Token { token: self.tree.tokens.len() as u32 - 1, index: index as u32 }
+            }
+            SyntaxTree::ErrorNode { tokens, expected, span } => {
+                let start = self.tree.tokens.len() as u32;
+                self.tree.tokens.extend(tokens);
+                self.tree.expected.push(expected);
+                Node::Error { tokens: start..self.tree.tokens.len() as u32, expected: self.tree.expected.len() as u32 - 1, span: to_u32(span) }
+            }
+        };
+
+        self.tree.nodes.push(node);
+        self.tree.nodes.len() as u32 - 1
+    }
+}
+
+impl<T: Token> NodeRef<'_, T> {
+    fn node(&self) -> &Node {
+        &self.tree.nodes[self.id as usize]
+    }
+
+    // This node as a SyntaxTree, given its children as SyntaxTrees.
+    fn view_node(&self, subexpressions: Vec<SyntaxTree<T>>) -> SyntaxTree<T> {
+        match self.node() {
+            Node::Rule { .. } => SyntaxTree::RuleNode {
+                rule_name: self.rule_name().expect("Is a rule").clone(),
+                subexpressions,
+                span: self.span(),
+            },
+            Node::Token { index, .. } => SyntaxTree::TokenNode {
+                token: self.token().expect("Is a token").clone(),
+                index: *index as usize,
+                captured: self.tree.captured.get(&self.id).cloned(),
+            },
+            Node::Error { .. } => {
+                let (tokens, expected) = self.error().expect("Is an error");
+                SyntaxTree::ErrorNode { tokens: tokens.to_vec(), expected: expected.clone(), span: self.span() }
+            }
+        }
+    }
+}
+
+fn to_u32(span: Range<usize>) -> Range<u32> {
+    span.start as u32..span.end as u32
+}
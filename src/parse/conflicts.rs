@@ -0,0 +1,196 @@
+/* Finds the places where one token of lookahead isn't enough to decide what a rule
+ * should do next: the conflicts that keep a rule out of the LL(1) tables. They aren't
+ * mistakes, since the other algorithms cope with them fine, but each one is somewhere
+ * the grammar might be ambiguous, or at least somewhere parsing has to guess.
+ *
+ * This uses the sets from first_set and follow_set, so terminals are compared as they
+ * are written (see first_follow.rs). Lookaheads are ignored, so a conflict that one
+ * settles is still reported. Ordered choices aren't checked, since their order settles
+ * any conflict between their alternatives. */
+
+use crate::define::{RuleExpression, describe_expression};
+use super::{Parser, SourceLocation, Token};
+use super::ll1_parser::{Analysis, TokenSet};
+
+use std::collections::HashMap;
+
+use by_address::ByAddress;
+use itertools::Itertools;
+
+
+/* Public Interface */
+
+/* Alternatives are numbered from 0, within the choice they belong to, like
+ * GrammarWarning's. Terminals are sorted, with "$" for the end of the input.
+ *
+ * Locations point into the definition the rule came from: at the start of each
+ * alternative (the pairs have `alternative` first, then `with`), or at the ?, * or +
+ * (or {n,m}) of a repetition. They are None for rules that weren't read from a
+ * definition, like those from ABNF, ANTLR or parsley_codegen. */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GrammarConflict {
+    // Both alternatives can start with the terminals.
+    FirstFirst { rule_name: String, alternative: usize, with: usize, terminals: Vec<String>, locations: Option<(SourceLocation, SourceLocation)> },
+    /* The alternative can match nothing, so it could be taken when the terminals come
+     * next, but so could the other alternative, which starts with them. */
+    FirstFollow { rule_name: String, alternative: usize, with: usize, terminals: Vec<String>, locations: Option<(SourceLocation, SourceLocation)> },
+    // Both alternatives can match nothing.
+    BothNullable { rule_name: String, alternative: usize, with: usize, locations: Option<(SourceLocation, SourceLocation)> },
+    /* Something optional or repeated can start with the terminals, and they can also
+     * come after it, so there's no telling whether to match it (again) or move on. */
+    RepetitionFollow { rule_name: String, terminals: Vec<String>, location: Option<SourceLocation> },
+}
+
+impl std::fmt::Display for GrammarConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |terminals: &[String]| terminals.iter().map(|terminal| format!("\"{terminal}\"")).join(", ");
+        let at = |location: &SourceLocation| format!("line {}, column {}", location.line, location.column);
+        // In the order the message names them.
+        let both_at = |locations: &Option<(SourceLocation, SourceLocation)>, swap: bool| match locations {
+            Some((first, second)) if swap => format!(" (at {} and {})", at(second), at(first)),
+            Some((first, second)) => format!(" (at {} and {})", at(first), at(second)),
+            None => String::new(),
+        };
+        match self {
+            GrammarConflict::FirstFirst { rule_name, alternative, with, terminals, locations } =>
+                write!(f, "Alternatives {with} and {alternative} of a choice in \"{rule_name}\" can both start with {}{}", 
+                    list(terminals), both_at(locations, true)),
+            GrammarConflict::FirstFollow { rule_name, alternative, with, terminals, locations } =>
+                write!(f, "Alternative {alternative} of a choice in \"{rule_name}\" can match nothing, but {} can follow the choice and start alternative {with}{}",
+                    list(terminals), both_at(locations, false)),
+            GrammarConflict::BothNullable { rule_name, alternative, with, locations } =>
+                write!(f, "Alternatives {with} and {alternative} of a choice in \"{rule_name}\" can both match nothing{}", both_at(locations, true)),
+            GrammarConflict::RepetitionFollow { rule_name, terminals, location } =>
+                write!(f, "Something optional or repeated in \"{rule_name}\" can start with {}, which can also follow it{}", 
+                    list(terminals), location.as_ref().map(|location| format!(" (at {})", at(location))).unwrap_or_default()),
+        }
+    }
+}
+
+impl<T: Token> Parser<T> {
+    /* Every LL(1) conflict in the grammar, sorted by kind and then by rule name. Like
+     * warnings, this runs every time it's called. */
+    pub fn conflicts(&self) -> Vec<GrammarConflict> {
+        let analysis = Analysis::new(&self.rules);
+        let follows = self.follow_sets();
+        let mut conflicts = vec![];
+
+        for (rule_name, expr) in &self.rules {
+            let locations = self.locations.get(rule_name.as_str()).map(|locations| locations.of_parts(expr)).unwrap_or_default();
+            let mut check = Check { analysis: &analysis, rule_name, ordered_choice: self.ordered_choice, locations, conflicts: &mut conflicts };
+            check.expr(expr, &follows[rule_name.as_str()]);
+        }
+
+        conflicts.sort();
+        conflicts.dedup();
+        conflicts
+    }
+}
+
+
+/* Private Implementation */
+
+// Walks one rule's expression along with what can follow each part, collecting conflicts.
+struct Check<'c> {
+    analysis: &'c Analysis,
+    rule_name: &'c str,
+    ordered_choice: bool,  // Every choice is ordered.
+    locations: HashMap<ByAddress<&'c RuleExpression>, &'c [SourceLocation]>,  // See RuleLocations::of_parts.
+    conflicts: &'c mut Vec<GrammarConflict>,
+}
+
+impl Check<'_> {
+    fn expr(&mut self, expr: &RuleExpression, follow: &TokenSet) {
+        match expr {
+            RuleExpression::Concatenation(exprs) => {
+                let mut rest = TokenSet::default();
+                let mut rest_nullable = true;
+                for expr in exprs.iter().rev() {
+                    let expr_follow = if rest_nullable { rest.union(follow) } else { rest.clone() };
+                    self.expr(expr, &expr_follow);
+
+                    let expr_first = self.analysis.first(expr);
+                    rest = if expr_first.nullable { expr_first.tokens.union(&rest) } else { expr_first.tokens };
+                    rest_nullable &= expr_first.nullable;
+                }
+            }
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+                if !self.ordered_choice && matches!(expr, RuleExpression::Alternatives(_)) {
+                    self.choice(expr, exprs, follow);
+                }
+                exprs.iter().for_each(|expr| self.expr(expr, follow));
+            }
+            RuleExpression::Optional(inner) | RuleExpression::Repetition(inner, 0, Some(1)) => {
+                self.repetition(expr, inner, follow);
+                self.expr(inner, follow);
+            }
+            RuleExpression::Many(inner) | RuleExpression::OneOrMore(inner) | RuleExpression::Repetition(inner, ..) => {
+                let repeats = !matches!(expr, RuleExpression::Repetition(_, min, Some(max)) if min == max);
+                if repeats {
+                    self.repetition(expr, inner, follow);
+                }
+                let inner_follow = if repeats { self.analysis.first(inner).tokens.union(follow) } else { follow.clone() };
+                self.expr(inner, &inner_follow);
+            }
+            _ => (),
+        }
+    }
+
+    fn choice(&mut self, choice: &RuleExpression, exprs: &[RuleExpression], follow: &TokenSet) {
+        let rule_name = self.rule_name.to_string();
+        let firsts = exprs.iter().map(|expr| self.analysis.first(expr)).collect::<Vec<_>>();
+        let alternatives = self.locations.get(&ByAddress(choice)).copied();
+        let locations = |alternative: usize, with: usize| alternatives.map(|alternatives| (alternatives[alternative], alternatives[with]));
+
+        for (alternative, first) in firsts.iter().enumerate() {
+            for (with, earlier) in firsts[..alternative].iter().enumerate() {
+                let terminals = shared_terminals(&earlier.tokens, &first.tokens);
+                if !terminals.is_empty() {
+                    self.conflicts.push(GrammarConflict::FirstFirst { 
+                        rule_name: rule_name.clone(), alternative, with, terminals, locations: locations(alternative, with) 
+                    });
+                }
+                if earlier.nullable && first.nullable {
+                    self.conflicts.push(GrammarConflict::BothNullable { rule_name: rule_name.clone(), alternative, with, locations: locations(alternative, with) });
+                }
+            }
+
+            if first.nullable {
+                for (with, other) in firsts.iter().enumerate().filter(|(with, _)| *with != alternative) {
+                    let terminals = shared_terminals(&other.tokens, follow);
+                    if !terminals.is_empty() {
+                        self.conflicts.push(GrammarConflict::FirstFollow { 
+                            rule_name: rule_name.clone(), alternative, with, terminals, locations: locations(alternative, with) 
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Repetitions of something that can match nothing are already a GrammarWarning, so only the tokens are checked.
+    fn repetition(&mut self, expr: &RuleExpression, inner: &RuleExpression, follow: &TokenSet) {
+        let terminals = shared_terminals(&self.analysis.first(inner).tokens, follow);
+        if !terminals.is_empty() {
+            let location = self.locations.get(&ByAddress(expr)).map(|locations| locations[0]);
+            self.conflicts.push(GrammarConflict::RepetitionFollow { rule_name: self.rule_name.to_string(), terminals, location });
+        }
+    }
+}
+
+// What both sets could match, as written. A wildcard could match anything in the other set.
+fn shared_terminals(a: &TokenSet, b: &TokenSet) -> Vec<String> {
+    let has_wildcard = |set: &TokenSet| set.tokens.contains(&RuleExpression::Wildcard);
+    let mut shared = a.tokens.iter().filter(|expr| b.tokens.contains(expr)).map(describe_expression).collect::<Vec<_>>();
+    if has_wildcard(a) {
+        shared.extend(b.tokens.iter().map(describe_expression));
+    }
+    if has_wildcard(b) {
+        shared.extend(a.tokens.iter().map(describe_expression));
+    }
+    if a.end && b.end {
+        shared.push("$".to_string());
+    }
+
+    shared.into_iter().sorted().dedup().collect()
+}
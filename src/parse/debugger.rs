@@ -0,0 +1,110 @@
+/* Stepping through a parse a token at a time, to see what the parser makes of the input
+ * as it goes. This is built on the Earley parser's chart, like streaming is: the set for
+ * each token index holds every production that could be part of a parse there, so it is
+ * the whole frontier of the parse at that point, rather than the one path a backtracking
+ * parser happens to be on. */
+
+use super::{AmbiguityPolicy, CharToken, Parser, ParseError, SyntaxTree, Token};
+use super::backtracking_parser::ParseState;
+use super::earley_parser::Chart;
+
+
+/* Public Interface */
+
+/* A parse that moves forward one token each time step() is called. Start one with
+ * Parser::debug_parse, and look at where it is with frontier().
+ *
+ * Like streaming, it always uses the Earley algorithm, whatever set_algorithm says. But
+ * all of the input is there from the start, so lookaheads work. Errors have the index of
+ * the token they are at, but no line and column, even for strings. */
+pub struct ParseDebugger<'p, T: Token> {
+    parser: &'p Parser<T>,
+    chart: Chart<'p, T>,
+    tokens: Vec<T>,
+    index: usize,  // The set that has been processed last. Tokens before it have been matched.
+}
+
+/* Where a ParseDebugger is up to. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frontier {
+    pub index: usize,  // The next token to match.
+    pub rules: Vec<(String, usize)>,  // The rules being parsed and the index each started at, innermost first.
+    pub items: Vec<(String, usize)>,  // Productions like `Sum → Sum • "+" Num`, and the index each started at.
+    pub expected: Vec<String>,  // What the next token could be, as errors describe it. "$" is the end of the input.
+}
+
+impl<T: Token> Parser<T> {
+    pub fn debug_parse(&self, tokens: &[T], start_rule: &str) -> Result<ParseDebugger<'_, T>, ParseError> {
+        let mut debugger = ParseDebugger { parser: self, chart: Chart::new(self, start_rule)?, tokens: tokens.to_vec(), index: 0 };
+        debugger.process()?;
+        Ok(debugger)
+    }
+}
+
+impl Parser<CharToken> {
+    pub fn debug_parse_string(&self, input: &str, start_rule: &str) -> Result<ParseDebugger<'_, CharToken>, ParseError> {
+        self.debug_parse(&super::string_to_tokens(input), start_rule)
+            .map_err(|err| super::locate_error(err, input))
+    }
+}
+
+impl<T: Token> ParseDebugger<'_, T> {
+    /* Matches the next token. Returns false if there are none left. Fails if no parse
+     * can get past it, and stays where it is. An unanchored parser doesn't fail here,
+     * since it can settle for the tokens before it. */
+    pub fn step(&mut self) -> Result<bool, ParseError> {
+        if self.is_done() {
+            return Ok(false);
+        }
+        if self.parser.anchored && !self.chart.can_continue_past(self.index) {
+            return Err(self.chart.failure_info.clone().into_error(self.parser, &self.tokens));
+        }
+
+        self.index += 1;
+        self.process()?;
+        Ok(true)
+    }
+
+    pub fn frontier(&self) -> Frontier {
+        Frontier {
+            index: self.index,
+            rules: self.chart.rules_in_progress(self.index).into_iter()
+                .map(|(rule_name, start)| (rule_name.to_string(), start))
+                .collect(),
+            items: self.chart.describe_set(self.index),
+            expected: self.chart.expected(self.index).into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    /* True once every token has been matched. */
+    pub fn is_done(&self) -> bool {
+        self.index == self.tokens.len()
+    }
+
+    pub fn tokens(&self) -> &[T] {
+        &self.tokens
+    }
+
+    /* Runs the rest of the parse, and returns the tree as parse_tokens would. */
+    pub fn finish(mut self) -> Result<SyntaxTree<T>, ParseError> {
+        while !self.is_done() {
+            self.index += 1;
+            self.process()?;
+        }
+
+        let ParseDebugger { parser, chart, tokens, .. } = self;
+        let want_all = !matches!(parser.ambiguity_policy, AmbiguityPolicy::FirstMatch);
+        parser.resolve_ambiguity(chart.trees(&tokens, want_all, parser.anchored)?)
+    }
+}
+
+
+/* Private Implementation */
+
+impl<T: Token> ParseDebugger<'_, T> {
+    // Lookaheads only look at the tokens, so a new ParseState each step is fine. The chart remembers what they found.
+    fn process(&mut self) -> Result<(), ParseError> {
+        let mut lookahead_state = ParseState::new(self.parser, &self.tokens);
+        self.chart.process(self.index, &self.tokens, Some(&mut lookahead_state))
+    }
+}
@@ -0,0 +1,1090 @@
+/* An Earley parser (see Earley, "An Efficient Context-Free Parsing Algorithm").
+ *
+ * Rule expressions are first flattened into plain productions. Subexpressions like
+ * groups and quantifiers get their own nameless nonterminals, which are left out
+ * of the final tree so that it comes out the same as from the backtracking parser.
+ * Earley parsing handles any context free grammar, so left recursion and ambiguity
+ * need no special treatment. Lookaheads aren't context free, so they are handed off
+ * to the backtracking parser. */
+
+use crate::{Token, define::{RuleExpression, describe_expression}};
+use super::{Parser, ParseError, SyntaxTree};
+use super::backtracking_parser::{Arena, Children, FailureCache, IntermediateSyntaxTree, ListId, NodeId, ParseState, single_token_matches};
+use super::budget::Deadline;
+use super::chart_dump::{ChartDump, DumpItem, DumpLink};
+use super::progress::ProgressReporter;
+use super::stats::StatsRecorder;
+use super::trace;
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use by_address::ByAddress;
+use itertools::Itertools;
+
+
+pub fn earley_parse<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, anchored: bool, deadline: &Deadline, 
+        stats: &StatsRecorder) -> Result<SyntaxTree<T>, ParseError> {
+    let mut trees = complete_parses(parser, tokens, start_rule, false, anchored, deadline, stats)?;
+    Ok(trees.swap_remove(0))
+}
+
+// Returns every distinct syntax tree that covers the whole input.
+pub fn earley_parse_all<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, anchored: bool, deadline: &Deadline, 
+        stats: &StatsRecorder) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+    complete_parses(parser, tokens, start_rule, true, anchored, deadline, stats)
+}
+
+// Like the backtracking parser's version, never returns an empty vector.
+// The deadline is checked between sets, and by lookaheads, which use the backtracking parser.
+fn complete_parses<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, want_all: bool, anchored: bool, deadline: &Deadline,
+        stats: &StatsRecorder) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+    let _span = trace::parse_span("earley", start_rule, tokens.len());
+    let mut chart = Chart::new(parser, start_rule)?;
+    let mut lookahead_state = ParseState::new(parser, tokens).with_deadline(deadline);
+    let mut progress = ProgressReporter::new(parser, tokens.len());
+    for index in 0..=tokens.len() {
+        progress.reached(index, || chart.sets.iter().map(|set| set.items.len()).sum());
+        if deadline.passed() {
+            return Err(ParseError::Cancelled { index, location: None });
+        }
+        chart.process(index, tokens, Some(&mut lookahead_state))?;
+
+        let width = chart.sets.get(index).map_or(0, |set| set.items.len());
+        stats.count(|stats| {
+            stats.continuations += width;
+            stats.peak_width = stats.peak_width.max(width);
+        });
+    }
+
+    chart.trees(tokens, want_all, anchored)
+}
+
+// Like complete_parses, but keeps the chart instead of building trees, parse or no parse.
+pub(super) fn earley_chart<'a, T: Token>(parser: &'a Parser<T>, tokens: &[T], start_rule: &str) -> Result<Chart<'a, T>, ParseError> {
+    let mut chart = Chart::new(parser, start_rule)?;
+    let mut lookahead_state = ParseState::new(parser, tokens);
+    for index in 0..=tokens.len() {
+        chart.process(index, tokens, Some(&mut lookahead_state))?;
+    }
+
+    Ok(chart)
+}
+
+
+const ORDERED_CHOICE_ERROR: &str = "Ordered choice is not supported by the Earley algorithm";
+
+
+/* Grammar */
+
+#[derive(Clone, Copy)]
+enum Symbol<'a> {
+    Token (&'a RuleExpression),  // Anything that matches exactly one token.
+    Nonterminal (usize),
+    EndOfInput,
+    Lookahead (&'a RuleExpression),  // The whole lookahead expression, not just its inner expression.
+    External (&'a str),  // Matches however many tokens the scanner says.
+}
+
+struct Production<'a> {
+    nonterminal: usize,
+    symbols: Vec<Symbol<'a>>,
+}
+
+struct Nonterminal<'a> {
+    rule_name: Option<&'a super::Symbol>,  // None for nonterminals made up for subexpressions.
+    productions: Vec<usize>,
+}
+
+struct Grammar<'a> {
+    nonterminals: Vec<Nonterminal<'a>>,
+    productions: Vec<Production<'a>>,
+    rule_ids: HashMap<&'a str, usize>,
+}
+
+impl<'a> Grammar<'a> {
+    fn new<T: Token>(parser: &'a Parser<T>) -> Result<Grammar<'a>, ParseError> {
+        let mut grammar = Grammar { nonterminals: vec![], productions: vec![], rule_ids: HashMap::new() };
+
+        // In a fixed order, so that the same rules always get the same numbering (see ChartState).
+        for rule_name in parser.rules.keys().sorted() {
+            let id = grammar.add_nonterminal(Some(rule_name));
+            grammar.rule_ids.insert(rule_name.as_str(), id);
+        }
+
+        for (rule_name, expr) in parser.rules.iter().sorted_by_key(|(rule_name, _)| *rule_name) {
+            let id = grammar.rule_ids[rule_name.as_str()];
+            for symbols in grammar.alternatives(expr)? {
+                grammar.add_production(id, symbols);
+            }
+        }
+
+        Ok(grammar)
+    }
+
+    fn add_nonterminal(&mut self, rule_name: Option<&'a super::Symbol>) -> usize {
+        self.nonterminals.push(Nonterminal { rule_name, productions: vec![] });
+        self.nonterminals.len() - 1
+    }
+
+    fn add_production(&mut self, nonterminal: usize, symbols: Vec<Symbol<'a>>) {
+        self.nonterminals[nonterminal].productions.push(self.productions.len());
+        self.productions.push(Production { nonterminal, symbols });
+    }
+
+    // Each alternative of the expression, as a sequence of symbols.
+    fn alternatives(&mut self, expr: &'a RuleExpression) -> Result<Vec<Vec<Symbol<'a>>>, ParseError> {
+        match expr {
+            RuleExpression::Alternatives(exprs) => {
+                let mut alternatives = vec![];
+                for expr in exprs {
+                    alternatives.append(&mut self.alternatives(expr)?);
+                }
+                Ok(alternatives)
+            }
+            _ => Ok(vec![self.sequence(expr)?]),
+        }
+    }
+
+    fn sequence(&mut self, expr: &'a RuleExpression) -> Result<Vec<Symbol<'a>>, ParseError> {
+        match expr {
+            RuleExpression::Concatenation(exprs) => {
+                let mut symbols = vec![];
+                for expr in exprs {
+                    symbols.append(&mut self.sequence(expr)?);
+                }
+                Ok(symbols)
+            }
+            _ => Ok(vec![self.symbol(expr)?]),
+        }
+    }
+
+    fn symbol(&mut self, expr: &'a RuleExpression) -> Result<Symbol<'a>, ParseError> {
+        let symbol = match expr {
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_)
+            | RuleExpression::Wildcard | RuleExpression::Negation(..) => Symbol::Token(expr),
+            RuleExpression::EndOfInput => Symbol::EndOfInput,
+            RuleExpression::External(rule_name) => Symbol::External(rule_name),
+            RuleExpression::PositiveLookahead(_) | RuleExpression::NegativeLookahead(..) => Symbol::Lookahead(expr),
+            RuleExpression::RuleName(rule_name) => Symbol::Nonterminal(*self.rule_ids.get(rule_name.as_str())
+                .ok_or_else(|| ParseError::UnknownRule { 
+                    rule_name: rule_name.to_string(), 
+                    suggestion: super::closest_name(rule_name, self.rule_ids.keys().copied()).map(ToString::to_string),
+                })?),
+            RuleExpression::Concatenation(_) | RuleExpression::Alternatives(_) => {
+                let id = self.add_nonterminal(None);
+                for symbols in self.alternatives(expr)? {
+                    self.add_production(id, symbols);
+                }
+                Symbol::Nonterminal(id)
+            }
+            RuleExpression::Optional(inner) => {
+                let id = self.add_nonterminal(None);
+                let inner = self.sequence(inner)?;
+                self.add_production(id, vec![]);
+                self.add_production(id, inner);
+                Symbol::Nonterminal(id)
+            }
+            RuleExpression::Many(inner) => self.repeat(inner, 0, None)?,
+            RuleExpression::OneOrMore(inner) => self.repeat(inner, 1, None)?,
+            RuleExpression::Repetition(inner, min, max) => self.repeat(inner, *min, *max)?,
+            RuleExpression::OrderedAlternatives(_) => return Err(ORDERED_CHOICE_ERROR.into()),
+        };
+
+        Ok(symbol)
+    }
+
+    /* Unbounded repetition is left recursive, since Earley parsers handle that best:
+     * Many -> Many inner | (nothing). Bounded repetition is a chain of optionals. */
+    fn repeat(&mut self, inner: &'a RuleExpression, min: usize, max: Option<usize>) -> Result<Symbol<'a>, ParseError> {
+        let inner = self.sequence(inner)?;
+
+        let tail = match max {
+            None => {
+                let id = self.add_nonterminal(None);
+                self.add_production(id, vec![]);
+                self.add_production(id, std::iter::once(Symbol::Nonterminal(id)).chain(inner.iter().copied()).collect());
+                vec![Symbol::Nonterminal(id)]
+            }
+            Some(max) => {
+                let mut tail = vec![];
+                for _ in min..max {
+                    let id = self.add_nonterminal(None);
+                    self.add_production(id, vec![]);
+                    self.add_production(id, inner.iter().copied().chain(tail).collect());
+                    tail = vec![Symbol::Nonterminal(id)];
+                }
+                tail
+            }
+        };
+
+        let id = self.add_nonterminal(None);
+        let symbols = std::iter::repeat_n(inner.iter().copied(), min).flatten().chain(tail).collect();
+        self.add_production(id, symbols);
+        Ok(Symbol::Nonterminal(id))
+    }
+}
+
+
+/* Recognizer */
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Item {
+    production: usize,
+    dot: usize,  // How many of the production's symbols have been matched.
+    origin: usize,  // The token index the production started at.
+}
+
+/* The items at one token index. There is a set for every token, so they are kept small:
+ * most only hold a few items, which are simply searched, and only big ones get indexed. */
+#[derive(Default)]
+struct Set {
+    items: Vec<Item>,
+    starts: Vec<Starts>,  // For each item.
+    waiting: Vec<(usize, Item)>,  // Items waiting on each nonterminal, sorted by nonterminal, then in the order they came.
+    positions: HashMap<Item, usize>,  // Where each item is in items, once there are more than BIG_SET of them.
+}
+
+const BIG_SET: usize = 16;
+
+impl Set {
+    fn position(&self, item: Item) -> Option<usize> {
+        match self.items.len() > BIG_SET {
+            true => self.positions.get(&item).copied(),
+            false => self.items.iter().position(|&other| other == item),
+        }
+    }
+
+    // Every index the item's last symbol started at, if the item is here.
+    fn starts(&self, item: Item) -> Option<&[usize]> {
+        self.position(item).map(|position| self.starts[position].as_slice())
+    }
+
+    // Returns where the item's last symbol started, for the caller to add to.
+    fn add(&mut self, item: Item) -> &mut Starts {
+        let position = match self.position(item) {
+            Some(position) => position,
+            None => {
+                self.items.push(item);
+                self.starts.push(Starts::Many(vec![]));
+                match self.items.len() {
+                    length if length == BIG_SET + 1 => {
+                        self.positions = self.items.iter().enumerate().map(|(position, &item)| (item, position)).collect();
+                    }
+                    length if length > BIG_SET => {
+                        self.positions.insert(item, length - 1);
+                    }
+                    _ => (),
+                }
+                self.items.len() - 1
+            }
+        };
+        &mut self.starts[position]
+    }
+
+    fn wait(&mut self, nonterminal: usize, item: Item) {
+        let position = self.waiting.partition_point(|&(other, _)| other <= nonterminal);
+        self.waiting.insert(position, (nonterminal, item));
+    }
+
+    fn waiting_on(&self, nonterminal: usize) -> &[(usize, Item)] {
+        let from = self.waiting.partition_point(|&(other, _)| other < nonterminal);
+        let to = self.waiting.partition_point(|&(other, _)| other <= nonterminal);
+        &self.waiting[from..to]
+    }
+}
+
+// Where an item's last symbol started. That's nearly always one place, which needs no allocation.
+enum Starts {
+    One(usize),
+    Many(Vec<usize>),  // Empty for items at the start of their production.
+}
+
+impl Starts {
+    fn push(&mut self, start: usize) {
+        match self {
+            Starts::Many(starts) if starts.is_empty() => *self = Starts::One(start),
+            Starts::One(first) => *self = Starts::Many(vec![*first, start]),
+            Starts::Many(starts) => starts.push(start),
+        }
+    }
+
+    fn as_slice(&self) -> &[usize] {
+        match self {
+            Starts::One(start) => std::slice::from_ref(start),
+            Starts::Many(starts) => starts,
+        }
+    }
+}
+
+/* The chart doesn't hold on to the tokens, and only needs the ones up to the set it
+ * is processing, so it can be filled in while the tokens are still arriving. */
+pub(super) struct Chart<'a, T: Token> {
+    grammar: Grammar<'a>,
+    parser: &'a Parser<T>,
+    start: usize,
+    sets: Vec<Set>,  // One per token index, plus one for the end.
+    completed: HashSet<(usize, usize)>,  // The nonterminals completed in the set being processed, with where they started.
+    ends: Vec<usize>,  // Every index the start rule matched up to from the beginning, in order.
+    lookaheads: HashMap<(ByAddress<&'a RuleExpression>, usize), bool>,
+    pub(super) failure_info: FailureCache<'a>,
+}
+
+impl<'a, T: Token> Chart<'a, T> {
+    pub(super) fn new(parser: &'a Parser<T>, start_rule: &str) -> Result<Chart<'a, T>, ParseError> {
+        if parser.ordered_choice {
+            return Err(ORDERED_CHOICE_ERROR.into());
+        }
+
+        let grammar = Grammar::new(parser)?;
+        let start = *grammar.rule_ids.get(start_rule)
+            .ok_or_else(|| parser.unknown_rule(start_rule))?;
+
+        let mut chart = Chart {
+            grammar,
+            parser,
+            start,
+            sets: vec![],
+            completed: HashSet::new(),
+            ends: vec![],
+            lookaheads: HashMap::new(),
+            failure_info: FailureCache::new(),
+        };
+
+        for i in 0..chart.grammar.nonterminals[start].productions.len() {
+            let production = chart.grammar.nonterminals[start].productions[i];
+            chart.add(0, Item { production, dot: 0, origin: 0 });
+        }
+
+        Ok(chart)
+    }
+
+    // Returns where the item's last symbol started, for the caller to add to.
+    fn add(&mut self, index: usize, item: Item) -> &mut Starts {
+        if self.sets.len() <= index {
+            self.sets.resize_with(index + 1, Set::default);
+        }
+        self.sets[index].add(item)
+    }
+
+    /* Moves the item from the set at `from` past its next symbol, which ends at `index`.
+     * Each item only moves from each set once, so there is no need to check for repeats. */
+    fn advance(&mut self, from: usize, index: usize, item: Item) {
+        self.add(index, Item { dot: item.dot + 1, ..item }).push(from);
+    }
+
+    /* Processes the set at `index`, which scans into the next one. Every earlier set must
+     * have been processed already. `tokens` has to reach past `index`, or end exactly at
+     * it if the input is over. Lookaheads need all of the input, so they can only be
+     * checked if there is a `lookahead_state`. */
+    pub(super) fn process(&mut self, index: usize, tokens: &[T], mut lookahead_state: Option<&mut ParseState<'a, '_, T>>)
+            -> Result<(), ParseError> {
+        self.completed.clear();
+
+        let mut next = 0;
+        while next < self.sets.get(index).map_or(0, |set| set.items.len()) {
+            let item = self.sets[index].items[next];
+            next += 1;
+
+            let production = &self.grammar.productions[item.production];
+            let Some(&symbol) = production.symbols.get(item.dot) else {
+                self.complete(index, item, tokens)?;
+                continue;
+            };
+
+            match symbol {
+                Symbol::Token(expr) => {
+                    if index < tokens.len() && single_token_matches(self.parser, expr, &tokens[index])? {
+                        self.advance(index, index + 1, item);
+                    }
+                    else {
+                        self.log_failure(index, item, describe_token(expr));
+                    }
+                }
+                Symbol::EndOfInput => {
+                    if index == tokens.len() {
+                        self.advance(index, index, item);
+                    }
+                    else {
+                        self.log_failure(index, item, "$");
+                    }
+                }
+                Symbol::Lookahead(expr) => {
+                    let Some(lookahead_state) = lookahead_state.as_deref_mut() else {
+                        return Err("Lookaheads need the whole input, so they can't be used while streaming".into());
+                    };
+
+                    if self.lookahead_passes(index, expr, lookahead_state)? {
+                        self.advance(index, index, item);
+                    }
+                    else if let RuleExpression::NegativeLookahead(_, description) = expr {
+                        self.log_failure(index, item, description);
+                    }
+                }
+                Symbol::External(rule_name) => {
+                    if lookahead_state.is_none() {
+                        return Err("External rules need the whole input, so they can't be used while streaming".into());
+                    }
+
+                    match self.parser.scan(rule_name, tokens, index)? {
+                        Some(length) => self.advance(index, index + length, item),
+                        None => self.log_failure(index, item, rule_name),
+                    }
+                }
+                Symbol::Nonterminal(nonterminal) => {
+                    self.sets[index].wait(nonterminal, item);
+
+                    for i in 0..self.grammar.nonterminals[nonterminal].productions.len() {
+                        let production = self.grammar.nonterminals[nonterminal].productions[i];
+                        self.add(index, Item { production, dot: 0, origin: index });
+                    }
+
+                    // The nonterminal may have already matched nothing here, before this item showed up.
+                    if self.completed.contains(&(nonterminal, index)) {
+                        self.advance(index, index, item);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // False once no parse can get past the set at `index`, whatever tokens come next.
+    pub(super) fn can_continue_past(&self, index: usize) -> bool {
+        self.sets.get(index + 1).is_some_and(|set| !set.items.is_empty())
+    }
+
+    /* Every rule that the items in the set at `index` are part of, with the index it
+     * started at, innermost (latest starting) first. Made up nonterminals are looked
+     * through to the rules they belong to. */
+    pub(super) fn rules_in_progress(&self, index: usize) -> Vec<(&'a str, usize)> {
+        let mut pending = self.sets.get(index).map(|set| set.items.clone()).unwrap_or_default();
+        let mut seen = pending.iter().copied().collect::<HashSet<Item>>();
+        let mut rules = vec![];
+
+        while let Some(item) = pending.pop() {
+            let nonterminal = self.grammar.productions[item.production].nonterminal;
+            if let Some(rule_name) = self.grammar.nonterminals[nonterminal].rule_name {
+                rules.push((rule_name.as_str(), item.origin));
+            }
+
+            let parents = self.sets.get(item.origin).map(|set| set.waiting_on(nonterminal)).unwrap_or_default();
+            for &(_, parent) in parents {
+                if seen.insert(parent) {
+                    pending.push(parent);
+                }
+            }
+        }
+
+        rules.sort_by(|(left_name, left_start), (right_name, right_start)| 
+            right_start.cmp(left_start).then(left_name.cmp(right_name)));
+        rules.dedup();
+        rules
+    }
+
+    // The dotted productions in the set at `index`, each with the index it started at.
+    pub(super) fn describe_set(&self, index: usize) -> Vec<(String, usize)> {
+        self.sets.get(index).into_iter().flat_map(|set| &set.items)
+            .map(|&item| (self.describe_item(item), item.origin))
+            .collect()
+    }
+
+    /* The tokens that the items in the set at `index` could match next, as they are
+     * described in errors, sorted. Lookaheads don't match tokens, so they are left out. */
+    pub(super) fn expected(&self, index: usize) -> Vec<&'a str> {
+        self.sets.get(index).into_iter().flat_map(|set| &set.items)
+            .filter_map(|item| match self.grammar.productions[item.production].symbols.get(item.dot)? {
+                Symbol::Token(expr) => Some(describe_token(expr)),
+                Symbol::EndOfInput => Some("$"),
+                Symbol::External(rule_name) => Some(*rule_name),
+                Symbol::Lookahead(_) | Symbol::Nonterminal(_) => None,
+            })
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
+    /* Builds the trees once every set has been processed. Different derivations can make
+     * the same tree, since groups and quantifiers leave no trace, so only distinct ones
+     * are returned. */
+    pub(super) fn trees(self, tokens: &[T], want_all: bool, anchored: bool) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+        let end = if anchored { Some(tokens.len()).filter(|end| self.ends.contains(end)) } else { self.ends.last().copied() };
+
+        let Some(end) = end else {
+            return Err(self.failure_info.into_error(self.parser, tokens));
+        };
+
+        let mut builder = TreeBuilder { 
+            chart: &self, 
+            tokens, 
+            want_all, 
+            arena: Arena::new(), 
+            in_progress: vec![], 
+            cut: usize::MAX, 
+            memo: HashMap::new(),
+            token_nodes: HashMap::new(),
+            singles: HashMap::new(),
+            joins: HashMap::new(),
+        };
+        let roots = builder.build((self.start, 0, end)).iter()
+            .map(|&children| builder.arena.flatten(children)[0])
+            .collect::<Vec<_>>();
+
+        if roots.is_empty() {
+            return Err("Earley parser recognized the input, but found no tree".into());
+        }
+
+        // The chart is as big as the trees, so let it go before they are built.
+        let TreeBuilder { arena, .. } = builder;
+        drop(self);
+        Ok(arena.distinct_trees(roots))
+    }
+
+    /* Every item, described, and how they lead to each other. See ChartDump. */
+    pub(super) fn dump(&self) -> ChartDump {
+        let positions = self.sets.iter().enumerate()
+            .flat_map(|(index, set)| set.items.iter().enumerate().map(move |(i, &item)| ((index, item), i)))
+            .collect::<HashMap<_, _>>();
+        let all_items = self.sets.iter().flat_map(|set| &set.items).copied().collect::<HashSet<_>>();
+
+        let mut sets = vec![];
+        let mut links = vec![];
+        for (index, set) in self.sets.iter().enumerate() {
+            let mut dump_set = vec![];
+            for (i, (&item, starts)) in set.items.iter().zip(&set.starts).enumerate() {
+                let symbols = &self.grammar.productions[item.production].symbols;
+                let complete = item.dot == symbols.len();
+                let dead = !complete && !all_items.contains(&Item { dot: item.dot + 1, ..item });
+                dump_set.push(DumpItem { text: self.describe_item(item), origin: item.origin, complete, dead });
+
+                if let Some(&Symbol::Nonterminal(nonterminal)) = symbols.get(item.dot) {
+                    for &production in &self.grammar.nonterminals[nonterminal].productions {
+                        if let Some(&to) = positions.get(&(index, Item { production, dot: 0, origin: index })) {
+                            links.push(DumpLink { from: (index, i), to: (index, to), predict: true });
+                        }
+                    }
+                }
+
+                // Where the item was before it matched its last symbol, which may have been more than one place.
+                if item.dot > 0 {
+                    let previous = Item { dot: item.dot - 1, ..item };
+                    for &start in starts.as_slice().iter().sorted() {
+                        if let Some(&from) = positions.get(&(start, previous)) {
+                            links.push(DumpLink { from: (start, from), to: (index, i), predict: false });
+                        }
+                    }
+                }
+            }
+            sets.push(dump_set);
+        }
+
+        ChartDump { sets, links }
+    }
+
+    // Like `Sum → Sum • "+" Num`.
+    fn describe_item(&self, item: Item) -> String {
+        let production = &self.grammar.productions[item.production];
+        let mut text = format!("{} →", self.nonterminal_name(production.nonterminal));
+        for (i, symbol) in production.symbols.iter().enumerate() {
+            if i == item.dot {
+                text += " •";
+            }
+            text += " ";
+            text += &match symbol {
+                Symbol::Token(expr) | Symbol::Lookahead(expr) => describe_expression(expr),
+                Symbol::Nonterminal(nonterminal) => self.nonterminal_name(*nonterminal),
+                Symbol::EndOfInput => "$".to_string(),
+                Symbol::External(rule_name) => rule_name.to_string(),
+            };
+        }
+        if item.dot == production.symbols.len() {
+            text += " •";
+        }
+        text
+    }
+
+    fn nonterminal_name(&self, nonterminal: usize) -> String {
+        match self.grammar.nonterminals[nonterminal].rule_name {
+            Some(rule_name) => rule_name.to_string(),
+            None => format!("#{nonterminal}"),
+        }
+    }
+
+    pub(super) fn save(&self) -> ChartState {
+        ChartState {
+            sets: self.sets.iter()
+                .map(|set| set.items.iter().zip(&set.starts)
+                    .map(|(item, starts)| ([item.production, item.dot, item.origin], starts.as_slice().to_vec()))
+                    .collect())
+                .collect(),
+            ends: self.ends.clone(),
+            failure_index: self.failure_info.index(),
+            failure_context: self.failure_info.context().iter().map(ToString::to_string).collect(),
+            failures: self.failure_info.failures()
+                .map(|(failure, rule_name)| (failure.to_string(), rule_name.map(ToString::to_string)))
+                .sorted()
+                .collect(),
+        }
+    }
+
+    /* Rebuilds a chart from a saved state. `processed` is how many sets had been processed
+     * when it was saved. Fails if the state can't have come from this grammar. */
+    pub(super) fn restore(parser: &'a Parser<T>, start_rule: &str, state: ChartState, processed: usize) 
+            -> Result<Chart<'a, T>, ParseError> {
+        let mut chart = Chart::new(parser, start_rule)?;
+        chart.sets.clear();
+
+        let mismatch = || ParseError::from("Saved parse state does not match this parser");
+        for (index, set) in state.sets.into_iter().enumerate() {
+            for ([production, dot, origin], starts) in set {
+                let symbols = &chart.grammar.productions.get(production).ok_or_else(mismatch)?.symbols;
+                if dot > symbols.len() || origin > index || (dot == 0) != starts.is_empty() 
+                        || starts.iter().any(|start| !(origin..=index).contains(start)) {
+                    return Err(mismatch());
+                }
+
+                let waiting_on = match symbols.get(dot) {
+                    Some(&Symbol::Nonterminal(nonterminal)) if index < processed => Some(nonterminal),
+                    _ => None,
+                };
+
+                let item = Item { production, dot, origin };
+                let added = chart.add(index, item);
+                starts.into_iter().for_each(|start| added.push(start));
+                if let Some(nonterminal) = waiting_on {
+                    chart.sets[index].wait(nonterminal, item);
+                }
+            }
+        }
+
+        if !state.ends.iter().tuple_windows().all(|(before, after)| before < after) || state.ends.last() > Some(&processed) {
+            return Err(mismatch());
+        }
+        chart.ends = state.ends;
+
+        // The failure log borrows its strings from the grammar, so look them up there.
+        let descriptions = chart.grammar.productions.iter()
+            .flat_map(|production| production.symbols.iter())
+            .filter_map(|symbol| match symbol {
+                Symbol::Token(expr) => Some(describe_token(expr)),
+                Symbol::EndOfInput => Some("$"),
+                Symbol::External(rule_name) => Some(*rule_name),
+                Symbol::Lookahead(RuleExpression::NegativeLookahead(_, description)) => Some(description.as_str()),
+                _ => None,
+            })
+            .chain(chart.grammar.nonterminals.iter().filter_map(|nonterminal| nonterminal.rule_name).map(super::Symbol::as_str))
+            .collect::<HashSet<&'a str>>();
+        let rule_names = chart.grammar.nonterminals.iter()
+            .filter_map(|nonterminal| nonterminal.rule_name)
+            .map(super::Symbol::as_str)
+            .collect::<HashSet<&'a str>>();
+        let context = state.failure_context.iter()
+            .map(|rule_name| rule_names.get(rule_name.as_str()).copied().ok_or_else(mismatch))
+            .collect::<Result<Vec<&'a str>, ParseError>>()?;
+        for (failure, rule_name) in state.failures {
+            let rule_name = match rule_name {
+                Some(rule_name) => Some(*rule_names.get(rule_name.as_str()).ok_or_else(mismatch)?),
+                None => None,
+            };
+            let failure = descriptions.get(failure.as_str()).ok_or_else(mismatch)?;
+            chart.failure_info.log_in_context(state.failure_index, failure, || (rule_name, context.clone()));
+        }
+
+        Ok(chart)
+    }
+
+    /* Logs a failure at `index` by `item`, along with the rules the item is part of,
+     * and the outermost of those that started there, other than the start rule. Where a
+     * rule is used in more than one place, this only follows the first. */
+    fn log_failure(&mut self, index: usize, item: Item, expected: &'a str) {
+        let (grammar, sets, start) = (&self.grammar, &self.sets, self.start);
+        self.failure_info.log_in_context(index, expected, || {
+            let mut lifted = None;
+            let mut context = vec![];
+            let mut item = item;
+            let mut seen = vec![];  // Only since the origin last changed, since going round a cycle never changes it.
+            while !seen.contains(&item) {
+                seen.push(item);
+                let nonterminal = grammar.productions[item.production].nonterminal;
+                if let Some(rule_name) = grammar.nonterminals[nonterminal].rule_name.map(super::Symbol::as_str) {
+                    context.push(rule_name);
+                    if item.origin == index && !(nonterminal == start && item.origin == 0) {
+                        lifted = Some(rule_name);
+                    }
+                }
+
+                match sets.get(item.origin).and_then(|set| set.waiting_on(nonterminal).first()) {
+                    Some(&(_, parent)) => {
+                        if parent.origin != item.origin {
+                            seen.clear();
+                        }
+                        item = parent;
+                    }
+                    None => break,
+                }
+            }
+
+            context.reverse();
+            (lifted, context)
+        });
+    }
+
+    fn complete(&mut self, index: usize, item: Item, tokens: &[T]) -> Result<(), ParseError> {
+        let nonterminal = self.grammar.productions[item.production].nonterminal;
+
+        if let Some(rule_name) = self.grammar.nonterminals[nonterminal].rule_name.map(super::Symbol::as_str) {
+            let predicates = self.parser.predicates.get(rule_name);
+            if !predicates.is_none_or(|predicates| predicates.iter().all(|predicate| predicate(tokens, item.origin..index))) {
+                self.log_failure(item.origin, item, rule_name);
+                return Ok(());
+            }
+        }
+
+        if !self.completed.insert((nonterminal, item.origin)) {
+            return Ok(());
+        }
+        if nonterminal == self.start && item.origin == 0 {
+            self.ends.push(index);
+        }
+
+        // Advancing never adds waiting items, so the list can't change under us.
+        for i in 0..self.sets[item.origin].waiting_on(nonterminal).len() {
+            let (_, waiting_item) = self.sets[item.origin].waiting_on(nonterminal)[i];
+            self.advance(item.origin, index, waiting_item);
+        }
+
+        Ok(())
+    }
+
+    fn lookahead_passes(&mut self, index: usize, expr: &'a RuleExpression, lookahead_state: &mut ParseState<'a, '_, T>) 
+            -> Result<bool, ParseError> {
+        if let Some(passes) = self.lookaheads.get(&(ByAddress(expr), index)) {
+            return Ok(*passes);
+        }
+
+        let passes = lookahead_state.matches_at(index, expr)?;
+        self.lookaheads.insert((ByAddress(expr), index), passes);
+        Ok(passes)
+    }
+}
+
+/* Everything in a chart, as plain numbers and strings. Productions and nonterminals are
+ * numbered in the same way for the same rules, even in another process. */
+pub(super) struct ChartState {
+    pub(super) sets: Vec<Vec<([usize; 3], Vec<usize>)>>,  // Items, as production, dot and origin, with where their last symbols started.
+    pub(super) ends: Vec<usize>,  // Where the start rule matched up to, in order.
+    pub(super) failure_index: usize,
+    pub(super) failure_context: Vec<String>,  // Rule names, outermost first.
+    pub(super) failures: Vec<(String, Option<String>)>,  // What was expected, and the rule it was expected as part of.
+}
+
+fn describe_token(expr: &RuleExpression) -> &str {
+    match expr {
+        RuleExpression::Terminal(term) => term,
+        RuleExpression::CharacterClass(class) => &class.source,
+        RuleExpression::Negation(_, description) => description,
+        _ => ".",
+    }
+}
+
+
+/* Tree Building
+ *
+ * Trees are put together in an arena, like the backtracking parser's, where a
+ * derivation is a list of joined subtrees rather than a vector of them. Sharing a
+ * derivation, or adding to one, is then one join instead of a copy. That matters for
+ * repetitions, which are chains of left recursive made up nonterminals, one link per
+ * match: the list from every link is only flattened once, into the rule node that
+ * holds it. Nonterminals can nest as deep as the input is long, so the ones being
+ * worked out are kept on a stack of their own, rather than on the call stack. */
+
+// Each entry is one way to match, as the list of subtrees it contributes to its parent.
+type Derivations = Rc<Vec<Children>>;
+
+type Key = (usize, usize, usize);  // A nonterminal, and the indexes it starts and ends at.
+
+struct TreeBuilder<'c, 'a, 't, T: Token> {
+    chart: &'c Chart<'a, T>,
+    tokens: &'t [T],
+    want_all: bool,  // Otherwise, stop at the first tree.
+    arena: Arena<'a, T>,
+    /* Breaks cycles, like a rule that can be just itself, by finding nothing for a key that
+     * is already being worked out further up. Keys are in the order they were started. */
+    in_progress: Vec<Frame>,
+    cut: usize,  // The earliest position in in_progress that a cycle was cut at, since it was last cleared.
+    memo: HashMap<Key, Derivations>,
+    /* When every tree is wanted, equal token nodes and lists are only made once, so that
+     * derivations that come out the same have the same list, and the repeats can be dropped
+     * as soon as they show up. Otherwise every made up nonterminal that matches the same way
+     * twice, like `(A | A)`, would double the number of derivations of everything above it. */
+    token_nodes: HashMap<(usize, Option<ByAddress<&'a RuleExpression>>), NodeId>,
+    singles: HashMap<NodeId, Children>,
+    joins: HashMap<(ListId, ListId), Children>,
+}
+
+// A nonterminal being worked out, one production, and one split of its tokens, at a time.
+struct Frame {
+    key: Key,
+    productions: usize,  // How many of the nonterminal's productions have been started.
+    splits: Option<Splits>,  // Of the latest production.
+    split: Vec<usize>,  // The split being worked on, if any.
+    children: Vec<Derivations>,  // For the symbols of the split, as far as they have been worked out.
+    derivations: Vec<Children>,
+}
+
+impl<'a, T: Token> TreeBuilder<'_, 'a, '_, T> {
+    fn build(&mut self, root: Key) -> Derivations {
+        self.start(root);
+        loop {
+            let grammar = &self.chart.grammar;
+            let frame = self.in_progress.last_mut().expect("Root is still being worked out");
+            let (nonterminal, start, end) = frame.key;
+            let productions = &grammar.nonterminals[nonterminal].productions;
+
+            if frame.split.is_empty() {
+                let found = !self.want_all && !frame.derivations.is_empty();
+                if let Some(split) = frame.splits.as_mut().and_then(Splits::next).filter(|_| !found) {
+                    frame.split = split;
+                }
+                else if let Some(&production) = productions.get(frame.productions).filter(|_| !found) {
+                    frame.splits = Some(Splits::new(self.chart, production, start, end));
+                    frame.productions += 1;
+                }
+                else if let Some(derivations) = self.finish() {
+                    return derivations;
+                }
+                continue;
+            }
+
+            let symbols = &grammar.productions[productions[frame.productions - 1]].symbols;
+            let Some(&symbol) = symbols.get(frame.children.len()) else {
+                let children = std::mem::take(&mut frame.children);
+                frame.split.clear();
+                let mut derivations = self.combine(&children);
+                self.in_progress.last_mut().expect("Frame is still there").derivations.append(&mut derivations);
+                continue;
+            };
+
+            let (from, to) = (frame.split[frame.children.len()], frame.split[frame.children.len() + 1]);
+            let derivations = match symbol {
+                Symbol::Token(expr) => Rc::new(vec![self.token(from, Some(expr))]),
+                Symbol::External(_) => {
+                    let mut children = None;
+                    for index in from..to {
+                        let token = self.token(index, None);
+                        children = self.join(children, token);
+                    }
+                    Rc::new(vec![children])
+                }
+                Symbol::EndOfInput | Symbol::Lookahead(_) => Rc::new(vec![None]),
+                Symbol::Nonterminal(nonterminal) => {
+                    let key = (nonterminal, from, to);
+                    if let Some(derivations) = self.memo.get(&key) {
+                        derivations.clone()
+                    }
+                    else if let Some(position) = self.position(key) {
+                        self.cut = self.cut.min(position);
+                        Rc::new(vec![])
+                    }
+                    else {
+                        self.start(key);
+                        continue;
+                    }
+                }
+            };
+            self.receive(derivations);
+        }
+    }
+
+    /* Where the key is in in_progress, if it is there. Each key's span is inside the span of
+     * the one below it, so only the keys on top with the same span as this one need checking. */
+    fn position(&self, key: Key) -> Option<usize> {
+        let (_, start, end) = key;
+        self.in_progress.iter().enumerate().rev()
+            .take_while(|(_, frame)| (frame.key.1, frame.key.2) == (start, end))
+            .find(|(_, frame)| frame.key == key)
+            .map(|(position, _)| position)
+    }
+
+    fn start(&mut self, key: Key) {
+        self.in_progress.push(Frame { key, productions: 0, splits: None, split: vec![], children: vec![], derivations: vec![] });
+    }
+
+    // Hands the derivations of the next symbol to the frame on top, which gives up on its split if there are none.
+    fn receive(&mut self, derivations: Derivations) {
+        let frame = self.in_progress.last_mut().expect("Something is waiting on the derivations");
+        if derivations.is_empty() {
+            frame.split.clear();
+            frame.children.clear();
+        }
+        else {
+            frame.children.push(derivations);
+        }
+    }
+
+    // Ends the frame on top, and returns its derivations if it was the last one.
+    fn finish(&mut self) -> Option<Derivations> {
+        let frame = self.in_progress.pop().expect("Frame is still there");
+        let position = self.in_progress.len();
+
+        let (nonterminal, start, end) = frame.key;
+        let rule_name = self.chart.grammar.nonterminals[nonterminal].rule_name;
+        let mut derivations = frame.derivations;
+        if self.want_all {
+            derivations = derivations.into_iter().unique().collect();
+        }
+        if let Some(rule_name) = rule_name {
+            for children in &mut derivations {
+                let subexpressions = self.arena.flatten(*children);
+                let node = self.arena.add(IntermediateSyntaxTree::RuleNode { rule_name, subexpressions, span: start..end });
+                *children = self.arena.single(node);
+            }
+        }
+
+        /* Cutting a cycle back to this key only leaves out derivations that contain themselves, so
+         * the result is still the whole answer. Cutting one back to a key further up isn't, since
+         * that key's derivations would be part of this one's, so the result can't be reused.
+         *
+         * Made up nonterminals are never reused. Repetitions are a chain of them, one per match,
+         * and each link is only wanted by the next one, so saving them all would only fill the
+         * memo. Anything they are part of is saved when the rule around them is. */
+        let derivations = Rc::new(derivations);
+        if self.cut >= position {
+            self.cut = usize::MAX;
+            if rule_name.is_some() {
+                self.memo.insert(frame.key, derivations.clone());
+            }
+        }
+
+        if self.in_progress.is_empty() {
+            return Some(derivations);
+        }
+        self.receive(derivations);
+        None
+    }
+
+    // Every way to take one derivation from each symbol, with the first symbol's changing fastest.
+    fn combine(&mut self, children: &[Derivations]) -> Vec<Children> {
+        let mut combined = vec![None];
+        for derivations in children.iter().rev() {
+            let mut longer = Vec::with_capacity(combined.len() * derivations.len());
+            for &rest in &combined {
+                for &first in derivations.iter() {
+                    longer.push(self.join(first, rest));
+                }
+            }
+            combined = longer;
+        }
+        combined
+    }
+
+    // The token at `index`, matched by `expr` (or by an external rule), as a list of its own.
+    fn token(&mut self, index: usize, expr: Option<&'a RuleExpression>) -> Children {
+        let key = (index, expr.map(ByAddress));
+        let node = match self.token_nodes.get(&key) {
+            Some(&node) => node,
+            None => {
+                let captured = expr.and_then(|expr| self.chart.parser.capture(expr, &self.tokens[index]));
+                let node = self.arena.add(IntermediateSyntaxTree::TokenNode(self.tokens[index].clone(), index, captured));
+                if self.want_all {
+                    self.token_nodes.insert(key, node);
+                }
+                node
+            }
+        };
+
+        match self.want_all {
+            true => *self.singles.entry(node).or_insert_with(|| self.arena.single(node)),
+            false => self.arena.single(node),
+        }
+    }
+
+    fn join(&mut self, left: Children, right: Children) -> Children {
+        match (left, right) {
+            (Some(left_list), Some(right_list)) if self.want_all => {
+                *self.joins.entry((left_list, right_list)).or_insert_with(|| self.arena.join(left, right))
+            }
+            _ => self.arena.join(left, right),
+        }
+    }
+}
+
+/* The ways a production's symbols can split up the tokens from one index to another,
+ * each as the index every symbol starts at, then the one the last symbol ends at. They
+ * come in order of where the first symbol ends, then the second, and so on. They are
+ * found backwards from the end, using where each item's last symbol started, so that
+ * no split that goes nowhere is ever tried. */
+struct Splits {
+    ends: HashMap<(usize, usize), Vec<usize>>,  // (Dot, index) -> where the symbol after the dot can end, in order.
+    walk: Vec<(usize, usize)>,  // The split so far, as each index, with how many of its ends have been tried.
+    length: usize,
+}
+
+impl Splits {
+    fn new<T: Token>(chart: &Chart<T>, production: usize, start: usize, end: usize) -> Splits {
+        let length = chart.grammar.productions[production].symbols.len();
+        let item = |dot| Item { production, dot, origin: start };
+        let starts = |dot, index: usize| chart.sets.get(index).and_then(|set| set.starts(item(dot)));
+
+        // Often the production doesn't match here at all.
+        if starts(length, end).is_none() {
+            return Splits { ends: HashMap::new(), walk: vec![], length };
+        }
+
+        // Usually each symbol could only have started in one place, so there's only one split.
+        let mut walk = vec![(end, 0)];
+        for dot in (1..=length).rev() {
+            match starts(dot, walk[walk.len() - 1].0) {
+                Some(&[from]) => walk.push((from, 0)),
+                _ => break,
+            }
+        }
+        if walk.len() == length + 1 && walk[length].0 == start && starts(0, start).is_some() {
+            walk.reverse();
+            walk.iter_mut().for_each(|(_, tried)| *tried = 1);
+            return Splits { ends: HashMap::new(), walk, length };
+        }
+
+        let mut pending = vec![(length, end)];
+        let mut found = pending.iter().copied().collect::<HashSet<_>>();
+        let mut ends: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        while let Some((dot, index)) = pending.pop() {
+            let Some(froms) = dot.checked_sub(1).and_then(|_| starts(dot, index)) else {
+                continue;
+            };
+            for &from in froms {
+                ends.entry((dot - 1, from)).or_default().push(index);
+                if found.insert((dot - 1, from)) {
+                    pending.push((dot - 1, from));
+                }
+            }
+        }
+        ends.values_mut().for_each(|ends| ends.sort_unstable());
+
+        let walk = if found.contains(&(0, start)) { vec![(start, 0)] } else { vec![] };
+        Splits { ends, walk, length }
+    }
+
+    fn next(&mut self) -> Option<Vec<usize>> {
+        while let Some(&(index, tried)) = self.walk.last() {
+            let dot = self.walk.len() - 1;
+            if dot == self.length {
+                let split = self.walk.iter().map(|&(index, _)| index).collect();
+                self.walk.pop();
+                return Some(split);
+            }
+
+            match self.ends.get(&(dot, index)).and_then(|ends| ends.get(tried)) {
+                Some(&end) => {
+                    self.walk[dot].1 += 1;
+                    self.walk.push((end, 0));
+                }
+                None => {
+                    self.walk.pop();
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,248 @@
+/* Writing the rules back out in a standard notation, for sharing a grammar with other
+ * tools, or putting it in a spec. Rules come out sorted by name, the syntactic rules
+ * first and then any lexical ones.
+ *
+ * Some of the definition language has no equivalent in either notation. In ISO EBNF
+ * those parts are written as special sequences like `? not followed by ";" ?`, and in
+ * W3C notation as comments like `/* not followed by ";" */`, so they still say what
+ * they mean, but other tools will ignore them. @ordered choices are written as plain
+ * ones, and labels aren't written at all. A single token that can be anything is
+ * `? any token ?` in ISO EBNF, and `Char` in W3C notation, like in the XML spec.
+ * Neither has escapes in strings, so control characters are written by code point, like
+ * `? U+000A ?` or `#xA`. */
+
+use super::{Parser, Token};
+use crate::define::RuleExpression;
+
+use itertools::Itertools;
+
+use std::collections::HashSet;
+
+
+/* Which notation Parser::to_ebnf writes. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EbnfNotation {
+    Iso,  // ISO/IEC 14977, like `Sum = Term, { "+", Term } ;`
+    W3c,  // From the XML spec, like `Sum ::= Term ( "+" Term )*`
+}
+
+impl<T: Token> Parser<T> {
+    /* The grammar's rules in the chosen notation, one per line. */
+    pub fn to_ebnf(&self, notation: EbnfNotation) -> String {
+        let lexical_rules = self.lexer.iter().flat_map(|lexer| lexer.parser.rules.iter().sorted_by_key(|(name, _)| *name));
+        let token_names = self.lexer.iter()
+            .flat_map(|lexer| lexer.token_rules.iter().map(|(name, _)| name.as_str()))
+            .collect::<HashSet<_>>();
+        let writer = Writer { notation, token_names };
+
+        self.rules.iter().sorted_by_key(|(name, _)| *name)
+            .chain(lexical_rules)
+            .map(|(name, expr)| match notation {
+                EbnfNotation::Iso => format!("{name} = {} ;\n", writer.write(expr, Precedence::Choice)),
+                EbnfNotation::W3c => format!("{name} ::= {}\n", writer.write(expr, Precedence::Choice)),
+            })
+            .collect()
+    }
+}
+
+
+/* Private Implementation */
+
+// How tightly an expression binds, so the writer knows when it needs parentheses.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    Choice,
+    Sequence,
+    Atom,
+}
+
+struct Writer<'a> {
+    notation: EbnfNotation,
+    token_names: HashSet<&'a str>,  // Terminals that are @token rules, written as references to them.
+}
+
+impl Writer<'_> {
+    // Writes the expression so that it can stand where something of at least the given precedence is expected.
+    fn write(&self, expr: &RuleExpression, context: Precedence) -> String {
+        wrapped(&expr.fold(|expr, subexpressions| self.write_unwrapped(expr, &subexpressions)), context)
+    }
+
+    // Given its subexpressions already written.
+    fn write_unwrapped(&self, expr: &RuleExpression, subexpressions: &[(String, Precedence)]) -> (String, Precedence) {
+        let iso = self.notation == EbnfNotation::Iso;
+        let inner = |context| wrapped(&subexpressions[0], context);
+        match expr {
+            RuleExpression::Terminal(terminal) => self.terminal(terminal),
+            RuleExpression::RuleName(name) => (name.to_string(), Precedence::Atom),
+            RuleExpression::Concatenation(exprs) => {
+                let pieces = merge_characters(exprs).into_iter()
+                    .map(|piece| match piece {
+                        Piece::Characters(text) => self.quote(&text),
+                        Piece::Expr(i) => (wrapped(&subexpressions[i], Precedence::Sequence), Precedence::Atom),
+                    })
+                    .collect::<Vec<_>>();
+                match pieces.len() {
+                    1 => pieces.into_iter().next().expect("One piece"),
+                    _ => (pieces.into_iter().map(|(text, _)| text).join(if iso { ", " } else { " " }), Precedence::Sequence),
+                }
+            }
+            RuleExpression::Alternatives(_) | RuleExpression::OrderedAlternatives(_) =>
+                (subexpressions.iter().map(|alternative| wrapped(alternative, Precedence::Sequence)).join(" | "), Precedence::Choice),
+            RuleExpression::Optional(_) if iso => (format!("[ {} ]", inner(Precedence::Choice)), Precedence::Atom),
+            RuleExpression::Many(_) if iso => (format!("{{ {} }}", inner(Precedence::Choice)), Precedence::Atom),
+            RuleExpression::OneOrMore(_) if iso => (format!("{{ {} }}-", inner(Precedence::Choice)), Precedence::Atom),
+            RuleExpression::Optional(_) => (format!("{}?", inner(Precedence::Atom)), Precedence::Atom),
+            RuleExpression::Many(_) => (format!("{}*", inner(Precedence::Atom)), Precedence::Atom),
+            RuleExpression::OneOrMore(_) => (format!("{}+", inner(Precedence::Atom)), Precedence::Atom),
+            RuleExpression::Repetition(expr, min, max) => self.repetition(expr, &subexpressions[0], *min, *max),
+            RuleExpression::CharacterClass(class) if iso => (format!("? {} ?", class.source), Precedence::Atom),
+            RuleExpression::CharacterClass(class) => {
+                let ranges = class.ranges().iter()
+                    .map(|(start, end)| if start == end { class_char(*start) } else { format!("{}-{}", class_char(*start), class_char(*end)) })
+                    .collect::<String>();
+                (format!("[{}{ranges}]", if class.is_negated() { "^" } else { "" }), Precedence::Atom)
+            }
+            RuleExpression::Negation(..) if iso => (format!("? any token ? - {}", inner(Precedence::Atom)), Precedence::Sequence),
+            RuleExpression::Negation(..) => (format!("Char - {}", inner(Precedence::Atom)), Precedence::Sequence),
+            RuleExpression::Wildcard => (if iso { "? any token ?" } else { "Char" }.to_string(), Precedence::Atom),
+            RuleExpression::EndOfInput => (self.special("end of input"), Precedence::Atom),
+            RuleExpression::External(name) => (self.special(&format!("external {name}")), Precedence::Atom),
+            RuleExpression::PositiveLookahead(_) =>
+                (self.special(&format!("followed by {}", inner(Precedence::Atom))), Precedence::Atom),
+            RuleExpression::NegativeLookahead(..) =>
+                (self.special(&format!("not followed by {}", inner(Precedence::Atom))), Precedence::Atom),
+        }
+    }
+
+    // {n,m} is n copies, and then m - n optional copies, since neither notation has counts that go up to a limit.
+    fn repetition(&self, expr: &RuleExpression, written: &(String, Precedence), min: usize, max: Option<usize>) -> (String, Precedence) {
+        let iso = self.notation == EbnfNotation::Iso;
+        let mut pieces = vec![];
+        match (iso, min) {
+            (_, 0) => (),
+            (true, 1) => pieces.push(wrapped(written, Precedence::Sequence)),
+            (true, min) => pieces.push(format!("{min} * {}", wrapped(written, Precedence::Atom))),
+            (false, min) => pieces.extend(std::iter::repeat_n(wrapped(written, Precedence::Sequence), min)),
+        }
+        let subexpressions = std::slice::from_ref(written);
+        match max {
+            None => pieces.push(self.write_unwrapped(&RuleExpression::Many(Box::new(expr.clone())), subexpressions).0),
+            Some(max) if max > min => {
+                let optional = self.write_unwrapped(&RuleExpression::Optional(Box::new(expr.clone())), subexpressions).0;
+                match (iso, max - min) {
+                    (true, 1) | (false, _) => pieces.extend(std::iter::repeat_n(optional, max - min)),
+                    (true, extra) => pieces.push(format!("{extra} * {optional}")),
+                }
+            }
+            Some(_) => (),
+        }
+        match pieces.len() {
+            0 => (self.special("nothing"), Precedence::Atom),
+            1 => (pieces.remove(0), Precedence::Sequence),
+            _ => (pieces.join(if iso { ", " } else { " " }), Precedence::Sequence),
+        }
+    }
+
+    fn terminal(&self, terminal: &str) -> (String, Precedence) {
+        if let Some(text) = terminal.strip_prefix("i\"").and_then(|rest| rest.strip_suffix('"')) {
+            return match self.notation {
+                // Inside a special sequence, so control characters can't have one of their own.
+                EbnfNotation::Iso => (format!("? {}, ignoring case ?", quote(text, |c| format!("U+{:04X}", c as u32)).join(" ")), Precedence::Atom),
+                EbnfNotation::W3c => (format!("{} /* ignoring case */", self.quote(text).0), Precedence::Sequence),
+            };
+        }
+        match terminal.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+            Some(text) => self.quote(text),
+            None if terminal.chars().count() == 1 => self.quote(terminal),
+            None if self.token_names.contains(terminal) => (terminal.to_string(), Precedence::Atom),
+            None => (self.special(&format!("token {terminal}")), Precedence::Atom),
+        }
+    }
+
+    // A sequence if the text needs more than one piece, see quote.
+    fn quote(&self, text: &str) -> (String, Precedence) {
+        let pieces = match self.notation {
+            EbnfNotation::Iso => quote(text, |c| format!("? U+{:04X} ?", c as u32)),
+            EbnfNotation::W3c => quote(text, |c| format!("#x{:X}", c as u32)),
+        };
+        match pieces.len() {
+            1 => (pieces.into_iter().next().expect("One piece"), Precedence::Atom),
+            _ => (pieces.join(if self.notation == EbnfNotation::Iso { ", " } else { " " }), Precedence::Sequence),
+        }
+    }
+
+    // Something the notation can't say, said in words.
+    fn special(&self, text: &str) -> String {
+        match self.notation {
+            EbnfNotation::Iso => format!("? {text} ?"),
+            EbnfNotation::W3c => format!("/* {text} */"),
+        }
+    }
+}
+
+enum Piece {
+    Characters (String),
+    Expr (usize),  // Its index among the expressions.
+}
+
+// Literals over single characters become a terminal per character, which are glued back into one string.
+fn merge_characters(exprs: &[RuleExpression]) -> Vec<Piece> {
+    let mut pieces = vec![];
+    for (i, expr) in exprs.iter().enumerate() {
+        match (expr, pieces.last_mut()) {
+            (RuleExpression::Terminal(terminal), Some(Piece::Characters(text))) if terminal.chars().count() == 1 => text.push_str(terminal),
+            (RuleExpression::Terminal(terminal), _) if terminal.chars().count() == 1 => pieces.push(Piece::Characters(terminal.clone())),
+            (_, _) => pieces.push(Piece::Expr(i)),
+        }
+    }
+    pieces
+}
+
+// Puts parentheses around written text if it has to stand where something of at least the given precedence is expected.
+fn wrapped((text, precedence): &(String, Precedence), context: Precedence) -> String {
+    if *precedence < context { format!("( {text} )") } else { text.clone() }
+}
+
+// Both notations quote with either kind of quote, and have no escapes, so a string with both is split up.
+/* Quotes the text, in as many pieces as it takes. Neither notation has escapes, so text
+ * with both kinds of quote is split around the double quotes, which get single ones, and
+ * control characters are written by their code point with `control`, since they would
+ * otherwise be written raw. */
+fn quote(text: &str, control: impl Fn(char) -> String) -> Vec<String> {
+    let mut pieces = vec![];
+    for piece in text.split_inclusive(char::is_control) {
+        let (run, control_char) = match piece.chars().last() {
+            Some(c) if c.is_control() => (&piece[..piece.len() - c.len_utf8()], Some(c)),
+            _ => (piece, None),
+        };
+
+        if !run.contains('"') {
+            pieces.push(format!("\"{run}\""));
+        }
+        else if !run.contains('\'') {
+            pieces.push(format!("'{run}'"));
+        }
+        else {
+            let parts = run.split('"').map(|part| (!part.is_empty()).then(|| format!("\"{part}\"")));
+            pieces.extend(Itertools::intersperse(parts, Some("'\"'".to_string())).flatten());
+        }
+        pieces.extend(control_char.map(&control));
+    }
+
+    // Runs that are only there to hold a control character aren't worth writing.
+    pieces.retain(|piece| piece != "\"\"");
+    if pieces.is_empty() {
+        pieces.push("\"\"".to_string());
+    }
+    pieces
+}
+
+// Characters in W3C classes, with anything that would confuse the class written as #xN.
+fn class_char(c: char) -> String {
+    if c.is_alphanumeric() || (c.is_ascii_punctuation() && !matches!(c, ']' | '-' | '^' | '\\' | '[')) {
+        c.to_string()
+    }
+    else {
+        format!("#x{:X}", c as u32)
+    }
+}
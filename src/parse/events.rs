@@ -0,0 +1,57 @@
+/* Parse output as a stream of events, for callers that build their own data structure
+ * and don't want a SyntaxTree in between. Each rule gives an Enter, then the events for
+ * everything it matched, then an Exit, so the events are the tree walked depth first.
+ *
+ * The LL(1) and backtracking algorithms produce events straight from their own working
+ * state. Earley, and ambiguity policies other than FirstMatch, have to compare whole
+ * trees anyway, so for them the tree is built and then walked. */
+
+use super::{AmbiguityPolicy, ParseAlgorithm, Parser, ParseError, SyntaxTree, Token};
+use super::backtracking_parser::backtracking_parse_events;
+use super::ll1_parser::ll1_parse_events;
+
+
+/* Public Interface */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseEvent<'a, T: Token> {
+    Enter (&'a str),  // A rule started.
+    Token (&'a T),  // A token was matched, by the innermost rule that has been entered.
+    Exit (&'a str),  // The innermost rule ended.
+}
+
+impl<T: Token> Parser<T> {
+    /* Like parse_tokens, but passes the parse to the callback one event at a time
+     * instead of returning a tree. Nothing is passed unless the parse succeeds, so a
+     * failed parse never leaves the caller with half a structure. */
+    pub fn parse_events(&self, tokens: &[T], start_rule: &str, mut on_event: impl FnMut(ParseEvent<T>)) -> Result<(), ParseError> {
+        self.check_stateless()?;
+        if let AmbiguityPolicy::FirstMatch = self.ambiguity_policy {
+            match self.algorithm {
+                ParseAlgorithm::Auto if ll1_parse_events(self, tokens, start_rule, self.anchored, &mut on_event) => return Ok(()),
+                ParseAlgorithm::Auto | ParseAlgorithm::Backtracking
+                    => return backtracking_parse_events(self, tokens, start_rule, self.anchored, &mut on_event),
+                ParseAlgorithm::Earley => (),
+            }
+        }
+
+        let tree = self.parse_tokens(tokens, start_rule)?;
+        tree_events(&tree, &mut on_event);
+        tree.dismantle();
+        Ok(())
+    }
+}
+
+
+/* Private Implementation */
+
+fn tree_events<T: Token>(tree: &SyntaxTree<T>, on_event: &mut impl FnMut(ParseEvent<T>)) {
+    tree.walk(|node, entering| match node {
+        SyntaxTree::RuleNode { rule_name, .. } if entering => on_event(ParseEvent::Enter(rule_name)),
+        SyntaxTree::RuleNode { rule_name, .. } => on_event(ParseEvent::Exit(rule_name)),
+        SyntaxTree::TokenNode { token, .. } if entering => on_event(ParseEvent::Token(token)),
+        // Only recovering parses make these, but the tokens still belong to the rule they're in.
+        SyntaxTree::ErrorNode { tokens, .. } if entering => tokens.iter().for_each(|token| on_event(ParseEvent::Token(token))),
+        SyntaxTree::TokenNode { .. } | SyntaxTree::ErrorNode { .. } => (),
+    });
+}
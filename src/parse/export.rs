@@ -0,0 +1,209 @@
+/* Writing trees out for other programs (and people) to read.
+ *
+ * to_sexpr gives the tree as an S-expression on one line, which is compact enough to
+ * read and diff in tests:
+ *
+ *     (PlusMinusExpr (MultDivExpr (AtomicExpr "a")) "+" (MultDivExpr (AtomicExpr "b")))
+ *
+ * Tokens are quoted strings, escaped like in Rust, and error nodes are (ERROR "text").
+ *
+ * to_dot gives a Graphviz graph, for looking at trees too big to read as text. Render
+ * it with something like `dot -Tsvg tree.dot > tree.svg`. Rule nodes are ovals
+ * labeled with the rule's name, tokens are boxes labeled with their text, and error
+ * nodes are red boxes.
+ *
+ * to_json gives one JSON object per node, on a single line, with these fields:
+ *
+ *     "type"        "rule", "token" or "error".
+ *     "rule"        Rule nodes only, the name of the rule.
+ *     "text"        Token and error nodes only, the text of the token(s), by Display.
+ *     "expected"    Error nodes only, what was expected instead, sorted.
+ *     "children"    Rule nodes only, the child nodes in order.
+ *     "span"        [start, end), token indices, like SyntaxTree::span.
+ *     "bytes"       [start, end), byte offsets into the source, like SyntaxTree::source_span.
+ *                   null if the tokens don't know where they came from.
+ *
+ * Fields always come in this order, and are always there for their type of node, so
+ * the output can be compared as text. Captured values aren't written. */
+
+use super::{SyntaxTree, Token};
+
+use std::fmt::Display;
+use std::io::Write;
+use std::ops::Range;
+
+
+impl<T: Token + Display> SyntaxTree<T> {
+    /* The tree as JSON, see the module docs for the schema. */
+    pub fn to_json(&self) -> String {
+        let mut json = vec![];
+        self.write_json(&mut json).expect("Writing to a Vec can't fail");
+        String::from_utf8(json).expect("Only strings are written")
+    }
+
+    /* The tree as an S-expression, see the module docs. */
+    pub fn to_sexpr(&self) -> String {
+        let mut sexpr = String::new();
+        self.sexpr_helper(&mut sexpr);
+        sexpr
+    }
+
+    /* The tree as a Graphviz graph, see the module docs. */
+    pub fn to_dot(&self) -> String {
+        let mut dot = "digraph SyntaxTree {\n    node [fontname=\"monospace\"];\n".to_string();
+        self.dot_helper(&mut dot);
+        dot += "}\n";
+        dot
+    }
+
+    /* Like to_json, but writes the JSON out instead of building a string, for big trees. */
+    pub fn write_json(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut result = Ok(());
+        let mut first_child = true;
+        self.walk(|node, entering| {
+            if result.is_err() {
+                return;
+            }
+            result = if entering {
+                write_json_start(node, &mut first_child, writer)
+            }
+            else {
+                first_child = false;
+                write_json_end(node, writer)
+            };
+        });
+        result
+    }
+}
+
+
+/* Private Implementation */
+
+impl<T: Token + Display> SyntaxTree<T> {
+    fn sexpr_helper(&self, sexpr: &mut String) {
+        let mut depth = 0;
+        self.walk(|node, entering| {
+            if !entering {
+                depth -= 1;
+                if let SyntaxTree::RuleNode {..} = node {
+                    sexpr.push(')');
+                }
+                return;
+            }
+
+            if depth > 0 {
+                sexpr.push(' ');
+            }
+            depth += 1;
+            match node {
+                SyntaxTree::RuleNode {rule_name, ..} => {
+                    sexpr.push('(');
+                    sexpr.push_str(rule_name);
+                }
+                SyntaxTree::TokenNode {token, ..} => *sexpr += &format!("{:?}", token.to_string()),
+                SyntaxTree::ErrorNode {tokens, ..} => 
+                    *sexpr += &format!("(ERROR {:?})", tokens.iter().map(ToString::to_string).collect::<String>()),
+            }
+        });
+    }
+
+    // Writes this node and everything under it, numbering the nodes in the order they're visited.
+    fn dot_helper(&self, dot: &mut String) {
+        let mut next_id = 0;
+        let mut ids = vec![];  // Of the node being visited and the nodes it is in.
+        self.walk(|node, entering| {
+            if !entering {
+                let id = ids.pop().expect("Node was entered");
+                if let Some(parent) = ids.last() {
+                    *dot += &format!("    n{parent} -> n{id};\n");
+                }
+                return;
+            }
+
+            let id = next_id;
+            next_id += 1;
+            ids.push(id);
+            match node {
+                SyntaxTree::RuleNode {rule_name, ..} => *dot += &format!("    n{id} [label={}];\n", dot_string(rule_name)),
+                SyntaxTree::TokenNode {token, ..} => *dot += &format!("    n{id} [label={}, shape=box];\n", dot_string(&token.to_string())),
+                SyntaxTree::ErrorNode {tokens, ..} => {
+                    let text = tokens.iter().map(ToString::to_string).collect::<String>();
+                    *dot += &format!("    n{id} [label={}, shape=box, color=red, fontcolor=red];\n", dot_string(&text));
+                }
+            }
+        });
+    }
+}
+
+// Everything of a node's JSON object up to its children. first_child is whether the node
+// is the first in its parent.
+fn write_json_start<T: Token + Display>(node: &SyntaxTree<T>, first_child: &mut bool, writer: &mut impl Write) -> std::io::Result<()> {
+    if !std::mem::replace(first_child, false) {
+        writer.write_all(b",")?;
+    }
+    match node {
+        SyntaxTree::RuleNode {rule_name, ..} => {
+            *first_child = true;
+            write!(writer, r#"{{"type":"rule","rule":{},"children":["#, json_string(rule_name))
+        }
+        SyntaxTree::TokenNode {token, ..} => {
+            write!(writer, r#"{{"type":"token","text":{}"#, json_string(&token.to_string()))
+        }
+        SyntaxTree::ErrorNode {tokens, expected, ..} => {
+            let text = tokens.iter().map(ToString::to_string).collect::<String>();
+            let mut expected = expected.iter().map(|name| json_string(name)).collect::<Vec<_>>();
+            expected.sort_unstable();
+            write!(writer, r#"{{"type":"error","text":{},"expected":[{}]"#, json_string(&text), expected.join(","))
+        }
+    }
+}
+
+// The rest of a node's JSON object, after its children.
+fn write_json_end<T: Token + Display>(node: &SyntaxTree<T>, writer: &mut impl Write) -> std::io::Result<()> {
+    if let SyntaxTree::RuleNode {..} = node {
+        writer.write_all(b"]")?;
+    }
+    write!(writer, r#","span":{},"bytes":{}}}"#, json_range(&node.span()), node.source_span().map_or("null".to_string(), |bytes| json_range(&bytes)))
+}
+
+pub(super) fn json_string(text: &str) -> String {
+    let mut string = String::with_capacity(text.len() + 2);
+    string.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\r' => string.push_str("\\r"),
+            '\t' => string.push_str("\\t"),
+            c if c < ' ' => string.push_str(&format!("\\u{:04x}", c as u32)),
+            c => string.push(c),
+        }
+    }
+    string.push('"');
+    string
+}
+
+/* A quoted DOT string. Control characters would break the label (or at least its line),
+ * so they are shown escaped instead, like \n or \u{1b}. */
+pub(super) fn dot_string(text: &str) -> String {
+    let mut string = String::with_capacity(text.len() + 2);
+    string.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\\\n"),
+            '\r' => string.push_str("\\\\r"),
+            '\t' => string.push_str("\\\\t"),
+            c if c.is_control() => string.push_str(&format!("\\\\u{{{:x}}}", c as u32)),
+            c => string.push(c),
+        }
+    }
+    string.push('"');
+    string
+}
+
+fn json_range(range: &Range<usize>) -> String {
+    format!("[{},{}]", range.start, range.end)
+}
@@ -0,0 +1,70 @@
+/* Finding matches of a rule inside input that the grammar doesn't describe as a
+ * whole, like the placeholders in a template. */
+
+use super::{Parser, ParseError, SyntaxTree, Token};
+
+
+/* The matches of a rule in some tokens, from Parser::find_iter. */
+pub struct Matches<'p, 't, T: Token> {
+    parser: &'p Parser<T>,
+    tokens: &'t [T],
+    rule_name: String,
+    index: usize,  // Where to look for the next match.
+    failed: bool,
+}
+
+impl<T: Token> Parser<T> {
+    /* Scans the tokens from left to right, and yields every match of the rule that
+     * doesn't overlap an earlier one. At each token, the longest match starting there is
+     * taken, and scanning carries on after it. Matches of no tokens are skipped. Spans
+     * in the trees are indices into all of the tokens.
+     *
+     * A parse that fails just means there's no match at that token, but any other error
+     * (like an unknown rule, or an ambiguous match with RejectAmbiguity) is yielded, and
+     * ends the iteration. This tries a parse at every token, so it is slower than
+     * parsing the same input with a grammar that covers all of it. */
+    pub fn find_iter<'p, 't>(&'p self, tokens: &'t [T], rule_name: &str) -> Matches<'p, 't, T> {
+        Matches { parser: self, tokens, rule_name: rule_name.to_string(), index: 0, failed: false }
+    }
+}
+
+impl<'p, 't, T: Token> Iterator for Matches<'p, 't, T> {
+    type Item = Result<SyntaxTree<T>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed && self.index < self.tokens.len() {
+            let start = self.index;
+            match self.parser.parse_prefix(&self.tokens[start..], &self.rule_name) {
+                Ok((mut tree, consumed)) if consumed > 0 => {
+                    self.index += consumed;
+                    offset_indices(&mut tree, start);
+                    return Some(Ok(tree));
+                }
+                Ok(_) | Err(ParseError::UnexpectedToken { .. } | ParseError::UnexpectedEof { .. }) => self.index += 1,
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+
+/* Private Implementation */
+
+fn offset_indices<T: Token>(tree: &mut SyntaxTree<T>, offset: usize) {
+    let mut pending = vec![tree];
+    while let Some(tree) = pending.pop() {
+        match tree {
+            SyntaxTree::RuleNode { subexpressions, span, .. } => {
+                *span = span.start + offset..span.end + offset;
+                pending.extend(subexpressions.iter_mut());
+            }
+            SyntaxTree::TokenNode { index, .. } => *index += offset,
+            SyntaxTree::ErrorNode { span, .. } => *span = span.start + offset..span.end + offset,
+        }
+    }
+}
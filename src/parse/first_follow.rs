@@ -0,0 +1,119 @@
+/* FIRST and FOLLOW sets: the terminals that can start a rule, and the ones that can
+ * come right after it. The first sets are the same ones the LL(1) tables are built
+ * from.
+ *
+ * Terminals are compared as they are written, so "a" and [a-z] are both listed even
+ * though they can match the same token. Lookaheads match no tokens, so they add
+ * nothing. An external rule could start with anything, so it shows up as ".".
+ *
+ * Any rule can be the start rule, but saying that the end of the input can follow
+ * every rule would make the follow sets useless. So like GrammarWarning::Unreachable,
+ * only rules that no other rule uses are taken to be start rules (unless every
+ * rule is used by another, when any of them could be). */
+
+use crate::define::{RuleExpression, describe_expression};
+use super::{Parser, ParseError, Token};
+use super::ll1_parser::{Analysis, TokenSet};
+
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+
+/* Public Interface */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirstSet {
+    pub terminals: Vec<String>,  // Sorted.
+    pub end: bool,  // Can start with $, the end of the input.
+    pub nullable: bool,  // Can match no tokens at all.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowSet {
+    pub terminals: Vec<String>,  // Sorted.
+    pub end: bool,  // Can be followed by the end of the input.
+}
+
+impl<T: Token> Parser<T> {
+    pub fn first_set(&self, rule_name: &str) -> Result<FirstSet, ParseError> {
+        let analysis = Analysis::new(&self.rules);
+        let first = analysis.firsts.get(rule_name).ok_or_else(|| self.unknown_rule(rule_name))?;
+        Ok(FirstSet { terminals: describe_terminals(&first.tokens), end: first.tokens.end, nullable: first.nullable })
+    }
+
+    pub fn follow_set(&self, rule_name: &str) -> Result<FollowSet, ParseError> {
+        let follows = self.follow_sets();
+        let follow = follows.get(rule_name).ok_or_else(|| self.unknown_rule(rule_name))?;
+        Ok(FollowSet { terminals: describe_terminals(follow), end: follow.end })
+    }
+}
+
+
+/* Private Implementation */
+
+impl<T: Token> Parser<T> {
+    pub(super) fn follow_sets(&self) -> HashMap<&str, TokenSet> {
+        let analysis = Analysis::new(&self.rules);
+
+        let mut used = vec![];
+        for (rule_name, expr) in &self.rules {
+            let mut referenced = vec![];
+            expr.referenced_rules(&mut referenced);
+            used.extend(referenced.into_iter().filter(|name| name != rule_name));
+        }
+
+        let any_start = self.rules.keys().all(|rule_name| used.contains(&rule_name.as_str()));
+        let mut follows = self.rules.keys()
+            .map(|rule_name| (rule_name.as_str(), TokenSet { tokens: vec![], end: any_start || !used.contains(&rule_name.as_str()) }))
+            .collect::<HashMap<_, _>>();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule_name, expr) in &self.rules {
+                let follow = follows[rule_name.as_str()].clone();
+                changed |= add_follows(expr, &follow, &analysis, &mut follows);
+            }
+        }
+
+        follows
+    }
+}
+
+/* Adds `follow` (what can come after the expression) to the follow set of every rule
+ * that can end the expression, and works out the rest within it. Returns true if any
+ * follow set grew. */
+fn add_follows(expr: &RuleExpression, follow: &TokenSet, analysis: &Analysis, follows: &mut HashMap<&str, TokenSet>) -> bool {
+    match expr {
+        RuleExpression::RuleName(rule_name) => follows.get_mut(rule_name.as_str()).is_some_and(|set| set.extend(follow)),
+        RuleExpression::Concatenation(exprs) => {
+            let mut changed = false;
+            let mut rest = TokenSet::default();
+            let mut rest_nullable = true;
+            for expr in exprs.iter().rev() {
+                let expr_follow = if rest_nullable { rest.union(follow) } else { rest.clone() };
+                changed |= add_follows(expr, &expr_follow, analysis, follows);
+
+                let expr_first = analysis.first(expr);
+                rest = if expr_first.nullable { expr_first.tokens.union(&rest) } else { expr_first.tokens };
+                rest_nullable &= expr_first.nullable;
+            }
+            changed
+        }
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+            exprs.iter().fold(false, |changed, expr| add_follows(expr, follow, analysis, follows) | changed)
+        }
+        RuleExpression::Optional(inner) | RuleExpression::Repetition(inner, _, Some(0 | 1)) => add_follows(inner, follow, analysis, follows),
+        // Anything that can repeat can be followed by itself.
+        RuleExpression::Many(inner) | RuleExpression::OneOrMore(inner) | RuleExpression::Repetition(inner, ..) => {
+            add_follows(inner, &analysis.first(inner).tokens.union(follow), analysis, follows)
+        }
+        // Whatever a lookahead looks at is still there to be matched afterwards, so it isn't followed by anything in particular.
+        _ => false,
+    }
+}
+
+fn describe_terminals(set: &TokenSet) -> Vec<String> {
+    set.tokens.iter().map(describe_expression).sorted().dedup().collect()
+}
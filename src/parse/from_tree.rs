@@ -0,0 +1,229 @@
+/* Turning syntax trees into your own types, usually with #[derive(FromSyntaxTree)].
+ *
+ * A derived struct is built from a node of its rule (named like the struct, or by
+ * #[rule("Name")] on it). Its fields are filled from the node's children, in order:
+ *
+ *     Field: X               The next child rule node, which has to be an X.
+ *     Field: Option<X>       The same, if the next child rule node is an X.
+ *     Field: Vec<X>          Every child rule node from here that is an X, until one isn't.
+ *     #[rule("Name")]        Only takes nodes of the rule Name.
+ *     #[token("Kind")]       Takes the next token child that the terminal Kind (or "text") matches, instead of a rule node.
+ *
+ * Tokens are skipped unless a field asks for them, so punctuation and keywords don't
+ * need fields. Every child rule node has to end up in a field, though, or the shape
+ * doesn't fit. A String takes the text of whatever it is given, and a SyntaxTree
+ * takes the node itself, for anything the derive can't express.
+ *
+ * A derived enum picks the first variant whose rule (named like the variant, or by
+ * #[rule("Name")] on it) matches the node, and whose fields fit, so variants can share
+ * a rule. With #[rule("Name")] on the enum itself, a node of that rule is looked
+ * through to its only child rule node, for rules like `Expr : Sum | Product ;`. */
+
+use super::{SyntaxTree, Token};
+
+use std::fmt::Display;
+use std::ops::Range;
+
+
+/* Builds a value from a syntax tree, see the module docs for how derived ones work. */
+pub trait FromSyntaxTree<T: Token>: Sized {
+    fn from_syntax_tree(tree: &SyntaxTree<T>) -> Result<Self, ShapeError>;
+
+    /* Whether the node is worth trying to build from, which decides which children
+     * fields take. Derived types accept nodes of their rules. */
+    fn accepts(_tree: &SyntaxTree<T>) -> bool {
+        true
+    }
+
+    /* What this type is built from, for error messages, like "FunctionDecl". */
+    fn describe() -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+}
+
+/* Why a tree couldn't be turned into a value. The span is the token indices of the
+ * node where things went wrong. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapeError {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl std::fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (tokens {}..{})", self.message, self.span.start, self.span.end)
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+impl<T: Token + Display> FromSyntaxTree<T> for String {
+    fn from_syntax_tree(tree: &SyntaxTree<T>) -> Result<Self, ShapeError> {
+        Ok(tree.token_text())
+    }
+
+    fn describe() -> String {
+        "text".to_string()
+    }
+}
+
+impl<T: Token, X: FromSyntaxTree<T>> FromSyntaxTree<T> for Box<X> {
+    fn from_syntax_tree(tree: &SyntaxTree<T>) -> Result<Self, ShapeError> {
+        X::from_syntax_tree(tree).map(Box::new)
+    }
+
+    fn accepts(tree: &SyntaxTree<T>) -> bool {
+        X::accepts(tree)
+    }
+
+    fn describe() -> String {
+        X::describe()
+    }
+}
+
+impl<T: Token> FromSyntaxTree<T> for SyntaxTree<T> {
+    fn from_syntax_tree(tree: &SyntaxTree<T>) -> Result<Self, ShapeError> {
+        Ok(tree.clone())
+    }
+
+    fn describe() -> String {
+        "a node".to_string()
+    }
+}
+
+
+/* Used by #[derive(FromSyntaxTree)], not meant to be used directly. Hands out a
+ * node's children to fields, in order. */
+#[doc(hidden)]
+pub struct FieldCursor<'t, T: Token> {
+    node: &'t SyntaxTree<T>,
+    type_name: &'static str,
+    position: usize,  // Index of the next child to look at.
+}
+
+/* Which children a field takes. */
+#[doc(hidden)]
+#[derive(Clone, Copy)]
+pub enum FieldFilter {
+    Rule (Option<&'static str>),  // Rule nodes, of the named rule if there is one.
+    Token (&'static str),  // Tokens the terminal matches.
+}
+
+impl<'t, T: Token> FieldCursor<'t, T> {
+    /* Starts on the node's children, failing if the node isn't of one of the rules. */
+    pub fn new(node: &'t SyntaxTree<T>, type_name: &'static str, rule_name: &str) -> Result<FieldCursor<'t, T>, ShapeError> {
+        if node.rule_name() != Some(rule_name) {
+            return Err(unexpected_shape(node, &format!("{rule_name} for {type_name}")));
+        }
+        Ok(FieldCursor { node, type_name, position: 0 })
+    }
+
+    /* For enums marked #[rule("Name")]: a node of that rule stands for its only child rule node. */
+    pub fn look_through(node: &'t SyntaxTree<T>, rule_name: &str) -> Result<&'t SyntaxTree<T>, ShapeError> {
+        if node.rule_name() != Some(rule_name) {
+            return Ok(node);
+        }
+
+        let mut rules = node.children().iter().filter(|child| child.rule_name().is_some());
+        match (rules.next(), rules.next()) {
+            (Some(child), None) => Ok(child),
+            _ => Err(shape_error(node, format!("Expected {rule_name} to have exactly one rule under it"))),
+        }
+    }
+
+    pub fn one<X: FromSyntaxTree<T>>(&mut self, field: &str, filter: FieldFilter) -> Result<X, ShapeError> {
+        match self.next::<X>(filter)? {
+            Some(child) => X::from_syntax_tree(child),
+            None => {
+                let found = self.node.children()[self.position..].iter().find(|child| !skipped(child, filter));
+                let message = format!("Expected {} for field `{field}` of {}, found {}",
+                    describe::<T, X>(filter), self.type_name, found.map_or("nothing".to_string(), describe_node));
+                Err(shape_error(found.unwrap_or(self.node), message))
+            }
+        }
+    }
+
+    pub fn optional<X: FromSyntaxTree<T>>(&mut self, filter: FieldFilter) -> Result<Option<X>, ShapeError> {
+        self.next::<X>(filter)?.map(X::from_syntax_tree).transpose()
+    }
+
+    pub fn many<X: FromSyntaxTree<T>>(&mut self, filter: FieldFilter) -> Result<Vec<X>, ShapeError> {
+        let mut values = vec![];
+        while let Some(child) = self.next::<X>(filter)? {
+            values.push(X::from_syntax_tree(child)?);
+        }
+        Ok(values)
+    }
+
+    /* Fails if any child rule node wasn't taken by a field. */
+    pub fn finish(self) -> Result<(), ShapeError> {
+        match self.node.children()[self.position..].iter().find(|child| child.rule_name().is_some()) {
+            Some(child) => Err(shape_error(child, format!("Unexpected {} in {}", describe_node(child), self.type_name))),
+            None => Ok(()),
+        }
+    }
+
+    // Takes the next child the field wants, if it's the next child that the field doesn't skip.
+    fn next<X: FromSyntaxTree<T>>(&mut self, filter: FieldFilter) -> Result<Option<&'t SyntaxTree<T>>, ShapeError> {
+        if let FieldFilter::Token(terminal) = filter {
+            if T::kind(terminal).is_none() {
+                return Err(shape_error(self.node, format!("Unknown token type {terminal} in {}", self.type_name)));
+            }
+        }
+
+        let children = self.node.children();
+        let Some(offset) = children[self.position..].iter().position(|child| !skipped(child, filter)) else {
+            return Ok(None);
+        };
+        let child = &children[self.position + offset];
+        if !wanted(child, filter) || !X::accepts(child) {
+            return Ok(None);
+        }
+
+        self.position += offset + 1;
+        Ok(Some(child))
+    }
+}
+
+/* For enums, when no variant fits. */
+#[doc(hidden)]
+pub fn unexpected_shape<T: Token>(node: &SyntaxTree<T>, expected: &str) -> ShapeError {
+    shape_error(node, format!("Expected {expected}, found {}", describe_node(node)))
+}
+
+
+/* Private Implementation */
+
+fn shape_error<T: Token>(node: &SyntaxTree<T>, message: String) -> ShapeError {
+    ShapeError { message, span: node.span() }
+}
+
+// Rule fields skip tokens, and token fields skip tokens that aren't theirs.
+fn skipped<T: Token>(child: &SyntaxTree<T>, filter: FieldFilter) -> bool {
+    matches!(child, SyntaxTree::TokenNode {..}) && !wanted(child, filter)
+}
+
+fn wanted<T: Token>(child: &SyntaxTree<T>, filter: FieldFilter) -> bool {
+    match (filter, child) {
+        (FieldFilter::Rule(None), SyntaxTree::RuleNode {..}) => true,
+        (FieldFilter::Rule(Some(rule_name)), SyntaxTree::RuleNode {rule_name: name, ..}) => name == rule_name,
+        (FieldFilter::Token(terminal), SyntaxTree::TokenNode {token, ..}) => T::kind(terminal).is_some_and(|kind| T::matches(&kind, token)),
+        _ => false,
+    }
+}
+
+fn describe<T: Token, X: FromSyntaxTree<T>>(filter: FieldFilter) -> String {
+    match filter {
+        FieldFilter::Rule(None) => X::describe(),
+        FieldFilter::Rule(Some(rule_name)) => rule_name.to_string(),
+        FieldFilter::Token(terminal) => format!("token {terminal}"),
+    }
+}
+
+fn describe_node<T: Token>(node: &SyntaxTree<T>) -> String {
+    match node {
+        SyntaxTree::RuleNode {rule_name, ..} => rule_name.to_string(),
+        SyntaxTree::TokenNode {..} => "a token".to_string(),
+        SyntaxTree::ErrorNode {..} => "an error".to_string(),
+    }
+}
@@ -0,0 +1,137 @@
+/* Rowan green trees, with the `rowan` feature turned on, so a grammar can drive
+ * rust-analyzer style tooling: green nodes are immutable and shared, so cloning a tree
+ * is cheap, and parsing with the same NodeCache again reuses the small nodes and the
+ * tokens that didn't change. Green trees are always lossless (see set_lossless), so
+ * the trivia is in them as tokens, whatever the parser's own setting is.
+ *
+ * Rowan identifies nodes and tokens by number. RowanKinds says which number goes with
+ * which name: 0 is for error nodes, then come the token kinds in the order they were
+ * defined (@skip rules included), then the other rules in alphabetical order. */
+
+use super::{LexedToken, ParseError, Parser, Symbol, SyntaxTree};
+
+use rowan::{GreenNode, GreenNodeBuilder, NodeCache, SyntaxKind};
+
+
+/* Public Interface */
+
+/* The kind of every node and token that can appear in a parser's green trees. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowanKinds {
+    names: Vec<String>,  // Indexed by the raw kind.
+}
+
+impl RowanKinds {
+    pub const ERROR: SyntaxKind = SyntaxKind(0);
+
+    /* The kind of a rule or token kind. The name "ERROR" gives RowanKinds::ERROR. */
+    pub fn kind(&self, name: &str) -> Option<SyntaxKind> {
+        self.names.iter()
+            .position(|candidate| candidate == name)
+            .map(|index| SyntaxKind(index as u16))
+    }
+
+    /* The name of a kind, or None if this parser doesn't have it. */
+    pub fn name(&self, kind: SyntaxKind) -> Option<&str> {
+        self.names.get(kind.0 as usize).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl Parser<LexedToken> {
+    pub fn rowan_kinds(&self) -> RowanKinds {
+        let mut rule_names = self.rules.keys().map(Symbol::to_string).collect::<Vec<_>>();
+        rule_names.sort_unstable();
+
+        let names = std::iter::once("ERROR".to_string())
+            .chain(self.lexer.iter().flat_map(|lexer| lexer.token_rules.iter().map(|(name, _)| name.clone())))
+            .chain(rule_names)
+            .collect();
+        RowanKinds { names }
+    }
+
+    /* Like parse_string, but builds a rowan GreenNode, with the kinds from rowan_kinds. */
+    pub fn parse_green(&self, input: &str, start_rule: &str) -> Result<GreenNode, ParseError> {
+        self.parse_green_with_cache(input, start_rule, &mut NodeCache::default())
+    }
+
+    /* Like parse_green, but shares nodes through the cache, so reparsing an edited input
+     * with the same cache reuses the tokens (and small nodes) that are the same as before. */
+    pub fn parse_green_with_cache(&self, input: &str, start_rule: &str, cache: &mut NodeCache) -> Result<GreenNode, ParseError> {
+        let (tree, _) = self.parse_string_keeping_trivia(input, start_rule, true)?;
+        let kinds = self.rowan_kinds();
+        let mut builder = GreenNodeBuilder::with_cache(cache);
+        build(&tree, &kinds, &mut builder);
+        Ok(builder.finish())
+    }
+}
+
+
+/* Private Implementation */
+
+fn build(tree: &SyntaxTree<LexedToken>, kinds: &RowanKinds, builder: &mut GreenNodeBuilder) {
+    tree.walk(|node, entering| match node {
+        SyntaxTree::RuleNode { rule_name, .. } if entering => builder.start_node(kinds.kind(rule_name).unwrap_or(RowanKinds::ERROR)),
+        SyntaxTree::RuleNode { .. } => builder.finish_node(),
+        SyntaxTree::TokenNode { token, .. } if entering => builder.token(token_kind(token, kinds), &token.text),
+        SyntaxTree::ErrorNode { tokens, .. } if entering => {
+            builder.start_node(RowanKinds::ERROR);
+            for token in tokens {
+                builder.token(token_kind(token, kinds), &token.text);
+            }
+            builder.finish_node();
+        }
+        SyntaxTree::TokenNode { .. } | SyntaxTree::ErrorNode { .. } => (),
+    });
+}
+
+fn token_kind(token: &LexedToken, kinds: &RowanKinds) -> SyntaxKind {
+    kinds.kind(&token.kind).unwrap_or(RowanKinds::ERROR)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{define_parser, LexedToken, Parser, RowanKinds};
+
+    #[test]
+    fn test_parse_green() {
+        let parser: Parser<LexedToken> = define_parser(r#"
+            @skip Space : " "+ ;
+            @token Name : [a-z]+ ;
+            @token Punctuation : [=;] ;
+            Statements : Statement+ ;
+            Statement : Name "=" Name ";" ;
+        "#).expect("Parser definition ok");
+        let kinds = parser.rowan_kinds();
+        assert_eq!((0..kinds.len() as u16).map(|kind| kinds.name(rowan::SyntaxKind(kind)).expect("Has a name")).collect::<Vec<_>>(),
+            vec!["ERROR", "Space", "Name", "Punctuation", "Statement", "Statements"]);
+
+        let input = " a = b; c=d; ";
+        let mut cache = rowan::NodeCache::default();
+        let green = parser.parse_green_with_cache(input, "Statements", &mut cache).expect("Parses");
+        assert_eq!(green.kind(), kinds.kind("Statements").expect("Has kind"));
+        assert_eq!(green.to_string(), input);
+
+        let statements = green.children().filter_map(|child| child.into_node()).collect::<Vec<_>>();
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].to_string(), "a = b;");
+        assert_eq!(statements[0].children().filter(|child| child.kind() == kinds.kind("Space").expect("Has kind")).count(), 2);
+        assert_eq!(kinds.kind("Nothing"), None);
+        assert_eq!(kinds.kind("ERROR"), Some(RowanKinds::ERROR));
+
+        // Parsed again with the same cache, the statement is equal, and its tokens are the very same ones.
+        let again = parser.parse_green_with_cache("a = b;", "Statements", &mut cache).expect("Parses");
+        let reused = again.children().next().and_then(|child| child.into_node()).expect("Has a statement");
+        assert_eq!(reused, statements[0]);
+        let first_token = |node: &rowan::GreenNodeData| node.children().next().and_then(|child| child.into_token()).expect("Has a token") as *const _;
+        assert_eq!(first_token(reused), first_token(statements[0]));
+    }
+}
@@ -0,0 +1,109 @@
+/* Trees that are only built where someone looks. The backtracking parser keeps its
+ * parse as shared nodes that hold little more than a rule name and some indices, and
+ * turning those into a SyntaxTree (copying every name and token) is a good part of the
+ * work for a big input. A LazyTree keeps the shared nodes instead, and builds the
+ * SyntaxTree for one subtree at a time, when it is asked for.
+ *
+ * Nodes are found by path: the index of a child of the root, then the index of one of
+ * its children, and so on. The empty path is the root. */
+
+use super::{CharToken, Parser, ParseError, SyntaxTree, Token};
+use super::backtracking_parser::{backtracking_parse_lazy, Arena, IntermediateSyntaxTree, NodeId};
+
+use std::ops::Range;
+
+
+/* Public Interface */
+
+pub struct LazyTree<'p, T: Token> {
+    arena: Arena<'p, T>,
+    root: NodeId,
+}
+
+impl<T: Token> Parser<T> {
+    /* Like parse_tokens, but returns a LazyTree. Lazy parses always use the backtracking
+     * algorithm, and when the input is ambiguous, they take the first parse whatever
+     * the ambiguity policy is. */
+    pub fn parse_lazy(&self, tokens: &[T], start_rule: &str) -> Result<LazyTree<'_, T>, ParseError> {
+        self.check_stateless()?;
+        let (arena, root) = backtracking_parse_lazy(self, tokens, start_rule, self.anchored)?;
+        Ok(LazyTree { arena, root })
+    }
+}
+
+impl Parser<CharToken> {
+    pub fn parse_string_lazy(&self, input: &str, start_rule: &str) -> Result<LazyTree<'_, CharToken>, ParseError> {
+        self.parse_lazy(&super::string_to_tokens(input), start_rule)
+            .map_err(|err| super::locate_error(err, input))
+    }
+}
+
+impl<T: Token> LazyTree<'_, T> {
+    /* Builds the SyntaxTree for the node at the path, or None if there is no such node.
+     * Spans and token indices are the same as in the whole tree. */
+    pub fn expand(&self, path: &[usize]) -> Option<SyntaxTree<T>> {
+        self.node(path).map(|node| self.arena.to_final(node))
+    }
+
+    /* Builds the whole tree, the same one parse_tokens would have returned. */
+    pub fn materialize(&self) -> SyntaxTree<T> {
+        self.arena.to_final(self.root)
+    }
+
+    /* The rule at the path, or None if the path leads to a token or nowhere. */
+    pub fn rule_name(&self, path: &[usize]) -> Option<&str> {
+        match self.arena.get(self.node(path)?) {
+            IntermediateSyntaxTree::RuleNode { rule_name, .. } => Some(rule_name),
+            IntermediateSyntaxTree::TokenNode(..) => None,
+        }
+    }
+
+    pub fn span(&self, path: &[usize]) -> Option<Range<usize>> {
+        match self.arena.get(self.node(path)?) {
+            IntermediateSyntaxTree::RuleNode { span, .. } => Some(span.clone()),
+            IntermediateSyntaxTree::TokenNode(_, index, _) => Some(*index..*index + 1),
+        }
+    }
+
+    /* How many children the node at the path has, which is 0 for tokens. */
+    pub fn child_count(&self, path: &[usize]) -> Option<usize> {
+        match self.arena.get(self.node(path)?) {
+            IntermediateSyntaxTree::RuleNode { subexpressions, .. } => Some(subexpressions.len()),
+            IntermediateSyntaxTree::TokenNode(..) => Some(0),
+        }
+    }
+
+    /* The path to the token node for the token at index, or None if the index is past
+     * the tokens the tree covers. Handy for expanding only the region around a point,
+     * like the rule under the cursor. */
+    pub fn path_to_token(&self, index: usize) -> Option<Vec<usize>> {
+        let mut path = vec![];
+        let mut node = self.root;
+        loop {
+            match self.arena.get(node) {
+                IntermediateSyntaxTree::TokenNode(_, token_index, _) => return Some(path).filter(|_| *token_index == index),
+                IntermediateSyntaxTree::RuleNode { subexpressions, .. } => {
+                    let (i, &child) = subexpressions.iter().enumerate()
+                        .find(|(_, &child)| match self.arena.get(child) {
+                            IntermediateSyntaxTree::RuleNode { span, .. } => span.contains(&index),
+                            IntermediateSyntaxTree::TokenNode(_, token_index, _) => *token_index == index,
+                        })?;
+                    path.push(i);
+                    node = child;
+                }
+            }
+        }
+    }
+}
+
+
+/* Private Implementation */
+
+impl<T: Token> LazyTree<'_, T> {
+    fn node(&self, path: &[usize]) -> Option<NodeId> {
+        path.iter().try_fold(self.root, |node, &i| match self.arena.get(node) {
+            IntermediateSyntaxTree::RuleNode { subexpressions, .. } => subexpressions.get(i).copied(),
+            IntermediateSyntaxTree::TokenNode(..) => None,
+        })
+    }
+}
@@ -0,0 +1,194 @@
+/* The built in lexer. Grammars can mark rules with @token or @skip, which makes
+ * them lexical rules: they are parsed over characters, and the input is split into
+ * tokens by repeatedly matching them. Everything else in the grammar is parsed over
+ * the resulting LexedTokens. */
+
+use super::{CharToken, LineMap, ParseError, Parser, Token};
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+
+/* Public Interface */
+
+/* A token produced by the built in lexer. `kind` is the name of the @token rule
+ * that produced it, and `span` is the range of bytes it covers in the input.
+ *
+ * In the grammar, `_Kind` (or just the rule name `Kind`) matches any token of that
+ * kind, and a string literal like "let" matches any token with exactly that text
+ * (or, for i"let", the same text ignoring case). */
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LexedToken {
+    pub kind: String,
+    pub text: String,
+    pub span: Range<usize>,
+}
+
+/* What a terminal matches in a grammar over LexedTokens. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexedTokenKind {
+    Kind (String),  // Any token made by the @token rule with this name.
+    Text (String),  // Any token with exactly this text.
+    TextIgnoringCase (String),  // Any token with this text, compared in lowercase. Stored lowercase.
+}
+
+impl Token for LexedToken {
+    type Kind = LexedTokenKind;
+
+    fn kind(token_type: &str) -> Option<LexedTokenKind> {
+        if let Some(text) = token_type.strip_prefix("i\"").and_then(|rest| rest.strip_suffix('"')) {
+            return Some(LexedTokenKind::TextIgnoringCase(text.to_lowercase()));
+        }
+
+        match token_type.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+            Some(text) => Some(LexedTokenKind::Text(text.to_string())),
+            None => Some(LexedTokenKind::Kind(token_type.to_string())),
+        }
+    }
+
+    fn matches(kind: &LexedTokenKind, token: &Self) -> bool {
+        match kind {
+            LexedTokenKind::Kind(kind) => *kind == token.kind,
+            LexedTokenKind::Text(text) => *text == token.text,
+            LexedTokenKind::TextIgnoringCase(text) => *text == token.text.to_lowercase(),
+        }
+    }
+
+    fn span(&self) -> Option<Range<usize>> {
+        Some(self.span.clone())
+    }
+
+    // Literals are kept whole and quoted, so that they can't be confused with kinds.
+    fn type_sequence_from_literal(literal: &str) -> Option<Vec<String>> {
+        Some(vec![format!("\"{literal}\"")])
+    }
+
+    fn type_sequence_from_literal_ignoring_case(literal: &str) -> Option<Vec<String>> {
+        Some(vec![format!("i\"{literal}\"")])
+    }
+}
+
+impl std::fmt::Display for LexedToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Parser<LexedToken> {
+    /* Runs only the lexical phase, splitting the input into tokens. On failure, the
+     * error's index is the index of the character that no token rule matched. */
+    pub fn tokenize(&self, input: &str) -> Result<Vec<LexedToken>, ParseError> {
+        self.tokenize_with_trivia(input).map(|(tokens, _)| tokens)
+    }
+
+    /* Like tokenize, but also returns the trivia: the tokens matched by @skip rules,
+     * in order. Their kind is the name of the @skip rule, so comments and whitespace
+     * can be told apart, and their spans show which tokens they sit between. */
+    pub fn tokenize_with_trivia(&self, input: &str) -> Result<(Vec<LexedToken>, Vec<LexedToken>), ParseError> {
+        match &self.lexer {
+            Some(lexer) => lexer.tokenize(input),
+            None => Err(ParseError::Internal("Parser has no @token rules, so it cannot tokenize".to_string())),
+        }
+    }
+
+    /* Tokenizes the input, then parses the tokens starting from start_rule. */
+    pub fn parse_string(&self, input: &str, start_rule: &str) -> Result<super::SyntaxTree<LexedToken>, ParseError> {
+        self.parse_string_with_trivia(input, start_rule).map(|(tree, _)| tree)
+    }
+
+    /* Like parse_string, but also returns the trivia, as in tokenize_with_trivia. */
+    pub fn parse_string_with_trivia(&self, input: &str, start_rule: &str) 
+            -> Result<(super::SyntaxTree<LexedToken>, Vec<LexedToken>), ParseError> {
+        self.parse_string_keeping_trivia(input, start_rule, self.lossless)
+    }
+}
+
+
+/* Private Implementation */
+
+impl Parser<LexedToken> {
+    // Like parse_string_with_trivia, but the caller decides whether the trivia also goes in the tree.
+    pub(crate) fn parse_string_keeping_trivia(&self, input: &str, start_rule: &str, lossless: bool)
+            -> Result<(super::SyntaxTree<LexedToken>, Vec<LexedToken>), ParseError> {
+        let (tokens, trivia) = self.tokenize_with_trivia(input)?;
+        self.parse_tokens(&tokens, start_rule)
+            .map(|tree| match lossless {
+                true => (super::lossless::add_trivia(tree, &tokens, &trivia), trivia),
+                false => (tree, trivia),
+            })
+            .map_err(|err| match err {
+                ParseError::UnexpectedToken { index, terminals, expected, context, span, .. } => ParseError::UnexpectedToken {
+                    index,
+                    terminals,
+                    expected,
+                    context,
+                    location: Some(LineMap::new(input).location_of_byte(tokens[index].span.start)),
+                    span,
+                },
+                err => err,
+            })
+    }
+}
+
+pub(crate) struct Lexer {
+    pub(crate) parser: Parser<CharToken>,  // Unanchored, holds every lexical rule.
+    pub(crate) token_rules: Vec<(String, bool)>,  // In definition order. True if the rule is skipped.
+}
+
+impl Lexer {
+    /* At each position, every token rule is tried and the longest match wins (so
+     * "letter" is an identifier, not the keyword "let" followed by "ter"). Ties go
+     * to the rule defined first. Rules that match nothing are ignored. */
+    fn tokenize(&self, input: &str) -> Result<(Vec<LexedToken>, Vec<LexedToken>), ParseError> {
+        let chars = super::string_to_tokens(input);
+        let byte_offsets = input.char_indices()
+            .map(|(offset, _)| offset)
+            .chain(std::iter::once(input.len()))
+            .collect::<Vec<usize>>();
+
+        let mut tokens = vec![];
+        let mut trivia = vec![];
+        let mut index = 0;
+        while index < chars.len() {
+            let mut longest: Option<(usize, &(String, bool))> = None;
+            for rule in &self.token_rules {
+                match self.parser.parse_tokens(&chars[index..], &rule.0) {
+                    Ok(tree) => {
+                        let length = tree.span().end;
+                        if length > 0 && longest.is_none_or(|(longest_length, _)| length > longest_length) {
+                            longest = Some((length, rule));
+                        }
+                    }
+                    Err(ParseError::UnexpectedToken { .. } | ParseError::UnexpectedEof { .. }) => (),
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let Some((length, (kind, skip))) = longest else {
+                return Err(ParseError::UnexpectedToken {
+                    index,
+                    terminals: self.token_rules.iter().map(|(kind, _)| kind.clone()).collect::<HashSet<String>>(),
+                    expected: self.token_rules.iter()
+                        .map(|(kind, _)| self.parser.labels.get(kind).unwrap_or(kind).clone())
+                        .collect::<HashSet<String>>(),
+                    context: vec![],
+                    location: Some(LineMap::new(input).location_of_char(index)),
+                    span: Some(byte_offsets[index]..byte_offsets[index + 1]),
+                });
+            };
+
+            let span = byte_offsets[index]..byte_offsets[index + length];
+            let token = LexedToken { kind: kind.clone(), text: input[span.clone()].to_string(), span };
+            if *skip {
+                trivia.push(token);
+            }
+            else {
+                tokens.push(token);
+            }
+            index += length;
+        }
+
+        Ok((tokens, trivia))
+    }
+}
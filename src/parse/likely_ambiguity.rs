@@ -0,0 +1,259 @@
+/* Looks for ambiguity in the grammar itself, before any input shows up to trip over
+ * it. Whether a grammar is ambiguous can't be decided in general, so this only tries
+ * every input up to some number of tokens, and reports the places in the rules where
+ * one of them can be matched more than one way:
+ *
+ *     A choice where two alternatives match the same input (vertical ambiguity).
+ *     A sequence where the input can be split between the parts in more than one way,
+ *     like `"a"? "a"?` matching a single "a" (horizontal ambiguity).
+ *     A repetition where the input can be split into repeats in more than one way,
+ *     like `("a" | "a" "a")*` matching "a" "a".
+ *
+ * Each place is checked on its own, trusting the rules inside it to have one way to
+ * match each input, so an ambiguous rule is blamed where it is defined rather than
+ * everywhere it is used.
+ *
+ * It's a heuristic, in both directions. Inputs are sequences of terminals as written,
+ * so "a" and [a-z] are never found to match the same token. Lookaheads and predicates
+ * are ignored, so something they make unambiguous is still reported. And ambiguity
+ * that only shows up in longer inputs isn't found. The alternatives of ordered choices
+ * aren't compared, since the first one that matches always wins. */
+
+use crate::define::{RuleExpression, describe_expression};
+use super::{Parser, Token};
+
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+
+
+/* Public Interface */
+
+/* Alternatives are numbered from 0, within the choice they belong to, like
+ * GrammarWarning's. The example is an input that can be matched more than one way,
+ * as the terminals that make it up. */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LikelyAmbiguity {
+    Alternatives { rule_name: String, alternative: usize, with: usize, example: Vec<String> },
+    Sequence { rule_name: String, example: Vec<String> },
+    Repetition { rule_name: String, example: Vec<String> },
+}
+
+impl std::fmt::Display for LikelyAmbiguity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |example: &[String]| match example {
+            [] => "nothing".to_string(),
+            _ => example.iter().map(|terminal| format!("\"{terminal}\"")).join(" "),
+        };
+        match self {
+            LikelyAmbiguity::Alternatives { rule_name, alternative, with, example } =>
+                write!(f, "Alternatives {with} and {alternative} of a choice in \"{rule_name}\" can both match {}", list(example)),
+            LikelyAmbiguity::Sequence { rule_name, example } =>
+                write!(f, "A sequence in \"{rule_name}\" can match {} in more than one way", list(example)),
+            LikelyAmbiguity::Repetition { rule_name, example } =>
+                write!(f, "Something repeated in \"{rule_name}\" can match {} in more than one way", list(example)),
+        }
+    }
+}
+
+impl<T: Token> Parser<T> {
+    /* Tries every input of up to max_tokens tokens, see the module docs, and returns
+     * what it finds sorted by kind and then by rule name, with the shortest example for
+     * each. The work grows exponentially with max_tokens, so start small: 4 or 5 finds
+     * most things in most grammars. */
+    pub fn likely_ambiguities(&self, max_tokens: usize) -> Vec<LikelyAmbiguity> {
+        let mut languages = Languages { rules: HashMap::new(), terminals: HashMap::new(), max_tokens };
+        languages.find_rules(self);
+
+        let mut found = vec![];
+        for (rule_name, expr) in &self.rules {
+            let mut check = Check { languages: &mut languages, rule_name, ordered_choice: self.ordered_choice, found: &mut found };
+            check.expr(expr);
+        }
+
+        found.sort();
+        found.dedup();
+        found
+    }
+}
+
+
+/* Private Implementation */
+
+type Sentence = Vec<usize>;  // Terminals, numbered by Languages.
+type Language = HashSet<Sentence>;
+
+// Every sentence up to max_tokens long that each rule can match.
+struct Languages<'p> {
+    rules: HashMap<&'p str, Language>,
+    terminals: HashMap<String, usize>,
+    max_tokens: usize,
+}
+
+impl<'p> Languages<'p> {
+    // Languages only ever grow, so comparing sizes is enough.
+    fn find_rules<T: Token>(&mut self, parser: &'p Parser<T>) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule_name, expr) in &parser.rules {
+                let language = self.language(expr);
+                if self.rules.get(rule_name.as_str()).map(HashSet::len) != Some(language.len()) {
+                    self.rules.insert(rule_name.as_str(), language);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    fn language(&mut self, expr: &RuleExpression) -> Language {
+        match expr {
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_) | RuleExpression::Wildcard
+            | RuleExpression::Negation(..) | RuleExpression::External(_) => {
+                let next = self.terminals.len();
+                let terminal = *self.terminals.entry(describe_expression(expr)).or_insert(next);
+                match self.max_tokens {
+                    0 => Language::new(),
+                    _ => HashSet::from([vec![terminal]]),
+                }
+            }
+            RuleExpression::RuleName(rule_name) => self.rules.get(rule_name.as_str()).cloned().unwrap_or_default(),
+            RuleExpression::Concatenation(exprs) => exprs.iter()
+                .fold(empty_sentence(), |language, expr| {
+                    let next = self.language(expr);
+                    self.concatenate(&language, &next)
+                }),
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+                exprs.iter().flat_map(|expr| self.language(expr)).collect()
+            }
+            RuleExpression::Optional(inner) => self.repeat(inner, 0, Some(1)),
+            RuleExpression::Many(inner) => self.repeat(inner, 0, None),
+            RuleExpression::OneOrMore(inner) => self.repeat(inner, 1, None),
+            RuleExpression::Repetition(inner, min, max) => self.repeat(inner, *min, *max),
+            RuleExpression::PositiveLookahead(_) | RuleExpression::NegativeLookahead(..) | RuleExpression::EndOfInput => empty_sentence(),
+        }
+    }
+
+    // Stops early once more repeats can't make anything new, since no sentence can get any longer.
+    fn repeat(&mut self, inner: &RuleExpression, min: usize, max: Option<usize>) -> Language {
+        let inner = self.language(inner);
+        let mut repeated = empty_sentence();  // Exactly `count` repeats.
+        let mut language = Language::new();
+        let mut count = 0;
+        loop {
+            if count >= min {
+                let before = language.len();
+                language.extend(repeated.iter().cloned());
+                if language.len() == before && count > min {
+                    return language;
+                }
+            }
+            if max.is_some_and(|max| count >= max) || repeated.is_empty() {
+                return language;
+            }
+            repeated = self.concatenate(&repeated, &inner);
+            count += 1;
+        }
+    }
+
+    fn concatenate(&self, left: &Language, right: &Language) -> Language {
+        left.iter()
+            .flat_map(|left| right.iter().map(move |right| [left.as_slice(), right].concat()))
+            .filter(|sentence| sentence.len() <= self.max_tokens)
+            .collect()
+    }
+
+    fn describe(&self, sentence: &Sentence) -> Vec<String> {
+        let names = self.terminals.iter().map(|(name, &terminal)| (terminal, name)).collect::<HashMap<_, _>>();
+        sentence.iter().map(|terminal| names[terminal].clone()).collect()
+    }
+
+    // The shortest, and then the first as written.
+    fn example<'s>(&self, sentences: impl Iterator<Item = &'s Sentence>) -> Option<Vec<String>> {
+        sentences.map(|sentence| self.describe(sentence)).min_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)))
+    }
+}
+
+fn empty_sentence() -> Language {
+    HashSet::from([vec![]])
+}
+
+// Walks one rule's expression, checking every choice, sequence and repetition in it.
+struct Check<'c, 'p> {
+    languages: &'c mut Languages<'p>,
+    rule_name: &'c str,
+    ordered_choice: bool,  // Every choice is ordered.
+    found: &'c mut Vec<LikelyAmbiguity>,
+}
+
+impl Check<'_, '_> {
+    fn expr(&mut self, expr: &RuleExpression) {
+        match expr {
+            RuleExpression::Alternatives(exprs) if !self.ordered_choice => {
+                let languages = exprs.iter().map(|expr| self.languages.language(expr)).collect::<Vec<_>>();
+                for (alternative, language) in languages.iter().enumerate() {
+                    for (with, earlier) in languages[..alternative].iter().enumerate() {
+                        if let Some(example) = self.languages.example(earlier.intersection(language)) {
+                            self.found.push(LikelyAmbiguity::Alternatives { rule_name: self.rule_name.to_string(), alternative, with, example });
+                        }
+                    }
+                }
+                exprs.iter().for_each(|expr| self.expr(expr));
+            }
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => exprs.iter().for_each(|expr| self.expr(expr)),
+            RuleExpression::Concatenation(exprs) => {
+                let parts = exprs.iter().map(|expr| self.languages.language(expr)).collect::<Vec<_>>();
+                let language = self.languages.language(expr);
+                let ambiguous = language.iter().filter(|sentence| splits(sentence, &parts) > 1).collect::<Vec<_>>();
+                if let Some(example) = self.languages.example(ambiguous.into_iter()) {
+                    self.found.push(LikelyAmbiguity::Sequence { rule_name: self.rule_name.to_string(), example });
+                }
+                exprs.iter().for_each(|expr| self.expr(expr));
+            }
+            RuleExpression::Many(inner) | RuleExpression::OneOrMore(inner) | RuleExpression::Repetition(inner, ..) => {
+                let repeats = !matches!(expr, RuleExpression::Repetition(_, _, Some(0 | 1)));
+                if repeats {
+                    let inner_language = self.languages.language(inner);
+                    let language = self.languages.language(expr);
+                    let ambiguous = language.iter().filter(|sentence| repeat_splits(sentence, &inner_language) > 1).collect::<Vec<_>>();
+                    if let Some(example) = self.languages.example(ambiguous.into_iter()) {
+                        self.found.push(LikelyAmbiguity::Repetition { rule_name: self.rule_name.to_string(), example });
+                    }
+                }
+                self.expr(inner);
+            }
+            RuleExpression::Optional(inner) | RuleExpression::PositiveLookahead(inner) | RuleExpression::NegativeLookahead(inner, _) => self.expr(inner),
+            _ => (),
+        }
+    }
+}
+
+// How many ways the sentence can be split between the parts, in order. Stops counting at 2.
+fn splits(sentence: &Sentence, parts: &[Language]) -> usize {
+    let mut ways = vec![0; sentence.len() + 1];  // Ways to match the sentence up to each index with the parts so far.
+    ways[0] = 1;
+    for part in parts {
+        let mut next = vec![0; sentence.len() + 1];
+        for start in 0..=sentence.len() {
+            for end in (start..=sentence.len()).filter(|&end| ways[start] > 0 && part.contains(&sentence[start..end])) {
+                next[end] = (next[end] + ways[start]).min(2);
+            }
+        }
+        ways = next;
+    }
+    ways[sentence.len()]
+}
+
+// How many ways the sentence can be split into repeats. Repeats that match nothing are left out, see GrammarWarning::NullableRepetition.
+fn repeat_splits(sentence: &Sentence, inner: &Language) -> usize {
+    let mut ways = vec![0; sentence.len() + 1];
+    ways[0] = 1;
+    for end in 1..=sentence.len() {
+        ways[end] = (0..end)
+            .filter(|&start| inner.contains(&sentence[start..end]))
+            .map(|start| ways[start])
+            .sum::<usize>()
+            .min(2);
+    }
+    ways[sentence.len()]
+}
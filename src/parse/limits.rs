@@ -0,0 +1,109 @@
+/* Limits on how much work a parse may do, so that a grammar that blows up on some input
+ * fails with ParseError::LimitExceeded, naming the rule and the token where it happened,
+ * instead of quietly eating all the time and memory there is. Unlike a ParseBudget,
+ * these count work rather than time, so a parse that exceeds them does it every time,
+ * on every machine, which makes them fit for tests.
+ *
+ * Only the backtracking algorithm checks them, since it is the one that blows up. With
+ * ParseAlgorithm::Auto, that is any grammar that isn't LL(1).
+ *
+ * Going over the memory limit is an error too, rather than a reason to throw memo
+ * entries away. The parse reads every entry it asks for as soon as it is made, and
+ * left recursion is grown in place in the memo map, so there is nothing it could
+ * safely forget part way through. */
+
+use super::{Parser, Token};
+
+use std::fmt::Display;
+
+
+/* Public Interface */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseLimits {
+    depth: Option<usize>,
+    steps: Option<usize>,
+    continuations: Option<usize>,
+    memory: Option<usize>,
+}
+
+/* Which limit was exceeded, and what it was set to. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Depth (usize),
+    Steps (usize),
+    Continuations (usize),
+    Memory (usize),
+}
+
+impl ParseLimits {
+    pub fn new() -> ParseLimits {
+        ParseLimits::default()
+    }
+
+    /* How many rules can be inside each other at once. */
+    pub fn depth(mut self, depth: usize) -> ParseLimits {
+        self.depth = Some(depth);
+        self
+    }
+
+    /* How many steps the whole parse can take. A step is roughly one expression
+     * tried at one token. */
+    pub fn steps(mut self, steps: usize) -> ParseLimits {
+        self.steps = Some(steps);
+        self
+    }
+
+    /* How many ways one expression can match starting at one token. Ambiguous
+     * grammars can have exponentially many. */
+    pub fn continuations(mut self, continuations: usize) -> ParseLimits {
+        self.continuations = Some(continuations);
+        self
+    }
+
+    /* Roughly how many bytes the memo map and the trees in progress can take up. The
+     * count leaves out hash table overhead and whatever the tokens allocate, so leave
+     * some room. */
+    pub fn memory(mut self, bytes: usize) -> ParseLimits {
+        self.memory = Some(bytes);
+        self
+    }
+}
+
+impl<T: Token> Parser<T> {
+    pub fn set_limits(&mut self, limits: ParseLimits) {
+        self.limits = limits;
+    }
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Depth(depth) => write!(f, "rule depth limit of {depth}"),
+            Limit::Steps(steps) => write!(f, "step limit of {steps}"),
+            Limit::Continuations(continuations) => write!(f, "limit of {continuations} matches per expression"),
+            Limit::Memory(bytes) => write!(f, "memory limit of {bytes} bytes"),
+        }
+    }
+}
+
+
+/* Private Implementation */
+
+impl ParseLimits {
+    pub(crate) fn check_depth(&self, depth: usize) -> Option<Limit> {
+        self.depth.filter(|max| depth > *max).map(Limit::Depth)
+    }
+
+    pub(crate) fn check_steps(&self, steps: usize) -> Option<Limit> {
+        self.steps.filter(|max| steps > *max).map(Limit::Steps)
+    }
+
+    pub(crate) fn check_continuations(&self, continuations: usize) -> Option<Limit> {
+        self.continuations.filter(|max| continuations > *max).map(Limit::Continuations)
+    }
+
+    pub(crate) fn check_memory(&self, bytes: impl FnOnce() -> usize) -> Option<Limit> {
+        self.memory.filter(|max| bytes() > *max).map(Limit::Memory)
+    }
+}
@@ -0,0 +1,235 @@
+/* Checks for things in a grammar that are allowed, but probably not what was meant.
+ * None of these stop the parser from working, so they are warnings rather than
+ * DefinitionErrors, and it's up to the user whether to look at them. */
+
+use super::{Parser, Token};
+use crate::define::RuleExpression;
+
+use std::collections::{HashMap, HashSet};
+
+
+/* Something suspicious about a rule. Alternatives are numbered from 0, within the
+ * choice they belong to, which is usually the whole body of the rule. */
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GrammarWarning {
+    Unproductive { rule_name: String },  // The rule can never match, since it always needs itself (or another such rule) first.
+    UnmatchableAlternative { rule_name: String, alternative: usize },  // The alternative uses a rule that can never match.
+    /* The alternative is never used: either it is the same as an earlier one, or the
+     * choice is ordered and an earlier one can match nothing, so that one always wins. */
+    ShadowedAlternative { rule_name: String, alternative: usize, by: usize },
+    NullableRepetition { rule_name: String },  // Repeats something that can match nothing, like ("a"?)*.
+    SingleUse { rule_name: String, used_by: String },  // Only used once, so it could be written inline.
+    /* No rule that nothing else uses leads to it, so it can only be reached by starting
+     * the parse from it (or another such rule). Not reported when every rule is used by
+     * another, since then any of them could be the start rule. */
+    Unreachable { rule_name: String },
+}
+
+impl std::fmt::Display for GrammarWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrammarWarning::Unproductive { rule_name } =>
+                write!(f, "Rule \"{rule_name}\" can never match, every way to match it needs itself first"),
+            GrammarWarning::UnmatchableAlternative { rule_name, alternative } =>
+                write!(f, "Alternative {alternative} of a choice in \"{rule_name}\" uses a rule that can never match"),
+            GrammarWarning::ShadowedAlternative { rule_name, alternative, by } =>
+                write!(f, "Alternative {alternative} of a choice in \"{rule_name}\" is never used, alternative {by} always wins"),
+            GrammarWarning::NullableRepetition { rule_name } =>
+                write!(f, "Rule \"{rule_name}\" repeats something that can match nothing"),
+            GrammarWarning::SingleUse { rule_name, used_by } =>
+                write!(f, "Rule \"{rule_name}\" is only used once (by \"{used_by}\"), and could be inlined"),
+            GrammarWarning::Unreachable { rule_name } =>
+                write!(f, "Rule \"{rule_name}\" can't be reached from any rule that nothing else uses"),
+        }
+    }
+}
+
+impl<T: Token> Parser<T> {
+    /* Lints the grammar, returning warnings sorted by kind and then by rule name. This
+     * looks at the syntactic rules only, and runs every time it's called, so hang on to
+     * the result if you need it twice.
+     *
+     * Rules with a label, predicates, actions or sync tokens are never reported as
+     * single use, since inlining them would lose those. */
+    pub fn warnings(&self) -> Vec<GrammarWarning> {
+        let productive = self.productive_rules();
+        let nullable = self.nullable_rules();
+        let mut warnings = vec![];
+
+        for (rule_name, expr) in &self.rules {
+            if !productive.contains(rule_name.as_str()) {
+                warnings.push(GrammarWarning::Unproductive { rule_name: rule_name.to_string() });
+            }
+
+            let mut lint = Lint { parser: self, rule_name, productive: &productive, nullable: &nullable, warnings: &mut warnings };
+            lint.expr(expr);
+        }
+
+        let mut uses: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (rule_name, expr) in &self.rules {
+            let mut names = vec![];
+            expr.referenced_rules(&mut names);
+            for name in names {
+                uses.entry(name).or_default().push(rule_name);
+            }
+        }
+        for (rule_name, users) in uses {
+            let keeps_its_name = self.labels.contains_key(rule_name) || self.predicates.contains_key(rule_name)
+                || self.stateful_predicates.contains_key(rule_name) || self.actions.contains_key(rule_name)
+                || self.sync_tokens.contains_key(rule_name);
+
+            if let [used_by] = users[..] {
+                if used_by != rule_name && self.rules.contains_key(rule_name) && !keeps_its_name {
+                    warnings.push(GrammarWarning::SingleUse { rule_name: rule_name.to_string(), used_by: used_by.to_string() });
+                }
+            }
+        }
+
+        warnings.extend(self.unreachable_rules().into_iter().map(|rule_name| GrammarWarning::Unreachable { rule_name: rule_name.to_string() }));
+
+        warnings.sort();
+        warnings.dedup();
+        warnings
+    }
+}
+
+
+/* Private Implementation */
+
+impl<T: Token> Parser<T> {
+    // Rules that match at least one input, found by growing the set until it stops changing.
+    fn productive_rules(&self) -> HashSet<&str> {
+        let mut productive = HashSet::new();
+        loop {
+            let found = self.rules.iter()
+                .filter(|(rule_name, expr)| !productive.contains(rule_name.as_str()) && is_productive(expr, &productive))
+                .map(|(rule_name, _)| rule_name.as_str())
+                .collect::<Vec<_>>();
+
+            if found.is_empty() {
+                return productive;
+            }
+            productive.extend(found);
+        }
+    }
+
+    /* We don't know which rule the user will start parsing from, but any rule that no
+     * other rule uses is a likely candidate. If every rule is used by some other rule,
+     * any of them could be the start. */
+    fn unreachable_rules(&self) -> Vec<&str> {
+        let references = self.rules.iter()
+            .map(|(rule_name, expr)| {
+                let mut names = vec![];
+                expr.referenced_rules(&mut names);
+                (rule_name.as_str(), names)
+            })
+            .collect::<HashMap<&str, Vec<&str>>>();
+
+        let start_rules = references.keys()
+            .filter(|rule_name| !references.iter().any(|(other, names)| other != *rule_name && names.contains(rule_name)))
+            .copied()
+            .collect::<Vec<&str>>();
+        if start_rules.is_empty() {
+            return vec![];
+        }
+
+        let mut reached = start_rules.iter().copied().collect::<HashSet<&str>>();
+        let mut to_visit = start_rules;
+        while let Some(rule_name) = to_visit.pop() {
+            for name in references.get(rule_name).into_iter().flatten() {
+                if reached.insert(name) {
+                    to_visit.push(name);
+                }
+            }
+        }
+
+        references.into_keys().filter(|rule_name| !reached.contains(rule_name)).collect()
+    }
+
+    fn nullable_rules(&self) -> HashSet<&str> {
+        let mut nullable = HashSet::new();
+        loop {
+            let found = self.rules.iter()
+                .filter(|(rule_name, expr)| !nullable.contains(rule_name.as_str()) && is_nullable(expr, &nullable))
+                .map(|(rule_name, _)| rule_name.as_str())
+                .collect::<Vec<_>>();
+
+            if found.is_empty() {
+                return nullable;
+            }
+            nullable.extend(found);
+        }
+    }
+}
+
+fn is_productive(expr: &RuleExpression, productive: &HashSet<&str>) -> bool {
+    match expr {
+        RuleExpression::RuleName(rule_name) => productive.contains(rule_name.as_str()),
+        RuleExpression::Concatenation(exprs) => exprs.iter().all(|expr| is_productive(expr, productive)),
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs)
+            => exprs.iter().any(|expr| is_productive(expr, productive)),
+        RuleExpression::OneOrMore(inner) | RuleExpression::Repetition(inner, 1.., _) => is_productive(inner, productive),
+        _ => true,
+    }
+}
+
+// External rules are left out, since their scanner could do anything.
+fn is_nullable(expr: &RuleExpression, nullable: &HashSet<&str>) -> bool {
+    match expr {
+        RuleExpression::RuleName(rule_name) => nullable.contains(rule_name.as_str()),
+        RuleExpression::Concatenation(exprs) => exprs.iter().all(|expr| is_nullable(expr, nullable)),
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs)
+            => exprs.iter().any(|expr| is_nullable(expr, nullable)),
+        RuleExpression::OneOrMore(inner) | RuleExpression::Repetition(inner, 1.., _) => is_nullable(inner, nullable),
+        RuleExpression::Optional(_) | RuleExpression::Many(_) | RuleExpression::Repetition(..)
+        | RuleExpression::PositiveLookahead(_) | RuleExpression::NegativeLookahead(..) => true,
+        RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_) | RuleExpression::Negation(..)
+        | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => false,
+    }
+}
+
+// Walks one rule's expression, collecting warnings about it.
+struct Lint<'l, T: Token> {
+    parser: &'l Parser<T>,
+    rule_name: &'l str,
+    productive: &'l HashSet<&'l str>,
+    nullable: &'l HashSet<&'l str>,
+    warnings: &'l mut Vec<GrammarWarning>,
+}
+
+impl<T: Token> Lint<'_, T> {
+    fn expr(&mut self, expr: &RuleExpression) {
+        match expr {
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+                let ordered = self.parser.ordered_choice || matches!(expr, RuleExpression::OrderedAlternatives(_));
+                let rule_name = self.rule_name.to_string();
+
+                for (alternative, alternative_expr) in exprs.iter().enumerate() {
+                    let earlier = &exprs[..alternative];
+                    let shadowed_by = earlier.iter().position(|earlier_expr| earlier_expr == alternative_expr)
+                        .or_else(|| earlier.iter().position(|earlier_expr| ordered && is_nullable(earlier_expr, self.nullable)));
+
+                    if let Some(by) = shadowed_by {
+                        self.warnings.push(GrammarWarning::ShadowedAlternative { rule_name: rule_name.clone(), alternative, by });
+                    }
+                    // Only worth pointing out if the rest of the rule can match, otherwise the rule itself is reported.
+                    else if !is_productive(alternative_expr, self.productive) && self.productive.contains(self.rule_name) {
+                        self.warnings.push(GrammarWarning::UnmatchableAlternative { rule_name: rule_name.clone(), alternative });
+                    }
+                    self.expr(alternative_expr);
+                }
+            }
+            RuleExpression::Many(inner) | RuleExpression::OneOrMore(inner) | RuleExpression::Repetition(inner, _, None) => {
+                if is_nullable(inner, self.nullable) {
+                    self.warnings.push(GrammarWarning::NullableRepetition { rule_name: self.rule_name.to_string() });
+                }
+                self.expr(inner);
+            }
+            RuleExpression::Concatenation(exprs) => exprs.iter().for_each(|expr| self.expr(expr)),
+            RuleExpression::Optional(inner) | RuleExpression::Repetition(inner, ..) | RuleExpression::PositiveLookahead(inner)
+            | RuleExpression::NegativeLookahead(inner, _) => self.expr(inner),
+            RuleExpression::Terminal(_) | RuleExpression::RuleName(_) | RuleExpression::CharacterClass(_) | RuleExpression::Negation(..)
+            | RuleExpression::Wildcard | RuleExpression::EndOfInput | RuleExpression::External(_) => (),
+        }
+    }
+}
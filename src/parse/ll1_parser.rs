@@ -0,0 +1,550 @@
+/* A table driven LL(1) parser, used as a fast path for the parts of a grammar that
+ * can be parsed deterministically.
+ *
+ * When a parser is made, every rule is checked. A rule is LL(1) if whenever it has
+ * a choice to make (between alternatives, or whether to repeat something again), a
+ * single token of lookahead is enough to make it. Those rules get prediction tables:
+ * for each option, the terminals that can start it (or follow it, if it can match
+ * nothing). Left recursion, lookaheads and repetitions of things that can match
+ * nothing are never LL(1).
+ *
+ * Terminals are only compared by how they are written, and two different terminals
+ * can match the same token (like "a" and [a-z]). So the tables are checked again
+ * against the actual tokens while parsing, and the parser gives up as soon as a
+ * token predicts more than one option. Giving up (or failing to parse) just means
+ * the general parser takes over, which also makes sure errors are the same.
+ *
+ * Ordered choice needs no special treatment, since only one option can start with
+ * the next token anyway. Unless an earlier option can match nothing, in which case
+ * ordered choice would take it, so that is left to the general parser too. */
+
+use crate::{Token, define::RuleExpression};
+use super::{ParseEvent, Parser, Symbol, SyntaxTree};
+use super::backtracking_parser::single_token_matches;
+use super::observe::Observers;
+use super::progress::ProgressReporter;
+use super::trace;
+
+use std::collections::{HashMap, HashSet};
+
+
+// Returns None if the fast path can't be used, or could not parse the input.
+pub fn ll1_parse<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, anchored: bool) -> Option<SyntaxTree<T>> {
+    let events = ll1_events(parser, tokens, start_rule, anchored)?;
+
+    let mut stack: Vec<(&Symbol, usize, Vec<SyntaxTree<T>>)> = vec![];
+    for event in events {
+        match event {
+            Ll1Event::Enter(rule_name, start) => stack.push((rule_name, start, vec![])),
+            Ll1Event::Token(index, expr) => {
+                let token = &tokens[index];
+                let node = SyntaxTree::TokenNode { token: token.clone(), index, captured: parser.capture(expr, token) };
+                stack.last_mut()?.2.push(node);
+            }
+            Ll1Event::Exit(end) => {
+                let (rule_name, start, subexpressions) = stack.pop()?;
+                let node = SyntaxTree::RuleNode { rule_name: rule_name.clone(), subexpressions, span: start..end };
+                match stack.last_mut() {
+                    Some(parent) => parent.2.push(node),
+                    None => return Some(node),
+                }
+            }
+        }
+    }
+    None
+}
+
+// Like ll1_parse, but hands each event to the callback instead of building a tree.
+// Nothing is passed to it unless the whole parse succeeds.
+pub fn ll1_parse_events<T: Token>(parser: &Parser<T>, tokens: &[T], start_rule: &str, anchored: bool,
+        on_event: &mut dyn FnMut(ParseEvent<T>)) -> bool {
+    let Some(events) = ll1_events(parser, tokens, start_rule, anchored) else {
+        return false;
+    };
+
+    let mut rule_names = vec![];
+    for event in events {
+        match event {
+            Ll1Event::Enter(rule_name, _) => {
+                rule_names.push(rule_name);
+                on_event(ParseEvent::Enter(rule_name));
+            }
+            Ll1Event::Token(index, _) => on_event(ParseEvent::Token(&tokens[index])),
+            Ll1Event::Exit(_) => on_event(ParseEvent::Exit(rule_names.pop().expect("Every exit has an enter"))),
+        }
+    }
+    true
+}
+
+fn ll1_events<'p, T: Token>(parser: &'p Parser<T>, tokens: &[T], start_rule: &str, anchored: bool) -> Option<Vec<Ll1Event<'p>>> {
+    if !anchored || !parser.predicates.is_empty() || !parser.prediction_tables.contains(start_rule) {
+        return None;
+    }
+
+    let _span = trace::parse_span("ll1", start_rule, tokens.len());
+    let progress = ProgressReporter::new(parser, tokens.len());
+    let mut state = Ll1State { parser, tokens, index: 0, events: vec![], progress, observers: &parser.observers };
+    state.parse_rule(start_rule)?;
+
+    Some(state.events).filter(|_| state.index == tokens.len())
+}
+
+
+/* Tables */
+
+pub(crate) struct PredictionTables {
+    rules: HashMap<Symbol, Step>,  // Only the rules that are LL(1), and only use LL(1) rules.
+}
+
+impl PredictionTables {
+    pub(crate) fn new(rules: &HashMap<Symbol, RuleExpression>) -> PredictionTables {
+        let mut analysis = Analysis::new(rules);
+
+        // Any rule can be the start rule, so any rule can be followed by the end of the input.
+        for rule_name in rules.keys() {
+            analysis.follows.insert(rule_name.to_string(), TokenSet { tokens: vec![], end: true });
+        }
+
+        let mut compiled = HashMap::new();
+        analysis.changed = true;
+        while analysis.changed {
+            analysis.changed = false;
+            compiled = rules.iter()
+                .filter_map(|(rule_name, expr)| {
+                    let follow = analysis.follows[rule_name.as_str()].clone();
+                    analysis.compile(expr, &follow).map(|step| (rule_name.clone(), step))
+                })
+                .collect::<HashMap<Symbol, Step>>();
+        }
+
+        for rule_name in left_recursive_rules(rules, &analysis) {
+            compiled.remove(rule_name.as_str());
+        }
+
+        // A rule that uses a rule that isn't LL(1) can't be parsed with the tables either.
+        loop {
+            let unusable = compiled.iter()
+                .filter(|(_, step)| step.rule_names().iter().any(|rule_name| !compiled.contains_key(*rule_name)))
+                .map(|(rule_name, _)| rule_name.clone())
+                .collect::<Vec<_>>();
+
+            if unusable.is_empty() {
+                break;
+            }
+            for rule_name in unusable {
+                compiled.remove(&rule_name);
+            }
+        }
+
+        PredictionTables { rules: compiled }
+    }
+
+    pub(crate) fn contains(&self, rule_name: &str) -> bool {
+        self.rules.contains_key(rule_name)
+    }
+}
+
+// A rule expression, with the tokens that predict each choice attached.
+enum Step {
+    Token (RuleExpression),  // Anything that matches exactly one token.
+    EndOfInput,
+    Rule (Symbol),
+    Sequence (Vec<Step>),
+    /* Each option, with the tokens that predict it. The flag is set if an option other
+     * than the last can match nothing, since ordered choice would always pick that. */
+    Choice (Vec<(TokenSet, Step)>, bool),
+    Repeat {inner: Box<Step>, min: usize, max: Option<usize>, again: TokenSet, done: TokenSet},
+}
+
+impl Step {
+    fn rule_names(&self) -> Vec<&str> {
+        match self {
+            Step::Token(_) | Step::EndOfInput => vec![],
+            Step::Rule(rule_name) => vec![rule_name],
+            Step::Sequence(steps) => steps.iter().flat_map(Step::rule_names).collect(),
+            Step::Choice(options, _) => options.iter().flat_map(|(_, step)| step.rule_names()).collect(),
+            Step::Repeat { inner, .. } => inner.rule_names(),
+        }
+    }
+}
+
+// The terminals that can come next. Each expression matches exactly one token.
+#[derive(Clone, Default)]
+pub(super) struct TokenSet {
+    pub(super) tokens: Vec<RuleExpression>,
+    pub(super) end: bool,  // The end of the input.
+}
+
+impl TokenSet {
+    fn single(expr: &RuleExpression) -> TokenSet {
+        TokenSet { tokens: vec![expr.clone()], end: false }
+    }
+
+    // Returns true if anything new was added.
+    pub(super) fn extend(&mut self, other: &TokenSet) -> bool {
+        let mut changed = other.end && !self.end;
+        self.end |= other.end;
+        for expr in &other.tokens {
+            if !self.tokens.contains(expr) {
+                self.tokens.push(expr.clone());
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub(super) fn union(&self, other: &TokenSet) -> TokenSet {
+        let mut union = self.clone();
+        union.extend(other);
+        union
+    }
+
+    // Only compares terminals as written. Whether they match the same token is checked while parsing.
+    fn overlaps(&self, other: &TokenSet) -> bool {
+        let has_wildcard = |set: &TokenSet| set.tokens.contains(&RuleExpression::Wildcard);
+
+        (self.end && other.end)
+            || (has_wildcard(self) && !other.tokens.is_empty())
+            || (has_wildcard(other) && !self.tokens.is_empty())
+            || self.tokens.iter().any(|expr| other.tokens.contains(expr))
+    }
+
+    // None if a terminal could not be checked against the token.
+    fn matches<T: Token>(&self, parser: &Parser<T>, tokens: &[T], index: usize) -> Option<bool> {
+        match tokens.get(index) {
+            None => Some(self.end),
+            Some(token) => {
+                for expr in &self.tokens {
+                    if single_token_matches(parser, expr, token).ok()? {
+                        return Some(true);
+                    }
+                }
+                Some(false)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(super) struct First {
+    pub(super) tokens: TokenSet,
+    pub(super) nullable: bool,  // Can match no tokens at all.
+}
+
+impl First {
+    fn size(&self) -> (usize, bool, bool) {
+        (self.tokens.tokens.len(), self.tokens.end, self.nullable)
+    }
+}
+
+pub(super) struct Analysis {
+    pub(super) firsts: HashMap<String, First>,
+    follows: HashMap<String, TokenSet>,
+    changed: bool,  // Set when a follow set grows.
+}
+
+impl Analysis {
+    // Works out the first set of every rule, but no follow sets yet.
+    pub(super) fn new(rules: &HashMap<Symbol, RuleExpression>) -> Analysis {
+        let mut analysis = Analysis { firsts: HashMap::new(), follows: HashMap::new(), changed: true };
+
+        while analysis.changed {
+            analysis.changed = false;
+            for (rule_name, expr) in rules {
+                // First sets only ever grow, so comparing sizes is enough.
+                let first = analysis.first(expr);
+                if analysis.firsts.get(rule_name.as_str()).map(First::size) != Some(first.size()) {
+                    analysis.firsts.insert(rule_name.to_string(), first);
+                    analysis.changed = true;
+                }
+            }
+        }
+
+        analysis
+    }
+
+    pub(super) fn first(&self, expr: &RuleExpression) -> First {
+        match expr {
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_)
+            | RuleExpression::Wildcard | RuleExpression::Negation(..) => First { tokens: TokenSet::single(expr), nullable: false },
+            RuleExpression::EndOfInput => First { tokens: TokenSet { tokens: vec![], end: true }, nullable: false },
+            RuleExpression::RuleName(rule_name) => self.firsts.get(rule_name.as_str()).cloned().unwrap_or_default(),
+            RuleExpression::Concatenation(exprs) => {
+                let mut first = First { tokens: TokenSet::default(), nullable: true };
+                for expr in exprs {
+                    let expr_first = self.first(expr);
+                    first.tokens.extend(&expr_first.tokens);
+                    if !expr_first.nullable {
+                        first.nullable = false;
+                        break;
+                    }
+                }
+                first
+            }
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+                let mut first = First::default();
+                for expr in exprs {
+                    let expr_first = self.first(expr);
+                    first.tokens.extend(&expr_first.tokens);
+                    first.nullable |= expr_first.nullable;
+                }
+                first
+            }
+            RuleExpression::Optional(inner) | RuleExpression::Many(inner) => First { nullable: true, ..self.first(inner) },
+            RuleExpression::OneOrMore(inner) => self.first(inner),
+            RuleExpression::Repetition(inner, min, _) => {
+                let first = self.first(inner);
+                First { nullable: first.nullable || *min == 0, ..first }
+            }
+            RuleExpression::PositiveLookahead(_) | RuleExpression::NegativeLookahead(..) => First { tokens: TokenSet::default(), nullable: true },
+            // Could be anything, so nothing that uses it can be predicted.
+            RuleExpression::External(_) => First { tokens: TokenSet::single(&RuleExpression::Wildcard), nullable: true },
+        }
+    }
+
+    /* Builds the steps for an expression, given what can follow it. Along the way, records
+     * what can follow each rule it uses. Returns None if the expression is not LL(1). */
+    fn compile(&mut self, expr: &RuleExpression, follow: &TokenSet) -> Option<Step> {
+        match expr {
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_)
+            | RuleExpression::Wildcard | RuleExpression::Negation(..) => Some(Step::Token(expr.clone())),
+            RuleExpression::EndOfInput => Some(Step::EndOfInput),
+            RuleExpression::RuleName(rule_name) => {
+                if self.follows.get_mut(rule_name.as_str())?.extend(follow) {
+                    self.changed = true;
+                }
+                Some(Step::Rule(rule_name.clone()))
+            }
+            RuleExpression::Concatenation(exprs) => {
+                let mut steps = vec![];
+                let mut rest = First { tokens: TokenSet::default(), nullable: true };
+                for expr in exprs.iter().rev() {
+                    let expr_follow = if rest.nullable { rest.tokens.union(follow) } else { rest.tokens.clone() };
+                    steps.push(self.compile(expr, &expr_follow)?);
+
+                    let expr_first = self.first(expr);
+                    rest = First {
+                        tokens: if expr_first.nullable { expr_first.tokens.union(&rest.tokens) } else { expr_first.tokens },
+                        nullable: expr_first.nullable && rest.nullable,
+                    };
+                }
+                steps.reverse();
+                Some(Step::Sequence(steps))
+            }
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+                let mut options: Vec<(TokenSet, Step)> = vec![];
+                let mut nullable_before_last = false;
+                for (i, expr) in exprs.iter().enumerate() {
+                    let first = self.first(expr);
+                    nullable_before_last |= first.nullable && i + 1 < exprs.len();
+                    let predict = if first.nullable { first.tokens.union(follow) } else { first.tokens };
+                    if options.iter().any(|(other, _)| other.overlaps(&predict)) {
+                        return None;
+                    }
+                    options.push((predict, self.compile(expr, follow)?));
+                }
+
+                if nullable_before_last && matches!(expr, RuleExpression::OrderedAlternatives(_)) {
+                    return None;
+                }
+                Some(Step::Choice(options, nullable_before_last))
+            }
+            RuleExpression::Optional(inner) => self.compile_repeat(inner, 0, Some(1), follow),
+            RuleExpression::Many(inner) => self.compile_repeat(inner, 0, None, follow),
+            RuleExpression::OneOrMore(inner) => self.compile_repeat(inner, 1, None, follow),
+            RuleExpression::Repetition(inner, min, max) => self.compile_repeat(inner, *min, *max, follow),
+            RuleExpression::PositiveLookahead(_) | RuleExpression::NegativeLookahead(..) | RuleExpression::External(_) => None,
+        }
+    }
+
+    fn compile_repeat(&mut self, inner: &RuleExpression, min: usize, max: Option<usize>, follow: &TokenSet) -> Option<Step> {
+        let first = self.first(inner);
+        if first.nullable || (max != Some(min) && first.tokens.overlaps(follow)) {
+            return None;
+        }
+
+        let inner_follow = first.tokens.union(follow);
+        let inner = self.compile(inner, &inner_follow)?;
+        Some(Step::Repeat { inner: Box::new(inner), min, max, again: first.tokens, done: follow.clone() })
+    }
+}
+
+// Rules that can reach themselves without consuming a token.
+fn left_recursive_rules(rules: &HashMap<Symbol, RuleExpression>, analysis: &Analysis) -> HashSet<String> {
+    let left_calls = rules.iter()
+        .map(|(rule_name, expr)| {
+            let mut calls = vec![];
+            leftmost_rules(expr, analysis, &mut calls);
+            (rule_name.as_str(), calls)
+        })
+        .collect::<HashMap<&str, Vec<&str>>>();
+
+    let mut recursive = HashSet::new();
+    for &rule_name in left_calls.keys() {
+        let mut stack = vec![rule_name];
+        let mut seen = HashSet::new();
+        while let Some(current) = stack.pop() {
+            for &called in left_calls.get(current).into_iter().flatten() {
+                if called == rule_name {
+                    recursive.insert(rule_name.to_string());
+                }
+                if seen.insert(called) {
+                    stack.push(called);
+                }
+            }
+        }
+    }
+
+    recursive
+}
+
+// Adds every rule that the expression can use before consuming any tokens.
+fn leftmost_rules<'a>(expr: &'a RuleExpression, analysis: &Analysis, calls: &mut Vec<&'a str>) {
+    match expr {
+        RuleExpression::RuleName(rule_name) => calls.push(rule_name),
+        RuleExpression::Concatenation(exprs) => {
+            for expr in exprs {
+                leftmost_rules(expr, analysis, calls);
+                if !analysis.first(expr).nullable {
+                    break;
+                }
+            }
+        }
+        RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => exprs.iter().for_each(|expr| leftmost_rules(expr, analysis, calls)),
+        RuleExpression::Optional(inner) | RuleExpression::Many(inner) | RuleExpression::OneOrMore(inner)
+        | RuleExpression::Repetition(inner, ..) => leftmost_rules(inner, analysis, calls),
+        _ => (),
+    }
+}
+
+
+/* Parsing */
+
+/* The parse is written down as a flat list of events rather than a tree, so that it
+ * can be thrown away cheaply if the fast path gives up halfway, and so that it can be
+ * turned into either a tree or events for the caller. */
+enum Ll1Event<'p> {
+    Enter (&'p Symbol, usize),  // The rule, and the index it starts at.
+    Token (usize, &'p RuleExpression),  // The index, and what the token matched, for captures.
+    Exit (usize),  // The index the rule ends at.
+}
+
+struct Ll1State<'p, 't, T: Token> {
+    parser: &'p Parser<T>,
+    tokens: &'t [T],
+    index: usize,  // The next token to parse.
+    events: Vec<Ll1Event<'p>>,
+    progress: ProgressReporter<'p>,
+    observers: &'p Observers<T>,
+}
+
+/* What is left of the parse, innermost last. The parse works through this rather than
+ * recursing, so that deeply nested input can't overflow the stack. */
+enum Work<'p> {
+    Step (&'p Step),
+    Sequence (std::slice::Iter<'p, Step>),  // The steps of a sequence that are still to come.
+    Repeat (&'p Step, usize),  // A Step::Repeat, and how many times its inner step has matched.
+    Exit (&'p Symbol, usize),  // Ends the rule that started at the index.
+}
+
+impl<'p, 't, T: Token> Ll1State<'p, 't, T> {
+    fn parse_rule(&mut self, rule_name: &str) -> Option<()> {
+        let mut work = vec![];
+        self.enter(rule_name, &mut work)?;
+        while let Some(next) = work.pop() {
+            if self.parse_step(next, &mut work).is_none() {
+                // Every rule that was still open fails with it, innermost first.
+                for open in work.iter().rev() {
+                    if let Work::Exit(rule_name, start) = open {
+                        self.observers.exit_rule(rule_name, *start, false);
+                    }
+                }
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    fn enter(&mut self, rule_name: &str, work: &mut Vec<Work<'p>>) -> Option<()> {
+        let (rule_name, step) = self.parser.prediction_tables.rules.get_key_value(rule_name)?;
+        let start = self.index;
+        self.events.push(Ll1Event::Enter(rule_name, start));
+        self.observers.enter_rule(rule_name, start);
+        work.extend([Work::Exit(rule_name, start), Work::Step(step)]);
+        Some(())
+    }
+
+    // Does the next piece of work, pushing whatever has to come after it.
+    fn parse_step(&mut self, next: Work<'p>, work: &mut Vec<Work<'p>>) -> Option<()> {
+        let step = match next {
+            Work::Step(step) => step,
+            Work::Sequence(mut steps) => {
+                if let Some(step) = steps.next() {
+                    work.extend([Work::Sequence(steps), Work::Step(step)]);
+                }
+                return Some(());
+            }
+            Work::Repeat(repeat, count) => {
+                let Step::Repeat { inner, min, max, again, done } = repeat else {
+                    unreachable!("only repeats are repeated");
+                };
+                if max.is_some_and(|max| count >= max) {
+                    return Some(());
+                }
+                if count >= *min {
+                    match (again.matches(self.parser, self.tokens, self.index)?, done.matches(self.parser, self.tokens, self.index)?) {
+                        (true, false) => (),
+                        (false, true) => return Some(()),
+                        _ => return None,
+                    }
+                }
+                work.extend([Work::Repeat(repeat, count + 1), Work::Step(inner)]);
+                return Some(());
+            }
+            Work::Exit(rule_name, start) => {
+                self.observers.exit_rule(rule_name, start, true);
+                self.events.push(Ll1Event::Exit(self.index));
+                return Some(());
+            }
+        };
+
+        match step {
+            Step::Token(expr) => {
+                let token = self.tokens.get(self.index)?;
+                if !single_token_matches(self.parser, expr, token).ok()? {
+                    return None;
+                }
+                self.events.push(Ll1Event::Token(self.index, expr));
+                self.observers.token(token, self.index);
+                self.index += 1;
+                self.progress.reached(self.index, || self.events.len());
+            }
+            Step::EndOfInput => {
+                if self.index != self.tokens.len() {
+                    return None;
+                }
+            }
+            Step::Rule(rule_name) => self.enter(rule_name, work)?,
+            Step::Sequence(steps) => work.push(Work::Sequence(steps.iter())),
+            Step::Choice(options, nullable_before_last) => {
+                if self.parser.ordered_choice && *nullable_before_last {
+                    return None;
+                }
+
+                let mut chosen = None;
+                for (predict, step) in options {
+                    if predict.matches(self.parser, self.tokens, self.index)? {
+                        if chosen.is_some() {
+                            return None;
+                        }
+                        chosen = Some(step);
+                    }
+                }
+                work.push(Work::Step(chosen?));
+            }
+            Step::Repeat { .. } => work.push(Work::Repeat(step, 0)),
+        }
+
+        Some(())
+    }
+}
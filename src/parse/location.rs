@@ -0,0 +1,67 @@
+
+/* A position in a source string. Lines and columns count from 1, and columns
+ * count characters rather than bytes. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+    pub byte_offset: usize,
+}
+
+/* Remembers where each line of a source string begins, so that offsets into the
+ * string can be translated into lines and columns. */
+pub struct LineMap<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,  // Byte offset of the first character of each line.
+    line_char_starts: Vec<usize>,  // Character index of the first character of each line.
+}
+
+impl<'a> LineMap<'a> {
+    pub fn new(source: &'a str) -> LineMap<'a> {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect::<Vec<usize>>();
+
+        let line_char_starts = std::iter::once(0)
+            .chain(line_starts.windows(2).scan(0, |chars, lines| {
+                *chars += source[lines[0]..lines[1]].chars().count();
+                Some(*chars)
+            }))
+            .collect();
+
+        LineMap { source, line_starts, line_char_starts }
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    /* Offsets past the end of the source are clamped to the end. */
+    pub fn location_of_byte(&self, byte_offset: usize) -> SourceLocation {
+        let byte_offset = byte_offset.min(self.source.len());
+        let line_index = self.line_starts.partition_point(|start| *start <= byte_offset) - 1;
+        let line_start = self.line_starts[line_index];
+
+        SourceLocation {
+            line: line_index + 1,
+            column: self.source[line_start..byte_offset].chars().count() + 1,
+            byte_offset,
+        }
+    }
+
+    /* Locates the nth character, i.e. the nth CharToken when parsing with parse_string. */
+    pub fn location_of_char(&self, char_index: usize) -> SourceLocation {
+        self.location_of_byte(self.byte_of_char(char_index))
+    }
+
+    /* The byte offset of the nth character. Only has to look through one line, so this
+     * is quick unless the lines are very long. Indices past the end give the length. */
+    pub fn byte_of_char(&self, char_index: usize) -> usize {
+        let line_index = self.line_char_starts.partition_point(|start| *start <= char_index) - 1;
+        let line_start = self.line_starts[line_index];
+
+        self.source[line_start..].char_indices()
+            .nth(char_index - self.line_char_starts[line_index])
+            .map_or(self.source.len(), |(i, _)| line_start + i)
+    }
+}
@@ -0,0 +1,128 @@
+/* Putting trivia back into trees, for lossless parsing (see Parser::set_lossless).
+ * The parse itself never sees trivia, so this happens afterwards: the trivia between
+ * real tokens g - 1 and g (gap g) becomes token nodes in the smallest node that holds
+ * both of those tokens, and every index and span is moved to count the trivia too. */
+
+use super::{LexedToken, SyntaxTree};
+
+use std::ops::Range;
+
+
+pub(crate) fn add_trivia(tree: SyntaxTree<LexedToken>, tokens: &[LexedToken], trivia: &[LexedToken]) -> SyntaxTree<LexedToken> {
+    let mut trivia = trivia.iter().collect::<Vec<_>>();
+    trivia.sort_by_key(|token| token.span.start);
+
+    // Merge the two by position, remembering where each real token and each gap ended up.
+    let mut merged_indices = vec![];
+    let mut gaps: Vec<Vec<(usize, &LexedToken)>> = vec![vec![]; tokens.len() + 1];
+    let mut trivia = trivia.into_iter().peekable();
+    let mut next = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        while let Some(piece) = trivia.next_if(|piece| piece.span.start < token.span.start) {
+            gaps[i].push((next, piece));
+            next += 1;
+        }
+        merged_indices.push(next);
+        next += 1;
+    }
+    for piece in trivia {
+        gaps[tokens.len()].push((next, piece));
+        next += 1;
+    }
+
+    let rebuilder = Rebuilder { merged_indices, gaps, total: next };
+    let span = tree.span();
+    let SyntaxTree::RuleNode { rule_name, mut subexpressions, .. } = rebuilder.rebuild(tree) else {
+        unreachable!("Parses always return a rule node");
+    };
+
+    // Trivia before the first token and after the last belongs to the root.
+    if span.start == 0 {
+        let leading = rebuilder.trivia_nodes(0);
+        subexpressions.splice(0..0, leading);
+    }
+    if span.end == rebuilder.merged_indices.len() && !(span.start == 0 && span.end == 0) {
+        subexpressions.extend(rebuilder.trivia_nodes(span.end));
+    }
+    let span = rebuilder.new_span(&subexpressions, span.start);
+    SyntaxTree::RuleNode { rule_name, subexpressions, span }
+}
+
+
+/* Private Implementation */
+
+struct Rebuilder<'a> {
+    merged_indices: Vec<usize>,  // Where each real token is, counting trivia.
+    gaps: Vec<Vec<(usize, &'a LexedToken)>>,  // The trivia before each real token, and after the last one.
+    total: usize,  // The number of tokens and trivia together.
+}
+
+impl Rebuilder<'_> {
+    fn rebuild(&self, tree: SyntaxTree<LexedToken>) -> SyntaxTree<LexedToken> {
+        // Each node's value keeps the span it had before, which its parent needs.
+        let (_, tree) = tree.fold_owned(|node, children| (node.span(), self.rebuild_node(node, children)));
+        tree
+    }
+
+    // Rule nodes come without their children, which are given already rebuilt, with their old spans.
+    fn rebuild_node(&self, node: SyntaxTree<LexedToken>, children: Vec<(Range<usize>, SyntaxTree<LexedToken>)>) -> SyntaxTree<LexedToken> {
+        match node {
+            SyntaxTree::TokenNode { token, index, captured } => SyntaxTree::TokenNode { token, index: self.merged_indices[index], captured },
+            SyntaxTree::ErrorNode { tokens, expected, span } => {
+                let mut with_trivia = vec![];
+                for (i, token) in span.clone().zip(tokens) {
+                    if i > span.start {
+                        with_trivia.extend(self.gaps[i].iter().map(|(_, piece)| (*piece).clone()));
+                    }
+                    with_trivia.push(token);
+                }
+                let span = match span.is_empty() {
+                    true => self.position(span.start)..self.position(span.start),
+                    false => self.merged_indices[span.start]..self.merged_indices[span.end - 1] + 1,
+                };
+                SyntaxTree::ErrorNode { tokens: with_trivia, expected, span }
+            }
+            SyntaxTree::RuleNode { rule_name, span, .. } => {
+                let (old_spans, mut children): (Vec<_>, Vec<_>) = children.into_iter().map(|(span, child)| (span, Some(child))).unzip();
+
+                // Gaps inside this node but not inside any child go just before the child that follows them.
+                let mut inserted: Vec<Vec<SyntaxTree<LexedToken>>> = vec![vec![]; children.len() + 1];
+                for gap in span.start + 1..span.end {
+                    if old_spans.iter().any(|child| child.start < gap && gap < child.end) {
+                        continue;
+                    }
+                    let position = old_spans.iter()
+                        .position(|child| !child.is_empty() && child.start >= gap)
+                        .unwrap_or(children.len());
+                    inserted[position].extend(self.trivia_nodes(gap));
+                }
+
+                let mut subexpressions = vec![];
+                for (i, before) in inserted.into_iter().enumerate() {
+                    subexpressions.extend(before);
+                    subexpressions.extend(children.get_mut(i).and_then(Option::take));
+                }
+                let span = self.new_span(&subexpressions, span.start);
+                SyntaxTree::RuleNode { rule_name, subexpressions, span }
+            }
+        }
+    }
+
+    fn trivia_nodes(&self, gap: usize) -> Vec<SyntaxTree<LexedToken>> {
+        self.gaps[gap].iter()
+            .map(|(index, piece)| SyntaxTree::TokenNode { token: (*piece).clone(), index: *index, captured: None })
+            .collect()
+    }
+
+    // Where an empty node at real index i goes, counting trivia.
+    fn position(&self, i: usize) -> usize {
+        self.merged_indices.get(i).copied().unwrap_or(self.total)
+    }
+
+    fn new_span(&self, children: &[SyntaxTree<LexedToken>], old_start: usize) -> Range<usize> {
+        match (children.first(), children.last()) {
+            (Some(first), Some(last)) => first.span().start..last.span().end,
+            _ => self.position(old_start)..self.position(old_start),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use super::{Parser, TerminalMatcher, Token};
+use crate::define::DefinitionError;
+
+use itertools::Itertools;
+
+use std::collections::HashSet;
+
+
+/* Decides what happens when merging two parsers that both define a rule. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    #[default]
+    Reject,  // Fail, naming the rules defined by both. Neither parser is changed.
+    KeepOurs,  // Keep the rule from the parser being merged into.
+    TakeTheirs,  // Replace the rule with the one from the other parser.
+}
+
+impl<T: Token> Parser<T> {
+    /* Adds every rule of another parser to this one, including its lexical rules,
+     * predicates, actions, sync tokens, labels, registered terminals, captures and
+     * scanners. A rule's predicates, actions, sync tokens and label stay with it, so if
+     * one side's version of a rule wins a conflict, so do they. Settings like the ambiguity policy are kept
+     * from this parser.
+     *
+     * Rules from one parser can use rules from the other, as long as they are
+     * declared with `extern Name;` in its definition. */
+    pub fn merge(&mut self, mut other: Parser<T>, policy: ConflictPolicy) -> Result<(), DefinitionError> {
+        if policy == ConflictPolicy::Reject {
+            let conflicts = other.rules.keys()
+                .chain(other.lexer.iter().flat_map(|lexer| lexer.parser.rules.keys()))
+                .filter(|rule_name| self.defines(rule_name))
+                .sorted()
+                .join(", ");
+
+            if !conflicts.is_empty() {
+                return Err(DefinitionError::new(format!("Both parsers define rules: {conflicts}")));
+            }
+        }
+
+        for (rule_name, expr) in other.rules {
+            if self.rules.contains_key(&rule_name) && policy == ConflictPolicy::KeepOurs {
+                continue;
+            }
+
+            self.predicates.remove(rule_name.as_str());
+            if let Some(predicates) = other.predicates.remove(rule_name.as_str()) {
+                self.predicates.insert(rule_name.to_string(), predicates);
+            }
+            self.stateful_predicates.remove(rule_name.as_str());
+            if let Some(predicates) = other.stateful_predicates.remove(rule_name.as_str()) {
+                self.stateful_predicates.insert(rule_name.to_string(), predicates);
+            }
+            self.actions.remove(rule_name.as_str());
+            if let Some(actions) = other.actions.remove(rule_name.as_str()) {
+                self.actions.insert(rule_name.to_string(), actions);
+            }
+            self.sync_tokens.remove(rule_name.as_str());
+            if let Some(sync_tokens) = other.sync_tokens.remove(rule_name.as_str()) {
+                self.sync_tokens.insert(rule_name.to_string(), sync_tokens);
+            }
+            self.labels.remove(rule_name.as_str());
+            if let Some(label) = other.labels.remove(rule_name.as_str()) {
+                self.labels.insert(rule_name.to_string(), label);
+            }
+            self.locations.remove(rule_name.as_str());
+            if let Some(locations) = other.locations.remove(rule_name.as_str()) {
+                self.locations.insert(rule_name.to_string(), locations);
+            }
+            self.rules.insert(rule_name, expr);
+        }
+
+        match (&mut self.lexer, other.lexer.take()) {
+            (Some(lexer), Some(other_lexer)) => {
+                for (rule_name, skip) in other_lexer.token_rules {
+                    match lexer.token_rules.iter_mut().find(|(ours, _)| *ours == rule_name) {
+                        Some((_, our_skip)) if policy == ConflictPolicy::TakeTheirs => *our_skip = skip,
+                        Some(_) => (),
+                        None => lexer.token_rules.push((rule_name, skip)),
+                    }
+                }
+                lexer.parser.merge(other_lexer.parser, policy)?;
+            }
+            (lexer @ None, other_lexer) => *lexer = other_lexer,
+            (Some(_), None) => (),
+        }
+
+        // A rule may have referred to a token that only the other parser's lexer defines.
+        if let Some(lexer) = &self.lexer {
+            let token_names = lexer.token_rules.iter()
+                .filter(|(_, skip)| !skip)
+                .map(|(rule_name, _)| rule_name.clone())
+                .collect::<HashSet<String>>();
+            for expr in self.rules.values_mut() {
+                expr.replace_token_references(&token_names);
+            }
+        }
+
+        // References between the two parsers' rules should share names like the rest do.
+        self.rules = super::intern_rule_names(std::mem::take(&mut self.rules));
+        self.prediction_tables = super::PredictionTables::new(&self.rules);
+        for (name, matcher) in other.terminals {
+            if matches!(matcher, TerminalMatcher::Custom(_)) 
+                    && (policy == ConflictPolicy::TakeTheirs || !self.terminals.contains_key(&name)) {
+                self.terminals.insert(name, matcher);
+            }
+        }
+        for (name, scanner) in other.scanners {
+            if policy == ConflictPolicy::TakeTheirs || !self.scanners.contains_key(&name) {
+                self.scanners.insert(name, scanner);
+            }
+        }
+        for (name, capture) in other.captures {
+            if policy == ConflictPolicy::TakeTheirs || !self.captures.contains_key(&name) {
+                self.captures.insert(name, capture);
+            }
+        }
+        let exprs = self.rules.values().chain(self.sync_tokens.values());
+        self.terminals = super::resolve_terminals(exprs, std::mem::take(&mut self.terminals))?;
+
+        self.externs.extend(other.externs);
+        let externs = std::mem::take(&mut self.externs);
+        self.externs = externs.into_iter().filter(|rule_name| !self.defines(rule_name)).collect();
+
+        Ok(())
+    }
+
+    fn defines(&self, rule_name: &str) -> bool {
+        self.rules.contains_key(rule_name) || self.lexer.as_ref().is_some_and(|lexer| lexer.parser.rules.contains_key(rule_name))
+    }
+}
@@ -1,26 +1,175 @@
 
+mod ambiguity;
 mod backtracking_parser;
+mod budget;
+mod chart_dump;
+mod compact;
+mod conflicts;
+mod debugger;
+mod earley_parser;
+mod ebnf;
+mod events;
+mod export;
+mod find;
+mod first_follow;
+mod lazy;
+mod from_tree;
+mod ll1_parser;
+mod limits;
+mod likely_ambiguity;
+mod lint;
+mod lexer;
+mod merge;
+mod observe;
+mod profile;
+mod progress;
+mod pattern;
+mod query;
+mod railroad;
+mod recovery;
+mod session;
+mod snapshot;
+mod stats;
+mod stateful;
+mod streaming;
+mod symbol;
+mod trace;
+mod transform;
+mod unparse;
+mod location;
+mod lossless;
+#[cfg(feature = "rowan")] mod green;
 #[cfg(test)] mod tests;
 
+pub use ambiguity::{AmbiguityPolicy, Ambiguity};
+pub use budget::ParseBudget;
+pub use chart_dump::ChartDump;
+pub use compact::{CompactTree, NodeRef};
+pub use conflicts::GrammarConflict;
+pub use debugger::{Frontier, ParseDebugger};
+#[cfg(feature = "rowan")] pub use green::RowanKinds;
+pub use ebnf::EbnfNotation;
+pub use events::ParseEvent;
+pub use find::Matches;
+pub use first_follow::{FirstSet, FollowSet};
+pub use lazy::LazyTree;
+pub use from_tree::{FromSyntaxTree, ShapeError, FieldCursor, FieldFilter, unexpected_shape};
+pub use location::{LineMap, SourceLocation};
+pub use lexer::{LexedToken, LexedTokenKind};
+pub use limits::{Limit, ParseLimits};
+pub use likely_ambiguity::LikelyAmbiguity;
+pub use lint::GrammarWarning;
+pub use merge::ConflictPolicy;
+pub use profile::{ParseProfile, RuleProfile};
+pub use progress::ParseProgress;
+pub use query::{Descendants, QueryError, TreeQuery};
+pub use session::ParseSession;
+pub use streaming::StreamingParse;
+pub use symbol::Symbol;
+pub use snapshot::ParseSnapshot;
+pub use stats::ParseStats;
 
-use backtracking_parser::backtracking_parse;
+pub(crate) use lexer::Lexer;
+use stateful::{Action, StatefulPredicate};
 
-use crate::define::RuleExpression;
 
+use backtracking_parser::{backtracking_parse, backtracking_parse_all};
+use earley_parser::{earley_parse, earley_parse_all};
+use ll1_parser::ll1_parse;
+use budget::Deadline;
+use observe::Observers;
+use progress::ProgressCallback;
+use stats::StatsRecorder;
+pub(crate) use ll1_parser::PredictionTables;
+
+use crate::define::{DefinitionError, RuleExpression, RuleLocations};
+
+use itertools::Itertools;
+
+use std::any::Any;
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
 
 
 /* Public Interface */
 
 pub struct Parser<T: Token> {
     pub(crate) phantom: std::marker::PhantomData<fn(&T)->T>,  // Act like we own a function mapping "Something that borrows T" to "Something that owns T"
-    pub(crate) rules: HashMap<String, RuleExpression>
+    pub(crate) rules: HashMap<Symbol, RuleExpression>,  // Keys are the interned names, see Symbol.
+    pub(crate) ambiguity_policy: AmbiguityPolicy,
+    pub(crate) anchored: bool,
+    pub(crate) lexer: Option<Box<Lexer>>,  // Only present if the grammar has @token rules.
+    pub(crate) predicates: HashMap<String, Vec<Predicate<T>>>,
+    pub(crate) externs: HashSet<String>,  // Rules used, but left for another parser to define.
+    pub(crate) algorithm: ParseAlgorithm,
+    pub(crate) prediction_tables: PredictionTables,  // Must be rebuilt whenever the rules change.
+    pub(crate) ordered_choice: bool,  // Every rule acts like it is marked @ordered.
+    pub(crate) lossless: bool,  // Trees from parse_string keep the trivia, see set_lossless.
+    pub(crate) terminals: HashMap<String, TerminalMatcher<T>>,  // Every terminal in the rules, resolved. Must be rebuilt whenever the rules change.
+    pub(crate) captures: HashMap<String, Capture<T>>,  // By terminal name.
+    pub(crate) scanners: HashMap<String, Scanner<T>>,  // By the name of the external rule.
+    pub(crate) stateful_predicates: HashMap<String, Vec<StatefulPredicate<T>>>,
+    pub(crate) actions: HashMap<String, Vec<Action<T>>>,
+    pub(crate) sync_tokens: HashMap<String, RuleExpression>,  // By rule, for error recovery. Each matches a single token.
+    pub(crate) labels: HashMap<String, String>,  // By rule, from @label. These replace the rule's name in errors.
+    pub(crate) locations: HashMap<String, RuleLocations>,  // By rule, where its parts are in the definition it came from.
+    pub(crate) limits: ParseLimits,  // Only checked by the backtracking algorithm.
+    pub(crate) progress: Option<(usize, ProgressCallback)>,  // How many tokens apart to report, see set_progress_callback.
+    pub(crate) observers: Observers<T>,  // See on_enter_rule.
 }
 
-#[derive(Debug)]
+/* Selects the algorithm that parses the tokens. Every algorithm produces the same
+ * trees for unambiguous grammars, they only differ in speed. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseAlgorithm {
+    #[default]
+    Auto,  // Let the parser pick: LL(1) tables for rules that allow it, otherwise Backtracking.
+    Backtracking,  // Memoized backtracking over every possible continuation.
+    Earley,  // Handles any grammar without surprises, but usually slower than Backtracking.
+}
+
+/* Receives every token in the input, and the span of tokens that a rule matched. */
+pub(crate) type Predicate<T> = Box<dyn Fn(&[T], Range<usize>) -> bool>;
+
+/* Decides whether a single token matches a terminal, see Parser::register_terminal. */
+pub(crate) type Matcher<T> = Box<dyn Fn(&T) -> bool>;
+
+/* A value taken from a token when it matched a terminal, see Parser::register_capture. */
+pub type Captured = Arc<dyn Any + Send + Sync>;
+
+pub(crate) type Capture<T> = Box<dyn Fn(&T) -> Option<Captured>>;
+
+/* Receives every token in the input and the index to start at, and returns how many
+ * tokens the external rule matches there, if it matches at all. */
+pub(crate) type Scanner<T> = Box<dyn Fn(&[T], usize) -> Option<usize>>;
+
+/* How a terminal in the rules is matched: by the token type's kind, or by a closure
+ * registered on the parser. */
+pub(crate) enum TerminalMatcher<T: Token> {
+    Kind (T::Kind),
+    Custom (Matcher<T>),
+}
+
+impl<T: Token> TerminalMatcher<T> {
+    pub(crate) fn matches(&self, token: &T) -> bool {
+        match self {
+            TerminalMatcher::Kind(kind) => T::matches(kind, token),
+            TerminalMatcher::Custom(matcher) => matcher(token),
+        }
+    }
+}
+
+/* Spans are token indices into the input. A rule's span is the half open range
+ * of tokens it covers, which is empty if the rule matched no tokens. Token nodes
+ * hold a captured value if the terminal they matched has a capture registered.
+ * Error nodes only appear in trees from parse_tokens_recovering, and hold the tokens
+ * that were skipped to make the parse work, along with what was expected instead. */
+#[derive(Debug, Clone)]
 pub enum SyntaxTree<T: Token> {
-    RuleNode {rule_name: String, subexpressions: Vec<SyntaxTree<T>>},
-    TokenNode (T)
+    RuleNode {rule_name: Symbol, subexpressions: Vec<SyntaxTree<T>>, span: Range<usize>},
+    TokenNode {token: T, index: usize, captured: Option<Captured>},
+    ErrorNode {tokens: Vec<T>, expected: HashSet<String>, span: Range<usize>},
 }
 
 impl<T: Token + std::fmt::Display> std::fmt::Display for SyntaxTree<T> {
@@ -32,33 +181,211 @@ impl<T: Token + std::fmt::Display> std::fmt::Display for SyntaxTree<T> {
 }
 
 impl<T: Token + std::fmt::Display> SyntaxTree<T> {
-    fn helper_fmt(&self, level: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("\n")?;
-        f.write_str(&" ".repeat(level * 4))?;
+    fn helper_fmt(&self, mut level: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut result = Ok(());
+        self.walk(|node, entering| {
+            if !entering {
+                level -= 1;
+                return;
+            }
+            if result.is_ok() {
+                result = write!(f, "\n{}", " ".repeat(level * 4)).and_then(|()| match node {
+                    SyntaxTree::RuleNode {rule_name, ..} => f.write_str(rule_name),
+                    SyntaxTree::TokenNode {token, ..} => f.write_str(&format!("token ({token})")),
+                    SyntaxTree::ErrorNode {tokens, ..} => {
+                        f.write_str(&format!("error ({})", tokens.iter().map(ToString::to_string).collect::<String>()))
+                    }
+                });
+            }
+            level += 1;
+        });
+        result
+    }
+}
+
+impl<T: Token> SyntaxTree<T> {
+    /* The range of token indices covered by this node. */
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            SyntaxTree::RuleNode {span, ..} | SyntaxTree::ErrorNode {span, ..} => span.clone(),
+            SyntaxTree::TokenNode {index, ..} => *index..*index + 1,
+        }
+    }
+
+    /* The range of bytes covered by this node, from the spans of its first and last
+     * tokens. None for nodes without tokens, or if the tokens don't know their spans. */
+    pub fn source_span(&self) -> Option<Range<usize>> {
         match self {
-            SyntaxTree::RuleNode {rule_name, subexpressions} => {
-                f.write_str(rule_name)?;
-                for expr in subexpressions {
-                    expr.helper_fmt(level + 1, f)?;
-                    // f.write_str("\n")?
+            SyntaxTree::TokenNode {token, ..} => token.span(),
+            SyntaxTree::RuleNode {..} => Some(self.outer_source_span(false)?.start..self.outer_source_span(true)?.end),
+            SyntaxTree::ErrorNode {tokens, ..} => Some(tokens.first()?.span()?.start..tokens.last()?.span()?.end),
+        }
+    }
+
+    /* The value captured from this node's token, if there is one of type V. */
+    pub fn captured<V: 'static>(&self) -> Option<&V> {
+        match self {
+            SyntaxTree::TokenNode {captured: Some(value), ..} => value.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /* Visits every node, depth first, as it is entered (true) and as it is left (false).
+     * The walk keeps its own stack, so that deep trees can't overflow the call stack, and
+     * it's what everything that goes through whole trees should use. */
+    pub(crate) fn walk<'t>(&'t self, mut visit: impl FnMut(&'t SyntaxTree<T>, bool)) {
+        let mut pending = vec![(self, true)];
+        while let Some((node, entering)) = pending.pop() {
+            visit(node, entering);
+            if entering {
+                pending.push((node, false));
+                if let SyntaxTree::RuleNode {subexpressions, ..} = node {
+                    pending.extend(subexpressions.iter().rev().map(|child| (child, true)));
                 }
-                Ok(())
-            },
-            SyntaxTree::TokenNode(token) => {
-                f.write_str(&format!("token ({token})"))
             }
         }
+    }
+
+    /* Works out a value for every node from its children's values, from the leaves up,
+     * and gives the root's. */
+    pub(crate) fn fold<'t, R>(&'t self, mut f: impl FnMut(&'t SyntaxTree<T>, Vec<R>) -> R) -> R {
+        let mut values = vec![];
+        self.walk(|node, entering| {
+            if !entering {
+                let children = match node {
+                    SyntaxTree::RuleNode {subexpressions, ..} => values.split_off(values.len() - subexpressions.len()),
+                    _ => vec![],
+                };
+                values.push(f(node, children));
+            }
+        });
+        values.pop().expect("The root has a value")
+    }
+
+    /* Like fold, but takes the tree apart. Rule nodes are given to f without their
+     * children, which f gets the values of instead. */
+    pub(crate) fn fold_owned<R>(self, mut f: impl FnMut(SyntaxTree<T>, Vec<R>) -> R) -> R {
+        // Each node being worked on, with the children still to do and the values of those done.
+        let mut pending = vec![];
+        let mut next = self;
+        loop {
+            let children = match &mut next {
+                SyntaxTree::RuleNode {subexpressions, ..} => std::mem::take(subexpressions).into_iter(),
+                _ => vec![].into_iter(),
+            };
+            pending.push((next, children, vec![]));
+
+            loop {
+                let (_, children, _) = pending.last_mut().expect("A node is being worked on");
+                if let Some(child) = children.next() {
+                    next = child;
+                    break;
+                }
+                let (node, _, values) = pending.pop().expect("A node is being worked on");
+                let value = f(node, values);
+                match pending.last_mut() {
+                    Some((_, _, values)) => values.push(value),
+                    None => return value,
+                }
+            }
+        }
+    }
 
+    /* Drops the tree a node at a time, for trees that may be deep. Dropping one whole
+     * recurses once for every level. */
+    pub(crate) fn dismantle(self) {
+        self.fold_owned(|_, _| ());
+    }
+
+    // The byte span of the first (or last) token under this node that knows its span.
+    fn outer_source_span(&self, last: bool) -> Option<Range<usize>> {
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            match node {
+                SyntaxTree::RuleNode {subexpressions, ..} if last => pending.extend(subexpressions),
+                SyntaxTree::RuleNode {subexpressions, ..} => pending.extend(subexpressions.iter().rev()),
+                leaf => if let Some(span) = leaf.source_span() {
+                    return Some(span);
+                }
+            }
+        }
+        None
     }
 }
 
+/* Trees from parse_string point back into the input, so the text of any node can be
+ * had without copying. Pass a LineMap of the same string that was parsed. */
+impl SyntaxTree<CharToken> {
+    /* The range of bytes covered by this node. */
+    pub fn byte_span(&self, source: &LineMap) -> Range<usize> {
+        let span = self.span();
+        source.byte_of_char(span.start)..source.byte_of_char(span.end)
+    }
+
+    pub fn text<'a>(&self, source: &LineMap<'a>) -> &'a str {
+        &source.source()[self.byte_span(source)]
+    }
+}
+
+/* Why a parse failed. UnexpectedToken and UnexpectedEof are syntax errors in the
+ * input, the rest are problems with the parser or how it was used. */
 #[derive(Debug)]
 pub enum ParseError {
-    Internal (String),
-    IncompleteParse {index: usize, terminals: HashSet<String>},  
-    OutOfInput { terminals: HashSet<String>}, 
+    Internal (String),  // Anything without a variant of its own, like a grammar that an algorithm can't handle.
+    UnknownRule { rule_name: String, suggestion: Option<String> },  // The start rule, or a rule it uses, isn't defined. Suggests a rule with a similar name.
+    UnexpectedToken {
+        index: usize, 
+        terminals: HashSet<String>,  // Every terminal that would have let the parse continue.
+        expected: HashSet<String>,  // The same, but terminals that start a rule there are replaced by the rule.
+        context: Vec<String>,  // The rules the parse was inside when it failed, outermost first.
+        location: Option<SourceLocation>,  // Only known when parsing strings.
+        span: Option<Range<usize>>,  // The bytes of the token at index, if the token knows them (see Token::span).
+    },
+    UnexpectedEof { terminals: HashSet<String>, expected: HashSet<String>, context: Vec<String> }, 
+    Ambiguous (Ambiguity),
+    Cancelled { index: usize, location: Option<SourceLocation> },  // The budget ran out. index is the furthest token the parse had reached.
+    LimitExceeded { limit: Limit, rule_name: Option<String>, index: usize, location: Option<SourceLocation> },  // See ParseLimits. The rule is the innermost one being parsed, if any.
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Internal(message) => write!(f, "{message}"),
+            ParseError::UnknownRule { rule_name, suggestion: None } => write!(f, "Rule \"{rule_name}\" not found"),
+            ParseError::UnknownRule { rule_name, suggestion: Some(suggestion) } => 
+                write!(f, "Rule \"{rule_name}\" not found, did you mean \"{suggestion}\"?"),
+            ParseError::UnexpectedToken { index, expected, location, .. } => {
+                match location {
+                    Some(location) => write!(f, "Unexpected token at line {}, column {}", location.line, location.column)?,
+                    None => write!(f, "Unexpected token at index {index}")?,
+                }
+                write_expected(f, expected)
+            }
+            ParseError::UnexpectedEof { expected, .. } => {
+                write!(f, "Unexpected end of input")?;
+                write_expected(f, expected)
+            }
+            ParseError::Ambiguous(Ambiguity { parse_count, rule_name, index }) => 
+                write!(f, "Input is ambiguous, {parse_count} parses disagree on \"{rule_name}\" at index {index}"),
+            ParseError::Cancelled { location: Some(location), .. } => 
+                write!(f, "Parse cancelled after reaching line {}, column {}", location.line, location.column),
+            ParseError::Cancelled { index, location: None } => write!(f, "Parse cancelled after reaching index {index}"),
+            ParseError::LimitExceeded { limit, rule_name, index, location } => {
+                write!(f, "Parse exceeded the {limit}")?;
+                if let Some(rule_name) = rule_name {
+                    write!(f, " in rule \"{rule_name}\"")?;
+                }
+                match location {
+                    Some(location) => write!(f, " at line {}, column {}", location.line, location.column),
+                    None => write!(f, " at index {index}"),
+                }
+            }
+        }
+    }
 }
 
+impl std::error::Error for ParseError {}
+
 impl From<&str> for ParseError {
     fn from(value: &str) -> Self {
         ParseError::Internal(value.to_string())
@@ -79,70 +406,514 @@ impl From<String> for ParseError {
  * and most parsing algorithms are O(n^3) worst case, so preprocessing to shorten the
  * list of tokens can be useful).
  * 
- * Tokens need not track their own location in the source file, that will eventually
- * be done by the parser. */
+ * Tokens need not track their own location in the source file, but if they do, they
+ * can say so with span(), and errors and trees will report byte ranges too. */
 pub trait Token : Sized + std::fmt::Debug + Clone {
+    /* What a terminal in the definition stands for, once it has been understood.
+     * Usually an enum of the kinds of token, or the token itself for simple alphabets. */
+    type Kind : std::fmt::Debug + Clone;
+
     /* If the parser definition contains a rule with a name starting with an underscore,
      * e.g. "_ascii_lower", then instead of acting as a normal rule, it will act
-     * as a special rule that dispatches to this function.
+     * as a special rule that matches tokens of a certain kind.
      * 
      * This function receives the token type (e.g. "ascii_lower") without the leading
-     * underscore. It should return true if the parser accepts the current token.
-     * 
-     * It is permitted to return ParseError if something goes wrong. For example, 
-     * receiving an unknown token_type. 
+     * underscore, and says which kind it means. It is called once per terminal when
+     * the parser is defined, and returning None for an unknown token type makes the
+     * definition fail, so typos are caught before anything is parsed.
      * 
      * Note: if you also override type_sequence_from_literal, then you define which
      * token_types are fed into this function. */
-    fn matches(token_type: &str, token: &Self) -> Result<bool, ParseError>;
+    fn kind(token_type: &str) -> Option<Self::Kind>;
+
+    /* Returns true if the token is of the given kind. This is called for every token
+     * the parser looks at, so it should be cheap. */
+    fn matches(kind: &Self::Kind, token: &Self) -> bool;
 
     /* Converts a literal string in the definition language into a sequence of
-     * strings that are later fed into match() as token_type, one by one.
+     * strings that are later fed into kind() as token_type, one by one.
      * 
      * Notably, CharToken provides this feature as the main way to match terminals. 
      * Most custom token types will not need to provide this. */
     fn type_sequence_from_literal(_literal: &str) -> Option<Vec<String>> {
         None
     }
+
+    /* Like type_sequence_from_literal, but for case insensitive literals such as
+     * i"select". If this returns None, the literal is instead matched one character
+     * at a time with character classes, which works for any token that provides as_char. */
+    fn type_sequence_from_literal_ignoring_case(_literal: &str) -> Option<Vec<String>> {
+        None
+    }
+
+    /* Tokens that stand for a single character can say which one, which allows
+     * them to be matched by character classes such as [a-z] in the definition.
+     * Parsing a character class against tokens that return None is an error. */
+    fn as_char(&self) -> Option<char> {
+        None
+    }
+
+    /* The range of bytes this token came from in the source, if it knows. */
+    fn span(&self) -> Option<Range<usize>> {
+        None
+    }
 }
 
 /* A token that represents  */
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CharToken {
     /* Unlike most tokens, a single field is sufficient, as all token_types have
-     * a single possible value (the character). */
-    pub token_type: String,  // String for annoying ownership reasons. Will validate that its a single character.
+     * a single possible value (the character). Being a char, tokens are cheap to
+     * make, so parsing a big string doesn't allocate for every character. */
+    pub token_type: char,
 }
 
 impl Token for CharToken {
+    type Kind = char;
+
     fn type_sequence_from_literal(literal: &str) -> Option<Vec<String>> {
-        return Some(literal.chars().map(|c| c.to_string()).collect())
+        Some(literal.chars().map(|c| c.to_string()).collect())
+    }
+
+    fn kind(token_type: &str) -> Option<char> {
+        let mut chars = token_type.chars();
+        chars.next().filter(|_| chars.next().is_none())
     }
 
     /* Simplest possible match behavior */
-    fn matches(token_type: &str, token: &Self) -> Result<bool, ParseError> {
-        Ok(token_type == token.token_type)
+    fn matches(kind: &char, token: &Self) -> bool {
+        *kind == token.token_type
+    }
+
+    fn as_char(&self) -> Option<char> {
+        Some(self.token_type)
     }
 }
 
 impl std::fmt::Display for CharToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.token_type)
+        write!(f, "{}", self.token_type)
+    }
+}
+
+/* A token for one byte of binary input, for defining parsers of file formats and
+ * protocols rather than text. In the definition, each byte stands for the character
+ * with the same value, so "\x0A" or [\x00-\x1F] match bytes by value, and plain
+ * ASCII literals like "GIF89a" match their bytes as usual. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ByteToken {
+    pub byte: u8,
+}
+
+impl Token for ByteToken {
+    type Kind = u8;
+
+    fn type_sequence_from_literal(literal: &str) -> Option<Vec<String>> {
+        Some(literal.chars().map(|c| c.to_string()).collect())
+    }
+
+    // Characters past \xFF don't stand for any byte.
+    fn kind(token_type: &str) -> Option<u8> {
+        CharToken::kind(token_type).and_then(|ch| u8::try_from(ch).ok())
+    }
+
+    fn matches(kind: &u8, token: &Self) -> bool {
+        *kind == token.byte
+    }
+
+    fn as_char(&self) -> Option<char> {
+        Some(char::from(self.byte))
+    }
+}
+
+impl std::fmt::Display for ByteToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.byte.is_ascii_graphic() || self.byte == b' ' {
+            write!(f, "{}", char::from(self.byte))
+        }
+        else {
+            write!(f, "\\x{:02X}", self.byte)
+        }
     }
 }
 
 impl<T: Token> Parser<T> {
+    pub(crate) fn from_rules(rules: HashMap<String, RuleExpression>, terminals: HashMap<String, Matcher<T>>) 
+            -> Result<Parser<T>, DefinitionError> {
+        let terminals = terminals.into_iter()
+            .map(|(name, matcher)| (name, TerminalMatcher::Custom(matcher)))
+            .collect();
+        let rules = intern_rule_names(rules.into_iter().map(|(name, expr)| (name.into(), expr)).collect());
+
+        Ok(Parser {
+            prediction_tables: PredictionTables::new(&rules),
+            terminals: resolve_terminals(rules.values(), terminals)?,
+            rules,
+            ambiguity_policy: AmbiguityPolicy::default(),
+            anchored: true,
+            lexer: None,
+            predicates: HashMap::new(),
+            externs: HashSet::new(),
+            algorithm: ParseAlgorithm::default(),
+            ordered_choice: false,
+            lossless: false,
+            captures: HashMap::new(),
+            scanners: HashMap::new(),
+            stateful_predicates: HashMap::new(),
+            actions: HashMap::new(),
+            sync_tokens: HashMap::new(),
+            labels: HashMap::new(),
+            locations: HashMap::new(),
+            limits: ParseLimits::default(),
+            progress: None,
+            observers: Observers::new(),
+            phantom: std::marker::PhantomData,
+        })
+    }
+
+    pub fn set_ambiguity_policy(&mut self, policy: AmbiguityPolicy) {
+        self.ambiguity_policy = policy;
+    }
+
+    /* Anchored parsers (the default) must consume every token. Unanchored parsers
+     * instead parse the longest prefix of the input that they can, and leave the
+     * rest. Check the span of the returned tree to see how much was consumed. */
+    pub fn set_anchored(&mut self, anchored: bool) {
+        self.anchored = anchored;
+    }
+
+    /* Adds a check that every match of a rule has to pass, on top of the grammar. The
+     * predicate receives all of the tokens and the span of the match, and returns false
+     * to reject it. This makes context sensitive decisions possible, e.g. only accepting
+     * an identifier as a type name if it was declared as one. A rule can have many
+     * predicates, and all of them have to accept. */
+    pub fn add_predicate(&mut self, rule_name: &str, predicate: impl Fn(&[T], Range<usize>) -> bool + 'static) 
+            -> Result<(), DefinitionError> {
+        self.add_boxed_predicate(rule_name, Box::new(predicate))
+    }
+
+    pub(crate) fn add_boxed_predicate(&mut self, rule_name: &str, predicate: Predicate<T>) -> Result<(), DefinitionError> {
+        if !self.rules.contains_key(rule_name) {
+            return Err(DefinitionError::new(format!("Cannot add a predicate to undefined rule \"{rule_name}\"")));
+        }
+
+        self.predicates.entry(rule_name.to_string()).or_default().push(predicate);
+        Ok(())
+    }
+
+    /* Makes the terminal `_name` match the tokens that the closure accepts, instead of
+     * asking the token type. This works for terminals the token type has never heard
+     * of, but those are rejected by define_parser before there is a parser to register
+     * them on, so register them with ParserBuilder::terminal instead. Registering
+     * a terminal the grammar already uses replaces how it is matched. */
+    pub fn register_terminal(&mut self, name: &str, matcher: impl Fn(&T) -> bool + 'static) {
+        self.terminals.insert(name.to_string(), TerminalMatcher::Custom(Box::new(matcher)));
+    }
+
+    /* Whenever the terminal `_name` matches a token, runs the closure on it and keeps
+     * the value it returns in the token's node, so that turning the tree into an AST
+     * doesn't have to pick the token apart again. Get the value back with
+     * SyntaxTree::captured. */
+    pub fn register_capture<V: Any + Send + Sync>(&mut self, name: &str, capture: impl Fn(&T) -> Option<V> + 'static) {
+        self.captures.insert(name.to_string(), Box::new(move |token| capture(token).map(|value| Arc::new(value) as Captured)));
+    }
+
+    // The value to keep in the node for a token matched by expr.
+    pub(crate) fn capture(&self, expr: &RuleExpression, token: &T) -> Option<Captured> {
+        match expr {
+            RuleExpression::Terminal(term) if !self.captures.is_empty() => self.captures.get(term).and_then(|capture| capture(token)),
+            _ => None,
+        }
+    }
+
+    /* Supplies the code behind a rule declared with `external Name;` in the definition.
+     * The scanner gets all of the tokens and the index to start at, and returns how many
+     * tokens it matches, or None if it doesn't match there. It can look as far ahead
+     * as it likes, which makes things like heredocs possible. Its tokens end up as
+     * the children of the rule's node. */
+    pub fn register_external(&mut self, rule_name: &str, scanner: impl Fn(&[T], usize) -> Option<usize> + 'static) 
+            -> Result<(), DefinitionError> {
+        self.add_boxed_scanner(rule_name, Box::new(scanner))
+    }
+
+    pub(crate) fn add_boxed_scanner(&mut self, rule_name: &str, scanner: Scanner<T>) -> Result<(), DefinitionError> {
+        match self.rules.get(rule_name) {
+            Some(RuleExpression::External(_)) => {
+                self.scanners.insert(rule_name.to_string(), scanner);
+                Ok(())
+            }
+            _ => Err(DefinitionError::new(format!("Cannot register a scanner for \"{rule_name}\", it is not declared external"))),
+        }
+    }
+
+    // How many tokens an external rule matches at index.
+    pub(crate) fn scan(&self, rule_name: &str, tokens: &[T], index: usize) -> Result<Option<usize>, ParseError> {
+        let scanner = self.scanners.get(rule_name)
+            .ok_or_else(|| ParseError::from(format!("No scanner registered for external rule \"{rule_name}\"")))?;
+
+        match scanner(tokens, index) {
+            Some(length) if index + length > tokens.len() => 
+                Err(format!("Scanner for \"{rule_name}\" matched past the end of the input").into()),
+            length => Ok(length),
+        }
+    }
+
+    // The error for a rule that isn't defined, which suggests one that is if the name looks like a typo.
+    pub(crate) fn unknown_rule(&self, rule_name: &str) -> ParseError {
+        ParseError::UnknownRule {
+            rule_name: rule_name.to_string(),
+            suggestion: closest_name(rule_name, self.rules.keys().map(Symbol::as_str)).map(ToString::to_string),
+        }
+    }
+
+    // Declared with `recover Rule : ... ;` in the definition.
+    pub(crate) fn add_sync_tokens(&mut self, rule_name: &str, expr: RuleExpression) -> Result<(), DefinitionError> {
+        if !self.rules.contains_key(rule_name) {
+            return Err(DefinitionError::new(format!("Cannot add sync tokens to undefined rule \"{rule_name}\"")));
+        }
+        if self.sync_tokens.insert(rule_name.to_string(), expr).is_some() {
+            return Err(DefinitionError::new(format!("Sync tokens for \"{rule_name}\" are declared more than once")));
+        }
+
+        self.terminals = resolve_terminals(self.rules.values().chain(self.sync_tokens.values()), std::mem::take(&mut self.terminals))?;
+        Ok(())
+    }
+
+    /* The label a rule was given with `@label("...")` in the definition, if any. Errors
+     * list the label instead of the rule's name in their expected sets. Labels on @token
+     * rules work too, since the token rule's name is the terminal that gets expected. */
+    pub fn label(&self, rule_name: &str) -> Option<&str> {
+        self.labels.get(rule_name)
+            .or_else(|| self.lexer.as_ref()?.parser.labels.get(rule_name))
+            .map(String::as_str)
+    }
+
+    pub fn set_algorithm(&mut self, algorithm: ParseAlgorithm) {
+        self.algorithm = algorithm;
+    }
+
+    /* Makes `|` an ordered choice in every rule, as in a PEG: the first alternative
+     * that matches is used, even if a later one would let the rest of the input parse.
+     * Repetitions are left alone, so `Word+` can still split the input more than one
+     * way. To do this for only some rules, mark them @ordered in the definition
+     * instead. The Earley algorithm can't do ordered choice. */
+    pub fn set_ordered_choice(&mut self, ordered_choice: bool) {
+        self.ordered_choice = ordered_choice;
+    }
+
+    /* Makes parse_string (over LexedTokens) keep everything the @skip rules matched in
+     * the tree, so that the tree's tokens are exactly the input and tree.unparse() gives
+     * it back. Trivia between two tokens goes in the smallest node holding both of them,
+     * and trivia before the first token or after the last goes in the root. Token
+     * indices and spans then count the trivia too. Grammars without a lexer already
+     * keep every character, so this changes nothing for them. */
+    pub fn set_lossless(&mut self, lossless: bool) {
+        self.lossless = lossless;
+    }
+
     pub fn parse_tokens(&self, tokens: &[T], start_rule: &str) -> Result<SyntaxTree<T>, ParseError> {
-        backtracking_parse(self, tokens, start_rule)
+        self.parse_tokens_anchored(tokens, start_rule, self.anchored, &Deadline::none(), &StatsRecorder::new())
+    }
+
+    /* Parses the longest prefix of the tokens that it can, whether or not the parser is
+     * anchored, and returns the tree along with how many tokens it consumed. This is for
+     * using a grammar as one piece of a bigger, hand written parser: pass the tokens from
+     * where that parser is up to, and carry on after the consumed ones. Spans in the tree
+     * are relative to the tokens passed in. */
+    pub fn parse_prefix(&self, tokens: &[T], start_rule: &str) -> Result<(SyntaxTree<T>, usize), ParseError> {
+        let tree = self.parse_tokens_anchored(tokens, start_rule, false, &Deadline::none(), &StatsRecorder::new())?;
+        let consumed = tree.span().end;
+        Ok((tree, consumed))
+    }
+
+    fn parse_tokens_anchored(&self, tokens: &[T], start_rule: &str, anchored: bool, deadline: &Deadline, stats: &StatsRecorder) -> Result<SyntaxTree<T>, ParseError> {
+        self.check_stateless()?;
+        if let AmbiguityPolicy::FirstMatch = self.ambiguity_policy {
+            return match self.algorithm {
+                ParseAlgorithm::Auto => match ll1_parse(self, tokens, start_rule, anchored) {
+                    Some(tree) => Ok(tree),
+                    None => backtracking_parse(self, tokens, start_rule, anchored, deadline, stats),
+                },
+                ParseAlgorithm::Backtracking => backtracking_parse(self, tokens, start_rule, anchored, deadline, stats),
+                ParseAlgorithm::Earley => earley_parse(self, tokens, start_rule, anchored, deadline, stats),
+            };
+        }
+
+        self.resolve_ambiguity(self.parse_all_anchored(tokens, start_rule, anchored, deadline, stats)?)
+    }
+
+    // Picks the tree to return out of every tree for the input, following the ambiguity policy.
+    fn resolve_ambiguity(&self, mut trees: Vec<SyntaxTree<T>>) -> Result<SyntaxTree<T>, ParseError> {
+        if trees.len() > 1 {
+            let ambiguity = Ambiguity::between(&trees);
+            match &self.ambiguity_policy {
+                AmbiguityPolicy::RejectAmbiguity => return Err(ParseError::Ambiguous(ambiguity)),
+                AmbiguityPolicy::WarnAndPick(callback) => callback(&ambiguity),
+                AmbiguityPolicy::FirstMatch => (),
+            }
+        }
+
+        Ok(trees.swap_remove(0))
+    }
+
+    /* Like parse_tokens, but returns every distinct syntax tree when the input
+     * is ambiguous, so that callers can disambiguate for themselves. A cycle in the
+     * grammar gives infinitely many trees, so only some are returned, and how many
+     * depends on the algorithm (see the README). */
+    pub fn parse_all(&self, tokens: &[T], start_rule: &str) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+        self.parse_all_anchored(tokens, start_rule, self.anchored, &Deadline::none(), &StatsRecorder::new())
+    }
+
+    fn parse_all_anchored(&self, tokens: &[T], start_rule: &str, anchored: bool, deadline: &Deadline, stats: &StatsRecorder) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+        self.check_stateless()?;
+        match self.algorithm {
+            // An LL(1) parse is the only possible parse.
+            ParseAlgorithm::Auto => match ll1_parse(self, tokens, start_rule, anchored) {
+                Some(tree) => Ok(vec![tree]),
+                None => backtracking_parse_all(self, tokens, start_rule, anchored, deadline, stats),
+            },
+            ParseAlgorithm::Backtracking => backtracking_parse_all(self, tokens, start_rule, anchored, deadline, stats),
+            ParseAlgorithm::Earley => earley_parse_all(self, tokens, start_rule, anchored, deadline, stats),
+        }
     }
 }
 
 impl Parser<CharToken> {
     pub fn parse_string(&self, input: &str, start_rule: &str) -> Result<SyntaxTree<CharToken>, ParseError> {
-        let tokens = input.chars()
-            .map(|ch| CharToken { token_type: ch.to_string() })
-            .collect::<Vec<_>>();
-        self.parse_tokens(&tokens, start_rule)
+        self.parse_tokens(&string_to_tokens(input), start_rule)
+            .map_err(|err| locate_error(err, input))
+    }
+
+    /* Like parse_prefix, but returns the rest of the input instead of a token count. */
+    pub fn parse_string_prefix<'a>(&self, input: &'a str, start_rule: &str) -> Result<(SyntaxTree<CharToken>, &'a str), ParseError> {
+        let (tree, consumed) = self.parse_prefix(&string_to_tokens(input), start_rule)
+            .map_err(|err| locate_error(err, input))?;
+        let rest = &input[LineMap::new(input).byte_of_char(consumed)..];
+        Ok((tree, rest))
+    }
+
+    pub fn parse_string_all(&self, input: &str, start_rule: &str) -> Result<Vec<SyntaxTree<CharToken>>, ParseError> {
+        self.parse_all(&string_to_tokens(input), start_rule)
+            .map_err(|err| locate_error(err, input))
+    }
+}
+
+impl Parser<ByteToken> {
+    /* Parses binary input. Errors give the index of the byte where parsing failed,
+     * there are no lines and columns to point at. */
+    pub fn parse_bytes(&self, input: &[u8], start_rule: &str) -> Result<SyntaxTree<ByteToken>, ParseError> {
+        self.parse_tokens(&bytes_to_tokens(input), start_rule)
+    }
+
+    pub fn parse_bytes_all(&self, input: &[u8], start_rule: &str) -> Result<Vec<SyntaxTree<ByteToken>>, ParseError> {
+        self.parse_all(&bytes_to_tokens(input), start_rule)
+    }
+}
+
+/* Makes every reference to a rule share the name of the rule itself, so that the trees
+ * built from any of them share it too. */
+pub(crate) fn intern_rule_names(mut rules: HashMap<Symbol, RuleExpression>) -> HashMap<Symbol, RuleExpression> {
+    let rule_names = rules.keys().cloned().collect::<HashSet<Symbol>>();
+    for expr in rules.values_mut() {
+        expr.intern_rule_names(&rule_names);
+    }
+    rules
+}
+
+/* Asks the token type what each terminal in the rules means, so that parsing only
+ * has to compare kinds. Registered matchers are kept, and take priority. */
+pub(crate) fn resolve_terminals<'r, T: Token>(exprs: impl IntoIterator<Item = &'r RuleExpression>, 
+        mut resolved: HashMap<String, TerminalMatcher<T>>) -> Result<HashMap<String, TerminalMatcher<T>>, DefinitionError> {
+    resolved.retain(|_, matcher| matches!(matcher, TerminalMatcher::Custom(_)));
+
+    let mut terminals = vec![];
+    for expr in exprs {
+        expr.terminals(&mut terminals);
+    }
+
+    for terminal in terminals {
+        if !resolved.contains_key(terminal) {
+            let kind = T::kind(terminal)
+                .ok_or_else(|| DefinitionError::new(format!("Unknown token type \"{terminal}\"")))?;
+            resolved.insert(terminal.to_string(), TerminalMatcher::Kind(kind));
+        }
+    }
+
+    Ok(resolved)
+}
+
+// Like ", expected X or Y", in a stable order.
+fn write_expected(f: &mut std::fmt::Formatter<'_>, expected: &HashSet<String>) -> std::fmt::Result {
+    let mut expected = expected.iter().sorted();
+    match expected.len() {
+        0 => Ok(()),
+        1 | 2 => write!(f, ", expected {}", expected.join(" or ")),
+        _ => write!(f, ", expected one of {}", expected.join(", ")),
+    }
+}
+
+fn locate_error(err: ParseError, input: &str) -> ParseError {
+    match err {
+        ParseError::UnexpectedToken { index, terminals, expected, context, .. } => {
+            let line_map = LineMap::new(input);
+            ParseError::UnexpectedToken { 
+                index, 
+                terminals, 
+                expected, 
+                context, 
+                location: Some(line_map.location_of_char(index)),
+                span: Some(line_map.byte_of_char(index)..line_map.byte_of_char(index + 1)),
+            }
+        },
+        ParseError::Cancelled { index, .. } => ParseError::Cancelled { index, location: Some(LineMap::new(input).location_of_char(index)) },
+        ParseError::LimitExceeded { limit, rule_name, index, .. } => 
+            ParseError::LimitExceeded { limit, rule_name, index, location: Some(LineMap::new(input).location_of_char(index)) },
+        err => err,
     }
 }
 
+/* The name most like `name`, if one is close enough that `name` is probably a typo of
+ * it: at most a third of its characters (but at least one, and never all of them) are
+ * different. Ties go to the name that sorts first. */
+pub(crate) fn closest_name<'n>(name: &str, names: impl IntoIterator<Item = &'n str>) -> Option<&'n str> {
+    let length = name.chars().count();
+    let allowed = (length / 3).max(1).min(length.saturating_sub(1));
+    names.into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= allowed)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+// Levenshtein distance, counting characters.
+fn edit_distance(left: &str, right: &str) -> usize {
+    let right = right.chars().collect::<Vec<char>>();
+    let mut previous = (0..=right.len()).collect::<Vec<usize>>();
+
+    for (i, left_char) in left.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, right_char) in right.iter().enumerate() {
+            let substitution = previous[j] + usize::from(left_char != *right_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[right.len()]
+}
+
+fn string_to_tokens(input: &str) -> Vec<CharToken> {
+    input.chars()
+        .map(|token_type| CharToken { token_type })
+        .collect()
+}
+
+
+fn bytes_to_tokens(input: &[u8]) -> Vec<ByteToken> {
+    input.iter()
+        .map(|&byte| ByteToken { byte })
+        .collect()
+}
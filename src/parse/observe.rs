@@ -0,0 +1,64 @@
+/* Callbacks from inside a parse, for live tracing or custom metrics. Rules are
+ * reported with the index they started at, and tokens as they are matched.
+ *
+ * Only the backtracking and LL(1) algorithms parse a rule in one piece, so only they
+ * call these. Earley parses call nothing. The backtracking parser memoizes, so a rule
+ * is only entered once at each token, however many times it is needed there, and it
+ * reports rules that go nowhere as well as the ones in the final tree. With
+ * ParseAlgorithm::Auto, if the LL(1) tables can't finish a parse, the rules they gave
+ * up on exit unmatched, and the backtracking parser starts over. Rules parsed by a
+ * lookahead aren't reported. */
+
+use super::{Parser, Token};
+
+
+/* Public Interface */
+
+impl<T: Token> Parser<T> {
+    /* Called with the rule's name and the index it starts at. Calling this again adds
+     * another callback, rather than replacing the last one. */
+    pub fn on_enter_rule(&mut self, callback: impl Fn(&str, usize) + 'static) {
+        self.observers.enter_rule.push(Box::new(callback));
+    }
+
+    /* Called with the rule's name, the index it started at, and whether it matched. */
+    pub fn on_exit_rule(&mut self, callback: impl Fn(&str, usize, bool) + 'static) {
+        self.observers.exit_rule.push(Box::new(callback));
+    }
+
+    /* Called with each token matched, and its index. */
+    pub fn on_token(&mut self, callback: impl Fn(&T, usize) + 'static) {
+        self.observers.token.push(Box::new(callback));
+    }
+}
+
+
+/* Private Implementation */
+
+type RuleCallback = Box<dyn Fn(&str, usize)>;
+type ExitCallback = Box<dyn Fn(&str, usize, bool)>;
+type TokenCallback<T> = Box<dyn Fn(&T, usize)>;
+
+pub(crate) struct Observers<T: Token> {
+    enter_rule: Vec<RuleCallback>,
+    exit_rule: Vec<ExitCallback>,
+    token: Vec<TokenCallback<T>>,
+}
+
+impl<T: Token> Observers<T> {
+    pub(crate) fn new() -> Observers<T> {
+        Observers { enter_rule: vec![], exit_rule: vec![], token: vec![] }
+    }
+
+    pub(crate) fn enter_rule(&self, rule_name: &str, index: usize) {
+        self.enter_rule.iter().for_each(|callback| callback(rule_name, index));
+    }
+
+    pub(crate) fn exit_rule(&self, rule_name: &str, index: usize, matched: bool) {
+        self.exit_rule.iter().for_each(|callback| callback(rule_name, index, matched));
+    }
+
+    pub(crate) fn token(&self, token: &T, index: usize) {
+        self.token.iter().for_each(|callback| callback(token, index));
+    }
+}
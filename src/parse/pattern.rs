@@ -0,0 +1,96 @@
+/* tree_match!, for taking syntax trees apart by their shape instead of by hand.
+ *
+ *     let value = tree_match!(tree,
+ *         AtomicExpr("(", inner @ _, ")") => evaluate(inner),
+ *         AtomicExpr(number @ Number) => parse_number(number),
+ *         _ => panic!("Not an expression"),
+ *     );
+ *
+ * The tree is matched against each arm in turn, and the first that fits gives the
+ * value. Patterns are:
+ *
+ *     _                    Anything.
+ *     "("                  A token node, whose token Displays as exactly that.
+ *     Number               A node of the rule Number, whatever is under it.
+ *     Rule(p1, p2, ...)    A node of the rule, with exactly those children, each matching its pattern.
+ *     Rule(p1, ..)         The same, but with any number of children after the ones given.
+ *     name @ pattern       Matches the pattern, and binds the node (a &SyntaxTree) to name.
+ *
+ * The macro is an expression, so unlike a match it can't check that the arms cover
+ * every tree. If none of them match, it panics, so you probably want a `_` arm last. */
+
+#[macro_export]
+macro_rules! tree_match {
+    ($tree:expr, $($arms:tt)*) => {{
+        let tree: &$crate::SyntaxTree<_> = &$tree;
+        #[allow(unreachable_code)]  // The panic, after a `_` arm.
+        let value = 'tree_match: {
+            $crate::__tree_match_arms!('tree_match, tree, [] $($arms)*);
+            panic!("tree_match! found no arm matching the tree")
+        };
+        value
+    }};
+}
+
+// Splits the arms up, collecting the tokens of each pattern until its =>.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __tree_match_arms {
+    ($label:lifetime, $tree:ident, []) => {};
+    ($label:lifetime, $tree:ident, [$($pattern:tt)+] => $body:expr $(, $($rest:tt)*)?) => {
+        $crate::__tree_match_pattern!($tree, [$($pattern)+], { break $label $body; });
+        $crate::__tree_match_arms!($label, $tree, [] $($($rest)*)?)
+    };
+    ($label:lifetime, $tree:ident, [$($pattern:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::__tree_match_arms!($label, $tree, [$($pattern)* $next] $($rest)*)
+    };
+}
+
+// Runs $then if the node matches the pattern, with the pattern's names bound.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __tree_match_pattern {
+    ($node:expr, [_], $then:block) => {{
+        let _ = $node;
+        $then
+    }};
+    ($node:expr, [$text:literal], $then:block) => {
+        if matches!($node, $crate::SyntaxTree::TokenNode { token, .. } if token.to_string() == $text) $then
+    };
+    ($node:expr, [$rule:ident], $then:block) => {
+        if $node.rule_name() == Some(stringify!($rule)) $then
+    };
+    ($node:expr, [$rule:ident ( $($children:tt)* )], $then:block) => {
+        if $node.rule_name() == Some(stringify!($rule)) {
+            let children = $node.children();
+            $crate::__tree_match_children!(children, 0, [], [$($children)*], $then)
+        }
+    };
+    ($node:expr, [$name:ident @ $($pattern:tt)+], $then:block) => {{
+        let $name = $node;
+        $crate::__tree_match_pattern!($name, [$($pattern)+], $then)
+    }};
+}
+
+// Matches the children one at a time, collecting the tokens of each pattern until its comma.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __tree_match_children {
+    ($children:ident, $index:expr, [], [], $then:block) => {
+        if $children.len() == $index $then
+    };
+    ($children:ident, $index:expr, [], [..], $then:block) => {{
+        let _ = $children;
+        $then
+    }};
+    ($children:ident, $index:expr, [$($pattern:tt)+], [$(, $($rest:tt)*)?], $then:block) => {
+        if let Some(child) = $children.get($index) {
+            $crate::__tree_match_pattern!(child, [$($pattern)+], {
+                $crate::__tree_match_children!($children, $index + 1, [], [$($($rest)*)?], $then)
+            })
+        }
+    };
+    ($children:ident, $index:expr, [$($pattern:tt)*], [$next:tt $($rest:tt)*], $then:block) => {
+        $crate::__tree_match_children!($children, $index, [$($pattern)* $next], [$($rest)*], $then)
+    };
+}
@@ -0,0 +1,121 @@
+/* Per rule numbers for a parse, for finding the rules that make a big grammar slow.
+ * Every time a rule is actually parsed at some token is an attempt (answers that came
+ * out of the memo are free, so they don't count), and each attempt is timed.
+ *
+ * Profiling always uses the backtracking algorithm, whatever set_algorithm says. It is
+ * the one that slow parses spend their time in, and the only one where a rule is parsed
+ * in one piece, so there is something to time. */
+
+use super::{AmbiguityPolicy, CharToken, Parser, ParseError, SyntaxTree, Token};
+use super::backtracking_parser::{backtracking_parse, backtracking_parse_all};
+use super::budget::Deadline;
+use super::stats::StatsRecorder;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+
+/* Public Interface */
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseProfile {
+    rules: Vec<RuleProfile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleProfile {
+    pub rule_name: String,
+    pub attempts: usize,
+    pub matches: usize,  // Attempts that matched at least one way.
+    pub self_time: Duration,  // Time in the rule itself, leaving out the rules inside it.
+    pub total_time: Duration,  // Time in the rule and the rules inside it. Recursive rules count the inner time more than once.
+}
+
+impl<T: Token> Parser<T> {
+    /* Like parse_tokens, but also returns the profile, whether or not the parse worked. */
+    pub fn parse_tokens_with_profile(&self, tokens: &[T], start_rule: &str) -> (Result<SyntaxTree<T>, ParseError>, ParseProfile) {
+        let recorder = StatsRecorder::profiling();
+        let result = self.check_stateless().and_then(|()| match self.ambiguity_policy {
+            AmbiguityPolicy::FirstMatch => backtracking_parse(self, tokens, start_rule, self.anchored, &Deadline::none(), &recorder),
+            _ => self.resolve_ambiguity(backtracking_parse_all(self, tokens, start_rule, self.anchored, &Deadline::none(), &recorder)?),
+        });
+
+        (result, recorder.profile())
+    }
+}
+
+impl Parser<CharToken> {
+    pub fn parse_string_with_profile(&self, input: &str, start_rule: &str) -> (Result<SyntaxTree<CharToken>, ParseError>, ParseProfile) {
+        let (result, profile) = self.parse_tokens_with_profile(&super::string_to_tokens(input), start_rule);
+        (result.map_err(|err| super::locate_error(err, input)), profile)
+    }
+}
+
+impl ParseProfile {
+    /* Every rule that was attempted, the one with the most self time first. */
+    pub fn rules(&self) -> &[RuleProfile] {
+        &self.rules
+    }
+
+    pub fn rule(&self, rule_name: &str) -> Option<&RuleProfile> {
+        self.rules.iter().find(|rule| rule.rule_name == rule_name)
+    }
+}
+
+/* A table of the rules, slowest first. */
+impl Display for ParseProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self.rules.iter().map(|rule| rule.rule_name.chars().count()).max().unwrap_or(0).max(4);
+        writeln!(f, "{:width$}  {:>10}  {:>10}  {:>12}  {:>12}", "Rule", "Attempts", "Matches", "Self", "Total")?;
+        for rule in &self.rules {
+            writeln!(f, "{:width$}  {:>10}  {:>10}  {:>12}  {:>12}", rule.rule_name, rule.attempts, rule.matches,
+                format!("{:.3?}", rule.self_time), format!("{:.3?}", rule.total_time))?;
+        }
+        Ok(())
+    }
+}
+
+
+/* Private Implementation */
+
+/* Collects a ParseProfile as rules are entered and exited. */
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    rules: HashMap<String, RuleProfile>,
+    stack: Vec<(Instant, Duration)>,  // When each rule being parsed was entered, and the time spent in rules inside it.
+}
+
+impl Profiler {
+    pub(crate) fn enter_rule(&mut self) {
+        self.stack.push((Instant::now(), Duration::ZERO));
+    }
+
+    pub(crate) fn exit_rule(&mut self, rule_name: &str, matched: bool) {
+        let Some((entered, inner_time)) = self.stack.pop() else {
+            return;
+        };
+        let total_time = entered.elapsed();
+        if let Some((_, parent_inner_time)) = self.stack.last_mut() {
+            *parent_inner_time += total_time;
+        }
+
+        let rule = self.rules.entry(rule_name.to_string()).or_insert_with(|| RuleProfile {
+            rule_name: rule_name.to_string(),
+            attempts: 0,
+            matches: 0,
+            self_time: Duration::ZERO,
+            total_time: Duration::ZERO,
+        });
+        rule.attempts += 1;
+        rule.matches += usize::from(matched);
+        rule.self_time += total_time.saturating_sub(inner_time);
+        rule.total_time += total_time;
+    }
+
+    pub(crate) fn finish(&self) -> ParseProfile {
+        let mut rules = self.rules.values().cloned().collect::<Vec<_>>();
+        rules.sort_by(|a, b| b.self_time.cmp(&a.self_time).then_with(|| a.rule_name.cmp(&b.rule_name)));
+        ParseProfile { rules }
+    }
+}
@@ -0,0 +1,65 @@
+/* Progress reports for long parses, so batch tools can show a progress bar, and notice
+ * a grammar that is running away (lots of work, with the position barely moving).
+ *
+ * Every algorithm reports as the furthest token it has reached passes each multiple of
+ * the interval. Backtracking can go back and forth over the input, so its position
+ * is a high water mark rather than what the final tree covers. With ParseAlgorithm::Auto,
+ * if the LL(1) tables can't finish a parse, the backtracking parser starts over, and so
+ * do the reports. */
+
+use super::{Parser, Token};
+
+
+/* Public Interface */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseProgress {
+    pub tokens: usize,  // The furthest token reached.
+    pub total_tokens: usize,
+    /* How much the algorithm is holding on to: memo entries for backtracking, chart
+     * items for Earley, and tree events for LL(1). If this grows much faster than
+     * tokens, the grammar is likely doing far more work than it should. */
+    pub live_entries: usize,
+}
+
+impl<T: Token> Parser<T> {
+    /* Calls the callback every time a parse gets another `every` tokens further. A
+     * parse with fewer tokens than that reports nothing. */
+    pub fn set_progress_callback(&mut self, every: usize, callback: impl Fn(ParseProgress) + 'static) {
+        self.progress = Some((every.max(1), Box::new(callback)));
+    }
+}
+
+
+/* Private Implementation */
+
+pub(crate) type ProgressCallback = Box<dyn Fn(ParseProgress)>;
+
+pub(crate) struct ProgressReporter<'p> {
+    callback: Option<&'p (usize, ProgressCallback)>,
+    next: usize,  // The token that triggers the next report.
+    total_tokens: usize,
+}
+
+impl<'p> ProgressReporter<'p> {
+    pub(crate) fn new<T: Token>(parser: &'p Parser<T>, total_tokens: usize) -> ProgressReporter<'p> {
+        let callback = parser.progress.as_ref();
+        ProgressReporter { callback, next: callback.map_or(0, |(every, _)| *every), total_tokens }
+    }
+
+    pub(crate) fn none() -> ProgressReporter<'p> {
+        ProgressReporter { callback: None, next: 0, total_tokens: 0 }
+    }
+
+    // live_entries is only asked for when there's something to report, since it may take counting.
+    pub(crate) fn reached(&mut self, index: usize, live_entries: impl FnOnce() -> usize) {
+        let Some((every, callback)) = self.callback else {
+            return;
+        };
+
+        if index >= self.next {
+            self.next = (index / every + 1) * every;
+            callback(ParseProgress { tokens: index, total_tokens: self.total_tokens, live_entries: live_entries() });
+        }
+    }
+}
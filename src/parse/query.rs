@@ -0,0 +1,277 @@
+/* Getting around a syntax tree without matching on it at every step.
+ *
+ * For anything more than a step or two, there are path queries, a little like XPath:
+ * `FunctionDecl/ParamList/Param` finds every Param in a ParamList in a FunctionDecl.
+ * The first step can match anywhere at or under the node the query is run on, and
+ * each step after it matches the children of the one before. A step is a rule name,
+ * `*` for any rule, or `**` for any number of levels (including none), and can be
+ * narrowed down by the text of its tokens, with `Name[text="main"]` or
+ * `Name[text!="main"]`. A node's text is the Display of its tokens, one after the
+ * other. */
+
+use super::{SyntaxTree, Token};
+
+use by_address::ByAddress;
+
+use std::collections::HashSet;
+use std::fmt::Display;
+
+
+/* A path query, parsed and ready to run on as many trees as you like. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeQuery {
+    steps: Vec<Step>,
+}
+
+/* Why a query didn't parse. The position is a byte offset into the query. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {} of the query", self.message, self.position)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl TreeQuery {
+    pub fn new(query: &str) -> Result<TreeQuery, QueryError> {
+        let mut reader = QueryReader { query, position: 0 };
+        let mut steps = vec![reader.step()?];
+        while reader.eat("/") {
+            steps.push(reader.step()?);
+        }
+
+        reader.skip_whitespace();
+        if reader.position < query.len() {
+            return Err(reader.error("Expected / or the end of the query"));
+        }
+        Ok(TreeQuery { steps })
+    }
+
+    /* Every node the query matches, at or under tree, in the order of the input.
+     * Nodes are only returned once, even if the query reaches them in more than one way. */
+    pub fn select<'t, T: Token + Display>(&self, tree: &'t SyntaxTree<T>) -> Vec<&'t SyntaxTree<T>> {
+        let mut nodes = tree.descendants().collect::<Vec<_>>();
+        for (i, step) in self.steps.iter().enumerate() {
+            // The first step already has every node to choose from.
+            let candidates = match (i, step) {
+                (0, _) => nodes,
+                (_, Step::AnyDepth) => nodes.into_iter().flat_map(SyntaxTree::descendants).collect(),
+                (_, Step::Rule {..}) => nodes.into_iter().flat_map(SyntaxTree::children).collect(),
+            };
+
+            let mut seen = HashSet::new();
+            nodes = candidates.into_iter()
+                .filter(|node| step.matches(node) && seen.insert(ByAddress(*node)))
+                .collect();
+        }
+
+        let found = nodes.into_iter().map(ByAddress).collect::<HashSet<_>>();
+        tree.descendants().filter(|node| found.contains(&ByAddress(*node))).collect()
+    }
+}
+
+impl<T: Token + Display> SyntaxTree<T> {
+    /* Runs a path query on this tree, see TreeQuery. To run the same query on many
+     * trees, make a TreeQuery once instead. */
+    pub fn select(&self, query: &str) -> Result<Vec<&SyntaxTree<T>>, QueryError> {
+        Ok(TreeQuery::new(query)?.select(self))
+    }
+
+    /* The Display of every token at or under this node, one after the other. */
+    pub fn token_text(&self) -> String {
+        self.descendants()
+            .flat_map(|node| match node {
+                SyntaxTree::TokenNode {token, ..} => vec![token.to_string()],
+                SyntaxTree::ErrorNode {tokens, ..} => tokens.iter().map(ToString::to_string).collect(),
+                SyntaxTree::RuleNode {..} => vec![],
+            })
+            .collect()
+    }
+}
+
+/* Every node in a tree, from SyntaxTree::descendants. */
+pub struct Descendants<'t, T: Token> {
+    stack: Vec<&'t SyntaxTree<T>>,  // Nodes still to visit, the next one last.
+}
+
+impl<T: Token> SyntaxTree<T> {
+    /* The nodes directly under this one. Empty for token and error nodes. */
+    pub fn children(&self) -> &[SyntaxTree<T>] {
+        match self {
+            SyntaxTree::RuleNode {subexpressions, ..} => subexpressions,
+            SyntaxTree::TokenNode {..} | SyntaxTree::ErrorNode {..} => &[],
+        }
+    }
+
+    /* The rule this node matched, if it is a rule node. */
+    pub fn rule_name(&self) -> Option<&str> {
+        match self {
+            SyntaxTree::RuleNode {rule_name, ..} => Some(rule_name),
+            SyntaxTree::TokenNode {..} | SyntaxTree::ErrorNode {..} => None,
+        }
+    }
+
+    /* The first node directly under this one that matched the rule. */
+    pub fn first_child(&self, rule_name: &str) -> Option<&SyntaxTree<T>> {
+        self.children().iter().find(|child| child.rule_name() == Some(rule_name))
+    }
+
+    /* The tokens directly under this one, in order. Tokens inside child rules aren't
+     * included, so for a rule like `Call : Name "(" Args ")"` these are the parentheses. */
+    pub fn children_tokens(&self) -> impl Iterator<Item = &T> {
+        self.children().iter().filter_map(|child| match child {
+            SyntaxTree::TokenNode {token, ..} => Some(token),
+            _ => None,
+        })
+    }
+
+    /* This node and every node under it, in the order they appear in the input (so
+     * each rule comes before its children). */
+    pub fn descendants(&self) -> Descendants<'_, T> {
+        Descendants { stack: vec![self] }
+    }
+
+    /* Every node of the rule at or under this one, outermost first. Nested matches are
+     * all found, so in `1 + (2 + 3)` a search for Sum finds both sums. */
+    pub fn find_all<'t>(&'t self, rule_name: &'t str) -> impl Iterator<Item = &'t SyntaxTree<T>> {
+        self.descendants().filter(move |node| node.rule_name() == Some(rule_name))
+    }
+}
+
+impl<'t, T: Token> Iterator for Descendants<'t, T> {
+    type Item = &'t SyntaxTree<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.stack.extend(node.children().iter().rev());
+        Some(node)
+    }
+}
+
+
+/* Private Implementation */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Rule { rule_name: Option<String>, predicates: Vec<(String, bool)> },  // None matches any rule. Predicates are text, and whether it should be equal.
+    AnyDepth,  // Matches every node, the previous step's descendants having been added first.
+}
+
+impl Step {
+    fn matches<T: Token + Display>(&self, node: &SyntaxTree<T>) -> bool {
+        match self {
+            Step::AnyDepth => true,
+            Step::Rule { rule_name, predicates } => {
+                let Some(name) = node.rule_name() else {
+                    return false;
+                };
+                if rule_name.as_ref().is_some_and(|rule_name| rule_name != name) {
+                    return false;
+                }
+                let text = if predicates.is_empty() { String::new() } else { node.token_text() };
+                predicates.iter().all(|(expected, equal)| (text == *expected) == *equal)
+            }
+        }
+    }
+}
+
+struct QueryReader<'q> {
+    query: &'q str,
+    position: usize,
+}
+
+impl QueryReader<'_> {
+    fn step(&mut self) -> Result<Step, QueryError> {
+        self.skip_whitespace();
+        if self.eat("**") {
+            return Ok(Step::AnyDepth);
+        }
+
+        let rule_name = if self.eat("*") {
+            None
+        }
+        else {
+            let name = self.rest().chars()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .collect::<String>();
+            if name.is_empty() {
+                return Err(self.error("Expected a rule name, * or **"));
+            }
+            self.position += name.len();
+            Some(name)
+        };
+
+        let mut predicates = vec![];
+        while self.eat("[") {
+            if !self.eat("text") {
+                return Err(self.error("Expected text"));
+            }
+            let equal = if self.eat("=") { true } else if self.eat("!=") { false } else {
+                return Err(self.error("Expected = or !="));
+            };
+            predicates.push((self.string()?, equal));
+            if !self.eat("]") {
+                return Err(self.error("Expected ]"));
+            }
+        }
+
+        Ok(Step::Rule { rule_name, predicates })
+    }
+
+    // A double quoted string, where \" and \\ stand for " and \.
+    fn string(&mut self) -> Result<String, QueryError> {
+        self.skip_whitespace();
+        let start = self.position;
+        if !self.eat("\"") {
+            return Err(self.error("Expected a string"));
+        }
+
+        let mut string = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.position += i + 1;
+                    return Ok(string);
+                }
+                '\\' => match chars.next() {
+                    Some((_, escaped @ ('"' | '\\'))) => string.push(escaped),
+                    _ => {
+                        self.position += i;
+                        return Err(self.error("Bad escape, expected \\\" or \\\\"));
+                    }
+                },
+                c => string.push(c),
+            }
+        }
+        self.position = start;
+        Err(self.error("Unterminated string"))
+    }
+
+    fn eat(&mut self, expected: &str) -> bool {
+        self.skip_whitespace();
+        let found = self.rest().starts_with(expected);
+        if found {
+            self.position += expected.len();
+        }
+        found
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.position = self.query.len() - self.rest().trim_start().len();
+    }
+
+    fn rest(&self) -> &str {
+        &self.query[self.position..]
+    }
+
+    fn error(&self, message: &str) -> QueryError {
+        QueryError { message: message.to_string(), position: self.position }
+    }
+}
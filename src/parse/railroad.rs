@@ -0,0 +1,243 @@
+/* Railroad diagrams of rules, as SVG, for documenting the language a grammar defines.
+ * Everything a rule can match is a path from the left end of the diagram to the right:
+ *
+ *     Terminals          Rounded boxes, with their text quoted, or their kind if they name one.
+ *     Rules              Square boxes, with the rule's name.
+ *     A B                One after the other.
+ *     A | B              Branching tracks, the first alternative on the straight track.
+ *     A? and A*          A track that goes around A.
+ *     A+ and A{n,m}      A track that loops back under A, labeled with the counts for {n,m}.
+ *     &A and !A          A dashed box around A, labeled with what is checked.
+ *
+ * Lengths are guessed from the number of characters, assuming a monospace font. */
+
+use super::{Parser, Token};
+use crate::define::RuleExpression;
+
+use std::fmt::Write;
+
+
+impl<T: Token> Parser<T> {
+    /* A railroad diagram of the rule, as an SVG document. Works for @token and @fragment
+     * rules too. None if there is no such rule. */
+    pub fn railroad_svg(&self, rule_name: &str) -> Option<String> {
+        let expr = self.rules.get(rule_name).or_else(|| self.lexer.as_ref()?.parser.rules.get(rule_name))?;
+        let diagram = layout(expr);
+
+        let width = diagram.width + 2 * MARGIN + 2 * END_LENGTH;
+        let height = diagram.up + diagram.down + 2 * MARGIN + TITLE_HEIGHT;
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n");
+        svg += "<style>\n";
+        svg += "path { stroke: black; stroke-width: 1.5; fill: none; }\n";
+        svg += "rect { stroke: black; stroke-width: 1.5; fill: #f4f4f4; }\n";
+        svg += "rect.lookahead { stroke-dasharray: 4 3; fill: none; }\n";
+        svg += "text { font-family: monospace; font-size: 12px; text-anchor: middle; dominant-baseline: central; }\n";
+        svg += "text.title { font-weight: bold; text-anchor: start; }\n";
+        svg += "</style>\n";
+        writeln!(svg, "<text class=\"title\" x=\"{MARGIN}\" y=\"{}\">{}</text>", MARGIN + TITLE_HEIGHT / 2, escape(rule_name)).expect("Infallible");
+
+        let end_x = END_LENGTH + diagram.width;
+        writeln!(svg, "<g transform=\"translate({MARGIN} {})\">", MARGIN + TITLE_HEIGHT + diagram.up).expect("Infallible");
+        writeln!(svg, "<path d=\"M0 -8 v16 M4 -8 v16 M4 0 h{}\"/>", END_LENGTH - 4).expect("Infallible");
+        translated(&mut svg, END_LENGTH, 0, &diagram.svg);
+        writeln!(svg, "<path d=\"M{end_x} 0 h{} M{} -8 v16 M{} -8 v16\"/>", END_LENGTH - 4, end_x + END_LENGTH - 4, end_x + END_LENGTH).expect("Infallible");
+        svg += "</g>\n</svg>\n";
+        Some(svg)
+    }
+}
+
+
+/* Private Implementation */
+
+const ARC: i32 = 10;  // Radius of the curves where tracks branch.
+const GAP: i32 = 10;  // Between things in a row, and between tracks.
+const BOX_HEIGHT: i32 = 22;
+const CHAR_WIDTH: i32 = 8;
+const MARGIN: i32 = 10;
+const END_LENGTH: i32 = 20;  // The bars at either end, and the track out of them.
+const TITLE_HEIGHT: i32 = 24;
+const LABEL_HEIGHT: i32 = 16;
+
+/* A piece of a diagram. The track comes in at (0, 0) and leaves at (width, 0), and
+ * the drawing reaches `up` above the track and `down` below it. */
+struct Diagram {
+    width: i32,
+    up: i32,
+    down: i32,
+    svg: String,
+}
+
+fn layout(expr: &RuleExpression) -> Diagram {
+    expr.fold(layout_expr)
+}
+
+// Given its subexpressions already laid out.
+fn layout_expr(expr: &RuleExpression, subexpressions: Vec<Diagram>) -> Diagram {
+    let only = |mut diagrams: Vec<Diagram>| diagrams.pop().expect("One subexpression");
+    match expr {
+        RuleExpression::Terminal(terminal) => boxed(&terminal_text(terminal), true),
+        RuleExpression::RuleName(name) => boxed(name, false),
+        RuleExpression::CharacterClass(class) => boxed(&class.source, true),
+        RuleExpression::Negation(_, description) => boxed(&format!("~{description}"), true),
+        RuleExpression::Wildcard => boxed(".", true),
+        RuleExpression::EndOfInput => boxed("$", true),
+        RuleExpression::External(name) => boxed(&format!("external {name}"), false),
+        RuleExpression::Concatenation(exprs) => {
+            let mut subexpressions = subexpressions.into_iter().map(Some).collect::<Vec<_>>();
+            sequence(merge_literals(exprs).into_iter().map(|piece| match piece {
+                Piece::Literal(text) => boxed(&format!("{text:?}"), true),
+                Piece::Expr(i) => subexpressions[i].take().expect("Each piece is laid out once"),
+            }).collect())
+        }
+        RuleExpression::Alternatives(_) | RuleExpression::OrderedAlternatives(_) => choice(subexpressions),
+        RuleExpression::Optional(_) => choice(vec![skip(), only(subexpressions)]),
+        RuleExpression::OneOrMore(_) => repeat(only(subexpressions), None),
+        RuleExpression::Many(_) => choice(vec![skip(), repeat(only(subexpressions), None)]),
+        RuleExpression::Repetition(_, min, max) => {
+            let label = match (min, max) {
+                (min, Some(max)) if min == max => format!("{min} times"),
+                (min, Some(max)) => format!("{min} to {max} times"),
+                (min, None) => format!("at least {min} times"),
+            };
+            let inner = only(subexpressions);
+            match (min, max) {
+                (_, Some(0)) => skip(),
+                (_, Some(1)) if *min == 1 => inner,
+                (0, Some(1)) => choice(vec![skip(), inner]),
+                (0, _) => choice(vec![skip(), repeat(inner, Some(&label))]),
+                _ => repeat(inner, Some(&label)),
+            }
+        }
+        RuleExpression::PositiveLookahead(_) => lookahead(only(subexpressions), "followed by"),
+        RuleExpression::NegativeLookahead(..) => lookahead(only(subexpressions), "not followed by"),
+    }
+}
+
+// A box with text in it, rounded for terminals.
+fn boxed(text: &str, rounded: bool) -> Diagram {
+    let width = text.chars().count() as i32 * CHAR_WIDTH + 2 * GAP;
+    let half = BOX_HEIGHT / 2;
+    let radius = if rounded { half } else { 0 };
+    Diagram {
+        width,
+        up: half,
+        down: half,
+        svg: format!(
+            "<rect x=\"0\" y=\"{}\" width=\"{width}\" height=\"{BOX_HEIGHT}\" rx=\"{radius}\"/>\n<text x=\"{}\" y=\"0\">{}</text>\n",
+            -half, width / 2, escape(text),
+        ),
+    }
+}
+
+fn skip() -> Diagram {
+    Diagram { width: 0, up: 0, down: 0, svg: String::new() }
+}
+
+fn sequence(items: Vec<Diagram>) -> Diagram {
+    let mut diagram = skip();
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            writeln!(diagram.svg, "<path d=\"M{} 0 h{GAP}\"/>", diagram.width).expect("Infallible");
+            diagram.width += GAP;
+        }
+        translated(&mut diagram.svg, diagram.width, 0, &item.svg);
+        diagram.width += item.width;
+        diagram.up = diagram.up.max(item.up);
+        diagram.down = diagram.down.max(item.down);
+    }
+    diagram
+}
+
+// The first option stays on the track, the rest branch off below it.
+fn choice(options: Vec<Diagram>) -> Diagram {
+    let inner = options.iter().map(|option| option.width).max().unwrap_or(0);
+    let width = inner + 4 * ARC;
+    let mut diagram = Diagram { width, up: options[0].up, down: 0, svg: String::new() };
+
+    let mut y = 0;
+    for (i, option) in options.iter().enumerate() {
+        if i > 0 {
+            y = (y + diagram.down + GAP + option.up).max(y + 2 * ARC);
+            writeln!(diagram.svg, "<path d=\"M0 0 a{ARC} {ARC} 0 0 1 {ARC} {ARC} V{} a{ARC} {ARC} 0 0 0 {ARC} {ARC}\"/>", y - ARC)
+                .expect("Infallible");
+            writeln!(diagram.svg, "<path d=\"M{} {y} H{} a{ARC} {ARC} 0 0 0 {ARC} {} V{ARC} a{ARC} {ARC} 0 0 1 {ARC} {}\"/>",
+                2 * ARC + option.width, 2 * ARC + inner, -ARC, -ARC).expect("Infallible");
+            diagram.down = 0;
+        }
+        else {
+            writeln!(diagram.svg, "<path d=\"M0 0 h{} M{} 0 H{width}\"/>", 2 * ARC, 2 * ARC + option.width).expect("Infallible");
+        }
+        translated(&mut diagram.svg, 2 * ARC, y, &option.svg);
+        diagram.down = diagram.down.max(option.down);
+    }
+
+    diagram.down += y;
+    diagram
+}
+
+// Goes through the item, then maybe loops back under it to go through again.
+fn repeat(item: Diagram, label: Option<&str>) -> Diagram {
+    let width = item.width + 2 * ARC;
+    let loop_y = (item.down + GAP).max(2 * ARC);
+    let mut svg = format!("<path d=\"M0 0 h{ARC} M{} 0 h{ARC}\"/>\n", ARC + item.width);
+    writeln!(svg, "<path d=\"M{} 0 a{ARC} {ARC} 0 0 1 {ARC} {ARC} V{} a{ARC} {ARC} 0 0 1 {} {ARC} H{ARC} a{ARC} {ARC} 0 0 1 {} {} V{ARC} a{ARC} {ARC} 0 0 1 {ARC} {}\"/>",
+        ARC + item.width, loop_y - ARC, -ARC, -ARC, -ARC, -ARC).expect("Infallible");
+    translated(&mut svg, ARC, 0, &item.svg);
+
+    let mut down = loop_y;
+    if let Some(label) = label {
+        writeln!(svg, "<text x=\"{}\" y=\"{}\">{}</text>", width / 2, loop_y + LABEL_HEIGHT / 2 + 2, escape(label)).expect("Infallible");
+        down += LABEL_HEIGHT;
+    }
+    Diagram { width, up: item.up, down, svg }
+}
+
+// A dashed box around the item, with the label above it.
+fn lookahead(item: Diagram, label: &str) -> Diagram {
+    let width = item.width.max(label.chars().count() as i32 * CHAR_WIDTH) + 2 * GAP;
+    let top = -item.up - GAP;
+    let mut svg = format!("<path d=\"M0 0 h{GAP} M{} 0 H{width}\"/>\n", GAP + item.width);
+    writeln!(svg, "<rect class=\"lookahead\" x=\"0\" y=\"{top}\" width=\"{width}\" height=\"{}\" rx=\"4\"/>", item.up + item.down + 2 * GAP)
+        .expect("Infallible");
+    writeln!(svg, "<text x=\"{}\" y=\"{}\">{}</text>", width / 2, top - LABEL_HEIGHT / 2, escape(label)).expect("Infallible");
+    translated(&mut svg, GAP, 0, &item.svg);
+    Diagram { width, up: item.up + GAP + LABEL_HEIGHT, down: item.down + GAP, svg }
+}
+
+fn translated(svg: &mut String, x: i32, y: i32, inner: &str) {
+    if !inner.is_empty() {
+        write!(svg, "<g transform=\"translate({x} {y})\">\n{inner}</g>\n").expect("Infallible");
+    }
+}
+
+enum Piece {
+    Literal (String),
+    Expr (usize),  // Its index among the expressions.
+}
+
+// Literals over single characters become a terminal per character, which are glued back into one box.
+fn merge_literals(exprs: &[RuleExpression]) -> Vec<Piece> {
+    let mut pieces = vec![];
+    for (i, expr) in exprs.iter().enumerate() {
+        match (expr, pieces.last_mut()) {
+            (RuleExpression::Terminal(terminal), Some(Piece::Literal(text))) if is_character(terminal) => text.push_str(terminal),
+            (RuleExpression::Terminal(terminal), _) if is_character(terminal) => pieces.push(Piece::Literal(terminal.clone())),
+            (_, _) => pieces.push(Piece::Expr(i)),
+        }
+    }
+    pieces
+}
+
+fn is_character(terminal: &str) -> bool {
+    terminal.chars().count() == 1
+}
+
+// Literal terminals are shown quoted, and kinds like Name as they are.
+fn terminal_text(terminal: &str) -> String {
+    if is_character(terminal) { format!("{terminal:?}") } else { terminal.to_string() }
+}
+
+// For text between tags, which is the only place text from the grammar goes.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -0,0 +1,248 @@
+/* Getting a tree out of input that doesn't parse, for editors and other tools that
+ * want to keep working while the input is broken.
+ *
+ * Recovery works on top of any algorithm: when a parse fails, we look for the
+ * smallest run of tokens around the failure that the parse gets past without, and
+ * put those tokens back into the tree as an error node. This repeats until the
+ * parse succeeds. */
+
+use super::{CharToken, Parser, ParseError, SyntaxTree, Token, string_to_tokens, locate_error};
+use super::backtracking_parser::{single_token_matches, ParseState};
+use super::earley_parser::Chart;
+use crate::define::RuleExpression;
+
+use itertools::Itertools;
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+
+impl<T: Token> Parser<T> {
+    /* Like parse_tokens, but syntax errors don't stop the parse. Each time it fails,
+     * the parser skips the fewest tokens it can to get past the failure, and keeps
+     * going. Returns the tree, in which the skipped tokens appear as
+     * SyntaxTree::ErrorNodes, along with an error for every failure, in input order.
+     * If nothing can be skipped to get past a failure, the whole input becomes one error
+     * node. Errors that skipping can't fix, like ambiguity, are still returned as Err.
+     *
+     * Rules can have sync tokens, declared like `recover Statement : ";" | "}" ;`. When
+     * a parse fails inside such a rule, the first thing tried is skipping everything
+     * from the start of the rule to its next sync token, both with and without the sync
+     * token itself. That is, the broken statement is thrown out as a whole. The
+     * innermost rule with sync tokens goes first, and its skip stops early at a sync
+     * token of any rule around it, so a broken statement can't take the `}` of its
+     * block with it. Finding the rules that a failure is
+     * inside takes an Earley parse, so this doesn't work for ordered choice.
+     *
+     * Each attempt is a full parse, so at most 256 skips are tried for each failure (the
+     * sync skips, then the shortest). If none of them works, it is as if nothing could
+     * be skipped. */
+    pub fn parse_tokens_recovering(&self, tokens: &[T], start_rule: &str) 
+            -> Result<(SyntaxTree<T>, Vec<ParseError>), ParseError> {
+        let mut remaining = tokens.to_vec();  // The tokens that haven't been skipped.
+        let mut error_nodes: Vec<SyntaxTree<T>> = vec![];  // In input order, with spans into tokens.
+        let mut errors = vec![];
+        let mut result = self.parse_tokens(&remaining, start_rule);
+
+        loop {
+            let err = match result {
+                Ok(mut tree) => {
+                    for error_node in &error_nodes {
+                        shift_indices(&mut tree, &error_node.span());
+                    }
+                    for error_node in error_nodes {
+                        insert_error(&mut tree, error_node);
+                    }
+                    return Ok((tree, errors));
+                }
+                Err(err) => err,
+            };
+
+            let Some((index, expected)) = failure(&err, remaining.len()) else {
+                return Err(err);
+            };
+            let skipped_so_far = tokens.len() - remaining.len();
+            errors.push(shift_error(err, skipped_so_far));
+
+            // Skipping into an earlier error's tokens would undo its recovery.
+            let earliest = error_nodes.last().map_or(0, |error_node| error_node.span().end - skipped_so_far);
+            let recovery = self.sync_candidates(&remaining, start_rule, index).into_iter()
+                .chain(skip_candidates(index, remaining.len()))
+                .filter(|skipped| skipped.start >= earliest)
+                .take(MAX_ATTEMPTS)
+                .find_map(|skipped| {
+                    let rest = remaining[..skipped.start].iter().chain(&remaining[skipped.end..]).cloned().collect::<Vec<T>>();
+                    match self.parse_tokens(&rest, start_rule) {
+                        Ok(tree) => Some((skipped, rest, Ok(tree))),
+                        // Getting stuck again where the tokens were skipped isn't progress.
+                        Err(err) if failure(&err, rest.len()).is_some_and(|(index, _)| index > skipped.start) 
+                            => Some((skipped, rest, Err(err))),
+                        Err(_) => None,
+                    }
+                });
+
+            let Some((skipped, rest, next_result)) = recovery else {
+                let error_node = SyntaxTree::ErrorNode { tokens: tokens.to_vec(), expected, span: 0..tokens.len() };
+                return Ok((error_node, errors));
+            };
+
+            error_nodes.push(SyntaxTree::ErrorNode {
+                tokens: remaining[skipped.clone()].to_vec(),
+                expected,
+                span: skipped.start + skipped_so_far..skipped.end + skipped_so_far,
+            });
+            remaining = rest;
+            result = next_result;
+        }
+    }
+}
+
+impl Parser<CharToken> {
+    pub fn parse_string_recovering(&self, input: &str, start_rule: &str) 
+            -> Result<(SyntaxTree<CharToken>, Vec<ParseError>), ParseError> {
+        let (tree, errors) = self.parse_tokens_recovering(&string_to_tokens(input), start_rule)
+            .map_err(|err| locate_error(err, input))?;
+        Ok((tree, errors.into_iter().map(|err| locate_error(err, input)).collect()))
+    }
+}
+
+
+/* Private Implementation */
+
+const MAX_ATTEMPTS: usize = 256;  // Skips tried for each failure.
+
+impl<T: Token> Parser<T> {
+    // The runs of tokens to try skipping first, given the sync tokens of the rules in progress at index.
+    fn sync_candidates(&self, tokens: &[T], start_rule: &str, index: usize) -> Vec<Range<usize>> {
+        if self.sync_tokens.is_empty() {
+            return vec![];
+        }
+
+        let Ok(mut chart) = Chart::new(self, start_rule) else {
+            return vec![];
+        };
+        let mut lookahead_state = ParseState::new(self, tokens);
+        for i in 0..=index {
+            if chart.process(i, tokens, Some(&mut lookahead_state)).is_err() {
+                return vec![];
+            }
+        }
+
+        // Rules with sync tokens, innermost first. A rule's skip also stops at the sync
+        // tokens of the rules around it, but leaves them for those rules.
+        let rules = chart.rules_in_progress(index).into_iter()
+            .filter_map(|(rule_name, start)| Some((self.sync_tokens.get(rule_name)?, start)))
+            .collect::<Vec<_>>();
+        let is_sync = |sync_tokens: &RuleExpression, token: &T| single_token_matches(self, sync_tokens, token).unwrap_or(false);
+
+        let mut candidates = vec![];
+        for (position, &(sync_tokens, start)) in rules.iter().enumerate() {
+            let stop = (index..tokens.len()).find_map(|i| {
+                if is_sync(sync_tokens, &tokens[i]) {
+                    Some((i, true))
+                }
+                else {
+                    rules[position + 1..].iter().any(|(outer, _)| is_sync(outer, &tokens[i])).then_some((i, false))
+                }
+            });
+
+            match stop {
+                Some((i, true)) => candidates.extend([start..i, start..i + 1]),
+                Some((i, false)) => candidates.push(start..i),
+                None => candidates.push(start..tokens.len()),
+            }
+        }
+
+        candidates.retain(|skipped| !skipped.is_empty());
+        candidates.into_iter().unique().collect()
+    }
+}
+
+/* Every run of tokens that includes the failure index (or starts or ends at it),
+ * shortest first. Among runs of the same length, the ones starting closer to the
+ * failure come first. */
+// Where a parse of token_count tokens failed, and what it expected there. None for errors that aren't syntax errors.
+fn failure(err: &ParseError, token_count: usize) -> Option<(usize, HashSet<String>)> {
+    match err {
+        ParseError::UnexpectedToken { index, terminals, .. } => Some((*index, terminals.clone())),
+        ParseError::UnexpectedEof { terminals, .. } => Some((token_count, terminals.clone())),
+        _ => None,
+    }
+}
+
+// Moves an error past the tokens skipped before it.
+fn shift_error(err: ParseError, skipped: usize) -> ParseError {
+    match err {
+        ParseError::UnexpectedToken { index, terminals, expected, context, location, span } => 
+            ParseError::UnexpectedToken { index: index + skipped, terminals, expected, context, location, span },
+        err => err,
+    }
+}
+
+fn skip_candidates(index: usize, token_count: usize) -> impl Iterator<Item = Range<usize>> {
+    (1..=token_count).flat_map(move |length| {
+        (index.saturating_sub(length)..=index).rev()
+            .map(move |start| start..start + length)
+            .filter(move |skipped| skipped.end <= token_count && skipped.end >= index)
+    })
+}
+
+/* The tree was parsed without the skipped tokens, so everything after them has to
+ * move up. Rules that start right where the tokens were skipped start after them,
+ * unless they are empty. */
+fn shift_indices<T: Token>(tree: &mut SyntaxTree<T>, skipped: &Range<usize>) {
+    let mut pending = vec![tree];
+    while let Some(tree) = pending.pop() {
+        match tree {
+            SyntaxTree::RuleNode { subexpressions, span, .. } => {
+                shift_span(span, skipped);
+                pending.extend(subexpressions.iter_mut());
+            }
+            SyntaxTree::TokenNode { index, .. } => {
+                if *index >= skipped.start {
+                    *index += skipped.len();
+                }
+            }
+            SyntaxTree::ErrorNode { span, .. } => shift_span(span, skipped),
+        }
+    }
+}
+
+fn shift_span(span: &mut Range<usize>, skipped: &Range<usize>) {
+    if span.start > skipped.start || (span.start == skipped.start && span.start < span.end) {
+        span.start += skipped.len();
+    }
+    if span.end > skipped.start {
+        span.end += skipped.len();
+    }
+}
+
+// Adds the error node to the innermost rule that covers it, widening the root if needed.
+fn insert_error<T: Token>(mut tree: &mut SyntaxTree<T>, error: SyntaxTree<T>) {
+    let skipped = error.span();
+    loop {
+        let SyntaxTree::RuleNode { subexpressions, span, .. } = tree else {
+            return;  // Trees are rooted at a rule.
+        };
+
+        span.start = span.start.min(skipped.start);
+        span.end = span.end.max(skipped.end);
+
+        let covering = subexpressions.iter().position(|subexpression| {
+            matches!(subexpression, SyntaxTree::RuleNode { .. })
+                && subexpression.span().start <= skipped.start
+                && subexpression.span().end >= skipped.end
+        });
+
+        match covering {
+            Some(i) => tree = &mut subexpressions[i],
+            None => {
+                let position = subexpressions.iter()
+                    .position(|subexpression| subexpression.span().start >= skipped.end)
+                    .unwrap_or(subexpressions.len());
+                subexpressions.insert(position, error);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+/* Parses that share their work. Parsing the same input again with another start rule
+ * reuses everything the backtracking parser memoized the first time, since a rule
+ * parsed at some token matches the same way whatever rule it was reached from. Moving
+ * on to new input throws the memo out, but keeps the memory it was in, so a session
+ * parsing lots of small inputs doesn't allocate it all over again each time.
+ *
+ * Sessions parse with the backtracking algorithm, whatever set_algorithm says, since
+ * that is the one with a memo to keep. With ParseAlgorithm::Auto, they still try the
+ * LL(1) tables first, which don't need one. */
+
+use super::{AmbiguityPolicy, CharToken, ParseAlgorithm, Parser, ParseError, SyntaxTree, Token};
+use super::backtracking_parser::{Memo, memoized_parse};
+use super::ll1_parser::ll1_parse;
+
+
+/* Start one with Parser::session. */
+pub struct ParseSession<'p, T: Token> {
+    parser: &'p Parser<T>,
+    tokens: Vec<T>,
+    memo: Memo<'p, T>,
+}
+
+impl<T: Token> Parser<T> {
+    pub fn session(&self) -> ParseSession<'_, T> {
+        ParseSession { parser: self, tokens: vec![], memo: Memo::new() }
+    }
+}
+
+impl<T: Token> ParseSession<'_, T> {
+    /* Parses new input, as parse_tokens would. */
+    pub fn parse_tokens(&mut self, tokens: &[T], start_rule: &str) -> Result<SyntaxTree<T>, ParseError> {
+        self.tokens.clear();
+        self.tokens.extend_from_slice(tokens);
+        self.memo.clear();
+        self.reparse(start_rule)
+    }
+
+    /* Parses the last input again, from another start rule. A parse that fails after
+     * starting from the memo is run again without it, so that the error is the same
+     * one a fresh parse would give (the memo doesn't remember why things failed). */
+    pub fn reparse(&mut self, start_rule: &str) -> Result<SyntaxTree<T>, ParseError> {
+        let parser = self.parser;
+        parser.check_stateless()?;
+        if parser.algorithm == ParseAlgorithm::Auto {
+            if let Some(tree) = ll1_parse(parser, &self.tokens, start_rule, parser.anchored) {
+                return Ok(tree);
+            }
+        }
+
+        let warm = !self.memo.is_empty();
+        let trees = match self.memoized_parse(start_rule) {
+            Err(_) if warm => self.memoized_parse(start_rule),
+            trees => trees,
+        };
+
+        parser.resolve_ambiguity(trees?)
+    }
+
+    /* The input of the last parse. */
+    pub fn tokens(&self) -> &[T] {
+        &self.tokens
+    }
+}
+
+impl ParseSession<'_, CharToken> {
+    pub fn parse_string(&mut self, input: &str, start_rule: &str) -> Result<SyntaxTree<CharToken>, ParseError> {
+        self.parse_tokens(&super::string_to_tokens(input), start_rule)
+            .map_err(|err| super::locate_error(err, input))
+    }
+
+    /* Like reparse, but with the error located in the string. */
+    pub fn reparse_string(&mut self, start_rule: &str) -> Result<SyntaxTree<CharToken>, ParseError> {
+        self.reparse(start_rule).map_err(|err| {
+            let input = self.tokens.iter().map(|token| token.token_type).collect::<String>();
+            super::locate_error(err, &input)
+        })
+    }
+}
+
+
+/* Private Implementation */
+
+impl<T: Token> ParseSession<'_, T> {
+    // Errors like going over a ParseLimits limit stop a parse part way through, leaving the memo
+    // half done, so it is thrown out after any error.
+    fn memoized_parse(&mut self, start_rule: &str) -> Result<Vec<SyntaxTree<T>>, ParseError> {
+        let all = !matches!(self.parser.ambiguity_policy, AmbiguityPolicy::FirstMatch);
+        let trees = memoized_parse(self.parser, &self.tokens, start_rule, self.parser.anchored, &mut self.memo, all);
+        if trees.is_err() {
+            self.memo.clear();
+        }
+        trees
+    }
+}
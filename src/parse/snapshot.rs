@@ -0,0 +1,191 @@
+/* Saving a streaming parse part way through, so that it can be picked up again later,
+ * even in another process. */
+
+use super::{Parser, ParseError, StreamingParse, Token};
+use super::earley_parser::{Chart, ChartState};
+
+use itertools::Itertools;
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+
+/* Public Interface */
+
+/* Everything a StreamingParse has done so far. Take one with StreamingParse::snapshot,
+ * and carry on from it with Parser::resume_streaming. It only works with a parser made
+ * from the same rules as the one that took it.
+ *
+ * to_bytes and from_bytes convert it to and from bytes, given a way to do the same for
+ * a single token. */
+pub struct ParseSnapshot<T: Token> {
+    start_rule: String,
+    tokens: Vec<T>,
+    chart: ChartState,
+    rules_hash: u64,  // Catches resuming with a different grammar.
+}
+
+impl<'p, T: Token> StreamingParse<'p, T> {
+    pub fn snapshot(&self) -> ParseSnapshot<T> {
+        ParseSnapshot {
+            start_rule: self.start_rule.clone(),
+            tokens: self.tokens.clone(),
+            chart: self.chart.save(),
+            rules_hash: rules_hash(self.parser),
+        }
+    }
+}
+
+impl<T: Token> Parser<T> {
+    pub fn resume_streaming(&self, snapshot: ParseSnapshot<T>) -> Result<StreamingParse<'_, T>, ParseError> {
+        if snapshot.rules_hash != rules_hash(self) {
+            return Err("Parse snapshot was taken with different rules".into());
+        }
+
+        let chart = Chart::restore(self, &snapshot.start_rule, snapshot.chart, snapshot.tokens.len())?;
+        Ok(StreamingParse { parser: self, start_rule: snapshot.start_rule, chart, tokens: snapshot.tokens })
+    }
+}
+
+impl<T: Token> ParseSnapshot<T> {
+    pub fn to_bytes(&self, mut encode_token: impl FnMut(&T) -> Vec<u8>) -> Vec<u8> {
+        let mut writer = Writer { bytes: MAGIC.to_vec() };
+
+        writer.word(self.rules_hash);
+        writer.string(self.start_rule.as_bytes());
+        writer.number(self.tokens.len());
+        for token in &self.tokens {
+            writer.string(&encode_token(token));
+        }
+
+        writer.number(self.chart.sets.len());
+        for set in &self.chart.sets {
+            writer.number(set.len());
+            for (item, starts) in set {
+                item.iter().for_each(|&number| writer.number(number));
+                writer.number(starts.len());
+                starts.iter().for_each(|&number| writer.number(number));
+            }
+        }
+        writer.number(self.chart.ends.len());
+        self.chart.ends.iter().for_each(|&number| writer.number(number));
+        writer.number(self.chart.failure_index);
+        writer.number(self.chart.failure_context.len());
+        for rule_name in &self.chart.failure_context {
+            writer.string(rule_name.as_bytes());
+        }
+        writer.number(self.chart.failures.len());
+        for (failure, rule_name) in &self.chart.failures {
+            writer.string(failure.as_bytes());
+            writer.string(rule_name.as_deref().unwrap_or("").as_bytes());  // Rule names are never empty.
+        }
+
+        writer.bytes
+    }
+
+    /* decode_token returns None if the bytes aren't a valid token. */
+    pub fn from_bytes(bytes: &[u8], mut decode_token: impl FnMut(&[u8]) -> Option<T>) -> Result<ParseSnapshot<T>, ParseError> {
+        let mut reader = Reader { bytes: bytes.strip_prefix(MAGIC).ok_or_else(corrupt)? };
+
+        let rules_hash = reader.word()?;
+        let start_rule = reader.text()?;
+        let tokens = (0..reader.number()?)
+            .map(|_| decode_token(reader.string()?).ok_or_else(|| ParseError::from("Could not decode a token in the parse snapshot")))
+            .collect::<Result<Vec<T>, ParseError>>()?;
+
+        let sets = (0..reader.number()?)
+            .map(|_| (0..reader.number()?).map(|_| Ok((reader.triple()?, reader.numbers()?))).collect())
+            .collect::<Result<Vec<Vec<([usize; 3], Vec<usize>)>>, ParseError>>()?;
+        let ends = reader.numbers()?;
+        let failure_index = reader.number()?;
+        let failure_context = (0..reader.number()?).map(|_| reader.text()).collect::<Result<Vec<_>, _>>()?;
+        let failures = (0..reader.number()?)
+            .map(|_| Ok((reader.text()?, Some(reader.text()?).filter(|rule_name| !rule_name.is_empty()))))
+            .collect::<Result<Vec<_>, ParseError>>()?;
+
+        if !reader.bytes.is_empty() {
+            return Err(corrupt());
+        }
+
+        Ok(ParseSnapshot { start_rule, tokens, chart: ChartState { sets, ends, failure_index, failure_context, failures }, rules_hash })
+    }
+}
+
+
+/* Private Implementation */
+
+const MAGIC: &[u8] = b"parsley snapshot 5\n";
+
+fn corrupt() -> ParseError {
+    "Parse snapshot is truncated or corrupt".into()
+}
+
+fn rules_hash<T: Token>(parser: &Parser<T>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (rule_name, expr) in parser.rules.iter().sorted_by_key(|(rule_name, _)| *rule_name) {
+        rule_name.hash(&mut hasher);
+        format!("{expr:?}").hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Numbers are 8 bytes, little endian. Strings are their length, then their bytes.
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn word(&mut self, word: u64) {
+        self.bytes.extend(word.to_le_bytes());
+    }
+
+    fn number(&mut self, number: usize) {
+        self.word(number as u64);
+    }
+
+    fn string(&mut self, string: &[u8]) {
+        self.number(string.len());
+        self.bytes.extend(string);
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, count: usize) -> Result<&'b [u8], ParseError> {
+        if count > self.bytes.len() {
+            return Err(corrupt());
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn word(&mut self) -> Result<u64, ParseError> {
+        let bytes = self.take(8)?.try_into().map_err(|_| corrupt())?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn number(&mut self) -> Result<usize, ParseError> {
+        usize::try_from(self.word()?).map_err(|_| corrupt())
+    }
+
+    fn string(&mut self) -> Result<&'b [u8], ParseError> {
+        let length = self.number()?;
+        self.take(length)
+    }
+
+    fn text(&mut self) -> Result<String, ParseError> {
+        String::from_utf8(self.string()?.to_vec()).map_err(|_| corrupt())
+    }
+
+    fn triple(&mut self) -> Result<[usize; 3], ParseError> {
+        Ok([self.number()?, self.number()?, self.number()?])
+    }
+
+    // A count, then that many numbers.
+    fn numbers(&mut self) -> Result<Vec<usize>, ParseError> {
+        (0..self.number()?).map(|_| self.number()).collect()
+    }
+}
@@ -0,0 +1,459 @@
+/* Parsing with user state, like a symbol table, that rules can read and update as
+ * they match.
+ *
+ * State doesn't fit the memoized algorithms: what a rule matches at an index may
+ * depend on the state, so a memo entry could not be reused. Instead this is a plain
+ * depth first search, where every path through the grammar carries its own copy of
+ * the state. Alternatives are tried in order, and each one starts from the state as
+ * it was before the choice, so whatever a failed alternative did is simply dropped.
+ * The first parse to succeed wins, along with the state it built up.
+ *
+ * Without memoization, this is exponential for grammars that backtrack a lot, and
+ * left recursion can't be handled at all. */
+
+use super::backtracking_parser::{single_token_matches, FailureCache};
+use super::{Parser, ParseError, Symbol, SyntaxTree, Token};
+use crate::define::{DefinitionError, RuleExpression};
+
+use std::any::Any;
+use std::cell::Cell;
+use std::ops::Range;
+use std::rc::Rc;
+
+
+/* Receives the state, every token in the input, and the span of tokens that a rule
+ * matched. Returns None if the state has a different type than it was registered for. */
+pub(crate) type StatefulPredicate<T> = Box<dyn Fn(&dyn Any, &[T], Range<usize>) -> Option<bool>>;
+
+pub(crate) type Action<T> = Box<dyn Fn(&mut dyn Any, &[T], Range<usize>) -> Option<()>>;
+
+impl<T: Token> Parser<T> {
+    /* Like add_predicate, but the predicate can also look at the state passed to
+     * parse_tokens_with_state. It sees the state as the actions of everything before
+     * the match left it. */
+    pub fn add_stateful_predicate<S: 'static>(&mut self, rule_name: &str,
+            predicate: impl Fn(&S, &[T], Range<usize>) -> bool + 'static) -> Result<(), DefinitionError> {
+        if !self.rules.contains_key(rule_name) {
+            return Err(DefinitionError::new(format!("Cannot add a predicate to undefined rule \"{rule_name}\"")));
+        }
+
+        self.stateful_predicates.entry(rule_name.to_string()).or_default().push(Box::new(move |state, tokens, span| {
+            state.downcast_ref::<S>().map(|state| predicate(state, tokens, span))
+        }));
+        Ok(())
+    }
+
+    /* Runs the action whenever the rule matches (after its predicates accept), so it can
+     * update the state, e.g. declaring the name a rule just matched. If the parse later
+     * backtracks out of the match, the update is undone. */
+    pub fn add_action<S: 'static>(&mut self, rule_name: &str, action: impl Fn(&mut S, &[T], Range<usize>) + 'static)
+            -> Result<(), DefinitionError> {
+        if !self.rules.contains_key(rule_name) {
+            return Err(DefinitionError::new(format!("Cannot add an action to undefined rule \"{rule_name}\"")));
+        }
+
+        self.actions.entry(rule_name.to_string()).or_default().push(Box::new(move |state, tokens, span| {
+            state.downcast_mut::<S>().map(|state| action(state, tokens, span))
+        }));
+        Ok(())
+    }
+
+    /* Parses with state that stateful predicates and actions can use, and returns the
+     * state as the successful parse left it. See the top of this file for how state
+     * and backtracking interact. The algorithm and ambiguity policy are ignored, the
+     * first parse found is returned. */
+    pub fn parse_tokens_with_state<S: Clone + 'static>(&self, tokens: &[T], start_rule: &str, state: S)
+            -> Result<(SyntaxTree<T>, S), ParseError> {
+        let start_expr = RuleExpression::RuleName(start_rule.into());
+        let mut search = StatefulParse {
+            parser: self,
+            tokens,
+            failure_info: FailureCache::new(),
+            error: None,
+            best: None,
+        };
+
+        search.parse(&start_expr, state, &mut |search, end, state, mut trees| {
+            if search.parser.anchored {
+                if end < search.tokens.len() {
+                    return false;
+                }
+                search.best = Some((trees.remove(0), state));
+                return true;
+            }
+
+            // Unanchored parses want the longest match, so keep looking.
+            if search.best.as_ref().is_none_or(|(tree, _)| tree.span().end < end) {
+                search.best = Some((trees.remove(0), state));
+            }
+            false
+        });
+
+        match (search.error, search.best) {
+            (Some(err), _) => Err(err),
+            (None, Some(best)) => Ok(best),
+            (None, None) => Err(search.failure_info.into_error(self, tokens)),
+        }
+    }
+
+    // Stateful predicates and actions only run in parse_tokens_with_state, so parsing without state would skip them.
+    pub(super) fn check_stateless(&self) -> Result<(), ParseError> {
+        if self.stateful_predicates.is_empty() && self.actions.is_empty() {
+            Ok(())
+        }
+        else {
+            Err("Parser has stateful predicates or actions, use parse_tokens_with_state".into())
+        }
+    }
+}
+
+
+/* Private Implementation */
+
+/* Receives the index after what was parsed, its state and the trees so far. Returns
+ * true to stop the search, either because it succeeded or because of an error. */
+type Done<'k, 'a, 't, T, S> = &'k mut dyn FnMut(&mut StatefulParse<'a, 't, T, S>, usize, S, Vec<SyntaxTree<T>>) -> bool;
+
+struct StatefulParse<'a, 't, T: Token, S> {
+    parser: &'a Parser<T>,
+    tokens: &'t [T],
+    failure_info: FailureCache<'a>,
+    error: Option<ParseError>,
+    best: Option<(SyntaxTree<T>, S)>,
+}
+
+/* The search keeps its own stacks rather than recursing, so that long or deeply nested
+ * input can't overflow the call stack. A path is how far one way through the grammar has
+ * got, and what is left to do once the expression it is on has matched. Paths that branch
+ * off from each other share what is left, as a list of frames. Whenever there is more than
+ * one way to go on, the path takes the first, and the others are saved as choices to come
+ * back to if it fails. */
+#[derive(Clone)]
+struct Path<'a, T: Token, S> {
+    index: usize,
+    state: S,
+    trees: Vec<SyntaxTree<T>>,
+    next: Next<'a, T, S>,
+}
+
+type Next<'a, T, S> = Option<Rc<Then<'a, T, S>>>;
+
+struct Then<'a, T: Token, S> {
+    frame: Frame<'a, T, S>,
+    rest: Next<'a, T, S>,
+}
+
+enum Frame<'a, T: Token, S> {
+    Sequence (&'a [RuleExpression]),  // The rest of a concatenation.
+    Rule { rule_name: &'a Symbol, start: usize, trees: Vec<SyntaxTree<T>> },  // With the trees from before the rule.
+    // After a match of the inner expression, which started at start, with count matches before it.
+    Repeat { inner: &'a RuleExpression, count: usize, min: usize, max: Option<usize>, start: usize },
+    Alternative (Rc<Cell<bool>>),  // Notes that the alternative matched, which ordered choice commits to.
+    // The lookahead's expression matched. Whatever it did is dropped, and outer goes on from before it.
+    Lookahead { expr: &'a RuleExpression, choices: usize, failure_info: FailureCache<'a>, outer: Path<'a, T, S> },
+}
+
+enum Choice<'a, T: Token, S> {
+    Alternatives { rest: &'a [RuleExpression], ordered: bool, matched: Rc<Cell<bool>>, path: Path<'a, T, S> },
+    Repeat { inner: &'a RuleExpression, count: usize, min: usize, max: Option<usize>, path: Path<'a, T, S> },  // One more match.
+    Lookahead { expr: &'a RuleExpression, failure_info: FailureCache<'a>, path: Path<'a, T, S> },  // Its expression didn't match.
+}
+
+enum Step<'a, T: Token, S> {
+    Parse (&'a RuleExpression, Path<'a, T, S>),
+    Matched (Path<'a, T, S>),
+    Fail,  // Go back to the latest choice.
+    Stop,
+}
+
+// Dropping a long list would otherwise recurse once for every frame.
+impl<T: Token, S> Drop for Then<'_, T, S> {
+    fn drop(&mut self) {
+        let mut rest = self.rest.take();
+        while let Some(then) = rest {
+            match Rc::try_unwrap(then) {
+                Ok(mut then) => rest = then.rest.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl<'a, 't, T: Token, S: Clone + 'static> StatefulParse<'a, 't, T, S> {
+    fn parse(&mut self, expr: &'a RuleExpression, state: S, done: Done<'_, 'a, 't, T, S>) {
+        let mut choices = vec![];
+        let mut step = Step::Parse(expr, Path { index: 0, state, trees: vec![], next: None });
+        loop {
+            step = match step {
+                Step::Parse(expr, path) => self.parse_expr(expr, path, &mut choices),
+                Step::Matched(mut path) => match path.next.take() {
+                    Some(then) => {
+                        path.next = then.rest.clone();
+                        self.parse_frame(&then.frame, path, &mut choices)
+                    }
+                    None if done(self, path.index, path.state, path.trees) => Step::Stop,
+                    None => Step::Fail,
+                },
+                Step::Fail => match choices.pop() {
+                    Some(choice) => self.choose(choice, &mut choices),
+                    None => return,
+                },
+                Step::Stop => return,
+            };
+        }
+    }
+
+    fn parse_expr(&mut self, expr: &'a RuleExpression, mut path: Path<'a, T, S>, choices: &mut Vec<Choice<'a, T, S>>) -> Step<'a, T, S> {
+        let index = path.index;
+        match expr {
+            RuleExpression::Terminal(_) | RuleExpression::CharacterClass(_)
+                    | RuleExpression::Wildcard | RuleExpression::Negation(_, _) => {
+                let matched = match self.tokens.get(index) {
+                    Some(token) => match single_token_matches(self.parser, expr, token) {
+                        Ok(matched) => matched,
+                        Err(err) => return self.fail_with(err),
+                    },
+                    None => false,
+                };
+
+                if !matched {
+                    self.failure_info.log(index, describe(expr));
+                    return Step::Fail;
+                }
+
+                let token = &self.tokens[index];
+                path.trees.push(SyntaxTree::TokenNode { token: token.clone(), index, captured: self.parser.capture(expr, token) });
+                path.index += 1;
+                Step::Matched(path)
+            },
+            RuleExpression::External(rule_name) => {
+                match self.parser.scan(rule_name, self.tokens, index) {
+                    Ok(Some(length)) => {
+                        path.trees.extend((index..index + length)
+                            .map(|i| SyntaxTree::TokenNode { token: self.tokens[i].clone(), index: i, captured: None }));
+                        path.index += length;
+                        Step::Matched(path)
+                    }
+                    Ok(None) => {
+                        self.failure_info.log(index, rule_name);
+                        Step::Fail
+                    }
+                    Err(err) => self.fail_with(err),
+                }
+            },
+            RuleExpression::EndOfInput => {
+                if index == self.tokens.len() {
+                    Step::Matched(path)
+                }
+                else {
+                    self.failure_info.log(index, "$");
+                    Step::Fail
+                }
+            },
+            RuleExpression::PositiveLookahead(inner_expr) | RuleExpression::NegativeLookahead(inner_expr, _) => {
+                // Whatever the lookahead looked at isn't really expected, so its failures are forgotten.
+                // Nothing the lookahead does to the state is kept either.
+                let failure_info = self.failure_info.clone();
+                let inner = Path { index, state: path.state.clone(), trees: vec![], next: None };
+                let frame = Frame::Lookahead { expr, choices: choices.len(), failure_info: failure_info.clone(), outer: path.clone() };
+                choices.push(Choice::Lookahead { expr, failure_info, path });
+                Step::Parse(inner_expr, Path { next: then(frame, None), ..inner })
+            },
+            RuleExpression::RuleName(rule_name) => {
+                let Some(rule_expr) = self.parser.rules.get(rule_name) else {
+                    return self.fail_with(self.parser.unknown_rule(rule_name));
+                };
+
+                if in_progress(&path.next, rule_name, index) {
+                    return self.fail_with(format!("Rule \"{rule_name}\" is left recursive, which parsing with state can't handle").into());
+                }
+
+                let next = then(Frame::Rule { rule_name, start: index, trees: path.trees }, path.next);
+                Step::Parse(rule_expr, Path { index, state: path.state, trees: vec![], next })
+            },
+            RuleExpression::Concatenation(exprs) => sequence(exprs, path),
+            RuleExpression::Alternatives(exprs) | RuleExpression::OrderedAlternatives(exprs) => {
+                let ordered = self.parser.ordered_choice || matches!(expr, RuleExpression::OrderedAlternatives(_));
+                match exprs.split_first() {
+                    Some((first, rest)) => alternative(first, rest, ordered, path, choices),
+                    None => Step::Fail,
+                }
+            },
+            RuleExpression::Optional(inner_expr) => repetition(inner_expr, 0, 0, Some(1), path, choices),
+            RuleExpression::Many(inner_expr) => repetition(inner_expr, 0, 0, None, path, choices),
+            RuleExpression::OneOrMore(inner_expr) => repetition(inner_expr, 0, 1, None, path, choices),
+            RuleExpression::Repetition(inner_expr, min, max) => repetition(inner_expr, 0, *min, *max, path, choices),
+        }
+    }
+
+    // Goes on from a frame, now that what came before it has matched.
+    fn parse_frame(&mut self, frame: &Frame<'a, T, S>, path: Path<'a, T, S>, choices: &mut Vec<Choice<'a, T, S>>) -> Step<'a, T, S> {
+        match frame {
+            Frame::Sequence(exprs) => sequence(exprs, path),
+            Frame::Rule { rule_name, start, trees } => {
+                let Path { index, state, trees: children, next } = path;
+                self.finish_rule(rule_name, *start..index, state, children, trees.clone(), next)
+            }
+            Frame::Repeat { inner, count, min, max, start } => {
+                // Repeating something that matched nothing would never end.
+                if path.index == *start && count >= min {
+                    return Step::Fail;
+                }
+                repetition(inner, count + 1, *min, *max, path, choices)
+            }
+            Frame::Alternative(matched) => {
+                matched.set(true);
+                Step::Matched(path)
+            }
+            Frame::Lookahead { expr, choices: before, failure_info, outer } => {
+                // The lookahead is settled, so there is no going back into it.
+                choices.truncate(*before);
+                self.failure_info = failure_info.clone();
+                match expr {
+                    RuleExpression::NegativeLookahead(_, description) => {
+                        self.failure_info.log(outer.index, description);
+                        Step::Fail
+                    }
+                    _ => Step::Matched(outer.clone()),
+                }
+            }
+        }
+    }
+
+    fn choose(&mut self, choice: Choice<'a, T, S>, choices: &mut Vec<Choice<'a, T, S>>) -> Step<'a, T, S> {
+        match choice {
+            Choice::Alternatives { rest, ordered, matched, path } => match rest.split_first() {
+                // Committed to the first alternative that matches, later ones aren't even tried.
+                Some((next, rest)) if !(ordered && matched.get()) => alternative(next, rest, ordered, path, choices),
+                _ => Step::Fail,
+            },
+            Choice::Repeat { inner, count, min, max, path } => {
+                let next = then(Frame::Repeat { inner, count, min, max, start: path.index }, path.next.clone());
+                Step::Parse(inner, Path { next, ..path })
+            }
+            Choice::Lookahead { expr, failure_info, path } => {
+                self.failure_info = failure_info;
+                match expr {
+                    RuleExpression::NegativeLookahead(..) => Step::Matched(path),
+                    _ => Step::Fail,
+                }
+            }
+        }
+    }
+
+    // Checks the predicates of a rule that matched span, and runs its actions before moving on.
+    fn finish_rule(&mut self, rule_name: &'a Symbol, span: Range<usize>, mut state: S, children: Vec<SyntaxTree<T>>,
+            mut trees: Vec<SyntaxTree<T>>, next: Next<'a, T, S>) -> Step<'a, T, S> {
+        let mut accepted = self.parser.predicates.get(rule_name.as_str())
+            .is_none_or(|predicates| predicates.iter().all(|predicate| predicate(self.tokens, span.clone())));
+
+        for predicate in self.parser.stateful_predicates.get(rule_name.as_str()).into_iter().flatten() {
+            if !accepted {
+                break;
+            }
+            match predicate(&state, self.tokens, span.clone()) {
+                Some(result) => accepted = result,
+                None => return self.fail_with(wrong_state_type(rule_name)),
+            }
+        }
+
+        if !accepted {
+            self.failure_info.log(span.start, rule_name.as_str());
+            return Step::Fail;
+        }
+
+        for action in self.parser.actions.get(rule_name.as_str()).into_iter().flatten() {
+            if action(&mut state, self.tokens, span.clone()).is_none() {
+                return self.fail_with(wrong_state_type(rule_name));
+            }
+        }
+
+        trees.push(SyntaxTree::RuleNode { rule_name: rule_name.clone(), subexpressions: children, span: span.clone() });
+        Step::Matched(Path { index: span.end, state, trees, next })
+    }
+
+    // Stops the search with an error.
+    fn fail_with(&mut self, err: ParseError) -> Step<'a, T, S> {
+        self.error = Some(err);
+        Step::Stop
+    }
+}
+
+fn then<'a, T: Token, S>(frame: Frame<'a, T, S>, rest: Next<'a, T, S>) -> Next<'a, T, S> {
+    Some(Rc::new(Then { frame, rest }))
+}
+
+fn sequence<'a, T: Token, S>(exprs: &'a [RuleExpression], mut path: Path<'a, T, S>) -> Step<'a, T, S> {
+    match exprs.split_first() {
+        None => Step::Matched(path),
+        Some((first, rest)) => {
+            if !rest.is_empty() {
+                path.next = then(Frame::Sequence(rest), path.next);
+            }
+            Step::Parse(first, path)
+        }
+    }
+}
+
+// Tries `expr`, leaving the alternatives after it for later.
+fn alternative<'a, T: Token, S: Clone>(expr: &'a RuleExpression, rest: &'a [RuleExpression], ordered: bool, mut path: Path<'a, T, S>,
+        choices: &mut Vec<Choice<'a, T, S>>) -> Step<'a, T, S> {
+    if !rest.is_empty() {
+        let matched = Rc::new(Cell::new(false));
+        choices.push(Choice::Alternatives { rest, ordered, matched: matched.clone(), path: path.clone() });
+        if ordered {
+            path.next = then(Frame::Alternative(matched), path.next);
+        }
+    }
+    Step::Parse(expr, path)
+}
+
+// After count matches, tries the fewest repetitions first, in the same order as the backtracking parser.
+fn repetition<'a, T: Token, S: Clone>(inner: &'a RuleExpression, count: usize, min: usize, max: Option<usize>, path: Path<'a, T, S>,
+        choices: &mut Vec<Choice<'a, T, S>>) -> Step<'a, T, S> {
+    let more = max.is_none_or(|max| count < max);
+    if count >= min {
+        if more {
+            choices.push(Choice::Repeat { inner, count, min, max, path: path.clone() });
+        }
+        return Step::Matched(path);
+    }
+    if !more {
+        return Step::Fail;
+    }
+
+    let next = then(Frame::Repeat { inner, count, min, max, start: path.index }, path.next.clone());
+    Step::Parse(inner, Path { next, ..path })
+}
+
+// Whether the rule is already being parsed at index on this path, which means it is left recursive.
+fn in_progress<T: Token, S>(mut next: &Next<'_, T, S>, rule_name: &str, index: usize) -> bool {
+    while let Some(then) = next {
+        match &then.frame {
+            // Rules further out started earlier, if not at the same index.
+            Frame::Rule { rule_name: other, start, .. } if *start == index && other.as_str() == rule_name => return true,
+            Frame::Rule { start, .. } if *start < index => return false,
+            Frame::Lookahead { outer, .. } => {
+                next = &outer.next;
+                continue;
+            }
+            _ => (),
+        }
+        next = &then.rest;
+    }
+    false
+}
+
+fn wrong_state_type(rule_name: &str) -> ParseError {
+    format!("A predicate or action on rule \"{rule_name}\" was registered for a different type of state").into()
+}
+
+// What to report as expected when a single token expression fails to match.
+fn describe(expr: &RuleExpression) -> &str {
+    match expr {
+        RuleExpression::Terminal(term) => term,
+        RuleExpression::CharacterClass(class) => &class.source,
+        RuleExpression::Negation(_, description) => description,
+        _ => ".",
+    }
+}
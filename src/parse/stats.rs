@@ -0,0 +1,100 @@
+/* Numbers about how a parse went, for working out which parts of a grammar make it
+ * slow. The counts are the backtracking parser's, which is where the work usually is.
+ * LL(1) parses don't memoize anything, so they only say how far they got and how long
+ * it took. Earley parses count their chart items as continuations, and that's all. */
+
+use super::{CharToken, Parser, ParseError, SyntaxTree, Token};
+use super::budget::Deadline;
+use super::profile::{ParseProfile, Profiler};
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+
+/* Public Interface */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseStats {
+    pub tokens: usize,  // How far the parse got: the end of the tree, or where the error is.
+    pub memo_hits: usize,  // Times an expression was needed at a token, and had already been parsed there.
+    pub memo_misses: usize,  // Times it hadn't, and had to be.
+    pub continuations: usize,  // Ways any expression matched at any token, added up.
+    pub nodes: usize,  // Tree nodes made, including those of parses that went nowhere.
+    pub peak_width: usize,  // The most ways one expression matched at one token, or the biggest Earley set.
+    pub elapsed: Duration,
+}
+
+impl<T: Token> Parser<T> {
+    /* Like parse_tokens, but also returns the stats, whether or not the parse worked. */
+    pub fn parse_tokens_with_stats(&self, tokens: &[T], start_rule: &str) -> (Result<SyntaxTree<T>, ParseError>, ParseStats) {
+        let recorder = StatsRecorder::new();
+        let started = Instant::now();
+        let result = self.parse_tokens_anchored(tokens, start_rule, self.anchored, &Deadline::none(), &recorder);
+
+        let mut stats = recorder.get();
+        stats.elapsed = started.elapsed();
+        stats.tokens = match &result {
+            Ok(tree) => tree.span().end,
+            Err(ParseError::UnexpectedToken { index, .. } | ParseError::Cancelled { index, .. } | ParseError::LimitExceeded { index, .. }) => *index,
+            Err(ParseError::UnexpectedEof { .. }) => tokens.len(),
+            Err(_) => 0,
+        };
+        (result, stats)
+    }
+}
+
+impl Parser<CharToken> {
+    pub fn parse_string_with_stats(&self, input: &str, start_rule: &str) -> (Result<SyntaxTree<CharToken>, ParseError>, ParseStats) {
+        let (result, stats) = self.parse_tokens_with_stats(&super::string_to_tokens(input), start_rule);
+        (result.map_err(|err| super::locate_error(err, input)), stats)
+    }
+}
+
+
+/* Private Implementation */
+
+/* Where the algorithms count, shared by everything working on one parse. Parses that
+ * nobody asked for stats about count into one that is thrown away. Only parses being
+ * profiled have a Profiler, since looking at the clock for every rule isn't free. */
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StatsRecorder {
+    stats: Rc<Cell<ParseStats>>,
+    profiler: Option<Rc<RefCell<Profiler>>>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn new() -> StatsRecorder {
+        StatsRecorder::default()
+    }
+
+    pub(crate) fn profiling() -> StatsRecorder {
+        StatsRecorder { profiler: Some(Rc::default()), ..StatsRecorder::default() }
+    }
+
+    pub(crate) fn enter_rule(&self) {
+        if let Some(profiler) = &self.profiler {
+            profiler.borrow_mut().enter_rule();
+        }
+    }
+
+    pub(crate) fn exit_rule(&self, rule_name: &str, matched: bool) {
+        if let Some(profiler) = &self.profiler {
+            profiler.borrow_mut().exit_rule(rule_name, matched);
+        }
+    }
+
+    pub(crate) fn profile(&self) -> ParseProfile {
+        self.profiler.as_ref().map_or_else(ParseProfile::default, |profiler| profiler.borrow().finish())
+    }
+
+    pub(crate) fn count(&self, update: impl FnOnce(&mut ParseStats)) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        self.stats.set(stats);
+    }
+
+    pub(crate) fn get(&self) -> ParseStats {
+        self.stats.get()
+    }
+}
@@ -0,0 +1,92 @@
+/* Parsing tokens as they arrive, rather than all at once. This is built on the Earley
+ * parser's chart, which only ever looks at the tokens up to the set it is working on. */
+
+use super::{AmbiguityPolicy, CharToken, Parser, ParseError, SyntaxTree, Token};
+use super::earley_parser::Chart;
+
+use std::io::BufRead;
+
+
+/* A parse that is fed one token at a time with feed(), then finished with finish().
+ * Start one with Parser::parse_streaming.
+ *
+ * Errors are reported as soon as a token can't be part of any parse, instead of
+ * when the input ends. The tree can only be built once every token is in, though,
+ * so the tokens and the parse state are kept until then.
+ *
+ * Streaming always uses the Earley algorithm, whatever set_algorithm says. Since the
+ * rest of the input isn't known yet, lookaheads can't be used, and predicates only
+ * see the tokens fed so far (which always includes the whole span they check). */
+pub struct StreamingParse<'p, T: Token> {
+    pub(super) parser: &'p Parser<T>,
+    pub(super) start_rule: String,
+    pub(super) chart: Chart<'p, T>,
+    pub(super) tokens: Vec<T>,
+}
+
+impl<T: Token> Parser<T> {
+    pub fn parse_streaming(&self, start_rule: &str) -> Result<StreamingParse<'_, T>, ParseError> {
+        Ok(StreamingParse { parser: self, start_rule: start_rule.to_string(), chart: Chart::new(self, start_rule)?, tokens: vec![] })
+    }
+}
+
+impl<'p, T: Token> StreamingParse<'p, T> {
+    /* Fails if no parse can get past this token. An unanchored parser doesn't fail here,
+     * since it can settle for the tokens before it. */
+    pub fn feed(&mut self, token: T) -> Result<(), ParseError> {
+        let index = self.tokens.len();
+        self.tokens.push(token);
+        self.chart.process(index, &self.tokens, None)?;
+
+        if self.parser.anchored && !self.chart.can_continue_past(index) {
+            return Err(self.chart.failure_info.clone().into_error(self.parser, &self.tokens));
+        }
+
+        Ok(())
+    }
+
+    /* Ends the input, and returns the tree as parse_tokens would. */
+    pub fn finish(self) -> Result<SyntaxTree<T>, ParseError> {
+        self.finish_keeping_tokens().0
+    }
+
+    fn finish_keeping_tokens(self) -> (Result<SyntaxTree<T>, ParseError>, Vec<T>) {
+        let StreamingParse { parser, mut chart, tokens, .. } = self;
+
+        let tree = chart.process(tokens.len(), &tokens, None).and_then(|()| {
+            let want_all = !matches!(parser.ambiguity_policy, AmbiguityPolicy::FirstMatch);
+            parser.resolve_ambiguity(chart.trees(&tokens, want_all, parser.anchored)?)
+        });
+
+        (tree, tokens)
+    }
+}
+
+impl Parser<CharToken> {
+    /* Like parse_string, but reads the input from a reader as it goes (a line at a time),
+     * feeding it to a streaming parse. The input has to be UTF-8. See StreamingParse
+     * for what streaming can and can't do. */
+    pub fn parse_reader(&self, mut reader: impl BufRead, start_rule: &str) -> Result<SyntaxTree<CharToken>, ParseError> {
+        let mut stream = self.parse_streaming(start_rule)?;
+
+        let mut line = vec![];
+        while reader.read_until(b'\n', &mut line).map_err(|err| ParseError::Internal(format!("Cannot read input: {err}")))? > 0 {
+            let text = std::str::from_utf8(&line).map_err(|err| ParseError::Internal(format!("Input is not UTF-8: {err}")))?;
+            for ch in text.chars() {
+                if let Err(err) = stream.feed(CharToken { token_type: ch }) {
+                    return Err(locate_in_tokens(err, &stream.tokens));
+                }
+            }
+            line.clear();
+        }
+
+        let (tree, tokens) = stream.finish_keeping_tokens();
+        tree.map_err(|err| locate_in_tokens(err, &tokens))
+    }
+}
+
+// Only done for errors, so rebuilding the input is fine.
+fn locate_in_tokens(err: ParseError, tokens: &[CharToken]) -> ParseError {
+    let input = tokens.iter().map(|token| token.token_type).collect::<String>();
+    super::locate_error(err, &input)
+}
@@ -248,8 +248,10 @@ fn errors() {
     "##).expect("Parser definition ok");
 
     match parser.parse_string("Color (1 7 0)", "Color") {
-        Err(ParseError::IncompleteParse { index, terminals }) => {
+        Err(ParseError::UnexpectedToken { index, terminals, location, span, .. }) => {
             assert_eq!(index, 9);
+            assert_eq!(location, Some(SourceLocation { line: 1, column: 10, byte_offset: 9 }));
+            assert_eq!(span, Some(9..10));
             assert!(terminals.contains("0"));
             assert!(terminals.contains("1"));
             assert!(terminals.contains("2"));
@@ -260,7 +262,7 @@ fn errors() {
     }
 
     match parser.parse_string("aisbiuag", "Color") {
-        Err(ParseError::IncompleteParse { index, terminals }) => {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
             assert_eq!(index, 0);
             assert!(terminals.contains("C"));
             assert!(terminals.contains("#"));
@@ -270,10 +272,2322 @@ fn errors() {
     }
 
     match parser.parse_string("Color (1 2 0", "Color") {
-        Err(ParseError::OutOfInput { terminals }) => {
+        Err(ParseError::UnexpectedEof { terminals, .. }) => {
             assert!(terminals.contains(")"));
             assert!(terminals.len() == 1);
         },
         _ => panic!("Expected out of input")
     }
 }
+
+#[test]
+fn error_messages() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Statement: Name "=" Expr ;
+        Name: [a-z]+ ;
+        Expr: [0-9]+ | "(" Expr ")" ;
+    "##).expect("Parser definition ok");
+
+    let message = |input: &str, start_rule: &str| parser.parse_string(input, start_rule).expect_err("Should fail").to_string();
+    assert_eq!(message("x=?", "Statement"), "Unexpected token at line 1, column 3, expected Expr");
+    assert_eq!(message("x=(1", "Statement"), "Unexpected end of input, expected ) or [0-9]");
+    assert_eq!(message("x", "Nowhere"), "Rule \"Nowhere\" not found");
+    assert!(matches!(parser.parse_string("x", "Nowhere"), Err(ParseError::UnknownRule { rule_name, suggestion: None }) if rule_name == "Nowhere"));
+    assert_eq!(message("x", "Statment"), "Rule \"Statment\" not found, did you mean \"Statement\"?");
+
+    let err: Box<dyn std::error::Error> = Box::new(parser.parse_string("=", "Statement").expect_err("Should fail"));
+    assert_eq!(err.to_string(), "Unexpected token at line 1, column 1, expected Name");
+}
+
+#[test]
+fn expected_rules() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Statement: Name "=" Expr ;
+        Name: [a-z]+ ;
+        Expr: Literal | "(" Expr ")" ;
+        Literal: [0-9]+ ;
+    "##).expect("Parser definition ok");
+
+    for algorithm in [ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+
+        match parser.parse_string("x=?", "Statement") {
+            Err(ParseError::UnexpectedToken { index, terminals, expected, .. }) => {
+                assert_eq!(index, 2);
+                assert_eq!(terminals, HashSet::from(["[0-9]".to_string(), "(".to_string()]));
+                assert_eq!(expected, HashSet::from(["Expr".to_string()]));
+            },
+            _ => panic!("Expected failed parse"),
+        }
+
+        // Once a rule has consumed tokens, its own terminals are expected.
+        match parser.parse_string("x=(1", "Statement") {
+            Err(ParseError::UnexpectedEof { terminals, expected, .. }) => {
+                assert_eq!(terminals, HashSet::from(["[0-9]".to_string(), ")".to_string()]));
+                assert_eq!(expected, terminals);
+            },
+            _ => panic!("Expected out of input"),
+        }
+
+        match parser.parse_string("x=((?", "Statement") {
+            Err(ParseError::UnexpectedToken { context, .. }) => assert_eq!(context, ["Statement", "Expr", "Expr", "Expr", "Literal"]),
+            _ => panic!("Expected failed parse"),
+        }
+
+        // The start rule is never expected, its terminals are.
+        match parser.parse_string("=", "Statement") {
+            Err(ParseError::UnexpectedToken { expected, .. }) => assert_eq!(expected, HashSet::from(["Name".to_string()])),
+            _ => panic!("Expected failed parse"),
+        }
+    }
+}
+
+#[test]
+fn labels() {
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ ]+ ;
+        @token @label("a name") Name: [a-z]+ ;
+        @token @label("a number") Number: [0-9]+ ;
+        @token Symbol: [=()] ;
+        Statement: Name "=" Expr ;
+        @label("an expression") Expr: Number | Name | "(" Expr ")" ;
+    "##).expect("Parser definition ok");
+
+    match parser.parse_string("x = =", "Statement") {
+        Err(ParseError::UnexpectedToken { terminals, expected, .. }) => {
+            assert_eq!(terminals, HashSet::from(["Number".to_string(), "Name".to_string(), "\"(\"".to_string()]));
+            assert_eq!(expected, HashSet::from(["an expression".to_string()]));
+        },
+        _ => panic!("Expected failed parse"),
+    }
+
+    match parser.parse_string("= x", "Statement") {
+        Err(ParseError::UnexpectedToken { expected, .. }) => assert_eq!(expected, HashSet::from(["a name".to_string()])),
+        _ => panic!("Expected failed parse"),
+    }
+
+    match parser.parse_string("x ?", "Statement") {
+        Err(ParseError::UnexpectedToken { index, expected, .. }) => {
+            assert_eq!(index, 2);
+            assert!(expected.contains("a name") && expected.contains("a number") && expected.contains("Whitespace"));
+        },
+        _ => panic!("Expected failed lexing"),
+    }
+}
+
+#[test]
+fn parse_all() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Start: A B ;
+        A: "x" | "x" "x" ;
+        B: "x" | "x" "x" ;
+    "##).expect("Parser definition ok");
+
+    let trees = parser
+        .parse_string_all("xxx", "Start")
+        .expect("No error");
+
+    assert_eq!(trees.len(), 2);
+    let trees = trees.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+    assert!(trees.contains(&indoc! {"
+        Syntax Tree {
+            Start
+                A
+                    token (x)
+                B
+                    token (x)
+                    token (x)
+        }"}.to_string()));
+
+    assert!(trees.contains(&indoc! {"
+        Syntax Tree {
+            Start
+                A
+                    token (x)
+                    token (x)
+                B
+                    token (x)
+        }"}.to_string()));
+
+    // Identical trees reached through different alternatives are only reported once.
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Start: "a" | "a" ;
+    "##).expect("Parser definition ok");
+
+    assert_eq!(parser.parse_string_all("a", "Start").expect("No error").len(), 1);
+    parser.parse_string_all("b", "Start").expect_err("Should fail");
+}
+
+#[test]
+fn ambiguity_policy() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Start: "y" Pair ;
+        Pair: A B ;
+        A: "x" | "x" "x" ;
+        B: "x" | "x" "x" ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("yxxx", "Start").expect("First match by default");
+
+    parser.set_ambiguity_policy(AmbiguityPolicy::RejectAmbiguity);
+    match parser.parse_string("yxxx", "Start") {
+        Err(ParseError::Ambiguous(ambiguity)) => {
+            assert_eq!(ambiguity, Ambiguity { parse_count: 2, rule_name: "Pair".to_string(), index: 1 });
+        },
+        _ => panic!("Expected ambiguous parse"),
+    }
+
+    // Unambiguous input is unaffected.
+    parser.parse_string("yxx", "Start").expect("No error");
+
+    let reported = std::rc::Rc::new(std::cell::Cell::new(0));
+    let reported_clone = reported.clone();
+    parser.set_ambiguity_policy(AmbiguityPolicy::WarnAndPick(Box::new(move |ambiguity| {
+        reported_clone.set(ambiguity.parse_count);
+    })));
+
+    parser.parse_string("yxxx", "Start").expect("Picks a parse");
+    assert_eq!(reported.get(), 2);
+}
+
+#[test]
+fn spans() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Start: "(" Inner ")" Empty ;
+        Inner: "a"+ ;
+        Empty: "b"? ;
+    "##).expect("Parser definition ok");
+
+    let tree = parser
+        .parse_string("(aaa)", "Start")
+        .expect("No error");
+
+    assert_eq!(tree.span(), 0..5);
+
+    let SyntaxTree::RuleNode { subexpressions, .. } = tree else { panic!("Expected rule node") };
+
+    match &subexpressions[0] {
+        SyntaxTree::TokenNode { token, index, .. } => {
+            assert_eq!(token.token_type, '(');
+            assert_eq!(*index, 0);
+        }
+        _ => panic!("Expected token node"),
+    }
+
+    assert_eq!(subexpressions[1].span(), 1..4);
+    assert_eq!(subexpressions[2].span(), 4..5);
+    assert_eq!(subexpressions[3].span(), 5..5);
+}
+
+#[test]
+fn error_locations() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Lines: Line+ ;
+        Line: "é"* "\n" ;
+    "##).expect("Parser definition ok");
+
+    match parser.parse_string("éé\n\néx\n", "Lines") {
+        Err(ParseError::UnexpectedToken { index, location, .. }) => {
+            assert_eq!(index, 5);
+            assert_eq!(location, Some(SourceLocation { line: 3, column: 2, byte_offset: 8 }));
+        },
+        _ => panic!("Expected failed parse"),
+    }
+
+    let map = LineMap::new("ab\ncd");
+    assert_eq!(map.location_of_char(0), SourceLocation { line: 1, column: 1, byte_offset: 0 });
+    assert_eq!(map.location_of_char(2), SourceLocation { line: 1, column: 3, byte_offset: 2 });
+    assert_eq!(map.location_of_char(3), SourceLocation { line: 2, column: 1, byte_offset: 3 });
+    assert_eq!(map.location_of_char(10), SourceLocation { line: 2, column: 3, byte_offset: 5 });
+}
+
+#[test]
+fn source_text() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Words: Word ((" " | "\n") Word)* ;
+        Word: [a-zßäöü]+ ;
+    "##).expect("Parser definition ok");
+
+    let input = "grüße aus\nköln";
+    let source = LineMap::new(input);
+    let tree = parser.parse_string(input, "Words").expect("No error");
+
+    let SyntaxTree::RuleNode { subexpressions, .. } = &tree else { panic!("Expected rule node") };
+    let words = subexpressions.iter()
+        .filter(|tree| matches!(tree, SyntaxTree::RuleNode { .. }))
+        .map(|word| (word.text(&source), word.byte_span(&source)))
+        .collect::<Vec<_>>();
+
+    assert_eq!(words, vec![("grüße", 0..7), ("aus", 8..11), ("köln", 12..17)]);
+    assert_eq!(tree.text(&source), input);
+}
+
+#[test]
+fn left_recursion() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Expr: Expr ("+" | "-") Term | Term ;
+        Term: "1" | "2" | "3" ;
+    "##).expect("Parser definition ok");
+
+    let tree = parser
+        .parse_string("1+2-3", "Expr")
+        .expect("No error");
+
+    // Left recursion gives left associative trees.
+    assert_eq!(tree.to_string(), indoc! {"
+        Syntax Tree {
+            Expr
+                Expr
+                    Expr
+                        Term
+                            token (1)
+                    token (+)
+                    Term
+                        token (2)
+                token (-)
+                Term
+                    token (3)
+        }"}
+    );
+
+    parser.parse_string("1+", "Expr").expect_err("Should fail");
+
+    // Indirect left recursion
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List: Items "." ;
+        Items: List "," "a" | Items "a" | "a" ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("aa.,a.", "List").expect("No error");
+    parser.parse_string("a.,", "List").expect_err("Should fail");
+
+    // Cycles terminate rather than producing infinitely many trees.
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Loop: Loop | "a" ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("a", "Loop").expect("No error");
+
+    // A cycle cut short inside one rule mustn't hide trees from the rules around it.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        S: A ;
+        A: (S S)? | "a" ;
+    "##).expect("Parser definition ok");
+
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+        let trees = parser.parse_string_all("aa", "S").expect("No error");
+        assert!(trees.iter().any(|tree| tree.to_string() == indoc! {"
+            Syntax Tree {
+                S
+                    A
+                        S
+                            A
+                                token (a)
+                        S
+                            A
+                                token (a)
+            }"}), "{algorithm:?}");
+    }
+
+    // Each algorithm stops a cycle at a different point, see the README.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        S: S | B ;
+        B: "b" ;
+    "##).expect("Parser definition ok");
+
+    parser.set_algorithm(ParseAlgorithm::Earley);
+    assert_eq!(parser.parse_string_all("b", "S").expect("No error").len(), 1);
+    parser.set_algorithm(ParseAlgorithm::Backtracking);
+    assert_eq!(parser.parse_string_all("b", "S").expect("No error").len(), 2);
+}
+
+#[test]
+fn character_classes() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Identifier: [a-zA-Z_] [a-zA-Z0-9_]* ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("snake_Case_42", "Identifier").expect("No error");
+    parser.parse_string("_", "Identifier").expect("No error");
+
+    match parser.parse_string("9lives", "Identifier") {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
+            assert_eq!(index, 0);
+            assert_eq!(terminals, HashSet::from(["[a-zA-Z_]".to_string()]));
+        },
+        _ => panic!("Expected failed parse"),
+    }
+}
+
+#[test]
+fn negation() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        String: "\"" [^"\n]* "\"" ;
+        Comment: "#" ~"\n"* "\n" ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("\"hello # world\"", "String").expect("No error");
+    parser.parse_string("\"hello\nworld\"", "String").expect_err("Should fail");
+    parser.parse_string("# \"hello\" # world\n", "Comment").expect("No error");
+
+    match parser.parse_string("#a\"\n", "String") {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
+            assert_eq!(index, 0);
+            assert_eq!(terminals, HashSet::from(["\"".to_string()]));
+        },
+        _ => panic!("Expected failed parse"),
+    }
+
+    match parser.parse_string("#\n\n", "Comment") {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
+            assert_eq!(index, 1);
+            assert_eq!(terminals, HashSet::from(["~\n".to_string()]));
+        },
+        _ => panic!("Expected failed parse"),
+    }
+}
+
+#[test]
+fn wildcard() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Comment: "/" "/" .* "\n" ;
+        Three: . . . ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("// anything goes: \"/[.\n", "Comment").expect("No error");
+    parser.parse_string("/*/\n", "Comment").expect_err("Should fail");
+    parser.parse_string("a\n.", "Three").expect("No error");
+
+    match parser.parse_string("ab", "Three") {
+        Err(ParseError::UnexpectedEof { terminals, .. }) => assert_eq!(terminals, HashSet::from([".".to_string()])),
+        _ => panic!("Expected out of input"),
+    }
+}
+
+#[test]
+fn anchoring() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Words: Word (" " Word)* ;
+        Word: [a-z]+ ;
+        Line: [a-z]* $ ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("hello world!", "Words").expect_err("Should fail");
+    
+    parser.set_anchored(false);
+    let tree = parser.parse_string("hello world!", "Words").expect("No error");
+    assert_eq!(tree.span(), 0..11);
+
+    parser.parse_string("!", "Words").expect_err("Should fail");
+
+    // The end of input anchor still works when parsing prefixes.
+    parser.parse_string("hello", "Line").expect("No error");
+    match parser.parse_string("hello world", "Line") {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
+            assert_eq!(index, 5);
+            assert!(terminals.contains("$"));
+        },
+        _ => panic!("Expected failed parse"),
+    }
+}
+
+#[test]
+fn repetition() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Year: Digit{4} ;
+        Color: "#" Hex{1,6} ;
+        Laugh: "ha"{2,} ;
+        Digit: [0-9] ;
+        Hex: [0-9a-fA-F] ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("2024", "Year").expect("No error");
+    parser.parse_string("202", "Year").expect_err("Should fail");
+    parser.parse_string("20245", "Year").expect_err("Should fail");
+
+    parser.parse_string("#f", "Color").expect("No error");
+    parser.parse_string("#00ff00", "Color").expect("No error");
+    parser.parse_string("#", "Color").expect_err("Should fail");
+    parser.parse_string("#00ff00a", "Color").expect_err("Should fail");
+
+    parser.parse_string("haha", "Laugh").expect("No error");
+    parser.parse_string("hahahahaha", "Laugh").expect("No error");
+    parser.parse_string("ha", "Laugh").expect_err("Should fail");
+}
+
+#[test]
+fn multi_character_literals() {
+    // Literals longer than one character match each character in turn, flattened into the rule.
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Statement: ("let" | "const") " " [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    let tree = parser.parse_string("let x", "Statement").expect("No error");
+    assert_eq!(tree.to_string(), indoc! {"
+        Syntax Tree {
+            Statement
+                token (l)
+                token (e)
+                token (t)
+                token ( )
+                token (x)
+        }"}
+    );
+
+    parser.parse_string("lex x", "Statement").expect_err("Should fail");
+    parser.parse_string("constant", "Statement").expect_err("Should fail");
+}
+
+#[test]
+fn lexer() {
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ \t\n]+ ;
+        @token Keyword: "let" ;
+        @token Identifier: Letter (Letter | Digit)* ;
+        @token Number: Digit+ ;
+        @token Punctuation: [=;] ;
+        @fragment Letter: [a-zA-Z_] ;
+        @fragment Digit: [0-9] ;
+
+        Statement: "let" Identifier "=" (_Number | Identifier) ";" ;
+    "##).expect("Parser definition ok");
+
+    let tokens = parser.tokenize("let letter =\n 42;").expect("No error");
+    assert_eq!(
+        tokens.iter().map(|token| (token.kind.as_str(), token.text.as_str())).collect::<Vec<_>>(),
+        vec![("Keyword", "let"), ("Identifier", "letter"), ("Punctuation", "="), ("Number", "42"), ("Punctuation", ";")]
+    );
+    assert_eq!(tokens[3].span, 14..16);
+
+    let tree = parser.parse_string("let x = y;", "Statement").expect("No error");
+    assert_eq!(tree.to_string(), indoc! {"
+        Syntax Tree {
+            Statement
+                token (let)
+                token (x)
+                token (=)
+                token (y)
+                token (;)
+        }"}
+    );
+
+    // Keywords aren't identifiers.
+    parser.parse_string("let let = 1;", "Statement").expect_err("Should fail");
+
+    match parser.parse_string("let x =\n;", "Statement") {
+        Err(ParseError::UnexpectedToken { index, location, .. }) => {
+            assert_eq!(index, 3);
+            assert_eq!(location.map(|location| (location.line, location.column)), Some((2, 1)));
+        },
+        _ => panic!("Expected failed parse"),
+    }
+
+    match parser.tokenize("let x = 1 + 2;") {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
+            assert_eq!(index, 10);
+            assert!(terminals.contains("Identifier"));
+        },
+        _ => panic!("Expected failed tokenize"),
+    }
+
+    let no_lexer: Parser<LexedToken> = crate::define::define_parser("A: _Word ;").expect("Parser definition ok");
+    no_lexer.tokenize("word").expect_err("Should fail");
+}
+
+#[test]
+fn trivia() {
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ \n]+ ;
+        @skip Comment: "//" ~"\n"* ;
+        @token Word: [a-z]+ ;
+
+        Words: Word+ ;
+    "##).expect("Parser definition ok");
+
+    let (tree, trivia) = parser.parse_string_with_trivia("one // first\ntwo", "Words").expect("No error");
+    assert_eq!(tree.span(), 0..2);
+    assert_eq!(
+        trivia.iter().map(|token| (token.kind.as_str(), token.text.as_str(), token.span.clone())).collect::<Vec<_>>(),
+        vec![("Whitespace", " ", 3..4), ("Comment", "// first", 4..12), ("Whitespace", "\n", 12..13)]
+    );
+
+    assert_eq!(parser.tokenize("one // first\ntwo").expect("No error").len(), 2);
+}
+
+#[test]
+fn case_insensitive_literals() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Query: i"select" " " [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("select x", "Query").expect("No error");
+    parser.parse_string("SELECT x", "Query").expect("No error");
+    parser.parse_string("SeLeCt x", "Query").expect("No error");
+    parser.parse_string("SELEKT x", "Query").expect_err("Should fail");
+
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: " "+ ;
+        @token Keyword: i"select" | i"from" ;
+        @token Word: [a-zA-Z]+ ;
+
+        Query: i"select" Word i"from" Word ;
+    "##).expect("Parser definition ok");
+
+    let tokens = parser.tokenize("Select x FROM y").expect("No error");
+    assert_eq!(tokens.iter().map(|token| token.kind.as_str()).collect::<Vec<_>>(), vec!["Keyword", "Word", "Keyword", "Word"]);
+    parser.parse_string("Select x FROM y", "Query").expect("No error");
+    parser.parse_string("Select x y", "Query").expect_err("Should fail");
+}
+
+#[test]
+fn lookahead() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Identifier: !Keyword [a-z]+ ;
+        Keyword: ("if" | "else") ![a-z] ;
+        Comment: "/*" (!"*/" .)* "*/" ;
+        Followed: "a" &"b" ;
+    "##).expect("Parser definition ok");
+
+    parser.parse_string("iffy", "Identifier").expect("No error");
+    parser.parse_string("foo", "Identifier").expect("No error");
+    match parser.parse_string("if", "Identifier") {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
+            assert_eq!(index, 0);
+            assert!(terminals.contains("!Keyword"));
+        },
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+
+    parser.parse_string("/* a * b */", "Comment").expect("No error");
+    parser.parse_string("/* a */ b */", "Comment").expect_err("Should fail");
+
+    // Lookahead doesn't consume anything, so this can never consume the "b".
+    parser.parse_string("ab", "Followed").expect_err("Should fail");
+    parser.set_anchored(false);
+    assert_eq!(parser.parse_string("ab", "Followed").expect("No error").span(), 0..1);
+    parser.parse_string("ac", "Followed").expect_err("Should fail");
+}
+
+#[test]
+fn predicates() {
+    // The classic C problem: "a * b;" is a declaration if a is a type, and a multiplication otherwise.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Statement: (Declaration | Multiplication) ";" ;
+        Declaration: TypeName " * " Name ;
+        Multiplication: Name " * " Name ;
+        TypeName: [a-z]+ ;
+        Name: [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    let type_names = ["size", "word"];
+    parser.add_predicate("TypeName", move |tokens, span| {
+        let name = tokens[span].iter().map(|token| token.token_type).collect::<String>();
+        type_names.contains(&name.as_str())
+    }).expect("Rule exists");
+
+    let is_declaration = |parser: &Parser<CharToken>, input: &str| match parser.parse_string(input, "Statement").expect("No error") {
+        SyntaxTree::RuleNode { subexpressions, .. } => matches!(
+            &subexpressions[0], SyntaxTree::RuleNode { rule_name, .. } if rule_name == "Declaration"
+        ),
+        _ => panic!("Expected rule node"),
+    };
+
+    assert!(is_declaration(&parser, "size * x;"));
+    assert!(!is_declaration(&parser, "sizes * x;"));
+
+    // Every predicate on a rule has to accept.
+    parser.add_predicate("Name", |_, span| span.len() == 1).expect("Rule exists");
+    assert!(is_declaration(&parser, "word * x;"));
+    parser.parse_string("ab * x;", "Statement").expect_err("Should fail");
+
+    assert!(parser.add_predicate("Missing", |_, _| true).is_err());
+}
+
+#[test]
+fn merge() {
+    let define = |definition: &str| -> Parser<CharToken> { crate::define::define_parser(definition).expect("Parser definition ok") };
+    let expressions = || define(r##"
+        Expr: Literal ("+" Literal)* ;
+        Literal: [0-9]+ ;
+    "##);
+    let statements = || define(r##"
+        extern Expr ;
+        Statement: "print " Expr ;
+        Literal: [a-z]+ ;
+    "##);
+
+    let mut parser = expressions();
+    match parser.merge(statements(), ConflictPolicy::Reject) {
+        Err(err) => assert_eq!(err.message, "Both parsers define rules: Literal"),
+        Ok(()) => panic!("Expected conflict"),
+    }
+    parser.parse_string("print 1", "Statement").expect_err("Should fail, nothing was merged");
+
+    parser.merge(statements(), ConflictPolicy::KeepOurs).expect("No conflict");
+    parser.parse_string("print 1+2", "Statement").expect("No error");
+    parser.parse_string("print a+b", "Statement").expect_err("Should fail");
+
+    let mut parser = expressions();
+    parser.add_predicate("Literal", |_, span| span.len() == 1).expect("Rule exists");
+    parser.merge(statements(), ConflictPolicy::TakeTheirs).expect("No conflict");
+    parser.parse_string("print ab+cd", "Statement").expect("No error");
+
+    let mut parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: " "+ ;
+        @token Number: [0-9]+ ;
+        Sum: Number ("+" Number)* ;
+        @token Plus: "+" ;
+    "##).expect("Parser definition ok");
+    parser.merge(crate::define::define_parser(r##"
+        extern Sum ;
+        @token Keyword: "print" ;
+        Statement: "print" Sum ;
+    "##).expect("Parser definition ok"), ConflictPolicy::Reject).expect("No conflict");
+    parser.parse_string("print 1 + 2", "Statement").expect("No error");
+
+    // extern is only a keyword when it doesn't start a rule.
+    let mut parser = define(r##"
+        extern: "x" Sum ;
+        extern Sum ;
+    "##);
+    parser.merge(define(r#"Sum: "y" ;"#), ConflictPolicy::Reject).expect("No conflict");
+    parser.parse_string("xy", "extern").expect("No error");
+}
+
+// Every algorithm, so that tests can check they agree.
+const ALGORITHMS: [ParseAlgorithm; 3] = [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley];
+
+#[test]
+fn algorithms_agree() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum: Sum ("+" | "-") Product | Product ;
+        Product: Product ("*" | "/") Atom | Atom ;
+        Atom: [a-z] | "(" Sum ")" | Call ;
+        Call: [a-z]+ "(" (Sum ("," Sum)*)? ")" ;
+    "##).expect("Parser definition ok");
+
+    let inputs = ["a", "a+b*c", "(a-b)/c", "f(a,b+c)*d", "ff()"];
+    let failures = ["", "a+", "f(a,)", "(a"];
+
+    parser.set_algorithm(ParseAlgorithm::Backtracking);
+    let expected = inputs.map(|input| parser.parse_string(input, "Sum").expect("No error").to_string());
+
+    for algorithm in ALGORITHMS {
+        parser.set_algorithm(algorithm);
+        for (input, expected) in inputs.iter().zip(&expected) {
+            assert_eq!(&parser.parse_string(input, "Sum").expect("No error").to_string(), expected, "{algorithm:?} on {input}");
+        }
+        for input in failures {
+            parser.parse_string(input, "Sum").expect_err("Should fail");
+        }
+    }
+}
+
+#[test]
+fn earley() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum: Sum "+" Sum | [0-9] ;
+        Spaces: (" "?)* "." ;
+        Word: !"no" [a-z]+ &$ ;
+        Even: Digit+ ;
+        Digit: [0-9] ;
+    "##).expect("Parser definition ok");
+    parser.set_algorithm(ParseAlgorithm::Earley);
+    parser.add_predicate("Even", |tokens, span| tokens[span.end - 1].token_type.to_digit(10).is_some_and(|n| n % 2 == 0))
+        .expect("Rule exists");
+
+    // Catalan numbers: 1, 1, 2, 5, 14 ways to group the sums.
+    for (input, count) in [("1", 1), ("1+2", 1), ("1+2+3", 2), ("1+2+3+4", 5), ("1+2+3+4+5", 14)] {
+        assert_eq!(parser.parse_string_all(input, "Sum").expect("No error").len(), count);
+    }
+
+    // Repeating something that can match nothing doesn't send Earley into a loop.
+    parser.parse_string("  .", "Spaces").expect("No error");
+
+    parser.parse_string("yes", "Word").expect("No error");
+    parser.parse_string("nope", "Word").expect_err("Should fail");
+
+    parser.parse_string("1234", "Even").expect("No error");
+    parser.parse_string("1235", "Even").expect_err("Should fail");
+
+    match parser.parse_string("1+2+", "Sum") {
+        Err(ParseError::UnexpectedEof { terminals, .. }) => assert!(terminals.contains("[0-9]")),
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+    match parser.parse_string("1+a", "Sum") {
+        Err(ParseError::UnexpectedToken { index, terminals, .. }) => {
+            assert_eq!(index, 2);
+            assert!(terminals.contains("[0-9]"));
+        },
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+}
+
+#[test]
+fn ll1_fast_path() {
+    let parser: Parser<LexedToken> = crate::define::define_parser(r#"
+        @skip Whitespace : [ \t\r\n]+ ;
+        @token Literal : [a-d] ;
+        @token Symbol : [-+*/()] ;
+
+        Expr : PlusMinusExpr ;
+        PlusMinusExpr :  MultDivExpr  (("+" | "-") MultDivExpr)* ;
+        MultDivExpr : AtomicExpr (("*" | "/") AtomicExpr)* ;
+        AtomicExpr : Literal | "(" PlusMinusExpr ")" ;
+
+        LeftRecursive : LeftRecursive "+" Literal | Literal ;
+        CommonPrefix : "(" Literal ")" | "(" Symbol ;
+        UsesCommonPrefix : CommonPrefix "+" ;
+        Overlapping : Literal | "a" ;
+    "#).expect("Parser definition ok");
+
+    for rule_name in ["Expr", "PlusMinusExpr", "MultDivExpr", "AtomicExpr", "Overlapping"] {
+        assert!(parser.prediction_tables.contains(rule_name), "{rule_name}");
+    }
+    for rule_name in ["LeftRecursive", "CommonPrefix", "UsesCommonPrefix"] {
+        assert!(!parser.prediction_tables.contains(rule_name), "{rule_name}");
+    }
+
+    let input = "   ( a + b)*( c +  a  * \n\n\n\t\t (  d )+ c  )";
+    let tokens = parser.tokenize(input).expect("No error");
+    let tree = ll1_parse(&parser, &tokens, "PlusMinusExpr", true).expect("Parses with the tables");
+    assert!(ambiguity::same_shape(&tree, &backtracking_parse(&parser, &tokens, "PlusMinusExpr", true, &budget::Deadline::none(), &stats::StatsRecorder::new()).expect("No error")));
+
+    // "a" is both a Literal and "a", which the tables can't tell apart, so the general parser takes over.
+    let tokens = parser.tokenize("a").expect("No error");
+    assert!(ll1_parse(&parser, &tokens, "Overlapping", true).is_none());
+    parser.parse_tokens(&tokens, "Overlapping").expect("No error");
+
+    // Failures are left to the general parser, so the errors are the same.
+    let tokens = parser.tokenize("(a + b").expect("No error");
+    assert!(ll1_parse(&parser, &tokens, "PlusMinusExpr", true).is_none());
+    assert!(matches!(parser.parse_tokens(&tokens, "PlusMinusExpr"), Err(ParseError::UnexpectedEof { .. })));
+}
+
+#[test]
+fn ordered_choice() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        @ordered Committed : ("a" | "a" "b") "c" ;
+        Uncommitted : ("a" | "a" "b") "c" ;
+        @ordered Keyword : "in" | "int" | [a-z]+ ;
+        Ambiguous : Word+ ;
+        Word : [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    // Once "a" matched, "a" "b" is never tried, so there's nothing left to match the "b".
+    parser.parse_string("abc", "Committed").expect_err("Should fail");
+    parser.parse_string("ac", "Committed").expect("No error");
+    parser.parse_string("abc", "Uncommitted").expect("No error");
+
+    parser.parse_string("in", "Keyword").expect("No error");
+    parser.parse_string("int", "Keyword").expect_err("Should fail");
+
+    // Only choices are ordered, repetitions can still be ambiguous.
+    assert_eq!(parser.parse_string_all("abc", "Ambiguous").expect("No error").len(), 4);
+    parser.set_ordered_choice(true);
+    assert_eq!(parser.parse_string_all("abc", "Ambiguous").expect("No error").len(), 4);
+    parser.parse_string("abc", "Uncommitted").expect_err("Should fail");
+
+    parser.set_algorithm(ParseAlgorithm::Earley);
+    parser.parse_string("ac", "Uncommitted").expect_err("Earley can't do ordered choice");
+}
+
+#[test]
+fn streaming() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum: Sum ("+" | "-") Product | Product ;
+        Product: Product ("*" | "/") Atom | Atom ;
+        Atom: [a-z] | "(" Sum ")" ;
+    "##).expect("Parser definition ok");
+
+    let feed_all = |input: &str| {
+        let mut stream = parser.parse_streaming("Sum").expect("Rule exists");
+        for (index, token) in string_to_tokens(input).into_iter().enumerate() {
+            stream.feed(token).map_err(|err| (index, err))?;
+        }
+        stream.finish().map_err(|err| (input.len(), err))
+    };
+
+    for input in ["a", "a+b*c", "(a-b)/(c+d)"] {
+        assert_eq!(feed_all(input).expect("No error").to_string(), parser.parse_string(input, "Sum").expect("No error").to_string());
+    }
+
+    // The error comes as soon as the bad token is fed, not at the end.
+    match feed_all("a+*bcdef") {
+        Err((2, ParseError::UnexpectedToken { index: 2, terminals, .. })) => assert!(terminals.contains("[a-z]")),
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+    match feed_all("(a+b") {
+        Err((4, ParseError::UnexpectedEof { terminals, .. })) => assert!(terminals.contains(")")),
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+
+    assert!(parser.parse_streaming("Missing").is_err());
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r#"Ahead: "a" &"b" "b" ;"#).expect("Parser definition ok");
+    let mut stream = parser.parse_streaming("Ahead").expect("Rule exists");
+    stream.feed(CharToken { token_type: 'a' }).expect("No error");
+    stream.feed(CharToken { token_type: 'b' }).expect_err("Lookaheads can't stream");
+}
+
+#[test]
+fn snapshots() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List: "[" (Item ("," Item)*)? "]" ;
+        Item: [a-z]+ | List ;
+    "##).expect("Parser definition ok");
+
+    let input = "[ab,[c,d],[],e]";
+    let mut first_half = string_to_tokens(input);
+    let second_half = first_half.split_off(7);
+
+    let mut stream = parser.parse_streaming("List").expect("Rule exists");
+    for token in first_half {
+        stream.feed(token).expect("No error");
+    }
+
+    let encode = |token: &CharToken| token.token_type.to_string().into_bytes();
+    let decode = |bytes: &[u8]| std::str::from_utf8(bytes).ok()?.chars().next().map(|token_type| CharToken { token_type });
+    let bytes = stream.snapshot().to_bytes(encode);
+    drop(stream);
+
+    // As if in another process, with the parser defined all over again.
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List: "[" (Item ("," Item)*)? "]" ;
+        Item: [a-z]+ | List ;
+    "##).expect("Parser definition ok");
+    let mut stream = parser.resume_streaming(ParseSnapshot::from_bytes(&bytes, decode).expect("No error")).expect("No error");
+    for token in second_half {
+        stream.feed(token).expect("No error");
+    }
+    assert_eq!(stream.finish().expect("No error").to_string(), parser.parse_string(input, "List").expect("No error").to_string());
+
+    // The snapshot remembers why the parse was failing, too.
+    let mut stream = parser.parse_streaming("List").expect("Rule exists");
+    stream.feed(CharToken { token_type: '[' }).expect("No error");
+    let stream = parser.resume_streaming(ParseSnapshot::from_bytes(&stream.snapshot().to_bytes(encode), decode).expect("No error"))
+        .expect("No error");
+    match stream.finish() {
+        Err(ParseError::UnexpectedEof { terminals, .. }) => assert!(terminals.contains("]") && terminals.contains("[a-z]")),
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+
+    let other_parser: Parser<CharToken> = crate::define::define_parser(r#"List: "[" "]" ;"#).expect("Parser definition ok");
+    assert!(other_parser.resume_streaming(ParseSnapshot::from_bytes(&bytes, decode).expect("No error")).is_err());
+    assert!(ParseSnapshot::from_bytes(&bytes[..bytes.len() - 1], decode).is_err());
+}
+
+#[test]
+fn parse_reader() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Lines: Line* ;
+        Line: [a-zé]* "\n" ;
+    "##).expect("Parser definition ok");
+
+    let input = "abc\nhé\n\nd\n";
+    assert_eq!(
+        parser.parse_reader(input.as_bytes(), "Lines").expect("No error").to_string(),
+        parser.parse_string(input, "Lines").expect("No error").to_string()
+    );
+
+    match parser.parse_reader("abc\nhé!\n".as_bytes(), "Lines") {
+        Err(ParseError::UnexpectedToken { index: 6, location: Some(location), .. }) => {
+            assert_eq!((location.line, location.column, location.byte_offset), (2, 3, 7));
+        }
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+
+    assert!(parser.parse_reader(&[b'a', 0xff, b'\n'][..], "Lines").is_err());
+}
+
+#[test]
+fn long_parse_reader() {
+    // A few megabytes, on a small stack. The chart and the tree grow in step with the input.
+    let lines = 100_000;
+    let shape = std::thread::Builder::new()
+        .stack_size(256 * 1024)
+        .spawn(move || {
+            let parser: Parser<CharToken> = crate::define::define_parser(r##"
+                Lines: Line* ;
+                Line: [a-z ]* "\n" ;
+            "##).expect("Parser definition ok");
+
+            let input = "the quick brown fox\n".repeat(lines);
+            parser.parse_reader(input.as_bytes(), "Lines").map(|tree| match tree {
+                SyntaxTree::RuleNode { subexpressions, .. } => (subexpressions.len(), subexpressions[lines - 1].span()),
+                other => panic!("Expected a rule node, got {other:?}"),
+            })
+        })
+        .expect("Thread starts")
+        .join()
+        .expect("No stack overflow");
+    assert_eq!(shape.expect("Parses"), (lines, (lines - 1) * 20..lines * 20));
+}
+
+#[test]
+fn parse_bytes() {
+    // A length prefixed record, then a byte in the top half.
+    let parser: Parser<ByteToken> = crate::define::define_parser(r##"
+        File: "PK" Record+ [\x80-\xFF] ;
+        Record: "\x01" [\x00-\xFF] | "\x02" [\x00-\xFF]{2} ;
+    "##).expect("Parser definition ok");
+
+    let tree = parser.parse_bytes(b"PK\x01\x00\x02\xff\x0a\xe9", "File").expect("No error");
+    assert_eq!(tree.span(), 0..8);
+    assert_eq!(ByteToken { byte: b'K' }.to_string(), "K");
+    assert_eq!(ByteToken { byte: 0xe9 }.to_string(), "\\xE9");
+    assert!(crate::define::define_parser::<ByteToken>(r#"File: "\u{100}" ;"#).is_err());
+
+    match parser.parse_bytes(b"PK\x03", "File") {
+        Err(ParseError::UnexpectedToken { index: 2, location: None, .. }) => (),
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+}
+
+#[test]
+fn registered_terminals() {
+    let mut parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ ]+ ;
+        @token Number: [0-9]+ ;
+        Sum: _Byte ("+" _Byte)* ;
+        @token Plus: "+" ;
+    "##).expect("Parser definition ok");
+
+    // Nothing is of kind Byte, until a matcher says otherwise.
+    assert!(parser.parse_string("1 + 2", "Sum").is_err());
+    parser.register_terminal("Byte", |token| token.kind == "Number" && token.text.parse::<u8>().is_ok());
+    assert_eq!(parser.parse_string("1 + 255", "Sum").expect("No error").span(), 0..3);
+    assert!(parser.parse_string("1 + 256", "Sum").is_err());
+
+    // Merged parsers keep their matchers.
+    let mut merged: Parser<LexedToken> = crate::define::define_parser("extern Sum; Sums: Sum+ ;").expect("Parser definition ok");
+    merged.merge(parser, ConflictPolicy::Reject).expect("No conflicts");
+    assert!(merged.parse_string("1 + 2 3", "Sums").is_ok());
+    assert!(merged.parse_string("1 + 2 300", "Sums").is_err());
+}
+
+#[test]
+fn captures() {
+    let mut parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ ]+ ;
+        @token Number: [0-9]+ ;
+        @token Name: [a-z]+ ;
+        Call: Name "(" Number ")" ;
+        @token Punctuation: [()] ;
+    "##).expect("Parser definition ok");
+    parser.register_capture("Number", |token: &LexedToken| token.text.parse::<i64>().ok());
+
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+        let tree = parser.parse_string("f ( 42 )", "Call").expect("No error");
+        let SyntaxTree::RuleNode { subexpressions, .. } = tree else { panic!("Expected rule node") };
+
+        assert_eq!(subexpressions[2].captured::<i64>(), Some(&42));
+        assert_eq!(subexpressions[2].captured::<u8>(), None);
+        assert_eq!(subexpressions[0].captured::<i64>(), None);
+    }
+}
+
+#[test]
+fn token_spans() {
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ ]+ ;
+        @token Word: [a-zé]+ ;
+        @token Punctuation: [.!] ;
+        Sentence: Subject Word* Bang "." ;
+        Subject: Word ;
+        Bang: "!"? ;
+    "##).expect("Parser definition ok");
+
+    let tree = parser.parse_string("  hé there .", "Sentence").expect("No error");
+    assert_eq!(tree.source_span(), Some(2..13));
+    let SyntaxTree::RuleNode { subexpressions, .. } = &tree else { panic!("Expected rule node") };
+    assert_eq!(subexpressions[0].source_span(), Some(2..5));
+    assert_eq!(subexpressions[1].source_span(), Some(6..11));
+
+    // Empty rules, and tokens that don't know where they are, have no span.
+    assert_eq!(subexpressions[2].source_span(), None);
+    assert_eq!(CharToken { token_type: 'a' }.span(), None);
+
+    match parser.parse_tokens(&parser.tokenize("hé ! !").expect("No error"), "Sentence") {
+        Err(ParseError::UnexpectedToken { index: 2, span, location: None, .. }) => assert_eq!(span, Some(6..7)),
+        other => panic!("Expected failed parse, got {other:?}"),
+    }
+}
+
+#[test]
+fn external_rules() {
+    // Heredocs: the body runs until a line holding just the word after <<.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Heredoc: "<<" Word "\n" HeredocBody Word ;
+        Word: [A-Z]+ ;
+        external HeredocBody ;
+    "##).expect("Parser definition ok");
+
+    assert!(parser.parse_string("<<EOF\nhi\nEOF", "Heredoc").is_err());
+    parser.register_external("HeredocBody", |tokens, start| {
+        let text = tokens.iter().map(|token| token.token_type).collect::<String>();
+        let word_end = text[..start - 1].len();
+        let word = &text[text[..word_end].rfind("<<")? + 2..word_end];
+        text[start..].find(&format!("\n{word}")).map(|end| end + 1)
+    }).expect("Rule is external");
+    assert!(parser.register_external("Word", |_, _| None).is_err());
+
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+        let input = "<<END\nEOF\nEND";
+        let tree = parser.parse_string(input, "Heredoc").expect("No error");
+        let SyntaxTree::RuleNode { subexpressions, .. } = &tree else { panic!("Expected rule node") };
+        assert_eq!(subexpressions[4].text(&LineMap::new(input)), "EOF\n");
+        assert_eq!(subexpressions[5].span(), 10..13);
+
+        assert!(parser.parse_string("<<END\nEOF\n", "Heredoc").is_err());
+    }
+
+    let mut stream = parser.parse_streaming("Heredoc").expect("Rule exists");
+    for token_type in "<<END\n".chars() {
+        let _ = stream.feed(CharToken { token_type });
+    }
+    assert!(stream.finish().is_err());
+
+    // external is only a keyword when it doesn't start a rule.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        external: "x" Body ;
+        external Body ;
+    "##).expect("Parser definition ok");
+    parser.register_external("Body", |tokens, start| Some(tokens.len() - start)).expect("Rule is external");
+    parser.parse_string("xyz", "external").expect("No error");
+}
+
+#[test]
+fn user_state() {
+    // The typedef problem: "t x" is a declaration only if t was declared as a type.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Program: (Statement ";")* ;
+        Statement: Typedef | Declaration | Product ;
+        Typedef: "type " Name ;
+        Declaration: TypeName " " Name ;
+        Product: Name " " Name ;
+        TypeName: Name ;
+        Name: [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    let text = |tokens: &[CharToken], span: Range<usize>| tokens[span].iter().map(|token| token.token_type).collect::<String>();
+    parser.add_action("Typedef", move |types: &mut HashSet<String>, tokens, span| {
+        types.insert(text(tokens, span.start + 5..span.end));
+    }).expect("Rule exists");
+    parser.add_stateful_predicate("TypeName", move |types: &HashSet<String>, tokens, span| types.contains(&text(tokens, span)))
+        .expect("Rule exists");
+    assert!(parser.add_action("Missing", |_: &mut HashSet<String>, _, _| ()).is_err());
+
+    let input = string_to_tokens("a b;type a;a b;");
+    let (tree, types) = parser.parse_tokens_with_state(&input, "Program", HashSet::new()).expect("No error");
+    let SyntaxTree::RuleNode { subexpressions, .. } = &tree else { panic!("Expected rule node") };
+    let statement_kinds = subexpressions.iter()
+        .filter_map(|statement| match statement {
+            SyntaxTree::RuleNode { subexpressions, .. } => match &subexpressions[0] {
+                SyntaxTree::RuleNode { rule_name, .. } => Some(rule_name.as_str()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(statement_kinds, vec!["Product", "Typedef", "Declaration"]);
+    assert_eq!(types, HashSet::from(["a".to_string()]));
+
+    // Parsing without state would skip the predicates, and a different type of state can't be used.
+    assert!(parser.parse_tokens(&input, "Program").is_err());
+    assert!(parser.parse_tokens_with_state(&input, "Program", 0).is_err());
+    assert!(matches!(
+        parser.parse_tokens_with_state(&string_to_tokens("a b;x;"), "Program", HashSet::<String>::new()),
+        Err(ParseError::UnexpectedToken { index: 5, .. })
+    ));
+
+    // A failed alternative's actions are undone.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Start: Letter "!" | Letter "?" ;
+        Letter: [a-z] ;
+    "##).expect("Parser definition ok");
+    parser.add_action("Letter", |count: &mut usize, _, _| *count += 1).expect("Rule exists");
+    let (_, count) = parser.parse_tokens_with_state(&string_to_tokens("a?"), "Start", 0usize).expect("No error");
+    assert_eq!(count, 1);
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum: Sum "+" "1" | "1" ;
+    "##).expect("Parser definition ok");
+    assert!(parser.parse_tokens_with_state(&string_to_tokens("1+1"), "Sum", ()).is_err());
+}
+
+#[test]
+fn error_recovery() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Program: (Statement ";")* ;
+        Statement: Name "=" Name ;
+        Name: [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    let (tree, errors) = parser.parse_string_recovering("a=b;c=d?;e=f;", "Program").expect("No error");
+    assert_eq!(tree.span(), 0..13);
+    assert!(matches!(&errors[..], [ParseError::UnexpectedToken { index: 7, location: Some(_), .. }]));
+    let SyntaxTree::RuleNode { subexpressions, .. } = &tree else { panic!("Expected rule node") };
+    assert_eq!(subexpressions.len(), 7);
+    match &subexpressions[3] {
+        SyntaxTree::ErrorNode { tokens, expected, span } => {
+            assert_eq!(tokens.iter().map(|token| token.token_type).collect::<String>(), "?");
+            assert_eq!(expected, &HashSet::from([";".to_string(), "[a-z]".to_string()]));
+            assert_eq!(span, &(7..8));
+        }
+        other => panic!("Expected error node, got {other:?}"),
+    }
+    assert_eq!(subexpressions[4].span(), 8..9);
+    assert_eq!(subexpressions[5].span(), 9..12);
+
+    // Errors at the very start widen the root, and input that parses is left alone.
+    let (tree, _) = parser.parse_string_recovering("?a=b;", "Program").expect("No error");
+    assert_eq!(tree.span(), 0..5);
+    assert!(matches!(&tree, SyntaxTree::RuleNode { subexpressions, .. } if matches!(subexpressions[0], SyntaxTree::ErrorNode { .. })));
+    let (tree, errors) = parser.parse_string_recovering("a=b;", "Program").expect("No error");
+    assert_eq!(tree.span(), 0..4);
+    assert!(errors.is_empty());
+
+    // Recovery keeps going after the first error.
+    let (tree, errors) = parser.parse_string_recovering("a=b?;c=d;e=?f;g==h;", "Program").expect("No error");
+    assert_eq!(tree.span(), 0..19);
+    let error_indices = errors.iter()
+        .map(|err| match err {
+            ParseError::UnexpectedToken { index, .. } => *index,
+            other => panic!("Expected incomplete parse, got {other:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(error_indices, vec![3, 11, 16]);
+    let SyntaxTree::RuleNode { subexpressions, .. } = &tree else { panic!("Expected rule node") };
+    let error_spans = subexpressions.iter()
+        .flat_map(|node| match node {
+            SyntaxTree::RuleNode { subexpressions, .. } => subexpressions.clone(),
+            other => vec![other.clone()],
+        })
+        .filter_map(|node| match node {
+            SyntaxTree::ErrorNode { span, .. } => Some(span),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(error_spans, vec![3..4, 11..12, 16..17]);
+
+    // Skipping can't make up for missing tokens.
+    let (tree, errors) = parser.parse_string_recovering("a=", "Statement").expect("No error");
+    assert!(matches!(tree, SyntaxTree::ErrorNode { span, .. } if span == (0..2)));
+    assert!(matches!(&errors[..], [ParseError::UnexpectedEof { .. }]));
+}
+
+#[test]
+fn recovery_attempts() {
+    // Nothing can be skipped to close the brackets. Trying every skip would mean a parse for
+    // every token, so recovery gives up after a fixed number of them.
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Nested : "(" Nested ")" | "x" ;
+    "##).expect("Parser definition ok");
+
+    let input = "(".repeat(1000) + "x";
+    let (tree, errors) = parser.parse_string_recovering(&input, "Nested").expect("No error");
+    assert!(matches!(tree, SyntaxTree::ErrorNode { span, .. } if span == (0..input.len())));
+    assert!(matches!(&errors[..], [ParseError::UnexpectedEof { .. }]));
+}
+
+#[test]
+fn sync_tokens() {
+    let definition = r##"
+        Program: (Statement ";")* ;
+        Statement: Name "=" Name | Block ;
+        Block: "{" (Statement ";")* "}" ;
+        Name: [a-z]+ ;
+    "##;
+    let skipped = |parser: &Parser<CharToken>, input: &str| {
+        let (tree, _) = parser.parse_string_recovering(input, "Program").expect("No error");
+        let mut spans = vec![];
+        let mut pending = vec![tree];
+        while let Some(node) = pending.pop() {
+            match node {
+                SyntaxTree::RuleNode { subexpressions, .. } => pending.extend(subexpressions),
+                SyntaxTree::ErrorNode { span, .. } => spans.push(span),
+                SyntaxTree::TokenNode { .. } => (),
+            }
+        }
+        spans.sort_by_key(|span| span.start);
+        spans
+    };
+
+    // Without sync tokens, the smallest skips win, even if they make a mess of the statements around them.
+    let parser: Parser<CharToken> = crate::define::define_parser(definition).expect("Parser definition ok");
+    assert_eq!(skipped(&parser, "a=b;c=;d=e;"), vec![6..7, 8..9]);
+
+    let parser: Parser<CharToken> = crate::define::define_parser(&format!(r##"{definition}
+        recover Statement : ";" ;
+        recover Block : "}}" ;
+    "##)).expect("Parser definition ok");
+    assert_eq!(skipped(&parser, "a=b;c=;d=e;"), vec![4..7]);
+    assert_eq!(skipped(&parser, "{a=b;c=?;};d=e;"), vec![5..9]);
+    assert_eq!(skipped(&parser, "{a=b;c=?};d=e;"), vec![5..8]);
+
+    assert!(crate::define::define_parser::<CharToken>("A : \"a\" ; recover B : \";\" ;").is_err());
+    assert!(crate::define::define_parser::<CharToken>("A : \"a\" ; recover A : \"ab\" ;").is_err());
+    assert!(crate::define::define_parser::<CharToken>("A : \"a\" ; recover A : \";\" ; recover A : \",\" ;").is_err());
+    assert!(crate::define::define_parser::<CharToken>("recover : \"a\" ;").is_ok());
+}
+
+#[test]
+fn parse_prefix() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum: Number ("+" Number)* ;
+        Number: [0-9]+ ;
+    "##).expect("Parser definition ok");
+
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+
+        let (tree, consumed) = parser.parse_prefix(&string_to_tokens("1+23+ é"), "Sum").expect("No error");
+        assert_eq!(consumed, 4);
+        assert_eq!(tree.span(), 0..4);
+
+        let (_, rest) = parser.parse_string_prefix("12+3é+4", "Sum").expect("No error");
+        assert_eq!(rest, "é+4");
+        assert_eq!(parser.parse_string_prefix("12", "Sum").expect("No error").1, "");
+        assert!(parser.parse_string_prefix("+1", "Sum").is_err());
+
+        // The parser itself stays anchored.
+        assert!(parser.parse_string("1+2 ", "Sum").is_err());
+    }
+}
+
+#[test]
+fn find_iter() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Placeholder: "{{" Name ("." Name)* "}}" ;
+        Name: [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    let input = "Hi {{user.name}}, {{ oops }} you have {{count}}{{x}} {{";
+    let source = LineMap::new(input);
+    let tokens = string_to_tokens(input);
+    let matches = parser.find_iter(&tokens, "Placeholder")
+        .map(|tree| tree.expect("No error").text(&source))
+        .collect::<Vec<_>>();
+    assert_eq!(matches, vec!["{{user.name}}", "{{count}}", "{{x}}"]);
+
+    let mut matches = parser.find_iter(&tokens, "Missing");
+    assert!(matches!(matches.next(), Some(Err(ParseError::UnknownRule { .. }))));
+    assert!(matches.next().is_none());
+}
+
+#[test]
+fn warnings() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Program: Statement* Loop? ;
+        Statement: Word ";" | Word ";" | Loop | Spaces* ;
+        Word: [a-z]+ ;
+        @ordered Spaces: " "? | " " " " ;
+        Loop: "(" Loop ")" ;
+    "##).expect("Parser definition ok");
+
+    assert_eq!(parser.warnings(), vec![
+        GrammarWarning::Unproductive { rule_name: "Loop".to_string() },
+        GrammarWarning::UnmatchableAlternative { rule_name: "Statement".to_string(), alternative: 2 },
+        GrammarWarning::ShadowedAlternative { rule_name: "Spaces".to_string(), alternative: 1, by: 0 },
+        GrammarWarning::ShadowedAlternative { rule_name: "Statement".to_string(), alternative: 1, by: 0 },
+        GrammarWarning::NullableRepetition { rule_name: "Program".to_string() },
+        GrammarWarning::NullableRepetition { rule_name: "Statement".to_string() },
+        GrammarWarning::SingleUse { rule_name: "Spaces".to_string(), used_by: "Statement".to_string() },
+        GrammarWarning::SingleUse { rule_name: "Statement".to_string(), used_by: "Program".to_string() },
+    ]);
+    assert_eq!(
+        parser.warnings()[2].to_string(),
+        "Alternative 1 of a choice in \"Spaces\" is never used, alternative 0 always wins"
+    );
+
+    // Expr and Term only use each other, and the grammar starts from Ident.
+    let parser: Parser<CharToken> = crate::define::define_parser(r#"
+        Expr : Term ("+" Term)* ;
+        Term : [0-9] | "(" Expr ")" ;
+        Ident : [a-z]+ ;
+    "#).expect("Parser definition ok");
+    assert_eq!(parser.warnings(), vec![
+        GrammarWarning::SingleUse { rule_name: "Expr".to_string(), used_by: "Term".to_string() },
+        GrammarWarning::Unreachable { rule_name: "Expr".to_string() },
+        GrammarWarning::Unreachable { rule_name: "Term".to_string() },
+    ]);
+    assert_eq!(parser.warnings()[1].to_string(), "Rule \"Expr\" can't be reached from any rule that nothing else uses");
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum: Number ("+" Number)* ;
+        @label("number") Number: [0-9]+ ;
+    "##).expect("Parser definition ok");
+    assert_eq!(parser.warnings(), vec![]);
+}
+
+#[test]
+fn transform() {
+    fn shape<T: Token + std::fmt::Display>(tree: &SyntaxTree<T>) -> String {
+        match tree {
+            SyntaxTree::RuleNode { rule_name, subexpressions, .. } => 
+                format!("{rule_name}({})", subexpressions.iter().map(shape).join(" ")),
+            SyntaxTree::TokenNode { token, .. } => token.to_string(),
+            SyntaxTree::ErrorNode { .. } => "error".to_string(),
+        }
+    }
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Term (Space? "+" Space? Term)* ;
+        Term : Atom ;
+        Atom : Number | "(" Sum ")" ;
+        Number : Digit+ ;
+        Digit : [0-9] ;
+        Space : " "+ ;
+    "##).expect("Parser definition ok");
+    let tree = parser.parse_string("1 + (23)", "Sum").expect("No error");
+
+    let tree = tree.remove_rules(&["Space"]);
+    assert_eq!(shape(&tree), "Sum(Term(Atom(Number(Digit(1)))) + Term(Atom(( Sum(Term(Atom(Number(Digit(2) Digit(3))))) ))))");
+    let tree = tree.lift_rules(&["Digit"]);
+    assert_eq!(shape(&tree), "Sum(Term(Atom(Number(1))) + Term(Atom(( Sum(Term(Atom(Number(2 3)))) ))))");
+    let tree = tree.collapse_chains();
+    assert_eq!(shape(&tree), "Sum(Number(1) + Atom(( Number(2 3) )))");
+    assert_eq!(tree.span(), 0..8);
+
+    let tree = tree.map_tokens(|token| ByteToken { byte: token.token_type as u8 });
+    let tree = tree.map_tokens(|token| CharToken { token_type: if token.byte.is_ascii_digit() { '#' } else { token.byte as char } });
+    assert_eq!(shape(&tree), "Sum(Number(#) + Atom(( Number(# #) )))");
+
+    // The root can collapse, but it is never removed.
+    let tree = parser.parse_string("4", "Term").expect("No error");
+    assert_eq!(shape(&tree.clone().collapse_chains()), "Digit(4)");
+    assert_eq!(shape(&tree.remove_rules(&["Term", "Atom"])), "Term()");
+}
+
+#[test]
+fn queries() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Term ("+" Term)* ;
+        Term : Number | "(" Sum ")" ;
+        Number : [0-9]+ ;
+    "##).expect("Parser definition ok");
+    let input = "1+(23+4)";
+    let source = LineMap::new(input);
+    let tree = parser.parse_string(input, "Sum").expect("No error");
+
+    let sums = tree.find_all("Sum").map(|sum| sum.text(&source)).collect::<Vec<_>>();
+    assert_eq!(sums, vec!["1+(23+4)", "23+4"]);
+    let numbers = tree.find_all("Number").map(|number| number.text(&source)).collect::<Vec<_>>();
+    assert_eq!(numbers, vec!["1", "23", "4"]);
+
+    assert_eq!(tree.descendants().count(), 17);
+    assert_eq!(tree.descendants().next().and_then(SyntaxTree::rule_name), Some("Sum"));
+
+    let term = tree.children().last().expect("Has children");
+    assert_eq!(term.children_tokens().map(|token| token.token_type).collect::<String>(), "()");
+    assert_eq!(term.first_child("Sum").map(|sum| sum.text(&source)), Some("23+4"));
+    assert!(term.first_child("Number").is_none());
+    assert!(tree.children()[1].children().is_empty());
+}
+
+#[test]
+fn path_queries() {
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Space : [ \n]+ ;
+        @token Name : [a-z]+ ;
+        @token Punctuation : [(),{};] ;
+        Program : FunctionDecl* ;
+        FunctionDecl : "fn" Name ParamList Block ;
+        ParamList : "(" (Param ("," Param)*)? ")" ;
+        Param : Name ;
+        Block : "{" (Call ";")* "}" ;
+        Call : Name "(" (Name ("," Name)*)? ")" ;
+    "##).expect("Parser definition ok");
+    let tokens = parser.tokenize("fn main() { print(a, b); } fn add(x, y) { sum(x, y); }").expect("Tokenizes");
+    let tree = parser.parse_tokens(&tokens, "Program").expect("No error");
+
+    let texts = |query: &str| tree.select(query).expect("Good query").iter().map(|node| node.token_text()).collect::<Vec<_>>();
+    assert_eq!(texts("FunctionDecl/ParamList/Param"), vec!["x", "y"]);
+    assert_eq!(texts("Param"), vec!["x", "y"]);
+    assert_eq!(texts("FunctionDecl[text!=\"fnmain(){print(a,b);}\"]/*/Call"), vec!["sum(x,y)"]);
+    assert_eq!(texts("Program/**/Call[text = \"print(a,b)\"]"), vec!["print(a,b)"]);
+    assert_eq!(texts("FunctionDecl/**/Name").len(), 0);  // Names are tokens, not rules.
+    assert_eq!(texts("Block/**").len(), 2 * 2 + 2 * 9);
+
+    let query = TreeQuery::new("ParamList / Param[text=\"y\"]").expect("Good query");
+    assert_eq!(query.select(&tree).len(), 1);
+    assert_eq!(query.select(&tree.children()[0]).len(), 0);
+
+    assert_eq!(TreeQuery::new("Block/").err().map(|err| err.to_string()), 
+        Some("Expected a rule name, * or ** at position 6 of the query".to_string()));
+    assert_eq!(TreeQuery::new("Call[text=\"x]").err().map(|err| err.position), Some(10));
+    assert_eq!(TreeQuery::new("Call[size=1]").err().map(|err| err.message), Some("Expected text".to_string()));
+    assert_eq!(TreeQuery::new("Call Name").err().map(|err| err.position), Some(5));
+}
+
+#[test]
+fn tree_match() {
+    fn evaluate(tree: &SyntaxTree<CharToken>, source: &LineMap) -> i64 {
+        crate::tree_match!(tree,
+            Sum(left @ _, "+", right @ Product) => evaluate(left, source) + evaluate(right, source),
+            Sum(product @ Product) => evaluate(product, source),
+            Product(left @ _, "*", right @ _) => evaluate(left, source) * evaluate(right, source),
+            Product(atom @ Atom) => evaluate(atom, source),
+            Atom("(", inner @ Sum(..), ")") => evaluate(inner, source),
+            Atom(number @ Number) => number.text(source).parse().expect("Digits"),
+        )
+    }
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Sum "+" Product | Product ;
+        Product : Product "*" Atom | Atom ;
+        Atom : "(" Sum ")" | Number ;
+        Number : [0-9]+ ;
+    "##).expect("Parser definition ok");
+
+    let input = "2*(3+4)+10";
+    let tree = parser.parse_string(input, "Sum").expect("No error");
+    assert_eq!(evaluate(&tree, &LineMap::new(input)), 24);
+
+    let shape = |tree: &SyntaxTree<CharToken>| crate::tree_match!(tree,
+        Sum(_) => "one",
+        Sum(_, ..) => "more",
+    );
+    assert_eq!(shape(&tree), "more");
+    assert_eq!(shape(&parser.parse_string("1", "Sum").expect("No error")), "one");
+}
+
+#[test]
+fn json() {
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Space : " "+ ;
+        @token Word : [a-z"]+ ;
+        Pair : Word Word ;
+    "##).expect("Parser definition ok");
+    let tokens = parser.tokenize(r#"say "hi""#).expect("No error");
+    let tree = parser.parse_tokens(&tokens, "Pair").expect("No error");
+    assert_eq!(tree.to_json(), concat!(
+        r#"{"type":"rule","rule":"Pair","children":["#,
+        r#"{"type":"token","text":"say","span":[0,1],"bytes":[0,3]},"#,
+        r#"{"type":"token","text":"\"hi\"","span":[1,2],"bytes":[4,8]}"#,
+        r#"],"span":[0,2],"bytes":[0,8]}"#,
+    ));
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List : (Item ";")* ;
+        Item : [a-z]+ ;
+        recover Item : ";" ;
+    "##).expect("Parser definition ok");
+    let (tree, _) = parser.parse_string_recovering("a;\n;", "List").expect("Recovers");
+    let mut written = vec![];
+    tree.write_json(&mut written).expect("No error");
+    assert_eq!(String::from_utf8(written).expect("UTF-8"), concat!(
+        r#"{"type":"rule","rule":"List","children":["#,
+        r#"{"type":"rule","rule":"Item","children":[{"type":"token","text":"a","span":[0,1],"bytes":null}],"span":[0,1],"bytes":null},"#,
+        r#"{"type":"token","text":";","span":[1,2],"bytes":null},"#,
+        r#"{"type":"error","text":"\n;","expected":["[a-z]"],"span":[2,4],"bytes":null}"#,
+        r#"],"span":[0,4],"bytes":null}"#,
+    ));
+}
+
+#[test]
+fn sexpr() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        PlusMinusExpr : MultDivExpr ("+" MultDivExpr)* ;
+        MultDivExpr : AtomicExpr ("*" AtomicExpr)* ;
+        AtomicExpr : [a-z"] | "(" PlusMinusExpr ")" ;
+    "##).expect("Parser definition ok");
+    let tree = parser.parse_string("a+\"*(b)", "PlusMinusExpr").expect("No error");
+    assert_eq!(tree.to_sexpr(), r#"(PlusMinusExpr (MultDivExpr (AtomicExpr "a")) "+" (MultDivExpr (AtomicExpr "\"") "*" (AtomicExpr "(" (PlusMinusExpr (MultDivExpr (AtomicExpr "b"))) ")")))"#);
+
+    let (tree, _) = parser.parse_string_recovering("a+\n", "PlusMinusExpr").expect("Recovers");
+    assert_eq!(tree.to_sexpr(), r#"(PlusMinusExpr (MultDivExpr (AtomicExpr "a")) (ERROR "+\n"))"#);
+}
+
+#[test]
+fn dot() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Call : Name "(" Name? ")" ;
+        Name : [a-z"\n\t\r\x1B]+ ;
+    "##).expect("Parser definition ok");
+    let tree = parser.parse_string("f(\"\n\t\r\x1B)", "Call").expect("No error");
+    assert_eq!(tree.to_dot(), indoc! {r#"
+        digraph SyntaxTree {
+            node [fontname="monospace"];
+            n0 [label="Call"];
+            n1 [label="Name"];
+            n2 [label="f", shape=box];
+            n1 -> n2;
+            n0 -> n1;
+            n3 [label="(", shape=box];
+            n0 -> n3;
+            n4 [label="Name"];
+            n5 [label="\"", shape=box];
+            n4 -> n5;
+            n6 [label="\\n", shape=box];
+            n4 -> n6;
+            n7 [label="\\t", shape=box];
+            n4 -> n7;
+            n8 [label="\\r", shape=box];
+            n4 -> n8;
+            n9 [label="\\u{1b}", shape=box];
+            n4 -> n9;
+            n0 -> n4;
+            n10 [label=")", shape=box];
+            n0 -> n10;
+        }
+    "#});
+}
+
+#[test]
+fn railroad() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Statement : "let " Name ("=" Value)? ";" | "print" !"ln" Value+ ;
+        Value : Name{1,3} | [0-9]* | . ;
+        Name : [a-z<]+ ;
+    "##).expect("Parser definition ok");
+
+    let svg = parser.railroad_svg("Statement").expect("Rule exists");
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+    assert!(svg.ends_with("</svg>\n"));
+    for text in [r#">Statement</text>"#, r#">"let "</text>"#, r#">Name</text>"#, r#">"print"</text>"#, r#">not followed by</text>"#, r#">"ln"</text>"#] {
+        assert!(svg.contains(text), "{text}");
+    }
+    assert_eq!(svg.matches("<rect").count(), 9);
+
+    let svg = parser.railroad_svg("Value").expect("Rule exists");
+    for text in [r#">1 to 3 times</text>"#, r#">[0-9]</text>"#, r#">.</text>"#] {
+        assert!(svg.contains(text), "{text}");
+    }
+    assert!(parser.railroad_svg("Name").expect("Rule exists").contains(">[a-z&lt;]</text>"));
+    assert_eq!(parser.railroad_svg("Nothing"), None);
+}
+
+#[test]
+fn ebnf() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Statement : "let " Name ("=" Value)? ";" | "print" !"ln" Value+ ;
+        Value : Name{1,3} | [0-9]* | . ;
+        Name : [a-z]+ ;
+    "##).expect("Parser definition ok");
+
+    assert_eq!(parser.to_ebnf(EbnfNotation::Iso), indoc! {r#"
+        Name = { ? [a-z] ? }- ;
+        Statement = "let ", Name, [ "=", Value ], ";" | "print", ? not followed by "ln" ?, { Value }- ;
+        Value = Name, 2 * [ Name ] | { ? [0-9] ? } | ? any token ? ;
+    "#});
+    assert_eq!(parser.to_ebnf(EbnfNotation::W3c), indoc! {r#"
+        Name ::= [a-z]+
+        Statement ::= "let " Name ( "=" Value )? ";" | "print" /* not followed by "ln" */ Value+
+        Value ::= Name Name? Name? | [0-9]* | Char
+    "#});
+
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Space : " "+ ;
+        @token Word : [a-z]+ ;
+        @token Quote : ["'] ;
+        Sentence : (Word | Quote)+ i"stop" ;
+    "##).expect("Parser definition ok");
+    assert_eq!(parser.to_ebnf(EbnfNotation::W3c), indoc! {r#"
+        Sentence ::= ( Word | Quote )+ "stop" /* ignoring case */
+        Quote ::= ["']
+        Space ::= " "+
+        Word ::= [a-z]+
+    "#});
+
+    // Neither notation has escapes, so quotes and control characters take pieces of their own.
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Quoted : "say \"it's\"\n" ;
+        Tab : "\t" ;
+        Both : ("'\"")+ ;
+    "##).expect("Parser definition ok");
+    assert_eq!(parser.to_ebnf(EbnfNotation::Iso), indoc! {r#"
+        Both = { "'", '"' }- ;
+        Quoted = "say ", '"', "it's", '"', ? U+000A ? ;
+        Tab = ? U+0009 ? ;
+    "#});
+    assert_eq!(parser.to_ebnf(EbnfNotation::W3c), indoc! {r#"
+        Both ::= ( "'" '"' )+
+        Quoted ::= "say " '"' "it's" '"' #xA
+        Tab ::= #x9
+    "#});
+}
+
+#[test]
+fn unparse() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List : (Item ";")* ;
+        Item : [a-z]+ ;
+        recover Item : ";" ;
+    "##).expect("Parser definition ok");
+    let (tree, _) = parser.parse_string_recovering("ab;c+d;e;", "List").expect("Recovers");
+    assert_eq!(tree.unparse(), "ab;c+d;e;");
+    assert_eq!(tree.unparse_with(|node, children| match node {
+        SyntaxTree::RuleNode { rule_name, .. } if rule_name == "Item" => Some(children.concat().to_uppercase()),
+        SyntaxTree::RuleNode { .. } => Some(children.join(" ")),
+        _ => None,
+    }), "AB ; c+d; E ;");  // The error node took the ";" with it.
+
+    let parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ \n]+ ;
+        @skip Comment: "//" ~"\n"* ;
+        @token Word: [a-z]+ ;
+        Words: Word+ ;
+    "##).expect("Parser definition ok");
+    let (tree, trivia) = parser.parse_string_with_trivia(" one // first\ntwo  three\n", "Words").expect("No error");
+    assert_eq!(tree.unparse(), "onetwothree");
+    assert_eq!(tree.unparse_with_trivia(&trivia), "one // first\ntwo  three");
+}
+
+#[test]
+fn lossless() {
+    let definition = r##"
+        @skip Whitespace: [ \n]+ ;
+        @skip Comment: "//" ~"\n"* ;
+        @token Word: [a-z]+ ;
+        @token Punctuation: [;] ;
+        List : Item* ;
+        Item : Word+ Nothing ";" ;
+        Nothing : "x"? ;
+    "##;
+    let parser: Parser<LexedToken> = crate::ParserBuilder::new(definition).lossless(true).build().expect("Parser definition ok");
+
+    let input = " // start\none two ; three\n;  // end\n";
+    let (tree, trivia) = parser.parse_string_with_trivia(input, "List").expect("No error");
+    assert_eq!(tree.unparse(), input);
+    assert_eq!(trivia.len(), 10);
+    assert_eq!(tree.span(), 0..15);
+
+    let SyntaxTree::RuleNode { subexpressions, .. } = &tree else { panic!("Expected rule node") };
+    assert_eq!(subexpressions.len(), 9);  // Three pieces of trivia before, two items with a space between, and three after.
+    assert_eq!(subexpressions[3].span(), 3..8);
+    assert_eq!(subexpressions[3].unparse(), "one two ;");
+    assert!(matches!(&subexpressions[3], SyntaxTree::RuleNode { subexpressions, .. }
+        if matches!(&subexpressions[3], SyntaxTree::RuleNode { rule_name, span, .. } if rule_name == "Nothing" && *span == (7..7))));
+    assert_eq!(subexpressions[5].unparse(), "three\n;");
+
+    let empty = parser.parse_string("  // nothing\n", "List").expect("No error");
+    assert_eq!(empty.unparse(), "  // nothing\n");
+
+    let plain: Parser<LexedToken> = crate::define::define_parser(definition).expect("Parser definition ok");
+    assert_eq!(plain.parse_string(input, "List").expect("No error").unparse(), "onetwo;three;");
+}
+
+#[test]
+fn events() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Number ("+" Number)* ;
+        Number : [0-9]+ ;
+        Left : Left "-" Number | Number ;
+    "##).expect("Parser definition ok");
+    let tokens = "1+23".chars().map(|ch| CharToken { token_type: ch }).collect::<Vec<_>>();
+
+    let describe = |event: ParseEvent<CharToken>| match event {
+        ParseEvent::Enter(rule_name) => format!("({rule_name}"),
+        ParseEvent::Token(token) => token.token_type.to_string(),
+        ParseEvent::Exit(_) => ")".to_string(),
+    };
+    let expected = "(Sum(Number1)+(Number23))";
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+        let mut events = String::new();
+        parser.parse_events(&tokens, "Sum", |event| events += &describe(event)).expect("Parses");
+        assert_eq!(events, expected);
+    }
+
+    // Left recursion isn't LL(1), so this one comes from the backtracking parser.
+    parser.set_algorithm(ParseAlgorithm::Auto);
+    let tokens = "1-2-3".chars().map(|ch| CharToken { token_type: ch }).collect::<Vec<_>>();
+    let mut events = String::new();
+    parser.parse_events(&tokens, "Left", |event| events += &describe(event)).expect("Parses");
+    assert_eq!(events, "(Left(Left(Left(Number1))-(Number2))-(Number3))");
+
+    let mut count = 0;
+    let tokens = "1+".chars().map(|ch| CharToken { token_type: ch }).collect::<Vec<_>>();
+    assert!(parser.parse_events(&tokens, "Sum", |_| count += 1).is_err());
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn lazy() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List : Item ("," Item)* ;
+        Item : [a-z]+ | "(" List ")" ;
+    "##).expect("Parser definition ok");
+
+    let input = "ab,(c,de),f";
+    let lazy = parser.parse_string_lazy(input, "List").expect("Parses");
+    assert_eq!(lazy.materialize().to_string(), parser.parse_string(input, "List").expect("Parses").to_string());
+    assert_eq!(lazy.rule_name(&[]), Some("List"));
+    assert_eq!(lazy.child_count(&[]), Some(5));
+    assert_eq!(lazy.rule_name(&[2]), Some("Item"));
+    assert_eq!(lazy.span(&[2]), Some(3..9));
+    assert_eq!(lazy.rule_name(&[1]), None);  // The "," token.
+    assert!(lazy.expand(&[9]).is_none());
+
+    let path = lazy.path_to_token(7).expect("Token is in the tree");
+    assert_eq!(path, vec![2, 1, 2, 1]);  // The "e" of "de", inside the inner list.
+    let item = lazy.expand(&path[..path.len() - 1]).expect("Path exists");
+    assert_eq!(item.span(), 6..8);
+    assert!(matches!(item, SyntaxTree::RuleNode { rule_name, .. } if rule_name == "Item"));
+    assert_eq!(lazy.path_to_token(11), None);
+
+    let err = parser.parse_string_lazy("ab,", "List").err().expect("Parse fails");
+    assert_eq!(err.to_string(), parser.parse_string("ab,", "List").expect_err("Parse fails").to_string());
+}
+
+#[test]
+fn interned_rule_names() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List : Item ("," Item)* ;
+        Item : [a-z]+ | "(" List ")" ;
+    "##).expect("Parser definition ok");
+
+    fn rule_names<'t>(tree: &'t SyntaxTree<CharToken>, names: &mut Vec<&'t Symbol>) {
+        if let SyntaxTree::RuleNode { rule_name, subexpressions, .. } = tree {
+            names.push(rule_name);
+            subexpressions.iter().for_each(|child| rule_names(child, names));
+        }
+    }
+
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+        let tree = parser.parse_string("ab,(c,de),f", "List").expect("Parses");
+        let mut names = vec![];
+        rule_names(&tree, &mut names);
+        assert_eq!(names.iter().filter(|name| **name == "List").count(), 2);
+
+        // Every node of a rule points at the same text as the rule's own name.
+        let (list, _) = parser.rules.get_key_value("List").expect("Rule exists");
+        let (item, _) = parser.rules.get_key_value("Item").expect("Rule exists");
+        for name in names {
+            let interned = if name == list { list } else { item };
+            assert_eq!(name.as_ptr(), interned.as_ptr());
+        }
+    }
+
+    assert_eq!(Symbol::from("Item"), "Item");
+    assert_eq!(Symbol::from("Item").to_string(), "Item");
+    assert_eq!(format!("{:?}", Symbol::from("Item")), "\"Item\"");
+}
+
+#[test]
+fn compact_tree() {
+    let mut parser: Parser<LexedToken> = crate::define::define_parser(r##"
+        @skip Whitespace: [ ]+ ;
+        @token Number: [0-9]+ ;
+        @token Name: [a-z]+ ;
+        Calls: Call+ ;
+        Call: Name "(" Number ")" ;
+        @token Punctuation: [()] ;
+    "##).expect("Parser definition ok");
+    parser.register_capture("Number", |token: &LexedToken| token.text.parse::<i64>().ok());
+
+    let tree = parser.parse_string("f(1) g(22) h(333)", "Calls").expect("Parses");
+    let expected = tree.to_string();
+    let compact = CompactTree::from(tree);
+    assert_eq!(compact.len(), 16);
+    assert_eq!(compact.to_tree().to_string(), expected);
+
+    let root = compact.root();
+    assert_eq!(root.rule_name().map(Symbol::as_str), Some("Calls"));
+    assert_eq!(root.span(), 0..12);
+    assert_eq!(root.children().len(), 3);
+    assert!(root.token().is_none());
+
+    let call = root.child(1).expect("Has a second call");
+    assert_eq!(call.rule_name().map(Symbol::as_str), Some("Call"));
+    assert_eq!(call.span(), 4..8);
+    let number = call.child(2).expect("Has a number");
+    assert_eq!(number.rule_name(), None);
+    assert_eq!(number.token().map(|token| token.text.as_str()), Some("22"));
+    assert_eq!(number.span(), 6..7);
+    assert_eq!(number.captured::<i64>(), Some(&22));
+    assert_eq!(call.child(0).and_then(|name| name.captured::<i64>()), None);
+    assert!(call.child(4).is_none());
+    assert!(matches!(call.view(), SyntaxTree::RuleNode { rule_name, span, .. } if rule_name == "Call" && span == (4..8)));
+    assert!(matches!(number.view(), SyntaxTree::TokenNode { index: 6, captured: Some(_), .. }));
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Program: (Statement ";")* ;
+        Statement: Name "=" Name ;
+        Name: [a-z]+ ;
+    "##).expect("Parser definition ok");
+    let (tree, _) = parser.parse_string_recovering("a=b;c=d?;e=f;", "Program").expect("No error");
+    let expected = tree.to_string();
+    let compact = CompactTree::from(tree);
+    assert_eq!(compact.to_tree().to_string(), expected);
+    let error = compact.root().child(3).expect("Has an error node");
+    let (tokens, expected) = error.error().expect("Is an error node");
+    assert_eq!(tokens.iter().map(|token| token.token_type).collect::<String>(), "?");
+    assert_eq!(expected, &HashSet::from([";".to_string(), "[a-z]".to_string()]));
+    assert_eq!(error.span(), 7..8);
+    assert_eq!(error.children().len(), 0);
+}
+
+#[test]
+fn deep_backtracking() {
+    // The backtracking parser keeps its own work stack, so deep input is fine on a small thread stack.
+    let depth = 20_000;
+    let entered = std::thread::Builder::new()
+        .stack_size(256 * 1024)
+        .spawn(move || {
+            let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+                Item : "(" Item ")" | "x" ;
+            "##).expect("Parser definition ok");
+            parser.set_algorithm(ParseAlgorithm::Backtracking);
+
+            let tokens = string_to_tokens(&("(".repeat(depth) + "x" + &")".repeat(depth)));
+            let mut entered = 0;
+            parser.parse_events(&tokens, "Item", |event| entered += matches!(event, ParseEvent::Enter(_)) as usize)
+                .map(|()| entered)
+        })
+        .expect("Thread starts")
+        .join()
+        .expect("No stack overflow");
+    assert_eq!(entered.expect("Parses"), depth + 1);
+}
+
+#[test]
+fn deep_trees() {
+    // Every parser and everything that goes through whole trees keeps its own stack, so
+    // deep trees are fine on a small thread stack. Dropping a tree whole still recurses.
+    let depth = 5_000;
+    let outputs = std::thread::Builder::new()
+        .stack_size(256 * 1024)
+        .spawn(move || {
+            let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+                Item : "(" Item ")" | "x" ;
+            "##).expect("Parser definition ok");
+            let input = "(".repeat(depth) + "x" + &")".repeat(depth);
+            let tokens = string_to_tokens(&input);
+
+            let tree = parser.parse_tokens(&tokens, "Item").expect("Parses");
+            let found = parser.find_iter(&tokens, "Item").next().expect("A match").expect("No error");
+            assert!(ambiguity::same_shape(&tree, &found));
+            found.dismantle();
+            let (stateful, ()) = parser.parse_tokens_with_state(&tokens, "Item", ()).expect("Parses");
+            assert!(ambiguity::same_shape(&tree, &stateful));
+            let compact = CompactTree::from(stateful);
+            let viewed = compact.to_tree();
+            assert!(ambiguity::same_shape(&tree, &viewed));
+            viewed.dismantle();
+
+            parser.set_algorithm(ParseAlgorithm::Backtracking);
+            let backtracked = parser.parse_tokens(&tokens, "Item").expect("Parses");
+            assert!(ambiguity::same_shape(&tree, &backtracked));
+            backtracked.dismantle();
+
+            // Events from the Earley parser come from walking its tree.
+            parser.set_algorithm(ParseAlgorithm::Earley);
+            let mut entered = 0;
+            parser.parse_events(&tokens, "Item", |event| entered += matches!(event, ParseEvent::Enter(_)) as usize).expect("Parses");
+
+            let outputs = (
+                entered,
+                tree.unparse() == input,
+                tree.to_sexpr().len(),
+                tree.to_json().matches(r#""type":"rule""#).count(),
+                tree.to_dot().matches(" -> ").count(),
+            );
+            let mapped = tree.map_tokens(|token| CharToken { token_type: token.token_type.to_ascii_uppercase() });
+            mapped.remove_rules(&["Item"]).dismantle();
+            outputs
+        })
+        .expect("Thread starts")
+        .join()
+        .expect("No stack overflow");
+
+    let nodes = 3 * depth + 2;
+    assert_eq!(outputs, (depth + 1, true, (r#"(Item "(" "#.len() + r#" ")")"#.len()) * depth + r#"(Item "x")"#.len(), depth + 1, nodes - 1));
+}
+
+#[test]
+fn long_earley() {
+    // Repetitions are chains of nonterminals as long as the input, which the Earley parser
+    // builds trees from without copying every link, or recursing down the chain.
+    let words = 10_000;
+    let shape = std::thread::Builder::new()
+        .stack_size(256 * 1024)
+        .spawn(move || {
+            let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+                Text : Word* ;
+                Word : [a-z]+ " " ;
+            "##).expect("Parser definition ok");
+            parser.set_algorithm(ParseAlgorithm::Earley);
+
+            parser.parse_string(&"word ".repeat(words), "Text").map(|tree| match tree {
+                SyntaxTree::RuleNode { subexpressions, .. } => (subexpressions.len(), subexpressions[words - 1].span()),
+                other => panic!("Expected a rule node, got {other:?}"),
+            })
+        })
+        .expect("Thread starts")
+        .join()
+        .expect("No stack overflow");
+    assert_eq!(shape.expect("Parses"), (words, (words - 1) * 5..words * 5));
+}
+
+#[test]
+fn long_earley_all() {
+    // Every word can be matched two ways, which make the same tree, so there are 2^1000 ways
+    // to get the one tree. Repeats are dropped as they are found, not once they are all built.
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Text : (Word | Word)* ;
+        Word : [a-z]+ " " ;
+    "##).expect("Parser definition ok");
+    parser.set_algorithm(ParseAlgorithm::Earley);
+    let input = "word ".repeat(1000);
+
+    let trees = parser.parse_string_all(&input, "Text").expect("Parses");
+    assert_eq!(trees.len(), 1);
+    assert_eq!(trees[0].span(), 0..input.len());
+
+    parser.set_ambiguity_policy(AmbiguityPolicy::RejectAmbiguity);
+    let mut stream = parser.parse_streaming("Text").expect("Rule exists");
+    for token in string_to_tokens(&input) {
+        stream.feed(token).expect("No error");
+    }
+    assert_eq!(stream.finish().expect("Not ambiguous").span(), 0..input.len());
+}
+
+#[test]
+fn budget() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Many : Many Many | "a" ;
+    "##).expect("Parser definition ok");
+    parser.set_algorithm(ParseAlgorithm::Backtracking);
+    let input = "a".repeat(40);
+
+    // Every split of the input is a parse, far too many to finish.
+    let started = std::time::Instant::now();
+    let budget = ParseBudget::new().timeout(std::time::Duration::from_millis(50));
+    let err = parser.parse_string_with_budget(&input, "Many", &budget).expect_err("Runs out of time");
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert!(matches!(err, ParseError::Cancelled { location: Some(_), .. }), "{err:?}");
+
+    let flag = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let budget = ParseBudget::new().cancel_flag(flag.clone());
+    for algorithm in [ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+        let err = parser.parse_string_with_budget("aaa", "Many", &budget).expect_err("Cancelled");
+        assert_eq!(err.to_string(), "Parse cancelled after reaching line 1, column 1");
+    }
+
+    flag.store(false, std::sync::atomic::Ordering::Relaxed);
+    assert!(parser.parse_string_with_budget("aaa", "Many", &budget).is_ok());
+    assert!(parser.parse_string_with_budget("aaa", "Many", &ParseBudget::new()).is_ok());
+}
+
+#[test]
+fn progress() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Word : "a"* ;
+    "##).expect("Parser definition ok");
+    let reports = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let sink = reports.clone();
+    parser.set_progress_callback(4, move |progress| sink.borrow_mut().push(progress));
+
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking, ParseAlgorithm::Earley] {
+        parser.set_algorithm(algorithm);
+        parser.parse_string(&"a".repeat(10), "Word").expect("Parses");
+        let reports = reports.take();
+        assert_eq!(reports.iter().map(|progress| progress.tokens).collect::<Vec<_>>(), vec![4, 8], "{algorithm:?}");
+        assert!(reports.iter().all(|progress| progress.total_tokens == 10 && progress.live_entries > 0), "{algorithm:?}");
+    }
+
+    parser.parse_string("aaa", "Word").expect("Parses");
+    assert!(reports.borrow().is_empty());
+}
+
+#[test]
+fn limits() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Many : Many Many | "a" ;
+        Nested : "(" Nested ")" | "x" ;
+    "##).expect("Parser definition ok");
+    parser.set_algorithm(ParseAlgorithm::Backtracking);
+
+    parser.set_limits(ParseLimits::new().continuations(100));
+    let err = parser.parse_string(&"a".repeat(40), "Many").expect_err("Too many ways to match");
+    assert!(matches!(&err, ParseError::LimitExceeded { limit: Limit::Continuations(100), rule_name: Some(rule_name), location: Some(_), .. } 
+        if rule_name == "Many"), "{err:?}");
+
+    parser.set_limits(ParseLimits::new().steps(10_000));
+    let err = parser.parse_string(&"a".repeat(40), "Many").expect_err("Too many steps");
+    assert!(matches!(err, ParseError::LimitExceeded { limit: Limit::Steps(10_000), .. }), "{err:?}");
+    assert!(parser.parse_string("aaa", "Many").is_ok());
+
+    parser.set_limits(ParseLimits::new().depth(5));
+    let err = parser.parse_string("(((((x)))))", "Nested").expect_err("Too deep");
+    assert_eq!(err.to_string(), "Parse exceeded the rule depth limit of 5 in rule \"Nested\" at line 1, column 6");
+    assert!(parser.parse_string("((((x))))", "Nested").is_ok());
+
+    parser.set_limits(ParseLimits::new().memory(1 << 20));
+    let err = parser.parse_string(&"a".repeat(40), "Many").expect_err("Too much memory");
+    assert!(matches!(err, ParseError::LimitExceeded { limit: Limit::Memory(_), .. }), "{err:?}");
+    assert!(parser.parse_string("aaaa", "Many").is_ok());
+}
+
+#[test]
+fn session() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Sum "+" Term | Term ;
+        Term : Term "*" Num | Num ;
+        Num : [0-9]+ ;
+        Statement : Sum ";" ;
+    "##).expect("Parser definition ok");
+    parser.set_algorithm(ParseAlgorithm::Backtracking);
+
+    let mut session = parser.session();
+    let same = |left: Result<SyntaxTree<CharToken>, ParseError>, right: Result<SyntaxTree<CharToken>, ParseError>| 
+        assert_eq!(left.map(|tree| tree.to_string()).map_err(|err| err.to_string()), right.map(|tree| tree.to_string()).map_err(|err| err.to_string()));
+    for input in ["1+2*3", "45*6+7+8"] {
+        same(session.parse_string(input, "Sum"), parser.parse_string(input, "Sum"));
+        same(session.reparse_string("Term"), parser.parse_string(input, "Term"));
+        same(session.reparse_string("Sum"), parser.parse_string(input, "Sum"));
+    }
+
+    // Errors are the same as a fresh parse's, even though the memo had what Sum parsed.
+    assert_eq!(session.tokens().len(), 8);
+    same(session.reparse_string("Statement"), parser.parse_string("45*6+7+8", "Statement"));
+    same(session.parse_string("1+2;", "Statement"), parser.parse_string("1+2;", "Statement"));
+}
+
+#[test]
+fn stats() {
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Sum "+" Num | Num ;
+        Num : [0-9]+ ;
+    "##).expect("Parser definition ok");
+    parser.set_algorithm(ParseAlgorithm::Backtracking);
+
+    let (tree, stats) = parser.parse_string_with_stats("1+22+3", "Sum");
+    assert!(tree.is_ok());
+    assert_eq!(stats.tokens, 6);
+    assert!(stats.memo_hits > 0 && stats.memo_misses > 0 && stats.nodes > 0, "{stats:?}");
+    assert!(stats.continuations >= stats.peak_width && stats.peak_width >= 2, "{stats:?}");
+
+    let (err, stats) = parser.parse_string_with_stats("1+22+", "Sum");
+    assert!(err.is_err());
+    assert_eq!(stats.tokens, 5);
+
+    parser.set_algorithm(ParseAlgorithm::Earley);
+    let (_, stats) = parser.parse_string_with_stats("1+22+3", "Sum");
+    assert_eq!((stats.tokens, stats.memo_hits, stats.nodes), (6, 0, 0));
+    assert!(stats.continuations > 6);
+}
+
+#[test]
+fn profile() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Num ("+" Num)* ;
+        Num : Digit+ ;
+        Digit : [0-9] ;
+    "##).expect("Parser definition ok");
+
+    let (tree, profile) = parser.parse_string_with_profile("1+22+333", "Sum");
+    assert!(tree.is_ok());
+    assert_eq!(profile.rule("Sum").map(|rule| (rule.attempts, rule.matches)), Some((1, 1)));
+    assert_eq!(profile.rule("Num").map(|rule| (rule.attempts, rule.matches)), Some((3, 3)));
+    // Each digit is tried once, plus once after each number, where it fails.
+    assert_eq!(profile.rule("Digit").map(|rule| (rule.attempts, rule.matches)), Some((9, 6)));
+
+    let sum = profile.rule("Sum").expect("Sum was parsed");
+    assert!(sum.total_time >= sum.self_time);
+    assert!(profile.rules().windows(2).all(|pair| pair[0].self_time >= pair[1].self_time));
+    assert_eq!(profile.to_string().lines().count(), 4);
+    assert!(profile.to_string().starts_with("Rule   "));
+}
+
+#[test]
+fn earley_chart() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Sum "+" Num | Num ;
+        Num : "1" | "2" ;
+    "##).expect("Parser definition ok");
+
+    // The parse fails, but the chart is still there to look at.
+    let chart = parser.earley_chart(&string_to_tokens("1+"), "Sum").expect("Grammar is fine");
+    assert_eq!(chart.len(), 10);
+    let json = chart.to_json();
+    assert!(json.starts_with(r#"{"sets":[[{"item":"Sum → • Sum + Num","origin":0,"complete":false,"dead":false},"#), "{json}");
+    assert!(json.contains(r#"{"item":"Sum → Sum + • Num","origin":0,"complete":false,"dead":true}"#), "{json}");
+    assert!(json.contains(r#"{"from":[1,2],"to":[2,0],"kind":"advance"}"#), "{json}");
+    assert_eq!(json.matches(r#""kind":"predict""#).count(), 6);
+
+    let dot = chart.to_dot();
+    assert!(dot.contains(r#"s1_1 [label="Sum → Num •  (0)", style=bold];"#), "{dot}");
+    assert!(dot.contains(r#"s2_1 [label="Num → • 1  (2)", color=red, fontcolor=red];"#), "{dot}");
+    assert!(dot.contains("    s0_2 -> s1_0;\n"), "{dot}");
+}
+
+#[test]
+fn debugger() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Sum "+" Num | Num ;
+        Num : "1" | "2" ;
+    "##).expect("Parser definition ok");
+
+    let mut debugger = parser.debug_parse_string("1+2", "Sum").expect("Grammar is fine");
+    let frontier = debugger.frontier();
+    assert_eq!(frontier.index, 0);
+    assert_eq!(frontier.rules, vec![("Num".to_string(), 0), ("Sum".to_string(), 0)]);
+    assert_eq!(frontier.expected, vec!["1", "2"]);
+    assert!(frontier.items.contains(&("Sum → • Sum + Num".to_string(), 0)));
+
+    assert!(debugger.step().expect("1 is fine"));
+    assert_eq!(debugger.frontier().expected, vec!["+"]);
+    assert!(debugger.step().expect("+ is fine"));
+    let frontier = debugger.frontier();
+    assert_eq!(frontier.rules, vec![("Num".to_string(), 2), ("Sum".to_string(), 0)]);
+    assert!(frontier.items.contains(&("Sum → Sum + • Num".to_string(), 0)));
+
+    assert!(debugger.step().expect("2 is fine"));
+    assert!(debugger.is_done());
+    assert!(!debugger.step().expect("Nothing left"));
+    assert_eq!(debugger.finish().expect("Parses").to_string(), parser.parse_string("1+2", "Sum").expect("Parses").to_string());
+
+    // A token that can't be matched stops the debugger before it.
+    let mut debugger = parser.debug_parse_string("1+x", "Sum").expect("Grammar is fine");
+    assert!(debugger.step().expect("1 is fine"));
+    assert!(debugger.step().expect("+ is fine"));
+    assert!(matches!(debugger.step(), Err(ParseError::UnexpectedToken { index: 2, .. })));
+    assert_eq!(debugger.frontier().index, 2);
+    assert!(debugger.finish().is_err());
+}
+
+#[test]
+fn observers() {
+    let log = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let mut parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Sum : Num ("+" Num)* ;
+        Num : "1" | "2" ;
+    "##).expect("Parser definition ok");
+    let enter_log = log.clone();
+    parser.on_enter_rule(move |rule_name, index| enter_log.borrow_mut().push(format!("enter {rule_name} {index}")));
+    let exit_log = log.clone();
+    parser.on_exit_rule(move |rule_name, index, matched| exit_log.borrow_mut().push(format!("exit {rule_name} {index} {matched}")));
+    let token_log = log.clone();
+    parser.on_token(move |token, index| token_log.borrow_mut().push(format!("token {} {index}", token.token_type)));
+
+    let expected = vec![
+        "enter Sum 0", "enter Num 0", "token 1 0", "exit Num 0 true", "token + 1",
+        "enter Num 2", "token 2 2", "exit Num 2 true", "exit Sum 0 true",
+    ];
+    for algorithm in [ParseAlgorithm::Auto, ParseAlgorithm::Backtracking] {
+        parser.set_algorithm(algorithm);
+        parser.parse_string("1+2", "Sum").expect("Parses");
+        assert_eq!(log.take(), expected, "{algorithm:?}");
+    }
+
+    // Rules that go nowhere are reported too.
+    parser.parse_string("1+", "Sum").expect_err("Doesn't parse");
+    assert!(log.take().contains(&"exit Num 2 false".to_string()));
+
+    parser.set_algorithm(ParseAlgorithm::Earley);
+    parser.parse_string("1+2", "Sum").expect("Parses");
+    assert!(log.take().is_empty());
+}
+
+#[test]
+fn first_and_follow_sets() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Expr : Term ("+" Term)* ;
+        Term : Factor ("*" Factor)* ;
+        Factor : "(" Expr ")" | Num ;
+        Num : [0-9]+ Maybe ;
+        Maybe : "x"? ;
+    "##).expect("Parser definition ok");
+
+    let strings = |terminals: &[&str]| terminals.iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(parser.first_set("Expr").expect("Rule exists"), FirstSet { terminals: strings(&["(", "[0-9]"]), end: false, nullable: false });
+    assert_eq!(parser.first_set("Maybe").expect("Rule exists"), FirstSet { terminals: strings(&["x"]), end: false, nullable: true });
+
+    // Expr isn't used by any other rule, so it is the start rule, and the end can follow it.
+    assert_eq!(parser.follow_set("Expr").expect("Rule exists"), FollowSet { terminals: strings(&[")"]), end: true });
+    assert_eq!(parser.follow_set("Factor").expect("Rule exists"), FollowSet { terminals: strings(&[")", "*", "+"]), end: true });
+    assert_eq!(parser.follow_set("Maybe").expect("Rule exists"), parser.follow_set("Factor").expect("Rule exists"));
+
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        List : "(" Item* ")" ;
+        Item : "x" | List ;
+    "##).expect("Parser definition ok");
+    assert_eq!(parser.follow_set("List").expect("Rule exists"), FollowSet { terminals: strings(&["(", ")", "x"]), end: true });
+
+    assert!(matches!(parser.first_set("Exp"), Err(ParseError::UnknownRule { .. })));
+    assert!(matches!(parser.follow_set("Exp"), Err(ParseError::UnknownRule { .. })));
+}
+
+#[test]
+fn conflicts() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Statement : Word "=" Word | Word "(" ")" ;
+        Block : "{" Inner "}" ;
+        Inner : "x"? | "}" ;
+        List : Word ("," Word)* ","? ;
+        Word : [a-z]+ ;
+        Either : "a"? | "b"* ;
+        @ordered Keyword : [a-z] | "if" ;
+    "##).expect("Parser definition ok");
+
+    let strings = |terminals: &[&str]| terminals.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let at = |line, column, byte_offset| SourceLocation { line, column, byte_offset };
+    assert_eq!(parser.conflicts(), vec![
+        GrammarConflict::FirstFirst { 
+            rule_name: "Statement".to_string(), alternative: 1, with: 0, terminals: strings(&["[a-z]"]), locations: Some((at(2, 37, 37), at(2, 21, 21)))
+        },
+        GrammarConflict::FirstFollow { 
+            rule_name: "Inner".to_string(), alternative: 0, with: 1, terminals: strings(&["}"]), locations: Some((at(4, 17, 100), at(4, 24, 107)))
+        },
+        GrammarConflict::BothNullable { rule_name: "Either".to_string(), alternative: 1, with: 0, locations: Some((at(7, 25, 200), at(7, 18, 193))) },
+        GrammarConflict::RepetitionFollow { rule_name: "List".to_string(), terminals: strings(&[","]), location: Some(at(5, 31, 143)) },
+    ]);
+    assert_eq!(
+        parser.conflicts()[0].to_string(),
+        "Alternatives 0 and 1 of a choice in \"Statement\" can both start with \"[a-z]\" (at line 2, column 21 and line 2, column 37)"
+    );
+    assert_eq!(
+        parser.conflicts()[3].to_string(),
+        "Something optional or repeated in \"List\" can start with \",\", which can also follow it (at line 5, column 31)"
+    );
+
+    // Extending a rule adds to its choice, wherever the new alternatives are.
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Value : ("a" | "b") "c" ;
+        extend Value : "a" | "d" ;
+    "##).expect("Parser definition ok");
+
+    assert_eq!(parser.conflicts(), vec![
+        GrammarConflict::FirstFirst { 
+            rule_name: "Value".to_string(), alternative: 1, with: 0, terminals: strings(&["a"]), locations: Some((at(3, 24, 58), at(2, 17, 17)))
+        },
+    ]);
+}
+
+#[test]
+fn likely_ambiguities() {
+    let parser: Parser<CharToken> = crate::define::define_parser(r##"
+        Program : Expr | Pair | Greeting ;
+        Expr : Expr "+" Expr | "1" ;
+        Pair : "a"? "a"? ;
+        Greeting : Hi | Hello ;
+        Hi : ("h" | "h" "h")+ ;
+        Hello : "h" "i"? ;
+        @ordered Keyword : "i" "f" | "i" ;
+    "##).expect("Parser definition ok");
+
+    let strings = |terminals: &[&str]| terminals.iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(parser.likely_ambiguities(5), vec![
+        LikelyAmbiguity::Alternatives { rule_name: "Greeting".to_string(), alternative: 1, with: 0, example: strings(&["h"]) },
+        LikelyAmbiguity::Sequence { rule_name: "Expr".to_string(), example: strings(&["1", "+", "1", "+", "1"]) },
+        LikelyAmbiguity::Sequence { rule_name: "Pair".to_string(), example: strings(&["a"]) },
+        LikelyAmbiguity::Repetition { rule_name: "Hi".to_string(), example: strings(&["h", "h"]) },
+    ]);
+    assert_eq!(
+        parser.likely_ambiguities(5)[1].to_string(),
+        "A sequence in \"Expr\" can match \"1\" \"+\" \"1\" \"+\" \"1\" in more than one way"
+    );
+
+    // Expr "+" Expr needs five tokens to go wrong.
+    assert!(!parser.likely_ambiguities(4).iter().any(|found| matches!(found, LikelyAmbiguity::Sequence { rule_name, .. } if rule_name == "Expr")));
+}
@@ -0,0 +1,134 @@
+/* tracing spans and events from inside the parse, with the `trace` feature turned on,
+ * for looking at what a parse did with a tracing subscriber (or a flamegraph made from
+ * one) instead of adding prints. Without the feature, everything here does nothing and
+ * compiles away.
+ *
+ * Each parse is a "parse" span, naming the algorithm. The backtracking parser also
+ * makes a "rule" span for every rule it parses at some token, and trace level events
+ * for memo hits, the alternatives it tries, and the failures that make it back up. */
+
+#[cfg(feature = "trace")]
+mod enabled {
+    use tracing::span::EnteredSpan;
+
+    pub(crate) struct SpanGuard (#[allow(dead_code)] EnteredSpan);  // Only held to be dropped.
+
+    pub(crate) fn parse_span(algorithm: &str, start_rule: &str, tokens: usize) -> SpanGuard {
+        SpanGuard(tracing::debug_span!("parse", algorithm, start_rule, tokens).entered())
+    }
+
+    /* The rules being parsed, innermost last. Rules end in the opposite order they
+     * start, so the spans can be kept on a stack. */
+    pub(crate) struct RuleSpans (Vec<EnteredSpan>);
+
+    impl RuleSpans {
+        pub(crate) fn new() -> RuleSpans {
+            RuleSpans(vec![])
+        }
+
+        pub(crate) fn enter(&mut self, rule_name: &str, index: usize) {
+            self.0.push(tracing::debug_span!("rule", rule = rule_name, index).entered());
+        }
+
+        pub(crate) fn exit(&mut self, matches: usize) {
+            tracing::trace!(matches, "rule done");
+            self.0.pop();
+        }
+    }
+
+    pub(crate) fn memo_hit(index: usize) {
+        tracing::trace!(index, "memo hit");
+    }
+
+    pub(crate) fn alternative(alternative: usize, index: usize) {
+        tracing::trace!(alternative, index, "trying alternative");
+    }
+
+    pub(crate) fn failure(expected: &str, index: usize) {
+        tracing::trace!(expected, index, "failed");
+    }
+}
+
+#[cfg(not(feature = "trace"))]
+mod enabled {
+    pub(crate) struct SpanGuard;
+
+    pub(crate) fn parse_span(_algorithm: &str, _start_rule: &str, _tokens: usize) -> SpanGuard {
+        SpanGuard
+    }
+
+    pub(crate) struct RuleSpans;
+
+    impl RuleSpans {
+        pub(crate) fn new() -> RuleSpans {
+            RuleSpans
+        }
+        pub(crate) fn enter(&mut self, _rule_name: &str, _index: usize) {}
+        pub(crate) fn exit(&mut self, _matches: usize) {}
+    }
+
+    pub(crate) fn memo_hit(_index: usize) {}
+    pub(crate) fn alternative(_alternative: usize, _index: usize) {}
+    pub(crate) fn failure(_expected: &str, _index: usize) {}
+}
+
+pub(crate) use enabled::*;
+
+
+#[cfg(all(test, feature = "trace"))]
+mod tests {
+    use crate::{define_parser, CharToken, ParseAlgorithm, Parser};
+
+    use std::sync::{Arc, Mutex};
+    use tracing::{Event, Metadata, span::{Attributes, Id, Record}};
+
+    #[derive(Clone, Default)]
+    struct Recorder (Arc<Mutex<Recorded>>);
+
+    #[derive(Clone, Default)]
+    struct Recorded {
+        spans: Vec<String>,  // Their names.
+        events: Vec<Vec<String>>,  // The names of their fields.
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut recorded = self.0.lock().expect("Not poisoned");
+            recorded.spans.push(span.metadata().name().to_string());
+            Id::from_u64(recorded.spans.len() as u64)
+        }
+
+        fn event(&self, event: &Event<'_>) {
+            let fields = event.metadata().fields().iter().map(|field| field.name().to_string()).collect();
+            self.0.lock().expect("Not poisoned").events.push(fields);
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut parser: Parser<CharToken> = define_parser(r#"
+            Greeting : Word ("," Word)* ;
+            Word : "hi" | "hello" ;
+        "#).expect("Parser definition ok");
+        parser.set_algorithm(ParseAlgorithm::Backtracking);
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || parser.parse_string("hello,hi", "Greeting").expect("Parses"));
+
+        let Recorded { spans, events } = recorder.0.lock().expect("Not poisoned").clone();
+        assert_eq!(spans, vec!["parse", "rule", "rule", "rule"]);
+        let has_field = |name: &str| events.iter().filter(|fields| fields.iter().any(|field| field == name)).count();
+        assert_eq!(has_field("alternative"), 4);
+        assert_eq!(has_field("matches"), 3);
+        assert!(has_field("expected") > 0);
+    }
+}
@@ -0,0 +1,80 @@
+/* Reshaping syntax trees. Trees straight out of the parser have a node for every rule
+ * that matched, which is a lot of nodes, most of them uninteresting. These take a
+ * tree and give back a new one with less in it, on the way to an abstract syntax
+ * tree. They can be chained, like tree.remove_rules(&["Space"]).collapse_chains().
+ *
+ * Spans aren't changed, so they still point into the tokens that were parsed. The
+ * root node is never removed or lifted, since a tree needs a root, but collapsing a
+ * chain can replace it with the rule at the bottom of the chain. */
+
+use super::{SyntaxTree, Token};
+
+
+impl<T: Token> SyntaxTree<T> {
+    /* Drops every node of the given rules, along with everything under them. */
+    pub fn remove_rules(self, rule_names: &[&str]) -> SyntaxTree<T> {
+        self.rewrite(&mut |node| match &node {
+            SyntaxTree::RuleNode {rule_name, ..} if rule_names.contains(&rule_name.as_str()) => vec![],
+            _ => vec![node],
+        })
+    }
+
+    /* Replaces every node of the given rules with its children, so the children
+     * belong to the rule around it instead. Handy for rules that only exist to group
+     * things, like a list's separators. */
+    pub fn lift_rules(self, rule_names: &[&str]) -> SyntaxTree<T> {
+        self.rewrite(&mut |node| match node {
+            SyntaxTree::RuleNode {rule_name, subexpressions, ..} if rule_names.contains(&rule_name.as_str()) => subexpressions,
+            node => vec![node],
+        })
+    }
+
+    /* Replaces every rule node that has a single child rule node with that child, so a
+     * chain like Expr -> Term -> Factor -> Number becomes just Number. Rules with a
+     * single token under them are left alone, since the rule name says what the token is. */
+    pub fn collapse_chains(self) -> SyntaxTree<T> {
+        let collapse = |node| match node {
+            SyntaxTree::RuleNode {mut subexpressions, ..}
+                    if subexpressions.len() == 1 && matches!(subexpressions[0], SyntaxTree::RuleNode {..}) => subexpressions.remove(0),
+            node => node,
+        };
+
+        let root = self.rewrite(&mut |node| vec![collapse(node)]);
+        collapse(root)
+    }
+
+    /* Turns every token into another, possibly of another type, keeping the shape of
+     * the tree. Tokens are visited in order, including those in error nodes. */
+    pub fn map_tokens<U: Token>(self, mut f: impl FnMut(T) -> U) -> SyntaxTree<U> {
+        self.fold_owned(|node, subexpressions| match node {
+            SyntaxTree::RuleNode {rule_name, span, ..} => SyntaxTree::RuleNode {rule_name, subexpressions, span},
+            SyntaxTree::TokenNode {token, index, captured} => SyntaxTree::TokenNode {token: f(token), index, captured},
+            SyntaxTree::ErrorNode {tokens, expected, span} =>
+                SyntaxTree::ErrorNode {tokens: tokens.into_iter().map(&mut f).collect(), expected, span},
+        })
+    }
+}
+
+
+/* Private Implementation */
+
+impl<T: Token> SyntaxTree<T> {
+    /* Rebuilds the tree from the bottom up, replacing every node below the root with
+     * whatever f gives back for it, after its own children have been replaced. */
+    fn rewrite(self, f: &mut impl FnMut(SyntaxTree<T>) -> Vec<SyntaxTree<T>>) -> SyntaxTree<T> {
+        match self {
+            SyntaxTree::RuleNode {rule_name, subexpressions, span} => {
+                let subexpressions = subexpressions.into_iter()
+                    .flat_map(|subexpression| subexpression.fold_owned(|mut node, children: Vec<Vec<SyntaxTree<T>>>| {
+                        if let SyntaxTree::RuleNode {subexpressions, ..} = &mut node {
+                            *subexpressions = children.into_iter().flatten().collect();
+                        }
+                        f(node)
+                    }))
+                    .collect();
+                SyntaxTree::RuleNode {rule_name, subexpressions, span}
+            }
+            node => node,
+        }
+    }
+}
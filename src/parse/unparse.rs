@@ -0,0 +1,55 @@
+/* Turning trees back into text, for tools that rewrite source code: parse it, change
+ * the tree, and unparse the result. Tokens are written with Display, which for
+ * CharTokens and LexedTokens is exactly the text they were made from. */
+
+use super::{LexedToken, SyntaxTree, Token};
+
+use std::fmt::Display;
+
+
+impl<T: Token + Display> SyntaxTree<T> {
+    /* The text of every token in the tree, in order, including the ones in error nodes.
+     * Trivia isn't in the tree, so for LexedTokens, see unparse_with_trivia. */
+    pub fn unparse(&self) -> String {
+        self.unparse_with(|_, _| None)
+    }
+
+    /* Like unparse, but the hook can write rule nodes its own way, for pretty printing.
+     * It is given each rule node and its children already unparsed, and returns the
+     * text for the node, or None to just join the children. For example, to put each
+     * statement on its own line:
+     *
+     *     tree.unparse_with(|node, children| match node {
+     *         SyntaxTree::RuleNode { rule_name, .. } if rule_name == "Statement" => Some(children.join(" ") + "\n"),
+     *         _ => None,
+     *     })
+     */
+    pub fn unparse_with(&self, mut hook: impl FnMut(&SyntaxTree<T>, &[String]) -> Option<String>) -> String {
+        self.fold(|node, children| match node {
+            SyntaxTree::RuleNode { .. } => hook(node, &children).unwrap_or_else(|| children.concat()),
+            SyntaxTree::TokenNode { token, .. } => token.to_string(),
+            SyntaxTree::ErrorNode { tokens, .. } => tokens.iter().map(ToString::to_string).collect(),
+        })
+    }
+}
+
+impl SyntaxTree<LexedToken> {
+    /* Like unparse, but puts back the trivia (from parse_string_with_trivia) that sits
+     * between this node's tokens, so comments and whitespace survive the round trip.
+     * Trivia before the node's first token or after its last isn't included. */
+    pub fn unparse_with_trivia(&self, trivia: &[LexedToken]) -> String {
+        let Some(span) = self.source_span() else {
+            return self.unparse();
+        };
+
+        let mut tokens = vec![];
+        self.walk(|node, entering| match node {
+            SyntaxTree::TokenNode { token, .. } if entering => tokens.push(token),
+            SyntaxTree::ErrorNode { tokens: error_tokens, .. } if entering => tokens.extend(error_tokens),
+            _ => (),
+        });
+        tokens.extend(trivia.iter().filter(|token| span.start <= token.span.start && token.span.end <= span.end));
+        tokens.sort_by_key(|token| token.span.start);
+        tokens.into_iter().map(|token| token.text.as_str()).collect()
+    }
+}
@@ -0,0 +1,125 @@
+/* Rendering parse errors for people, as the line of input with the problem on it and
+ * a caret underneath, like compilers do:
+ *
+ *     error: Unexpected token at line 2, column 9, expected Expr
+ *       |
+ *     2 | let x = ;
+ *       |         ^
+ *       = while parsing Program > Statement > Expr
+ *
+ * Everything is plain text, so it's up to you to add colors. */
+
+use crate::{LineMap, ParseError, SourceLocation};
+
+
+/* Renders the error against the source it came from. Syntax errors point at their
+ * token: by its span if it knows one, and otherwise by treating the token index as a
+ * character index, which is right for anything parsed with parse_string. The end of
+ * input is pointed at just past the last character. Other errors are only their
+ * message, since they aren't about any place in the source. */
+pub fn render(source: &str, err: &ParseError) -> String {
+    let line_map = LineMap::new(source);
+    let mut report = format!("error: {err}\n");
+
+    let (start, end, context) = match err {
+        ParseError::UnexpectedToken { span: Some(span), context, .. } if span.end <= source.len() => 
+            (line_map.location_of_byte(span.start), line_map.location_of_byte(span.end), context),
+        ParseError::UnexpectedToken { index, location, context, .. } => {
+            let start = location.unwrap_or_else(|| line_map.location_of_char(*index));
+            let width = source[start.byte_offset..].chars().next().map_or(0, char::len_utf8);
+            (start, line_map.location_of_byte(start.byte_offset + width), context)
+        }
+        ParseError::UnexpectedEof { context, .. } => {
+            let end = line_map.location_of_byte(source.len());
+            (end, end, context)
+        }
+        _ => return report,
+    };
+
+    write_snippet(&mut report, source, start, end);
+    if !context.is_empty() {
+        let gutter = " ".repeat(start.line.to_string().len());
+        report += &format!("{gutter} = while parsing {}\n", context.join(" > "));
+    }
+    report
+}
+
+
+/* Private Implementation */
+
+// The line that start is on, with carets under everything from start to end (or at least one caret, and no further than the end of the line).
+fn write_snippet(report: &mut String, source: &str, start: SourceLocation, end: SourceLocation) {
+    let line_start = source[..start.byte_offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[line_start..].find('\n').map_or(source.len(), |i| line_start + i);
+    let line = source[line_start..line_end].trim_end_matches('\r');
+
+    let width = if end.line == start.line { end.column.saturating_sub(start.column).max(1) } else { 1 };
+    let width = width.min((line.chars().count() + 2).saturating_sub(start.column).max(1));
+
+    let number = start.line.to_string();
+    let gutter = " ".repeat(number.len());
+    report.push_str(&format!("{gutter} |\n{number} | {line}\n"));
+    report.push_str(&format!("{gutter} | {}{}\n", " ".repeat(start.column - 1), "^".repeat(width)));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{define_parser, CharToken, LexedToken, Parser};
+
+    use indoc::indoc;
+
+    #[test]
+    fn test_render() {
+        let parser: Parser<CharToken> = define_parser(r#"
+            Program : (Statement "\n")* ;
+            Statement : "let " Name " = " Name ";" ;
+            Name : [a-z]+ ;
+        "#).expect("Parser definition ok");
+
+        let source = "let a = b;\nlet c = ;\n";
+        let err = parser.parse_string(source, "Program").expect_err("Parse fails");
+        assert_eq!(render(source, &err), indoc! {"
+            error: Unexpected token at line 2, column 9, expected Name
+              |
+            2 | let c = ;
+              |         ^
+              = while parsing Program > Statement > Name
+        "});
+
+        let source = "let a = b;\nlet c = d";
+        let err = parser.parse_string(source, "Program").expect_err("Parse fails");
+        assert_eq!(render(source, &err), indoc! {"
+            error: Unexpected end of input, expected ; or [a-z]
+              |
+            2 | let c = d
+              |          ^
+              = while parsing Program > Statement > Name
+        "});
+
+        let err = parser.parse_string(source, "Missing").expect_err("Parse fails");
+        assert_eq!(render(source, &err), "error: Rule \"Missing\" not found\n");
+    }
+
+    #[test]
+    fn test_render_span() {
+        let parser: Parser<LexedToken> = define_parser(r#"
+            @skip Space : " "+ ;
+            @token Number : [0-9]+ ;
+            @token Plus : "+" ;
+            Sum : Number (Plus Number)* ;
+        "#).expect("Parser definition ok");
+
+        let source = "12 + 345 678";
+        let tokens = parser.tokenize(source).expect("Tokenizes");
+        let err = parser.parse_tokens(&tokens, "Sum").expect_err("Parse fails");
+        assert_eq!(render(source, &err), indoc! {"
+            error: Unexpected token at index 3, expected Plus
+              |
+            1 | 12 + 345 678
+              |          ^^^
+              = while parsing Sum
+        "});
+    }
+}
@@ -0,0 +1,104 @@
+/* serde::Serialize and Deserialize for SyntaxTree, with the `serde` feature turned on.
+ *
+ * Trees are written like the enum is declared, so in JSON a rule node looks like
+ * {"RuleNode": {"rule_name": "Expr", "subexpressions": [...], "span": {"start": 0, "end": 3}}}.
+ * Captured values can't be written, since they could be anything, so trees read back
+ * in don't have them. The expected set of an error node is written sorted, so the
+ * same tree always comes out the same.
+ *
+ * Deep trees nest deeply, and serde_json refuses to read past 128 levels unless its
+ * recursion limit is turned off. */
+
+use crate::{SyntaxTree, Token};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+
+impl<T: Token + Serialize> Serialize for SyntaxTree<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let borrowed = match self {
+            SyntaxTree::RuleNode {rule_name, subexpressions, span} => BorrowedTree::RuleNode {rule_name, subexpressions, span},
+            SyntaxTree::TokenNode {token, index, ..} => BorrowedTree::TokenNode {token, index: *index},
+            SyntaxTree::ErrorNode {tokens, expected, span} => {
+                let mut expected = expected.iter().map(String::as_str).collect::<Vec<_>>();
+                expected.sort_unstable();
+                BorrowedTree::ErrorNode {tokens, expected, span}
+            }
+        };
+        borrowed.serialize(serializer)
+    }
+}
+
+impl<'de, T: Token + Deserialize<'de>> Deserialize<'de> for SyntaxTree<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match OwnedTree::deserialize(deserializer)? {
+            OwnedTree::RuleNode {rule_name, subexpressions, span} => SyntaxTree::RuleNode {rule_name: rule_name.into(), subexpressions, span},
+            OwnedTree::TokenNode {token, index} => SyntaxTree::TokenNode {token, index, captured: None},
+            OwnedTree::ErrorNode {tokens, expected, span} => SyntaxTree::ErrorNode {tokens, expected, span},
+        })
+    }
+}
+
+
+/* Private Implementation */
+
+// SyntaxTree as it is written and read. The derives do the work, these only leave out captured values.
+#[derive(Serialize)]
+#[serde(rename = "SyntaxTree")]
+#[allow(clippy::enum_variant_names)]  // They have to match SyntaxTree's.
+enum BorrowedTree<'a, T: Token> {
+    RuleNode {rule_name: &'a str, subexpressions: &'a [SyntaxTree<T>], span: &'a Range<usize>},
+    TokenNode {token: &'a T, index: usize},
+    ErrorNode {tokens: &'a [T], expected: Vec<&'a str>, span: &'a Range<usize>},
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "SyntaxTree")]
+#[allow(clippy::enum_variant_names)]  // They have to match SyntaxTree's.
+enum OwnedTree<T: Token> {
+    RuleNode {rule_name: String, subexpressions: Vec<SyntaxTree<T>>, span: Range<usize>},
+    TokenNode {token: T, index: usize},
+    ErrorNode {tokens: Vec<T>, expected: HashSet<String>, span: Range<usize>},
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{define_parser, CharToken, LexedToken, Parser, SyntaxTree};
+
+    #[test]
+    fn test_round_trip() {
+        let parser: Parser<LexedToken> = define_parser(r#"
+            @skip Space : " "+ ;
+            @token Name : [a-z]+ ;
+            @token Punctuation : [=;] ;
+            Statement : Name "=" Name ";" ;
+        "#).expect("Parser definition ok");
+        let tokens = parser.tokenize("a = b;").expect("Tokenizes");
+        let tree = parser.parse_tokens(&tokens, "Statement").expect("Parses");
+
+        let json = serde_json::to_string(&tree).expect("Serializes");
+        assert!(json.starts_with(r#"{"RuleNode":{"rule_name":"Statement","subexpressions":[{"TokenNode":{"token":{"kind":"Name","text":"a","span":{"start":0,"end":1}},"index":0}}"#));
+        let read: SyntaxTree<LexedToken> = serde_json::from_str(&json).expect("Deserializes");
+        assert_eq!(read.to_string(), tree.to_string());
+        assert_eq!(read.span(), 0..4);
+    }
+
+    #[test]
+    fn test_error_nodes() {
+        let parser: Parser<CharToken> = define_parser(r#"
+            List : (Item ";")* ;
+            Item : [a-z]+ ;
+            recover Item : ";" ;
+        "#).expect("Parser definition ok");
+        let (tree, _) = parser.parse_string_recovering("ab;1;c;", "List").expect("Recovers");
+
+        let json = serde_json::to_string(&tree).expect("Serializes");
+        assert!(json.contains(r#"{"ErrorNode":{"tokens":[{"token_type":"1"},{"token_type":";"}],"expected":["[a-z]"],"span":{"start":3,"end":5}}}"#));
+        let read: SyntaxTree<CharToken> = serde_json::from_str(&json).expect("Deserializes");
+        assert_eq!(serde_json::to_string(&read).expect("Serializes"), json);
+    }
+}
@@ -0,0 +1,57 @@
+use parsley::{FromSyntaxTree, LexedToken, Parser};
+
+// The generated code, checked in so that the test below notices when it changes.
+#[path = "codegen/ast.rs"]
+mod ast;
+
+
+fn parser() -> Parser<LexedToken> {
+    parsley::define_parser(r#"
+        @skip Space : [ \n]+ ;
+        @token Keyword : "let" | "print" ;
+        @token Name : [a-z]+ ;
+        @token Digits : [0-9]+ ;
+        @token Punctuation : [=;,+*()] ;
+        Program : Statement* $ ;
+        Statement : Let | Print | Empty ;
+        Let : "let" Name "=" Expr ";" ;
+        Print : "print" Expr ("," Expr)* ";" ;
+        Empty : ";" ;
+        Expr : Sum | Term ;
+        Sum : Expr "+" Term ;
+        Term : Atom ("*" Atom)* ;
+        Atom : Name | Digits | "(" Expr ")" ;
+    "#).expect("Parser definition ok")
+}
+
+#[test]
+fn generated_code() {
+    assert_eq!(parsley::codegen::generate_ast(&parser()), include_str!("codegen/ast.rs"));
+}
+
+#[test]
+fn generated_types() {
+    let parser = parser();
+    let tokens = parser.tokenize("let x = 1 + 2 * y; print (x), 3; ;").expect("Tokenizes");
+    let tree = parser.parse_tokens(&tokens, "Program").expect("Parses");
+    let program = ast::Program::from_syntax_tree(&tree).expect("Fits");
+
+    let atom = |name: Option<&str>, digits: Option<&str>| ast::Atom {
+        name: name.map(str::to_string), digits: digits.map(str::to_string), expr: None,
+    };
+    let term = |atoms| ast::Expr::Term(Box::new(ast::Term { atoms }));
+    assert_eq!(program.statements, vec![
+        ast::Statement::Let(ast::Let {
+            name: "x".to_string(),
+            expr: ast::Expr::Sum(Box::new(ast::Sum {
+                expr: Box::new(term(vec![atom(None, Some("1"))])),
+                term: Box::new(ast::Term { atoms: vec![atom(None, Some("2")), atom(Some("y"), None)] }),
+            })),
+        }),
+        ast::Statement::Print(ast::Print { exprs: vec![
+            term(vec![ast::Atom { name: None, digits: None, expr: Some(Box::new(term(vec![atom(Some("x"), None)]))) }]),
+            term(vec![atom(None, Some("3"))]),
+        ]}),
+        ast::Statement::Empty(ast::Empty),
+    ]);
+}
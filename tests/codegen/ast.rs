@@ -0,0 +1,90 @@
+// Generated from the grammar by parsley::codegen::generate_ast, edits will be lost.
+
+#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+pub struct Atom {
+    #[token("Name")] pub name: Option<String>,
+    #[token("Digits")] pub digits: Option<String>,
+    pub expr: Option<Box<Expr>>,
+}
+
+#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+pub struct Empty;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Sum(Box<Sum>),
+    Term(Box<Term>),
+}
+
+impl<T: ::parsley::Token + std::fmt::Display> ::parsley::FromSyntaxTree<T> for Expr {
+    fn from_syntax_tree(tree: &::parsley::SyntaxTree<T>) -> Result<Self, ::parsley::ShapeError> {
+        let tree = ::parsley::FieldCursor::look_through(tree, "Expr")?;
+        match tree.rule_name() {
+            Some("Sum") => ::parsley::FromSyntaxTree::from_syntax_tree(tree).map(Expr::Sum),
+            Some("Term") => ::parsley::FromSyntaxTree::from_syntax_tree(tree).map(Expr::Term),
+            _ => Err(::parsley::unexpected_shape(tree, "Sum or Term")),
+        }
+    }
+
+    fn accepts(tree: &::parsley::SyntaxTree<T>) -> bool {
+        tree.rule_name() == Some("Expr")
+    }
+
+    fn describe() -> String {
+        String::from("Expr")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+pub struct Let {
+    #[token("Name")] pub name: String,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+pub struct Print {
+    pub exprs: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+pub struct Program {
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Let(Let),
+    Print(Print),
+    Empty(Empty),
+}
+
+impl<T: ::parsley::Token + std::fmt::Display> ::parsley::FromSyntaxTree<T> for Statement {
+    fn from_syntax_tree(tree: &::parsley::SyntaxTree<T>) -> Result<Self, ::parsley::ShapeError> {
+        let tree = ::parsley::FieldCursor::look_through(tree, "Statement")?;
+        match tree.rule_name() {
+            Some("Let") => ::parsley::FromSyntaxTree::from_syntax_tree(tree).map(Statement::Let),
+            Some("Print") => ::parsley::FromSyntaxTree::from_syntax_tree(tree).map(Statement::Print),
+            Some("Empty") => ::parsley::FromSyntaxTree::from_syntax_tree(tree).map(Statement::Empty),
+            _ => Err(::parsley::unexpected_shape(tree, "Let or Print or Empty")),
+        }
+    }
+
+    fn accepts(tree: &::parsley::SyntaxTree<T>) -> bool {
+        tree.rule_name() == Some("Statement")
+    }
+
+    fn describe() -> String {
+        String::from("Statement")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+pub struct Sum {
+    pub expr: Box<Expr>,
+    pub term: Box<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq, ::parsley::FromSyntaxTree)]
+pub struct Term {
+    pub atoms: Vec<Atom>,
+}
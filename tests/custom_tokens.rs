@@ -4,17 +4,33 @@ use parsley::Token;
 #[derive(Debug, Clone)]
 struct CustomToken (String);
 
+#[derive(Debug, Clone)]
+enum CustomKind {
+    NonKeyword,
+    KeywordFor,
+    KeywordWhile,
+}
+
 impl Token for CustomToken {
-    fn matches(token_type: &str, token: &Self) -> Result<bool, parsley::ParseError> {
+    type Kind = CustomKind;
+
+    fn kind(token_type: &str) -> Option<CustomKind> {
         match token_type {
-            "NonKeyword" => 
-                Ok(token.0 != "for" && token.0 != "while"),
-            "KeywordFor" => 
-                Ok(token.0 == "for"),
-            "KeywordWhile" => 
-                Ok(token.0 == "while"),
-            _ => 
-                Err("Bad token type".into())
+            "NonKeyword" => Some(CustomKind::NonKeyword),
+            "KeywordFor" => Some(CustomKind::KeywordFor),
+            "KeywordWhile" => Some(CustomKind::KeywordWhile),
+            _ => None,
+        }
+    }
+
+    fn matches(kind: &CustomKind, token: &Self) -> bool {
+        match kind {
+            CustomKind::NonKeyword => 
+                token.0 != "for" && token.0 != "while",
+            CustomKind::KeywordFor => 
+                token.0 == "for",
+            CustomKind::KeywordWhile => 
+                token.0 == "while",
         }
     }
 }
@@ -76,4 +92,64 @@ fn custom_tokens() {
     ];
 
     parser.parse_tokens(&tokens, "Program").expect_err("Parse should fail");
-}
\ No newline at end of file
+
+    // Misspelled token types are caught when the parser is defined.
+    assert!(parsley::define_parser::<CustomToken>("Program : _KeywordDo ;").is_err());
+}
+
+#[test]
+fn character_classes_need_char_tokens() {
+    let parser = parsley::define_parser::<CustomToken>(r#"
+        Program : [a-z] ;
+    "#).expect("Defined successfully");
+
+    parser.parse_tokens(&[CustomToken("a".to_string())], "Program").expect_err("Parse should fail");
+}
+
+#[derive(Debug, Clone, parsley::ParsleyToken)]
+enum DerivedToken {
+    #[token("Keyword")]
+    For,
+    #[token("Keyword")]
+    While,
+    Ident (String),
+    Number { value: i64 },
+    Semicolon,
+}
+
+impl std::fmt::Display for DerivedToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DerivedToken::Ident(name) => f.write_str(name),
+            DerivedToken::Number { value } => write!(f, "{value}"),
+            other => write!(f, "{other:?}"),
+        }
+    }
+}
+
+#[test]
+fn derived_tokens() {
+    let parser = parsley::define_parser::<DerivedToken>(r#"
+        Loop : _Keyword (_Ident | _Number)+ ;
+    "#).expect("Defined successfully");
+
+    let tokens = vec![
+        DerivedToken::While,
+        DerivedToken::Ident("x".to_string()),
+        DerivedToken::Number { value: 3 },
+    ];
+    assert_eq!(parser.parse_tokens(&tokens, "Loop").expect("Parsed successfully").to_string(), indoc::indoc!{"
+    Syntax Tree {
+        Loop
+            token (While)
+            token (x)
+            token (3)
+    }"});
+    assert!(parser.parse_tokens(&[DerivedToken::For, DerivedToken::Semicolon], "Loop").is_err());
+
+    let keyword = DerivedToken::kind("Keyword").expect("Known token type");
+    assert!(DerivedToken::matches(&keyword, &DerivedToken::For));
+    assert!(!DerivedToken::matches(&keyword, &DerivedToken::Semicolon));
+    assert!(DerivedToken::kind("For").is_none());
+    assert!(parsley::define_parser::<DerivedToken>("Loop : _For ;").is_err());
+}
@@ -0,0 +1,102 @@
+use parsley::{FromSyntaxTree, LexedToken, Parser, ShapeError, SyntaxTree};
+
+
+#[derive(Debug, PartialEq, FromSyntaxTree)]
+struct Program {
+    functions: Vec<FunctionDecl>,
+}
+
+#[derive(Debug, PartialEq, FromSyntaxTree)]
+struct FunctionDecl {
+    #[token("Name")]
+    name: String,
+    params: Option<ParamList>,
+    body: Vec<Expr>,
+}
+
+#[derive(Debug, PartialEq, FromSyntaxTree)]
+struct ParamList (#[rule("Param")] Vec<String>);
+
+#[derive(Debug, PartialEq, FromSyntaxTree)]
+#[rule("Expr")]
+enum Expr {
+    #[rule("Sum")]
+    Add(Box<Expr>, Box<Expr>),
+    Number(#[token("Digits")] String),
+    #[rule("Neg")]
+    Negate { inner: Box<Expr> },
+    Nothing,
+}
+
+fn parser() -> Parser<LexedToken> {
+    parsley::define_parser(r#"
+        @skip Space : [ \n]+ ;
+        @token Keyword : "fn" ;
+        @token Name : [a-z]+ ;
+        @token Digits : [0-9]+ ;
+        @token Punctuation : [(),{};+\-] ;
+        Program : FunctionDecl* ;
+        FunctionDecl : "fn" Name "(" ParamList? ")" "{" (Expr ";")* "}" ;
+        ParamList : Param ("," Param)* ;
+        Param : Name ;
+        Expr : Sum | Number | Neg | Nothing ;
+        Sum : Expr "+" Expr ;
+        Number : Digits ;
+        Neg : "-" Expr ;
+        Nothing : "(" ")" ;
+    "#).expect("Parser definition ok")
+}
+
+fn convert<X: FromSyntaxTree<LexedToken>>(input: &str, rule_name: &str) -> Result<X, ShapeError> {
+    let parser = parser();
+    let tokens = parser.tokenize(input).expect("Tokenizes");
+    X::from_syntax_tree(&parser.parse_tokens(&tokens, rule_name).expect("Parses"))
+}
+
+#[test]
+fn derived_types() {
+    let program = convert::<Program>("fn main() { 1; -2; () ; } fn f(a, b) {}", "Program").expect("Fits");
+    assert_eq!(program, Program { functions: vec![
+        FunctionDecl {
+            name: "main".to_string(),
+            params: None,
+            body: vec![
+                Expr::Number("1".to_string()),
+                Expr::Negate { inner: Box::new(Expr::Number("2".to_string())) },
+                Expr::Nothing,
+            ],
+        },
+        FunctionDecl {
+            name: "f".to_string(),
+            params: Some(ParamList(vec!["a".to_string(), "b".to_string()])),
+            body: vec![],
+        },
+    ]});
+
+    assert_eq!(convert::<Expr>("1 + 2", "Expr"),
+        Ok(Expr::Add(Box::new(Expr::Number("1".to_string())), Box::new(Expr::Number("2".to_string())))));
+    assert_eq!(convert::<Expr>("3", "Number"), Ok(Expr::Number("3".to_string())));
+    assert!(matches!(convert::<SyntaxTree<LexedToken>>("3", "Number"), Ok(SyntaxTree::RuleNode { .. })));
+}
+
+#[test]
+fn shape_errors() {
+    let err = convert::<FunctionDecl>("7", "Expr").expect_err("Wrong rule");
+    assert_eq!(err.message, "Expected FunctionDecl for FunctionDecl, found Expr");
+    assert_eq!(err.to_string(), "Expected FunctionDecl for FunctionDecl, found Expr (tokens 0..1)");
+
+    let err = convert::<Expr>("fn f() {}", "FunctionDecl").expect_err("Wrong rule");
+    assert_eq!(err.message, "Expected Sum or Number or Neg or Nothing, found FunctionDecl");
+
+    #[derive(Debug, FromSyntaxTree)]
+    #[allow(dead_code)]
+    struct FunctionDecl {
+        #[token("Name")]
+        name: String,
+        params: ParamList,
+    }
+    let err = convert::<FunctionDecl>("fn f() {}", "FunctionDecl").expect_err("No params");
+    assert_eq!(err.message, "Expected ParamList for field `params` of FunctionDecl, found nothing");
+    let err = convert::<FunctionDecl>("fn f(x) { 1; }", "FunctionDecl").expect_err("Body left over");
+    assert_eq!((err.message.as_str(), err.span.clone()), ("Unexpected Expr in FunctionDecl", 6..7));
+}